@@ -0,0 +1,128 @@
+use dfu_nusb::dfuse_file::Element;
+use dfu_nusb::error::Error;
+use dfu_nusb::MemoryLayout;
+
+const PT_LOAD: u32 = 1;
+
+fn u16_at(buf: &[u8], at: usize) -> Result<u16, Error> {
+    buf.get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| Error::Argument(format!("ELF truncated at offset {}", at)))
+}
+
+fn u32_at(buf: &[u8], at: usize) -> Result<u32, Error> {
+    buf.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| Error::Argument(format!("ELF truncated at offset {}", at)))
+}
+
+/// The loadable segments of a 32 bit little endian ELF at their load (physical) addresses,
+/// sorted and with adjacent segments merged. `.data` is placed in flash after `.text` this way.
+pub fn loadable(buf: &[u8]) -> Result<Vec<Element>, Error> {
+    if buf.get(..4) != Some(b"\x7FELF") {
+        return Err(Error::Argument("not an ELF file".into()));
+    }
+    if buf.get(4) != Some(&1) || buf.get(5) != Some(&1) {
+        return Err(Error::Argument("only 32 bit little endian ELF files can be flashed".into()));
+    }
+    let (phoff, phentsize, phnum) = (u32_at(buf, 0x1C)? as usize, u16_at(buf, 0x2A)? as usize, u16_at(buf, 0x2C)?);
+    let mut segments = Vec::new();
+    for i in 0..phnum as usize {
+        let ph = phoff + i * phentsize;
+        let (kind, offset, address, size) = (u32_at(buf, ph)?, u32_at(buf, ph + 4)?, u32_at(buf, ph + 12)?, u32_at(buf, ph + 16)?);
+        if kind != PT_LOAD || size == 0 {
+            continue;
+        }
+        let data = buf
+            .get(offset as usize..offset as usize + size as usize)
+            .ok_or_else(|| Error::Argument(format!("ELF segment {} runs past the end of the file", i)))?;
+        segments.push(Element {
+            address,
+            data: data.to_vec(),
+        });
+    }
+    segments.sort_by_key(|e| e.address);
+    let mut merged: Vec<Element> = Vec::new();
+    for segment in segments {
+        match merged.last_mut() {
+            Some(last) if last.address as u64 + last.data.len() as u64 == segment.address as u64 => {
+                last.data.extend(segment.data)
+            }
+            _ => merged.push(segment),
+        }
+    }
+    Ok(merged)
+}
+
+/// Join segments sharing a page, filling the gap with 0xFF, as writing the second would erase
+/// the page again and lose the first
+pub fn join_within_pages(elements: Vec<Element>, layout: &MemoryLayout) -> Result<Vec<Element>, Error> {
+    let mut joined: Vec<Element> = Vec::new();
+    for element in elements {
+        if let Some(last) = joined.last_mut() {
+            let page = layout.address(last.address + last.data.len() as u32 - 1)?;
+            if element.address < page.address + page.size {
+                last.data.resize((element.address - last.address) as usize, 0xFF);
+                last.data.extend(element.data);
+                continue;
+            }
+        }
+        joined.push(element);
+    }
+    Ok(joined)
+}
+
+mod tests {
+    /// ELF header and program headers for `segments` of (type, load address, data)
+    #[allow(dead_code)]
+    fn elf(segments: &[(u32, u32, &[u8])]) -> Vec<u8> {
+        let mut buf = vec![0; 52];
+        buf[..6].copy_from_slice(b"\x7FELF\x01\x01");
+        buf[0x1C..0x20].copy_from_slice(&52u32.to_le_bytes());
+        buf[0x2A..0x2C].copy_from_slice(&32u16.to_le_bytes());
+        buf[0x2C..0x2E].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        let mut offset = 52 + 32 * segments.len() as u32;
+        for (kind, address, data) in segments {
+            for field in [*kind, offset, *address, *address, data.len() as u32, data.len() as u32, 5, 4] {
+                buf.extend(field.to_le_bytes());
+            }
+            offset += data.len() as u32;
+        }
+        for (_, _, data) in segments {
+            buf.extend(*data);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_loadable() {
+        use crate::elf::*;
+        let buf = elf(&[(PT_LOAD, 0x0800_0004, &[3, 4]), (PT_LOAD, 0x0800_0000, &[1, 2, 0, 0]), (PT_LOAD, 0x2000_0000, &[]), (4, 0x0800_1000, &[9])]);
+        let elements = loadable(&buf).unwrap();
+        assert_eq!(1, elements.len());
+        assert_eq!((0x0800_0000, vec![1, 2, 0, 0, 3, 4]), (elements[0].address, elements[0].data.clone()));
+        let gap = elf(&[(PT_LOAD, 0x0800_0000, &[1]), (PT_LOAD, 0x0800_4000, &[2])]);
+        assert_eq!(2, loadable(&gap).unwrap().len());
+        assert!(loadable(b"MZ\0\0\0\0").is_err());
+        assert!(loadable(b"\x7FELF").is_err());
+        let mut elf64 = buf.clone();
+        elf64[4] = 2;
+        assert!(loadable(&elf64).is_err());
+        assert!(loadable(&buf[..60]).is_err());
+    }
+
+    #[test]
+    fn test_join_within_pages() {
+        use crate::elf::*;
+        use std::str::FromStr;
+        let layout = MemoryLayout::from_str("@Internal Flash  /0x08000000/04*016Kg").unwrap();
+        let element = |address, data: &[u8]| Element {
+            address,
+            data: data.to_vec(),
+        };
+        let joined = join_within_pages(vec![element(0x0800_0000, &[1, 2]), element(0x0800_0004, &[3])], &layout).unwrap();
+        assert_eq!(vec![element(0x0800_0000, &[1, 2, 0xFF, 0xFF, 3])], joined);
+        let apart = vec![element(0x0800_0000, &[1]), element(0x0800_4000, &[2])];
+        assert_eq!(apart.clone(), join_within_pages(apart, &layout).unwrap());
+    }
+}
@@ -0,0 +1,233 @@
+mod elf;
+
+use clap::Parser;
+use dfu_nusb::core::{AltSetting, Dfu};
+use dfu_nusb::error::Error;
+use dfu_nusb::DeviceFilter;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// `[package.metadata.dfu]` of the project being flashed
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+struct Metadata {
+    /// vendor_id:product_id
+    dev: String,
+    serial: Option<String>,
+    intf: u8,
+    alt: AltSetting,
+    transfer_size: Option<u16>,
+    verify: bool,
+    /// Start the application at its lowest load address when done
+    reset: bool,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata {
+            dev: "0483:df11".into(),
+            serial: None,
+            intf: 0,
+            alt: AltSetting::Number(0),
+            transfer_size: None,
+            verify: true,
+            reset: true,
+        }
+    }
+}
+
+impl Metadata {
+    fn parse(manifest: &str) -> Result<Self, Error> {
+        let manifest: toml::Table = toml::from_str(manifest).map_err(|e| Error::Argument(format!("Cargo.toml: {}", e)))?;
+        match manifest.get("package").and_then(|p| p.get("metadata")).and_then(|m| m.get("dfu")) {
+            Some(dfu) => dfu
+                .clone()
+                .try_into()
+                .map_err(|e| Error::Argument(format!("[package.metadata.dfu]: {}", e))),
+            None => Ok(Metadata::default()),
+        }
+    }
+
+    fn filter(&self) -> Result<DeviceFilter, Error> {
+        let (vid, pid) = self
+            .dev
+            .split_once(':')
+            .and_then(|(v, p)| Some((u16::from_str_radix(v, 16).ok()?, u16::from_str_radix(p, 16).ok()?)))
+            .ok_or_else(|| Error::Argument(format!("dev = '{}' is not vendor_id:product_id", self.dev)))?;
+        Ok(DeviceFilter {
+            serial: self.serial.clone(),
+            ..DeviceFilter::vid_pid(vid, pid)
+        })
+    }
+}
+
+#[derive(Parser)]
+#[command(name = "cargo", bin_name = "cargo")]
+enum Cargo {
+    #[command(version, about)]
+    Dfu(DfuArgs),
+}
+
+#[derive(clap::Args)]
+struct DfuArgs {
+    #[command(subcommand)]
+    command: DfuCommand,
+}
+
+#[derive(clap::Subcommand)]
+enum DfuCommand {
+    /// Build the project, write its ELF over DFU, verify it and start it
+    Run(RunArgs),
+}
+
+#[derive(clap::Args)]
+struct RunArgs {
+    /// Skip verifying, overriding [package.metadata.dfu]
+    #[arg(long)]
+    no_verify: bool,
+    /// Stay in DFU mode, overriding [package.metadata.dfu]
+    #[arg(long)]
+    no_reset: bool,
+    /// Arguments for cargo build, e.g. --release --bin app
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    cargo_args: Vec<String>,
+}
+
+/// Manifest of the package cargo would build here
+fn manifest_path() -> Result<PathBuf, Error> {
+    let out = Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+        .args(["locate-project", "--message-format", "plain"])
+        .output()?;
+    if !out.status.success() {
+        return Err(Error::Argument(String::from_utf8_lossy(&out.stderr).trim().to_string()));
+    }
+    Ok(PathBuf::from(String::from_utf8_lossy(&out.stdout).trim()))
+}
+
+/// The executable among the artifacts in cargo's `--message-format json` output, the last one
+/// when there are several
+fn executable(messages: &str) -> Option<PathBuf> {
+    messages
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|m| m["reason"] == "compiler-artifact")
+        .find_map(|m| m["executable"].as_str().map(PathBuf::from))
+}
+
+fn build(cargo_args: &[String]) -> Result<PathBuf, Error> {
+    let out = Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+        .args(["build", "--message-format", "json-render-diagnostics"])
+        .args(cargo_args)
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !out.status.success() {
+        return Err(Error::Argument(format!("cargo build failed with {}", out.status)));
+    }
+    executable(&String::from_utf8_lossy(&out.stdout))
+        .ok_or_else(|| Error::Argument("cargo build produced no executable, select one with --bin".into()))
+}
+
+async fn flash(metadata: &Metadata, elf_path: &Path, a: &RunArgs) -> Result<(), Error> {
+    let elements = elf::loadable(&std::fs::read(elf_path)?)?;
+    let start = elements
+        .first()
+        .map(|e| e.address)
+        .ok_or_else(|| Error::Argument(format!("{:?} has no loadable segments", elf_path)))?;
+    let mut dfu = Dfu::open(&metadata.filter()?, metadata.intf, &metadata.alt).await?;
+    if let Some(transfer_size) = metadata.transfer_size {
+        dfu.set_transfer_size(transfer_size)?;
+    }
+    let elements = elf::join_within_pages(elements, dfu.memory_layout())?;
+    for element in &elements {
+        log::info!("Write {} bytes at 0x{:08X}", element.data.len(), element.address);
+        dfu.download_slice(&element.data, element.address).await?;
+    }
+    if metadata.verify && !a.no_verify {
+        for element in &elements {
+            dfu.verify_slice(&element.data, element.address).await?;
+        }
+        log::info!("Verified");
+    }
+    if metadata.reset && !a.no_reset {
+        log::info!("Start application at 0x{:08X}", start);
+        dfu.reset_stm32(start).await?;
+    }
+    Ok(())
+}
+
+async fn run(a: &RunArgs) -> Result<(), Error> {
+    let metadata = Metadata::parse(&std::fs::read_to_string(manifest_path()?)?)?;
+    let elf_path = build(&a.cargo_args)?;
+    log::info!("Flash {:?} to {}", elf_path, metadata.dev);
+    flash(&metadata, &elf_path, a).await
+}
+
+#[tokio::main]
+async fn main() {
+    let filter = tracing_subscriber::EnvFilter::builder()
+        .with_default_directive(tracing_subscriber::filter::LevelFilter::INFO.into())
+        .from_env_lossy();
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+    let Cargo::Dfu(args) = Cargo::parse();
+    let result = match &args.command {
+        DfuCommand::Run(a) => run(a).await,
+    };
+    if let Err(err) = result {
+        log::error!("{}", err);
+        std::process::exit(err.exit_code().into());
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_metadata() {
+        use crate::*;
+        let metadata = Metadata::parse(
+            r#"
+            [package]
+            name = "app"
+            [package.metadata.dfu]
+            dev = "0483:DF11"
+            serial = "3574364C3034"
+            alt = "Internal Flash"
+            verify = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(AltSetting::Name("Internal Flash".into()), metadata.alt);
+        assert!(!metadata.verify && metadata.reset);
+        let filter = metadata.filter().unwrap();
+        assert_eq!((Some(0x0483), Some(0xdf11)), (filter.vendor_id, filter.product_id));
+        assert_eq!(Some("3574364C3034"), filter.serial.as_deref());
+        assert_eq!(Metadata::default(), Metadata::parse("[package]\nname = \"app\"").unwrap());
+        assert!(Metadata::parse("[package.metadata.dfu]\nspeed = 1").is_err());
+        let bad = Metadata {
+            dev: "stm32".into(),
+            ..Metadata::default()
+        };
+        assert!(bad.filter().is_err());
+    }
+
+    #[test]
+    fn test_executable() {
+        use crate::*;
+        let messages = concat!(
+            r#"{"reason":"compiler-artifact","executable":null}"#,
+            "\n",
+            r#"{"reason":"compiler-artifact","executable":"/p/target/thumbv7em-none-eabihf/release/app"}"#,
+            "\n",
+            r#"{"reason":"build-finished","success":true}"#,
+        );
+        assert_eq!(
+            Some(PathBuf::from("/p/target/thumbv7em-none-eabihf/release/app")),
+            executable(messages)
+        );
+        assert_eq!(None, executable(""));
+        let cli = Cargo::try_parse_from(["cargo", "dfu", "run", "--no-verify", "--release", "--bin", "app"]).unwrap();
+        let Cargo::Dfu(DfuArgs { command: DfuCommand::Run(a) }) = cli;
+        assert!(a.no_verify);
+        assert_eq!(vec!["--release", "--bin", "app"], a.cargo_args);
+    }
+}
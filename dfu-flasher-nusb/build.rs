@@ -0,0 +1,12 @@
+fn main() {
+    println!("cargo:rerun-if-changed=proto/dfu_flasher.proto");
+    #[cfg(feature = "grpc")]
+    {
+        // protox compiles the proto in Rust, no protoc needed
+        let fds = protox::compile(["proto/dfu_flasher.proto"], ["proto"]).expect("compile dfu_flasher.proto");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(fds)
+            .expect("generate gRPC service");
+    }
+}
@@ -0,0 +1,236 @@
+use dfu_nusb::error::Error;
+use dfu_nusb::MemoryLayout;
+use std::fmt;
+use std::str::FromStr;
+
+pub fn parse_int(src: &str) -> Result<u32, std::num::ParseIntError> {
+    let src = src.replace("_", "");
+    if let Some(idx) = src.find("0x") {
+        return u32::from_str_radix(&src[idx + 2..], 16);
+    }
+    src.parse()
+}
+
+/// What an address expression is relative to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Base {
+    Absolute(u32),
+    /// Start of the first page in the memory layout
+    Flash,
+    /// End of the last page in the memory layout
+    End,
+}
+
+/// Address such as `0x08000000`, `flash`, `flash+0x4000` or `end-0x800`.
+/// Symbolic addresses are resolved against the memory layout of the selected alt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Address {
+    base: Base,
+    offset: i64,
+}
+
+impl From<u32> for Address {
+    fn from(address: u32) -> Self {
+        Address {
+            base: Base::Absolute(address),
+            offset: 0,
+        }
+    }
+}
+
+impl Address {
+    /// Start of the memory layout
+    pub fn flash() -> Self {
+        Address {
+            base: Base::Flash,
+            offset: 0,
+        }
+    }
+
+    pub fn resolve(&self, layout: &MemoryLayout) -> Result<u32, Error> {
+        let base = match self.base {
+            Base::Absolute(a) => a,
+            Base::Flash => layout
+                .start_address()
+                .ok_or_else(|| Error::MemoryLayout("no pages".into()))?,
+            Base::End => layout.end_address()?,
+        };
+        u32::try_from(base as i64 + self.offset)
+            .map_err(|_| Error::Argument(format!("address {} is out of range", self)))
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (base, offset) = match s.find(['+', '-']) {
+            Some(idx) => {
+                let offset = parse_int(&s[idx + 1..]).map_err(|e| format!("'{}': {}", s, e))?;
+                if &s[idx..idx + 1] == "-" {
+                    (&s[..idx], -(offset as i64))
+                } else {
+                    (&s[..idx], offset as i64)
+                }
+            }
+            None => (s, 0),
+        };
+        let base = match base.to_lowercase().as_str() {
+            "flash" | "start" => Base::Flash,
+            "end" => Base::End,
+            _ => Base::Absolute(parse_int(base).map_err(|e| format!("'{}': {}", s, e))?),
+        };
+        Ok(Address { base, offset })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.base {
+            Base::Absolute(a) => write!(f, "0x{:08X}", a)?,
+            Base::Flash => write!(f, "flash")?,
+            Base::End => write!(f, "end")?,
+        }
+        match self.offset {
+            0 => Ok(()),
+            o if o < 0 => write!(f, "-0x{:X}", -o),
+            o => write!(f, "+0x{:X}", o),
+        }
+    }
+}
+
+pub fn parse_address_and_length_as_some(
+    dfuse_address: &str,
+) -> Result<(Address, Option<u32>), String> {
+    let mut sp = dfuse_address.split(':');
+    let address = Address::from_str(sp.next().unwrap_or("flash"))?;
+    let length = if let Some(s) = sp.next() {
+        Some(parse_int(s).map_err(|e| format!("'{}': {}", s, e))?)
+    } else {
+        None
+    };
+    Ok((address, length))
+}
+
+pub fn parse_address_and_length(address: &str) -> Result<(Address, u32), String> {
+    let a = parse_address_and_length_as_some(address)?;
+    Ok((a.0, a.1.unwrap_or(0)))
+}
+
+/// `alias=target[:length]` of `--alias`, the length defaults to the rest of the memory layout
+pub fn parse_alias(alias: &str) -> Result<(Address, (Address, Option<u32>)), String> {
+    let (address, target) = alias
+        .split_once('=')
+        .ok_or_else(|| format!("'{}': expect alias=target[:length]", alias))?;
+    Ok((Address::from_str(address)?, parse_address_and_length_as_some(target)?))
+}
+
+/// dfu-util style `address[:length][:leave]` given to `-s` together with `-D`/`-U`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DfuseAddress {
+    pub address: Address,
+    pub length: Option<u32>,
+    pub leave: bool,
+}
+
+pub fn parse_dfuse_address(dfuse_address: &str) -> Result<DfuseAddress, String> {
+    let mut sp = dfuse_address.split(':');
+    let mut a = DfuseAddress {
+        address: Address::from_str(sp.next().unwrap_or("flash"))?,
+        length: None,
+        leave: false,
+    };
+    for modifier in sp {
+        match modifier {
+            "leave" => a.leave = true,
+            m => a.length = Some(parse_int(m).map_err(|_| format!("unsupported modifier '{}'", m))?),
+        }
+    }
+    Ok(a)
+}
+
+mod tests {
+    #[test]
+    fn test_parse_int() {
+        use crate::address::*;
+        assert_eq!(Ok(0x0010_0000), parse_int("0x00100000"));
+        assert_eq!(Ok(10), parse_int("10"));
+        assert_eq!(Ok(0x00B0_0000), parse_int("0x00B0_0000"));
+        assert!(parse_int("0x00Z0_0000").is_err());
+    }
+
+    #[test]
+    fn test_parse_address_and_length() {
+        use crate::address::*;
+        assert!(parse_address_and_length("0xFF00_0000")
+            .map(|(a, l)| {
+                assert_eq!(Address::from(0xFF00_0000), a);
+                assert_eq!(0, l);
+            })
+            .is_ok());
+        assert!(parse_address_and_length("0xFF00_0000:1024")
+            .map(|(a, l)| {
+                assert_eq!(Address::from(0xFF00_0000), a);
+                assert_eq!(1024, l);
+            })
+            .is_ok());
+        assert!(parse_address_and_length("0xFF00_0000:0x1000").is_ok());
+        assert!(parse_address_and_length("0xZZ00_0000:0x1000").is_err());
+    }
+    #[test]
+    fn test_parse_address_and_length_as_some() {
+        use crate::address::*;
+        assert!(parse_address_and_length_as_some("0xFF00_0000")
+            .map(|(a, l)| {
+                assert_eq!(Address::from(0xFF00_0000), a);
+                assert_eq!(None, l);
+            })
+            .is_ok());
+        assert!(parse_address_and_length_as_some("0xFF00_0000:1024")
+            .map(|(a, l)| {
+                assert_eq!(Address::from(0xFF00_0000), a);
+                assert_eq!(Some(1024), l);
+            })
+            .is_ok());
+        assert!(parse_address_and_length("0xFF00_0000:0x1000").is_ok());
+        assert!(parse_address_and_length("0xZZ00_0000:0x1000").is_err());
+    }
+
+    #[test]
+    fn test_address_expressions() {
+        use crate::address::*;
+        let m = MemoryLayout::from_str("/0x08010000/02*16K,01*64K").unwrap();
+        let resolve = |s: &str| Address::from_str(s).unwrap().resolve(&m).unwrap();
+        assert_eq!(0x0801_0000, resolve("flash"));
+        assert_eq!(0x0801_4000, resolve("flash+0x4000"));
+        assert_eq!(0x0801_4000, resolve("FLASH+16384"));
+        assert_eq!(0x0802_7800, resolve("end-0x800"));
+        assert_eq!(0x0800_0100, resolve("0x0800_0000+0x100"));
+        assert!(Address::from_str("flash-0x0801_0001")
+            .unwrap()
+            .resolve(&m)
+            .is_err());
+        assert!(Address::from_str("bogus+1").is_err());
+        assert!(Address::from_str("end-").is_err());
+        assert_eq!(
+            "end-0x800",
+            Address::from_str("end-0x800").unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_dfuse_address() {
+        use crate::address::*;
+        let a = parse_dfuse_address("0x0800_4000:leave").unwrap();
+        assert_eq!(Address::from(0x0800_4000), a.address);
+        assert_eq!(None, a.length);
+        assert!(a.leave);
+        let a = parse_dfuse_address("0x0800_4000:1024:leave").unwrap();
+        assert_eq!(Some(1024), a.length);
+        assert!(a.leave);
+        let a = parse_dfuse_address("flash:0x400").unwrap();
+        assert_eq!(Some(0x400), a.length);
+        assert!(!a.leave);
+        assert!(parse_dfuse_address("0x0800_0000:force").is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use crate::address::{parse_int, Address};
+use dfu_nusb::error::Error;
+use dfu_nusb::{Dfu, RetryPolicy};
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+#[derive(clap::Args, PartialEq)]
+pub struct BenchmarkArgs {
+    /// Scratch region which is erased and overwritten, may be relative like end-0x4000
+    #[arg(short = 's', long)]
+    pub address: Address,
+    /// Bytes transferred per run
+    #[arg(short, long, default_value = "16384", value_parser = parse_int)]
+    pub length: u32,
+    /// Comma separated transfer sizes [default: powers of two up to the device transfer size]
+    #[arg(long, value_delimiter = ',', value_parser = parse_int)]
+    pub sizes: Option<Vec<u32>>,
+    /// Comma separated GET_STATUS poll intervals in milliseconds
+    #[arg(long, default_value = "0,10,100", value_delimiter = ',', value_parser = parse_int)]
+    pub poll_intervals: Vec<u32>,
+    /// Only measure reads, leave the flash untouched
+    #[arg(long)]
+    pub read_only: bool,
+}
+
+fn rate(length: u32, elapsed: Duration) -> String {
+    format!("{:.1} KB/s", length as f64 / 1024.0 / elapsed.as_secs_f64())
+}
+
+/// Default sizes, powers of two from 256 bytes up to and including `max`
+fn default_sizes(max: u16) -> Vec<u32> {
+    let mut sizes: Vec<u32> = (8..16)
+        .map(|p| 1 << p)
+        .take_while(|s| *s < max as u32)
+        .collect();
+    sizes.push(max as u32);
+    sizes
+}
+
+pub async fn benchmark(dfu: &mut Dfu, a: &BenchmarkArgs) -> Result<(), Error> {
+    let address = a.address.resolve(dfu.memory_layout())?;
+    let original_size = dfu.transfer_size();
+    let original_policy = dfu.retry_policy().clone();
+    let sizes = a.sizes.clone().unwrap_or_else(|| default_sizes(original_size));
+    let data: Vec<u8> = (0..a.length).map(|i| (i * 7 + 3) as u8).collect();
+    let mut buf = Vec::with_capacity(a.length as usize);
+
+    println!(
+        "Benchmark {} bytes at 0x{:08X}\n{:>8} {:>8} {:>14} {:>14}",
+        a.length, address, "size", "poll ms", "read", "write"
+    );
+    for size in sizes {
+        let size = u16::try_from(size)
+            .map_err(|_| Error::Argument(format!("transfer size {} is too large", size)))?;
+        dfu.set_transfer_size(size)?;
+        for poll in &a.poll_intervals {
+            dfu.set_retry_policy(RetryPolicy {
+                poll_interval: Duration::from_millis(*poll as u64),
+                ..original_policy.clone()
+            });
+            buf.clear();
+            let started = Instant::now();
+            let read = match dfu.upload(&mut buf, address, a.length).await {
+                Ok(()) => rate(a.length, started.elapsed()),
+                Err(e) => {
+                    log::warn!("Read with {} bytes {} ms failed: {}", size, poll, e);
+                    dfu.abort_to_idle_clear_once().await?;
+                    "failed".into()
+                }
+            };
+            let write = if a.read_only {
+                "-".into()
+            } else {
+                let started = Instant::now();
+                match dfu.download_raw(&mut Cursor::new(&data), address, a.length).await {
+                    Ok(()) => rate(a.length, started.elapsed()),
+                    Err(e) => {
+                        log::warn!("Write with {} bytes {} ms failed: {}", size, poll, e);
+                        dfu.abort_to_idle_clear_once().await?;
+                        "failed".into()
+                    }
+                }
+            };
+            println!("{:>8} {:>8} {:>14} {:>14}", size, poll, read, write);
+        }
+    }
+    dfu.set_transfer_size(original_size)?;
+    dfu.set_retry_policy(original_policy);
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_default_sizes() {
+        use crate::benchmark::*;
+        assert_eq!(vec![256, 512, 1024, 2048], default_sizes(2048));
+        assert_eq!(vec![256, 512, 1000], default_sizes(1000));
+        assert_eq!(vec![64], default_sizes(64));
+    }
+}
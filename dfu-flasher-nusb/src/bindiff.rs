@@ -0,0 +1,96 @@
+use crate::address::parse_int;
+use crate::color::Theme;
+use crate::verify_diff::{self, Diff, Mismatch};
+use dfu_nusb::error::Error;
+use dfu_nusb::hex::to_hex;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// Differing regions shown as hex
+const MAX_REGIONS: usize = 16;
+
+#[derive(clap::Args, PartialEq)]
+pub struct BindiffArgs {
+    /// Reference image, e.g. a release artifact, shown as expected
+    pub a: PathBuf,
+    /// Image compared with it, e.g. a device readout, shown as actual
+    pub b: PathBuf,
+    /// Target address of the first byte of both files
+    #[arg(long, default_value = "0", value_parser = parse_int)]
+    pub base_address: u32,
+    /// Write every differing range with its offset, expected and actual bytes to <file> as JSON
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub diff_out: Option<PathBuf>,
+}
+
+/// Differing ranges of `a` and `b` at `base_address`, bytes only in the longer file included
+pub fn diff(base_address: u32, a: &[u8], b: &[u8]) -> Vec<Mismatch> {
+    let mut found = verify_diff::mismatches(base_address, base_address, a, b);
+    if b.len() > a.len() {
+        found.push(Mismatch {
+            offset: a.len() as u32,
+            address: base_address + a.len() as u32,
+            expected: String::new(),
+            actual: to_hex(&b[a.len()..]),
+        });
+    }
+    found
+}
+
+/// One line per differing range, then the differing rows as hex
+fn render(base_address: u32, a: &[u8], b: &[u8], found: &[Mismatch], color: bool) -> String {
+    let mut out = String::new();
+    for m in found {
+        let len = m.expected.len().max(m.actual.len()) as u32 / 2;
+        let _ = writeln!(out, "0x{:08X}..0x{:08X} {} bytes", m.address, m.address + len, len);
+    }
+    out.push('\n');
+    out.push_str(&verify_diff::render(base_address, a, b, MAX_REGIONS, color));
+    out
+}
+
+/// Compare two files, failing like verify at the first difference
+pub fn bindiff(args: &BindiffArgs) -> Result<(), Error> {
+    let (a, b) = (std::fs::read(&args.a)?, std::fs::read(&args.b)?);
+    let found = diff(args.base_address, &a, &b);
+    if let Some(path) = &args.diff_out {
+        let diff = Diff {
+            address: args.base_address,
+            length: a.len().max(b.len()) as u32,
+            mismatches: found.clone(),
+        };
+        verify_diff::write_json(path, &diff)?;
+    }
+    match found.first() {
+        None => {
+            log::info!("{:?} and {:?} are identical, {} bytes", args.a, args.b, a.len());
+            Ok(())
+        }
+        Some(first) => {
+            print!("{}", render(args.base_address, &a, &b, &found, Theme::stdout().enabled()));
+            log::info!("{} differing ranges between {:?} and {:?}", found.len(), args.a, args.b);
+            Err(Error::Verify(first.address))
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_diff() {
+        use crate::bindiff::*;
+        let a = b"0123456789abcdef".to_vec();
+        let mut b = a.clone();
+        b[4] = b'!';
+        b.extend_from_slice(b"XY");
+        let found = diff(0x0800_0000, &a, &b);
+        assert_eq!(2, found.len());
+        assert_eq!((0x0800_0004, "34", "21"), (found[0].address, found[0].expected.as_str(), found[0].actual.as_str()));
+        assert_eq!((0x10, "", "5859"), (found[1].offset, found[1].expected.as_str(), found[1].actual.as_str()));
+        let out = render(0x0800_0000, &a, &b, &found, false);
+        assert!(out.starts_with("0x08000004..0x08000005 1 bytes\n0x08000010..0x08000012 2 bytes\n\n"));
+        assert!(out.contains("0x08000000 expected 30 31 32 33 34"));
+        assert!(diff(0, &a, &a).is_empty());
+        // A shorter b shows up as bytes missing from actual
+        assert_eq!("", diff(0, &a, &a[..12])[0].actual);
+    }
+}
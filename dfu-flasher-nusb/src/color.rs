@@ -0,0 +1,77 @@
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Turn colors off for the whole run, done for `--no-color` and a `NO_COLOR` variable that is not empty
+pub fn init(no_color: bool) {
+    DISABLED.store(disabled(no_color, std::env::var_os("NO_COLOR").as_deref()), Ordering::Relaxed);
+}
+
+fn disabled(no_color: bool, env: Option<&OsStr>) -> bool {
+    no_color || env.is_some_and(|v| !v.is_empty())
+}
+
+/// Colors for one output stream: green for success, red for failure, yellow for warnings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Theme {
+    enabled: bool,
+}
+
+impl Theme {
+    /// Colors for `stream`, off unless it is a terminal
+    pub fn of(stream: &impl IsTerminal) -> Self {
+        Theme {
+            enabled: !DISABLED.load(Ordering::Relaxed) && stream.is_terminal(),
+        }
+    }
+
+    pub fn stdout() -> Self {
+        Theme::of(&std::io::stdout())
+    }
+
+    pub fn stderr() -> Self {
+        Theme::of(&std::io::stderr())
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn paint(&self, code: &str, s: impl Display) -> String {
+        match self.enabled {
+            true => format!("\x1b[{}m{}\x1b[0m", code, s),
+            false => s.to_string(),
+        }
+    }
+
+    pub fn success(&self, s: impl Display) -> String {
+        self.paint("32", s)
+    }
+
+    pub fn failure(&self, s: impl Display) -> String {
+        self.paint("1;31", s)
+    }
+
+    pub fn warning(&self, s: impl Display) -> String {
+        self.paint("33", s)
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_theme() {
+        use crate::color::*;
+        let on = Theme { enabled: true };
+        assert_eq!("\x1b[32mok\x1b[0m", on.success("ok"));
+        assert_eq!("\x1b[1;31mFAIL\x1b[0m", on.failure("FAIL"));
+        assert_eq!("\x1b[33mfixed\x1b[0m", on.warning("fixed"));
+        assert_eq!("ok", Theme { enabled: false }.success("ok"));
+        assert!(!disabled(false, None));
+        assert!(!disabled(false, Some(OsStr::new(""))));
+        assert!(disabled(false, Some(OsStr::new("1"))));
+        assert!(disabled(true, None));
+    }
+}
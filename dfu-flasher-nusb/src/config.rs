@@ -0,0 +1,344 @@
+use dfu_nusb::error::Error;
+use dfu_nusb::AltSetting;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const USER_CONFIG: &str = "dfu-flasher/config.toml";
+const LOCAL_CONFIG: &str = ".dfu-flasher.toml";
+
+/// Defaults which can be given in a config file instead of on the command line
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Settings {
+    /// vendor_id:product_id
+    pub dev: Option<String>,
+    /// bus:device
+    pub bus_device: Option<String>,
+    /// USB serial number
+    pub serial: Option<String>,
+    /// Physical port path like 1-4.2
+    pub port: Option<String>,
+    /// sysfs path on Linux, instance ID on Windows
+    pub platform_id: Option<String>,
+    pub intf: Option<u8>,
+    pub alt: Option<AltSetting>,
+    pub transfer_size: Option<u16>,
+    pub timeout: Option<u64>,
+    pub retries: Option<u8>,
+    /// LANGID to read string descriptors in, e.g. 0x0407
+    pub language: Option<u16>,
+    /// Start the application at this address after a write
+    pub reset: Option<String>,
+    /// Verify after a write
+    pub verify: Option<bool>,
+}
+
+impl Settings {
+    pub fn selects_device(&self) -> bool {
+        self.dev.is_some()
+            || self.bus_device.is_some()
+            || self.serial.is_some()
+            || self.port.is_some()
+            || self.platform_id.is_some()
+    }
+
+    /// Read settings from `DFU_FLASHER_<OPTION>` environment variables
+    pub fn from_env() -> Result<Settings, Error> {
+        Settings::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Settings, Error> {
+        fn parse<T: FromStr>(var: &impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>, Error> {
+            var(name)
+                .map(|v| v.parse().map_err(|_| Error::Argument(format!("Invalid {}='{}'", name, v))))
+                .transpose()
+        }
+        Ok(Settings {
+            dev: var("DFU_FLASHER_DEV"),
+            bus_device: var("DFU_FLASHER_BUS_DEVICE"),
+            serial: var("DFU_FLASHER_SERIAL"),
+            port: var("DFU_FLASHER_PORT"),
+            platform_id: var("DFU_FLASHER_PLATFORM_ID"),
+            intf: parse(&var, "DFU_FLASHER_INTF")?,
+            alt: parse(&var, "DFU_FLASHER_ALT")?,
+            transfer_size: parse(&var, "DFU_FLASHER_TRANSFER_SIZE")?,
+            timeout: parse(&var, "DFU_FLASHER_TIMEOUT")?,
+            retries: parse(&var, "DFU_FLASHER_RETRIES")?,
+            language: var("DFU_FLASHER_LANGUAGE")
+                .map(|v| parse_language(&v).map_err(Error::Argument))
+                .transpose()?,
+            reset: var("DFU_FLASHER_RESET"),
+            verify: parse(&var, "DFU_FLASHER_VERIFY")?,
+        })
+    }
+
+    /// Fill everything not set in `self` from `other`.
+    /// The device is selected as a whole so the options selecting it are never mixed.
+    pub fn or(self, other: Settings) -> Settings {
+        let device = if self.selects_device() {
+            self.clone()
+        } else {
+            other.clone()
+        };
+        Settings {
+            dev: device.dev,
+            bus_device: device.bus_device,
+            serial: device.serial,
+            port: device.port,
+            platform_id: device.platform_id,
+            intf: self.intf.or(other.intf),
+            alt: self.alt.or(other.alt),
+            transfer_size: self.transfer_size.or(other.transfer_size),
+            timeout: self.timeout.or(other.timeout),
+            retries: self.retries.or(other.retries),
+            language: self.language.or(other.language),
+            reset: self.reset.or(other.reset),
+            verify: self.verify.or(other.verify),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    pub settings: Settings,
+    pub profile: HashMap<String, Settings>,
+    /// Named devices selected with --device
+    pub device: HashMap<String, Settings>,
+}
+
+impl Config {
+    pub fn parse(s: &str, path: &Path) -> Result<Self, Error> {
+        let err = |e: toml::de::Error| Error::Argument(format!("config {:?}: {}", path, e));
+        let mut table: toml::Table = toml::from_str(s).map_err(err)?;
+        let profile = match table.remove("profile") {
+            Some(p) => p.try_into().map_err(err)?,
+            None => HashMap::new(),
+        };
+        let device = match table.remove("device") {
+            Some(d) => d.try_into().map_err(err)?,
+            None => HashMap::new(),
+        };
+        Ok(Config {
+            settings: toml::Value::Table(table).try_into().map_err(err)?,
+            profile,
+            device,
+        })
+    }
+
+    fn read(path: &Path) -> Result<Self, Error> {
+        log::debug!("Read config {:?}", path);
+        Config::parse(&std::fs::read_to_string(path)?, path)
+    }
+
+    /// Load the user config and the project-local config, the latter taking precedence
+    pub fn load() -> Result<Self, Error> {
+        let mut config = Config::default();
+        for path in [user_config_path(), local_config_path()].into_iter().flatten() {
+            if path.is_file() {
+                config = Config::read(&path)?.or(config);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Fill everything not set in `self` from `other`
+    pub fn or(self, other: Config) -> Config {
+        fn merge(
+            mut a: HashMap<String, Settings>,
+            b: HashMap<String, Settings>,
+        ) -> HashMap<String, Settings> {
+            for (name, settings) in b {
+                let settings = match a.remove(&name) {
+                    Some(s) => s.or(settings),
+                    None => settings,
+                };
+                a.insert(name, settings);
+            }
+            a
+        }
+        Config {
+            settings: self.settings.or(other.settings),
+            profile: merge(self.profile, other.profile),
+            device: merge(self.device, other.device),
+        }
+    }
+
+    /// Settings of a [device.<name>] entry
+    pub fn device(&self, name: &str) -> Result<Settings, Error> {
+        self.device
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::Argument(format!("No device named '{}' in config", name)))
+    }
+
+    /// Settings with the named profile applied on top of the defaults
+    pub fn settings(&self, profile: Option<&str>) -> Result<Settings, Error> {
+        match profile {
+            None => Ok(self.settings.clone()),
+            Some(name) => self
+                .profile
+                .get(name)
+                .map(|p| p.clone().or(self.settings.clone()))
+                .ok_or_else(|| Error::Argument(format!("No profile named '{}' in config", name))),
+        }
+    }
+}
+
+/// Parse `vendor_id:product_id` given as hex
+pub fn parse_vid_pid(dev: &str) -> Result<(u16, u16), Error> {
+    let mut dp = dev.split(':');
+    let id_vendor = u16::from_str_radix(dp.next().unwrap_or(""), 16).unwrap_or(0);
+    let id_product = u16::from_str_radix(dp.next().unwrap_or(""), 16).unwrap_or(0);
+    if id_vendor == 0 || id_product == 0 {
+        return Err(Error::Argument("Expect a device:product as hex".into()));
+    }
+    Ok((id_vendor, id_product))
+}
+
+/// Parse a LANGID given as hex like 0x0407 or as decimal
+pub fn parse_language(s: &str) -> Result<u16, String> {
+    let language = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    language.map_err(|e| format!("'{}': {}", s, e))
+}
+
+/// `$XDG_CONFIG_HOME/dfu-flasher/config.toml`, defaulting to `~/.config`
+pub fn user_config_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(dir.join(USER_CONFIG))
+}
+
+/// Nearest `.dfu-flasher.toml` in the current directory or its parents
+pub fn local_config_path() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|d| d.join(LOCAL_CONFIG))
+        .find(|p| p.is_file())
+}
+
+mod tests {
+    #[test]
+    fn test_config_parse() {
+        use crate::config::*;
+        let c = Config::parse(
+            r#"
+            dev = "0483:df11"
+            alt = "Internal Flash"
+            transfer-size = 1024
+            verify = true
+
+            [profile.f4]
+            alt = 0
+            reset = "flash+0x4000"
+            "#,
+            Path::new("test.toml"),
+        )
+        .unwrap();
+        assert_eq!(Some("0483:df11".into()), c.settings.dev);
+        assert_eq!(Some(AltSetting::Name("Internal Flash".into())), c.settings.alt);
+        assert_eq!(Some(1024), c.settings.transfer_size);
+
+        let s = c.settings(Some("f4")).unwrap();
+        assert_eq!(Some(AltSetting::Number(0)), s.alt);
+        assert_eq!(Some("flash+0x4000".into()), s.reset);
+        assert_eq!(Some(true), s.verify);
+        assert_eq!(Some("0483:df11".into()), s.dev);
+        assert!(c.settings(Some("missing")).is_err());
+        assert!(Config::parse("bogus = 1", Path::new("test.toml")).is_err());
+    }
+
+    #[test]
+    fn test_parse_vid_pid() {
+        use crate::config::*;
+        assert_eq!((0x0483, 0xdf11), parse_vid_pid("0483:df11").unwrap());
+        assert!(parse_vid_pid("0483").is_err());
+        assert!(parse_vid_pid("0483:zz").is_err());
+    }
+
+    #[test]
+    fn test_parse_language() {
+        use crate::config::*;
+        assert_eq!(Ok(0x0407), parse_language("0x0407"));
+        assert_eq!(Ok(1033), parse_language("1033"));
+        assert!(parse_language("0x10000").is_err());
+        assert!(parse_language("de").is_err());
+    }
+
+    #[test]
+    fn test_settings_or() {
+        use crate::config::*;
+        let cli = Settings {
+            bus_device: Some("1:7".into()),
+            ..Default::default()
+        };
+        let file = Settings {
+            dev: Some("0483:df11".into()),
+            intf: Some(1),
+            ..Default::default()
+        };
+        let s = cli.or(file);
+        assert_eq!(None, s.dev);
+        assert_eq!(Some("1:7".into()), s.bus_device);
+        assert_eq!(Some(1), s.intf);
+
+        let cli = Settings {
+            platform_id: Some("/sys/bus/usb/devices/1-4.2".into()),
+            ..Default::default()
+        };
+        let s = cli.or(Settings::default().or(s));
+        assert_eq!(None, s.bus_device);
+        assert_eq!(Some(1), s.intf);
+    }
+
+    #[test]
+    fn test_settings_from_vars() {
+        use crate::config::*;
+        let vars = |name: &str| match name {
+            "DFU_FLASHER_SERIAL" => Some("3574364C3034".to_string()),
+            "DFU_FLASHER_ALT" => Some("1".to_string()),
+            "DFU_FLASHER_TRANSFER_SIZE" => Some("2048".to_string()),
+            _ => None,
+        };
+        let s = Settings::from_vars(vars).unwrap();
+        assert_eq!(Some("3574364C3034".into()), s.serial);
+        assert_eq!(Some(AltSetting::Number(1)), s.alt);
+        assert_eq!(Some(2048), s.transfer_size);
+        assert_eq!(None, s.dev);
+        let vars = |name: &str| (name == "DFU_FLASHER_RETRIES").then(|| "many".to_string());
+        assert!(Settings::from_vars(vars).is_err());
+    }
+
+    #[test]
+    fn test_config_device() {
+        use crate::config::*;
+        let user = Config::parse(
+            r#"
+            [device.devboard]
+            serial = "3574364C3034"
+            alt = "Internal Flash"
+            "#,
+            Path::new("user.toml"),
+        )
+        .unwrap();
+        let local = Config::parse(
+            r#"
+            [device.devboard]
+            alt = 1
+            "#,
+            Path::new("local.toml"),
+        )
+        .unwrap();
+        let c = local.or(user);
+        let d = c.device("devboard").unwrap();
+        assert_eq!(Some("3574364C3034".into()), d.serial);
+        assert_eq!(Some(AltSetting::Number(1)), d.alt);
+        assert!(c.device("other").is_err());
+    }
+}
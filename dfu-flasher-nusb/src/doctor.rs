@@ -0,0 +1,74 @@
+use crate::color::Theme;
+use dfu_nusb::core::AltSetting;
+use dfu_nusb::diagnose::{diagnose, Outcome, Report};
+use dfu_nusb::error::Error;
+use dfu_nusb::DeviceFilter;
+
+#[derive(clap::Args, PartialEq)]
+pub struct DoctorArgs {
+    /// Reset the USB port when the device no longer answers GET_STATUS
+    #[arg(long)]
+    pub usb_reset: bool,
+}
+
+/// The report with its outcomes in color
+pub fn render(report: &Report, theme: Theme) -> String {
+    let mut out = String::new();
+    for finding in &report.findings {
+        let tag = match finding.outcome {
+            Outcome::Ok => theme.success(" ok  "),
+            Outcome::Fixed => theme.warning("fixed"),
+            Outcome::Failed => theme.failure("FAIL "),
+        };
+        out += &format!("[{}] {}: {}\n", tag, finding.check, finding.detail);
+    }
+    out
+}
+
+pub async fn doctor(
+    a: &DoctorArgs,
+    filter: &DeviceFilter,
+    intf: u8,
+    alt: &AltSetting,
+    language: Option<u16>,
+) -> Result<(), Error> {
+    let report = diagnose(filter, intf, alt, language, a.usb_reset).await;
+    let theme = Theme::stdout();
+    print!("{}", render(&report, theme));
+    match report.failure() {
+        None => {
+            println!("{}", theme.success("Device is ready"));
+            Ok(())
+        }
+        Some(f) => Err(match f.check {
+            "find" => Error::DeviceNotFound(f.detail.clone()),
+            "lock" => Error::Busy(f.detail.clone()),
+            check => Error::USB(format!("doctor {} check", check), std::io::Error::other(f.detail.clone())),
+        }),
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_render() {
+        use crate::color::Theme;
+        use crate::doctor::*;
+        use dfu_nusb::diagnose::Finding;
+        let report = Report {
+            findings: vec![
+                Finding {
+                    check: "find",
+                    outcome: Outcome::Ok,
+                    detail: "0483:df11".into(),
+                },
+                Finding {
+                    check: "status",
+                    outcome: Outcome::Fixed,
+                    detail: "cleared errTARGET".into(),
+                },
+            ],
+        };
+        // Without a terminal it matches the plain report
+        assert_eq!(report.to_string(), render(&report, Theme::stdout()));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::address::parse_int;
+use crate::result_log::sha256_hex;
+use dfu_nusb::error::Error;
+use dfu_nusb::{Dfu, MemoryLayout};
+use std::path::PathBuf;
+
+/// Name of the manifest in the dump directory
+pub const INDEX: &str = "index.json";
+
+#[derive(clap::Args, PartialEq)]
+pub struct DumpArgs {
+    /// Directory for the region files and index.json
+    #[arg(short, long, value_hint = clap::ValueHint::DirPath)]
+    pub dir: PathBuf,
+    /// Split into files of <bytes> instead of one per memory layout region
+    #[arg(long, value_parser = parse_int)]
+    pub chunk_size: Option<u32>,
+}
+
+/// `(address, length)` of the readable memory, one per run of contiguous pages of the same size
+/// and access, or `chunk_size` pieces of contiguous memory
+pub fn regions(layout: &MemoryLayout, chunk_size: Option<u32>) -> Vec<(u32, u32)> {
+    let mut regions: Vec<(u32, u32)> = Vec::new();
+    let mut previous: Option<&dfu_nusb::memory_layout::Page> = None;
+    for page in layout.pages().iter().filter(|p| p.access.is_none_or(|a| a.readable())) {
+        let joins = previous.is_some_and(|p| {
+            p.address + p.size == page.address && (chunk_size.is_some() || (p.size, p.access) == (page.size, page.access))
+        });
+        match regions.last_mut() {
+            Some(region) if joins => region.1 += page.size,
+            _ => regions.push((page.address, page.size)),
+        }
+        previous = Some(page);
+    }
+    match chunk_size {
+        Some(chunk) if chunk > 0 => regions
+            .into_iter()
+            .flat_map(|(address, length)| {
+                (0..length.div_ceil(chunk)).map(move |i| (address + i * chunk, chunk.min(length - i * chunk)))
+            })
+            .collect(),
+        _ => regions,
+    }
+}
+
+/// Read every region into `<dir>/0x<address>.bin` and list them with their SHA-256 in
+/// `<dir>/index.json`
+pub async fn dump(dfu: &mut Dfu, a: &DumpArgs) -> Result<(), Error> {
+    if a.chunk_size == Some(0) {
+        return Err(Error::Argument("--chunk-size must not be 0".into()));
+    }
+    std::fs::create_dir_all(&a.dir)?;
+    let mut index = Vec::new();
+    for (address, length) in regions(dfu.memory_layout(), a.chunk_size) {
+        let mut data = vec![0; length as usize];
+        let read = dfu.read_flash_to_slice(address, &mut data).await?;
+        data.truncate(read);
+        let file = format!("0x{:08X}.bin", address);
+        std::fs::write(a.dir.join(&file), &data)?;
+        log::info!("Dumped 0x{:08X} {} bytes to {}", address, read, file);
+        index.push(serde_json::json!({
+            "file": file,
+            "address": format!("0x{:08X}", address),
+            "length": read,
+            "sha256": sha256_hex(&data),
+        }));
+    }
+    let index = serde_json::json!({ "regions": index });
+    std::fs::write(a.dir.join(INDEX), format!("{:#}\n", index))?;
+    log::info!("Wrote {:?}", a.dir.join(INDEX));
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_regions() {
+        use crate::dump::*;
+        use std::str::FromStr;
+        let layout = MemoryLayout::from_str("/0x08000000/04*016Kg,01*064Kg,03*128Kg").unwrap();
+        assert_eq!(
+            vec![(0x0800_0000, 0x1_0000), (0x0801_0000, 0x1_0000), (0x0802_0000, 0x6_0000)],
+            regions(&layout, None)
+        );
+        let chunks = regions(&layout, Some(0x3_0000));
+        assert_eq!((0x0800_0000, 0x3_0000), chunks[0]);
+        assert_eq!((0x0806_0000, 0x2_0000), *chunks.last().unwrap());
+        assert_eq!(0x8_0000, chunks.iter().map(|c| c.1).sum::<u32>());
+        // Unreadable pages are left out
+        let layout = MemoryLayout::from_str("/0x1FFF0000/01*016Ka,01*016Kd,01*016Kg").unwrap();
+        assert_eq!(vec![(0x1FFF_0000, 0x4000), (0x1FFF_8000, 0x4000)], regions(&layout, None));
+    }
+}
@@ -0,0 +1,152 @@
+use crate::list;
+use crate::serve::{FlashRequest, Job, JobState, Server};
+use dfu_nusb::error::{Error, ExitCode};
+use dfu_nusb::port_path;
+use futures_lite::Stream;
+use std::pin::Pin;
+use std::sync::Arc;
+use tonic::{Code, Request, Response, Status};
+
+mod pb {
+    tonic::include_proto!("dfu_flasher.v1");
+}
+use pb::flasher_server::{Flasher, FlasherServer};
+
+/// gRPC code of each class of [Error], anything else is `Internal`
+const CODES: &[(ExitCode, Code)] = &[
+    (ExitCode::DeviceNotFound, Code::NotFound),
+    (ExitCode::Argument, Code::InvalidArgument),
+    (ExitCode::Address, Code::InvalidArgument),
+    (ExitCode::MemoryLayout, Code::FailedPrecondition),
+    (ExitCode::RuntimeMode, Code::FailedPrecondition),
+    (ExitCode::DriverNotBound, Code::FailedPrecondition),
+    (ExitCode::Protected, Code::FailedPrecondition),
+    (ExitCode::Busy, Code::Unavailable),
+    (ExitCode::ExclusiveAccess, Code::Unavailable),
+    (ExitCode::Stalled, Code::Unavailable),
+    (ExitCode::PermissionDenied, Code::PermissionDenied),
+    (ExitCode::AccessRestricted, Code::PermissionDenied),
+    (ExitCode::Verify, Code::DataLoss),
+    (ExitCode::Interrupted, Code::Cancelled),
+];
+
+pub fn code(exit_code: i32) -> Code {
+    CODES
+        .iter()
+        .find(|(e, _)| *e as i32 == exit_code)
+        .map_or(Code::Internal, |(_, c)| *c)
+}
+
+/// Status carrying the command line exit code as `dfu-exit-code` metadata
+fn status(message: &str, exit_code: i32) -> Status {
+    let mut status = Status::new(code(exit_code), message);
+    if let Ok(value) = exit_code.to_string().parse() {
+        status.metadata_mut().insert("dfu-exit-code", value);
+    }
+    status
+}
+
+fn error_status(e: &Error) -> Status {
+    status(&e.to_string(), e.exit_code().into())
+}
+
+fn progress(job: &Job) -> pb::FlashProgress {
+    let state = match job.state {
+        JobState::Queued => pb::JobState::Queued,
+        JobState::Running => pb::JobState::Running,
+        JobState::Done | JobState::Failed => pb::JobState::Done,
+    };
+    pb::FlashProgress {
+        job: job.id,
+        state: state.into(),
+        written: job.written as u64,
+        total: job.total as u64,
+        serial: job.serial.clone(),
+    }
+}
+
+struct Service {
+    server: Arc<Server>,
+}
+
+type ProgressStream = Pin<Box<dyn Stream<Item = Result<pb::FlashProgress, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Flasher for Service {
+    async fn list_devices(
+        &self,
+        _: Request<pb::ListDevicesRequest>,
+    ) -> Result<Response<pb::ListDevicesResponse>, Status> {
+        let devices = list::devices(false)
+            .map_err(|e| error_status(&e))?
+            .iter()
+            .map(|dev| pb::Device {
+                bus: dev.bus_number() as u32,
+                address: dev.device_address() as u32,
+                vendor_id: dev.vendor_id() as u32,
+                product_id: dev.product_id() as u32,
+                serial: dev.serial_number().map(String::from),
+                port: port_path(dev),
+                description: list::describe(dev),
+            })
+            .collect();
+        Ok(Response::new(pb::ListDevicesResponse { devices }))
+    }
+
+    type FlashStream = ProgressStream;
+
+    async fn flash(&self, request: Request<pb::FlashRequest>) -> Result<Response<ProgressStream>, Status> {
+        let r = request.into_inner();
+        let device = r.device.unwrap_or_default();
+        let req = FlashRequest {
+            firmware: 0,
+            dev: device.dev,
+            serial: device.serial,
+            port: device.port,
+            address: r.address,
+            verify: !r.no_verify,
+            reset: r.reset,
+        };
+        let rx = self
+            .server
+            .start_job(req, Arc::new(r.image))
+            .map_err(|e| error_status(&e))?;
+        let stream = futures_lite::stream::unfold((rx, true, false), |(mut rx, first, done)| async move {
+            if done || (!first && rx.changed().await.is_err()) {
+                return None;
+            }
+            let job = rx.borrow_and_update().clone();
+            let item = match (&job.error, job.exit_code) {
+                (Some(e), Some(exit_code)) => Err(status(e, exit_code)),
+                _ => Ok(progress(&job)),
+            };
+            Some((item, (rx, false, job.finished())))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+pub async fn serve(addr: &str, server: Arc<Server>) -> Result<(), Error> {
+    let addr = addr
+        .parse()
+        .map_err(|e| Error::Argument(format!("gRPC address '{}': {}", addr, e)))?;
+    log::info!("Serving gRPC on {}", addr);
+    tonic::transport::Server::builder()
+        .add_service(FlasherServer::new(Service { server }))
+        .serve(addr)
+        .await
+        .map_err(|e| Error::Argument(format!("gRPC: {}", e)))
+}
+
+mod tests {
+    #[test]
+    fn test_code() {
+        use crate::grpc::*;
+        assert_eq!(Code::NotFound, code(ExitCode::DeviceNotFound as i32));
+        assert_eq!(Code::DataLoss, code(ExitCode::Verify as i32));
+        assert_eq!(Code::Internal, code(ExitCode::Usb as i32));
+        let s = status("busy", ExitCode::Busy as i32);
+        assert_eq!(Code::Unavailable, s.code());
+        assert_eq!("77", s.metadata().get("dfu-exit-code").unwrap().to_str().unwrap());
+    }
+}
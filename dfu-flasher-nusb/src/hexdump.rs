@@ -0,0 +1,47 @@
+use std::fmt::Write;
+
+/// Hex dump with absolute addresses starting at `address`, `width` bytes per row and an ASCII gutter
+pub fn hex_dump(address: u32, bytes: &[u8], width: usize) -> String {
+    let mut out = String::new();
+    for (i, row) in bytes.chunks(width).enumerate() {
+        let _ = write!(out, "0x{:08X}: ", address as usize + i * width);
+        for b in row {
+            let _ = write!(out, "{:02X} ", b);
+        }
+        for _ in row.len()..width {
+            out.push_str("   ");
+        }
+        out.push('|');
+        out.extend(row.iter().map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+pub fn parse_width(s: &str) -> Result<usize, String> {
+    match s {
+        "8" | "16" | "32" => Ok(s.parse().unwrap()),
+        _ => Err(format!("width '{}' must be 8, 16 or 32", s)),
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_hex_dump() {
+        use crate::hexdump::*;
+        assert_eq!(
+            "0x08000010: 41 42 00 7F 20 31 32 33 |AB.. 123|\n0x08000018: 34 %s|4|\n"
+                .replace("%s", &" ".repeat(21)),
+            hex_dump(0x0800_0010, b"AB\0\x7f 1234", 8)
+        );
+        assert_eq!("", hex_dump(0, &[], 16));
+        assert_eq!(Ok(32), parse_width("32"));
+        assert!(parse_width("12").is_err());
+    }
+}
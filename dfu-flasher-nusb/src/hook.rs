@@ -0,0 +1,88 @@
+use crate::result_log::Record;
+use dfu_nusb::error::Error;
+use std::process::Command;
+
+/// Environment describing the device and the result of the action to a hook command
+pub fn environment(action: &str, record: &Record, result: &Result<(), Error>) -> Vec<(&'static str, String)> {
+    let (result, exit_code) = match result {
+        Ok(()) => ("ok".to_string(), 0),
+        Err(e) => (e.to_string(), i32::from(e.exit_code())),
+    };
+    let optional = |s: &Option<String>| s.clone().unwrap_or_default();
+    vec![
+        ("DFU_HOOK_ACTION", action.to_string()),
+        ("DFU_HOOK_SERIAL", optional(&record.serial)),
+        ("DFU_HOOK_CHIP_ID", optional(&record.chip_id)),
+        ("DFU_HOOK_SHA256", optional(&record.sha256)),
+        ("DFU_HOOK_RESULT", result),
+        ("DFU_HOOK_EXIT_CODE", exit_code.to_string()),
+        ("DFU_HOOK_DURATION_MS", record.duration_ms.to_string()),
+    ]
+}
+
+fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    };
+    #[cfg(not(windows))]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
+
+/// Run `command` through the shell with `env`, a failing hook is only logged as the action has
+/// already finished
+pub fn run(command: &str, env: &[(&'static str, String)]) {
+    log::info!("Run hook: {}", command);
+    match shell(command).envs(env.iter().map(|(k, v)| (k, v))).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => log::warn!("Hook `{}` failed with {}", command, status),
+        Err(e) => log::warn!("Hook `{}` could not be started: {}", command, e),
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_environment() {
+        use crate::hook::*;
+        let mut record = Record::new(std::time::SystemTime::UNIX_EPOCH);
+        record.serial = Some("3574364C3034".into());
+        record.duration_ms = 1200;
+        let env = environment("Detach", &record, &Ok(()));
+        let get = |env: &[(&str, String)], k| env.iter().find(|(n, _)| *n == k).unwrap().1.clone();
+        assert_eq!("3574364C3034", get(&env, "DFU_HOOK_SERIAL"));
+        assert_eq!("", get(&env, "DFU_HOOK_CHIP_ID"));
+        assert_eq!("", get(&env, "DFU_HOOK_SHA256"));
+        assert_eq!(("ok", "0"), (get(&env, "DFU_HOOK_RESULT").as_str(), get(&env, "DFU_HOOK_EXIT_CODE").as_str()));
+        assert_eq!("1200", get(&env, "DFU_HOOK_DURATION_MS"));
+        record.sha256 = Some(crate::result_log::sha256_hex(b"abc"));
+        let env = environment("Write", &record, &Err(Error::Verify(0x0800_0000)));
+        assert_eq!("74", get(&env, "DFU_HOOK_EXIT_CODE"));
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            get(&env, "DFU_HOOK_SHA256")
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_run() {
+        use crate::hook::*;
+        let dir = std::env::temp_dir().join(format!("dfu-hook-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out");
+        run(
+            &format!("echo \"$DFU_HOOK_RESULT $DFU_HOOK_SERIAL\" > {}", out.display()),
+            &[("DFU_HOOK_RESULT", "ok".into()), ("DFU_HOOK_SERIAL", "ABC".into())],
+        );
+        assert_eq!("ok ABC\n", std::fs::read_to_string(&out).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+use crate::address::{parse_address_and_length_as_some, Address};
+use dfu_nusb::error::Error;
+use dfu_nusb::{Dfu, DfuTransport, MemoryLayout};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// `address[:length]=file` given to `write --write`
+pub fn parse_write(s: &str) -> Result<((Address, Option<u32>), PathBuf), String> {
+    let (address, file_name) = s
+        .split_once('=')
+        .ok_or_else(|| format!("'{}': expect address[:length]=file", s))?;
+    if file_name.is_empty() {
+        return Err(format!("'{}': missing file", s));
+    }
+    Ok((parse_address_and_length_as_some(address)?, PathBuf::from(file_name)))
+}
+
+/// A file to write and where it goes
+pub struct Image {
+    pub file_name: PathBuf,
+    pub file: File,
+    pub address: u32,
+    /// Bytes to write, with padding
+    pub len: u32,
+    /// Bytes taken from the file
+    file_len: u32,
+    map: Option<memmap2::Mmap>,
+    padded: Option<Vec<u8>>,
+}
+
+impl Image {
+    /// Open `file_name` for writing at `address`, mapped with `mmap` and padded to the end of its
+    /// last page with `pad`. Fails when the image runs past the 4 GiB address space.
+    pub fn open<T: DfuTransport>(
+        dfu: &Dfu<T>,
+        file_name: &Path,
+        address: (Address, Option<u32>),
+        mmap: bool,
+        pad: Option<u8>,
+    ) -> Result<Self, Error> {
+        let mut file = OpenOptions::new().read(true).open(file_name)?;
+        let file_len = crate::get_length_from_file(&file, address.1)?;
+        let map = crate::map_file(&file, mmap)?;
+        let address = address.0.resolve(dfu.memory_layout())?;
+        let padded = match pad {
+            Some(byte) => {
+                let data = match &map {
+                    Some(map) => map[..file_len as usize].to_vec(),
+                    None => {
+                        let mut data = vec![0; file_len as usize];
+                        file.read_exact(&mut data)?;
+                        file.seek(SeekFrom::Start(0))?;
+                        data
+                    }
+                };
+                // Pages of an alias are those of the memory it maps to
+                let padded = crate::pad_to_page(data, dfu.canonical_address(address), byte, dfu.memory_layout())?;
+                log::info!("Padded {:?} with {} bytes of 0x{:02X}", file_name, padded.len() as u32 - file_len, byte);
+                Some(padded)
+            }
+            None => None,
+        };
+        let len = padded.as_ref().map_or(file_len, |p| p.len() as u32);
+        if address.checked_add(len).is_none() {
+            return Err(Error::Argument(format!(
+                "{} bytes at 0x{:08X} do not fit in the address space",
+                len, address
+            )));
+        }
+        Ok(Image {
+            file_name: file_name.to_path_buf(),
+            file,
+            address,
+            len,
+            file_len,
+            map,
+            padded,
+        })
+    }
+
+    /// The image in memory, `None` when it is streamed from `file`
+    pub fn data(&self) -> Option<&[u8]> {
+        self.padded
+            .as_deref()
+            .or(self.map.as_ref().map(|map| &map[..self.file_len as usize]))
+    }
+
+    /// The bytes taken from the file, without padding
+    pub fn contents(&self) -> Result<Vec<u8>, Error> {
+        Ok(match &self.map {
+            Some(map) => map[..self.file_len as usize].to_vec(),
+            None => std::fs::read(&self.file_name)?[..self.file_len as usize].to_vec(),
+        })
+    }
+}
+
+/// Fail when two of the `(address, length)` ranges of `what` overlap or share a page, writing the
+/// second would erase what the first wrote there
+pub fn check_disjoint(what: &str, ranges: &[(u32, u32)], layout: &MemoryLayout) -> Result<(), Error> {
+    let mut sorted = ranges.to_vec();
+    sorted.sort();
+    for pair in sorted.windows(2) {
+        let ((a, a_len), (b, _)) = (pair[0], pair[1]);
+        let last = a.saturating_add(a_len.saturating_sub(1));
+        if b <= last {
+            return Err(Error::Argument(format!("{} at 0x{:08X} and 0x{:08X} overlap", what, a, b)));
+        }
+        if let (Ok(page), Ok(next)) = (layout.address(last), layout.address(b)) {
+            if page.address == next.address {
+                return Err(Error::Argument(format!(
+                    "{} at 0x{:08X} and 0x{:08X} share the page at 0x{:08X}",
+                    what, a, b, page.address
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_parse_write() {
+        use crate::images::*;
+        let ((address, length), file_name) = parse_write("0x08000000:0x4000=boot.bin").unwrap();
+        assert_eq!((Address::from(0x0800_0000), Some(0x4000)), (address, length));
+        assert_eq!(PathBuf::from("boot.bin"), file_name);
+        assert_eq!(None, parse_write("flash+0x8000=app.bin").unwrap().0 .1);
+        assert!(parse_write("app.bin").is_err());
+        assert!(parse_write("0x08000000=").is_err());
+    }
+
+    #[test]
+    fn test_check_disjoint() {
+        use crate::images::*;
+        use std::str::FromStr;
+        let layout = MemoryLayout::from_str("/0x08000000/04*016Kg,01*064Kg").unwrap();
+        assert!(check_disjoint("Images", &[(0x0800_4000, 0x1000), (0x0800_0000, 0x4000)], &layout).is_ok());
+        let err = check_disjoint("Images", &[(0x0800_0000, 0x3000), (0x0800_3800, 0x100)], &layout).unwrap_err();
+        assert!(err.to_string().contains("share the page at 0x08000000"));
+        let err = check_disjoint("Images", &[(0x0800_0000, 0x5000), (0x0800_4000, 0x100)], &layout).unwrap_err();
+        assert!(err.to_string().contains("overlap"));
+        assert!(check_disjoint("Images", &[(0xFFFF_FF00, 0x200), (0xFFFF_FFFF, 1)], &layout).is_err());
+    }
+
+    #[test]
+    fn test_open_past_4gib() {
+        use crate::images::*;
+        use dfu_nusb::DfuseEmulator;
+        let dfu = DfuseEmulator::stm32f4().into_dfu();
+        let file_name = std::env::temp_dir().join(format!("dfu-image-{}.bin", std::process::id()));
+        std::fs::write(&file_name, [0; 0x100]).unwrap();
+        let image = Image::open(&dfu, &file_name, (Address::flash(), None), false, None).unwrap();
+        assert_eq!((0x0800_0000, 0x100), (image.address, image.len));
+        let open = |address| Image::open(&dfu, &file_name, (Address::from(address), None), false, None);
+        assert!(open(0xFFFF_FEFF).is_ok());
+        assert!(matches!(open(0xFFFF_FF00), Err(Error::Argument(_))));
+        assert!(matches!(open(0xFFFF_FFF0), Err(Error::Argument(_))));
+        std::fs::remove_file(&file_name).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+use dfu_nusb::error::Error;
+use dfu_nusb::{Dfu, Identity};
+use std::fmt::Write;
+
+#[derive(clap::Args, PartialEq)]
+pub struct InfoArgs {
+    /// Part number the device should be, e.g. STM32F405RG, warned about when the chip differs
+    #[arg(long)]
+    pub chip: Option<String>,
+}
+
+/// USB descriptors of the opened device
+pub fn describe(dfu: &Dfu) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "Manufacturer: {}", dfu.manufacturer_string().unwrap_or("-"));
+    let _ = writeln!(out, "Product:      {}", dfu.product_string().unwrap_or("-"));
+    let _ = writeln!(out, "Serial:       {}", dfu.serial_number().unwrap_or("-"));
+    if let Some(bcd) = dfu.bcd_device() {
+        let _ = writeln!(out, "bcdDevice:    {:X}.{:02X}", bcd >> 8, bcd & 0xFF);
+    }
+    let _ = writeln!(out, "Interface:    {} alt {}", dfu.interface_number(), dfu.alt_setting());
+    let descriptor = dfu.dfu_descriptor();
+    let _ = writeln!(
+        out,
+        "DFU:          version {:X}.{:02X}, attributes 0x{:02X}, transfer size {}",
+        descriptor.dfu_version >> 8,
+        descriptor.dfu_version & 0xFF,
+        descriptor.attributes,
+        descriptor.transfer_size
+    );
+    out
+}
+
+/// Whether `identity` is the `expected` part, unknown chips never are
+pub fn chip_matches(identity: &Identity, expected: &str) -> bool {
+    identity.chip.is_some_and(|c| c.matches(expected))
+}
+
+pub async fn info(dfu: &mut Dfu, a: &InfoArgs) -> Result<(), Error> {
+    print!("{}", describe(dfu));
+    match dfu.identify().await {
+        Ok(identity) => {
+            println!("Chip:         {}", identity);
+            if let Some(expected) = &a.chip {
+                if !chip_matches(&identity, expected) {
+                    log::warn!("Expected {} but the device is {}", expected, identity);
+                }
+            }
+        }
+        Err(e) => {
+            println!("Chip:         -");
+            log::warn!("Could not read DBGMCU_IDCODE: {}", e);
+        }
+    }
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_chip_matches() {
+        use crate::info::*;
+        let identity = Identity {
+            idcode: 0x1001_6413,
+            chip: dfu_nusb::chip::chip(0x413),
+            flash_kb: Some(1024),
+        };
+        assert!(chip_matches(&identity, "STM32F405RG"));
+        assert!(!chip_matches(&identity, "STM32F411CE"));
+        let unknown = Identity { chip: None, ..identity };
+        assert!(!chip_matches(&unknown, "STM32F405RG"));
+    }
+
+    #[test]
+    fn test_parse_info() {
+        use crate::info::*;
+        use crate::{Action, Args};
+        use clap::Parser;
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "info", "--chip", "STM32F405RG"]).unwrap();
+        assert!(matches!(args.action, Some(Action::Info(InfoArgs { chip: Some(c) })) if c == "STM32F405RG"));
+    }
+}
@@ -0,0 +1,101 @@
+use dfu_nusb::MemoryLayout;
+use std::fmt::Write;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LayoutFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+impl FromStr for LayoutFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "table" => Ok(LayoutFormat::Table),
+            "json" => Ok(LayoutFormat::Json),
+            "csv" => Ok(LayoutFormat::Csv),
+            s => Err(format!("unsupported format '{}', expect table, json or csv", s)),
+        }
+    }
+}
+
+#[derive(clap::Args, PartialEq)]
+pub struct MemoryLayoutArgs {
+    /// table, json or csv
+    #[arg(long, default_value = "table")]
+    pub format: LayoutFormat,
+}
+
+pub fn render(layout: &MemoryLayout, format: LayoutFormat) -> String {
+    let mut out = String::new();
+    let access = |p: &dfu_nusb::memory_layout::Page| p.access.map(|a| a.to_string());
+    match format {
+        LayoutFormat::Table => {
+            let _ = writeln!(out, "{:>5} {:>10} {:>10} {:>10} Access", "Index", "Start", "End", "Size");
+            for (i, p) in layout.pages().iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "{:>5} 0x{:08X} 0x{:08X} {:>10} {}",
+                    i,
+                    p.address,
+                    p.address + p.size - 1,
+                    p.size,
+                    access(p).as_deref().unwrap_or("?")
+                );
+            }
+        }
+        LayoutFormat::Csv => {
+            out.push_str("index,start,end,size,access\n");
+            for (i, p) in layout.pages().iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "{},0x{:08X},0x{:08X},{},{}",
+                    i,
+                    p.address,
+                    p.address + p.size - 1,
+                    p.size,
+                    access(p).unwrap_or_default()
+                );
+            }
+        }
+        LayoutFormat::Json => {
+            let pages: Vec<_> = layout
+                .pages()
+                .iter()
+                .enumerate()
+                .map(|(i, p)| {
+                    serde_json::json!({
+                        "index": i,
+                        "start": format!("0x{:08X}", p.address),
+                        "end": format!("0x{:08X}", p.address + p.size - 1),
+                        "size": p.size,
+                        "access": access(p),
+                    })
+                })
+                .collect();
+            let _ = writeln!(out, "{}", serde_json::Value::from(pages));
+        }
+    }
+    out
+}
+
+mod tests {
+    #[test]
+    fn test_render_layout() {
+        use crate::layout::*;
+        let m = MemoryLayout::from_str("/0x08000000/01*016Kg,01*064Ka").unwrap();
+        assert_eq!(
+            "index,start,end,size,access\n0,0x08000000,0x08003FFF,16384,rew\n1,0x08004000,0x08013FFF,65536,r--\n",
+            render(&m, LayoutFormat::Csv)
+        );
+        assert_eq!(
+            "[{\"access\":\"rew\",\"end\":\"0x08003FFF\",\"index\":0,\"size\":16384,\"start\":\"0x08000000\"},\
+             {\"access\":\"r--\",\"end\":\"0x08013FFF\",\"index\":1,\"size\":65536,\"start\":\"0x08004000\"}]\n",
+            render(&m, LayoutFormat::Json)
+        );
+        assert!(render(&m, LayoutFormat::Table).contains("    1 0x08004000 0x08013FFF      65536 r--\n"));
+        assert!(LayoutFormat::from_str("xml").is_err());
+    }
+}
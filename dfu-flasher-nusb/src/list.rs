@@ -0,0 +1,56 @@
+use dfu_nusb::error::Error;
+use dfu_nusb::{dfu_mode, list_dfu_devices, platform_id, port_path, DeviceFilter};
+
+#[derive(clap::Args, PartialEq)]
+pub struct ListArgs {
+    /// Only list STM32 bootloaders (0483:df11)
+    #[arg(long)]
+    pub st: bool,
+    /// Also print the --platform-id of each device, always done where there is no port path
+    #[arg(long)]
+    pub ids: bool,
+}
+
+/// One line per device with the options selecting it
+pub fn describe(dev: &nusb::DeviceInfo) -> String {
+    let mut line = format!(
+        "--bus-device {}:{} or -d {:04x}:{:04x}",
+        dev.bus_number(),
+        dev.device_address(),
+        dev.vendor_id(),
+        dev.product_id()
+    );
+    if let Some(port) = port_path(dev) {
+        line += &format!(" --port {}", port);
+    }
+    if let Some(serial) = dev.serial_number() {
+        line += &format!(" --serial {}", serial);
+    }
+    if let Some(product) = dev.product_string() {
+        line += &format!(" '{}'", product);
+    }
+    if let Some(mode) = dfu_mode(dev) {
+        line += &format!(" [{}]", mode);
+    }
+    line
+}
+
+pub fn devices(st: bool) -> Result<Vec<nusb::DeviceInfo>, Error> {
+    let filter = if st {
+        DeviceFilter::vid_pid(0x0483, 0xdf11)
+    } else {
+        DeviceFilter::default()
+    };
+    list_dfu_devices(&filter)
+}
+
+pub fn list(a: &ListArgs) -> Result<(), Error> {
+    for dev in devices(a.st)? {
+        if a.ids || port_path(&dev).is_none() || cfg!(not(target_os = "linux")) {
+            println!("{} --platform-id {}", describe(&dev), platform_id(&dev));
+        } else {
+            println!("{}", describe(&dev));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use crate::color::Theme;
+use dfu_nusb::error::Error;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, Layer, Registry};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line including the current span fields
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            s => Err(format!("unsupported log format '{}', expect text or json", s)),
+        }
+    }
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+fn layer<W>(format: LogFormat, writer: W, ansi: bool) -> fmt::Layer<Registry, fmt::format::DefaultFields, fmt::format::Format, W>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    fmt::layer().with_writer(writer).with_ansi(ansi && format == LogFormat::Text)
+}
+
+fn boxed<W>(format: LogFormat, writer: W, ansi: bool, filter: impl tracing_subscriber::layer::Filter<Registry> + Send + Sync + 'static) -> BoxedLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => layer(format, writer, ansi).with_filter(filter).boxed(),
+        LogFormat::Json => layer(format, writer, ansi).json().with_filter(filter).boxed(),
+    }
+}
+
+/// Log to stderr, or stdout when `stdout` leaves stderr to progress events, and optionally to
+/// `log_file`. `quiet` keeps only errors on the console, `log_file` gets trace level regardless.
+/// Records of the `log` crate, as used by dfu-nusb, are forwarded as well. Hex dumps of every
+/// control transfer need a `verbose` of 3, on the console and in `log_file` alike.
+pub fn init(
+    verbose: usize,
+    quiet: bool,
+    format: LogFormat,
+    log_file: Option<&Path>,
+    stdout: bool,
+) -> Result<(), Error> {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    let wire = || {
+        let level = if verbose >= 3 { "trace" } else { "off" };
+        format!("{}={}", dfu_nusb::transport::WIRE_TARGET, level).parse().expect("valid directive")
+    };
+    let console = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy()
+        .add_directive(wire());
+    let mut layers = vec![match stdout {
+        true => boxed(format, std::io::stdout, Theme::stdout().enabled(), console),
+        false => boxed(format, std::io::stderr, Theme::stderr().enabled(), console),
+    }];
+    if let Some(path) = log_file {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let filter = EnvFilter::default().add_directive(LevelFilter::TRACE.into()).add_directive(wire());
+        layers.push(boxed(format, Mutex::new(file), false, filter));
+    }
+    tracing_subscriber::registry()
+        .with(layers)
+        .try_init()
+        .map_err(|e| Error::Argument(format!("logger: {}", e)))
+}
+
+mod tests {
+    #[test]
+    fn test_log_format() {
+        use crate::logging::*;
+        assert_eq!(Ok(LogFormat::Json), LogFormat::from_str("json"));
+        assert_eq!(Ok(LogFormat::Text), LogFormat::from_str("text"));
+        assert!(LogFormat::from_str("xml").is_err());
+    }
+}
@@ -1,243 +1,613 @@
-use dfu_nusb::core::Dfu;
+mod address;
+mod benchmark;
+mod bindiff;
+mod color;
+mod config;
+mod doctor;
+mod dump;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod hexdump;
+mod hook;
+mod images;
+mod info;
+mod layout;
+mod list;
+mod logging;
+#[cfg(feature = "monitor")]
+mod monitor;
+mod option_bytes;
+mod progress;
+mod provision;
+mod raw;
+mod result_log;
+#[cfg(feature = "serve")]
+mod serve;
+mod setup;
+mod stats;
+mod supported_commands;
+mod udev;
+mod unpack;
+mod update;
+mod verify_diff;
+
+use address::{
+    parse_address_and_length, parse_address_and_length_as_some, parse_alias, parse_dfuse_address, Address,
+    DfuseAddress,
+};
+use benchmark::BenchmarkArgs;
+use bindiff::BindiffArgs;
+use config::{parse_language, parse_vid_pid, Config, Settings};
+use doctor::DoctorArgs;
+use dump::DumpArgs;
+use images::{parse_write, Image};
+use info::InfoArgs;
+use layout::MemoryLayoutArgs;
+use list::ListArgs;
+use logging::LogFormat;
+use option_bytes::{ProtectArgs, SetRdpArgs, UnprotectArgs};
+use progress::ProgressFormat;
+use provision::ProvisionArgs;
+use raw::RawArgs;
+use result_log::{sha256_hex, Record, ResultLog};
+#[cfg(feature = "serve")]
+use serve::ServeArgs;
+use setup::Setup;
+use supported_commands::SupportedCommandsArgs;
+use udev::UdevRuleArgs;
+use unpack::UnpackArgs;
+use update::UpdateArgs;
+use dfu_nusb::core::{AltSetting, Dfu};
+use dfu_nusb::{Backup, DeviceFilter, OperationSummary};
 use dfu_nusb::error::Error;
 use dfu_nusb::status::State;
 use log::info;
-use pretty_hex::PrettyHex;
+use clap::{ArgAction, Parser, Subcommand, ValueHint};
 use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::path::PathBuf;
-use structopt::StructOpt;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Instant, SystemTime};
+use tracing::field::Empty;
+use tracing::Instrument;
 
-fn parse_int(src: &str) -> Result<u32, std::num::ParseIntError> {
-    let src = src.replace("_", "");
-    if let Some(idx) = src.find("0x") {
-        return u32::from_str_radix(&src[idx + 2..], 16);
-    }
-    src.parse()
+#[derive(clap::Args, PartialEq)]
+struct STMResetArgs {
+    #[arg(short = 's', long, default_value = "flash")]
+    address: Address,
 }
 
-fn parse_address_and_length_as_some(
-    dfuse_address: &str,
-) -> Result<(u32, Option<u32>), std::num::ParseIntError> {
-    let mut sp = dfuse_address.split(':');
-    let a = sp.next().unwrap_or("0x0800_0000");
-    let address = parse_int(a)?;
-    let length = if let Some(s) = sp.next() {
-        Some(parse_int(s)?)
-    } else {
-        None
-    };
-    Ok((address, length))
+#[derive(clap::Args, PartialEq)]
+struct EraseAllArgs {
+    /// Read address:length before erasing and write it back afterwards, e.g. a calibration page, may be given repeatedly
+    #[arg(long, value_parser = parse_address_and_length)]
+    keep: Vec<(Address, u32)>,
 }
 
-fn parse_address_and_length(address: &str) -> Result<(u32, u32), std::num::ParseIntError> {
-    let a = parse_address_and_length_as_some(address)?;
-    Ok((a.0, a.1.unwrap_or(0)))
+#[derive(clap::Args, PartialEq)]
+struct AddressArgs {
+    /// start_address:num_pages
+    #[arg(short = 's', long, value_parser = parse_address_and_length)]
+    address: (Address, u32),
 }
 
-mod tests {
-    #[test]
-    fn test_parse_int() {
-        use crate::*;
-        assert_eq!(Ok(0x0010_0000), parse_int("0x00100000"));
-        assert_eq!(Ok(10), parse_int("10"));
-        assert_eq!(Ok(0x00B0_0000), parse_int("0x00B0_0000"));
-        assert!(parse_int("0x00Z0_0000").is_err());
-    }
+#[derive(clap::Args, PartialEq)]
+struct ReadAddressArgs {
+    #[command(flatten)]
+    region: AddressArgs,
+    /// Bytes per row of the hex dump
+    #[arg(short, long, default_value = "16", value_parser = hexdump::parse_width)]
+    width: usize,
+    /// Write the raw bytes to [file] or to stdout instead of a hex dump
+    #[arg(long, num_args = 0..=1, require_equals = true, value_hint = ValueHint::FilePath)]
+    raw: Option<Option<PathBuf>>,
+}
 
-    #[test]
-    fn test_parse_address_and_length() {
-        use crate::*;
-        assert!(parse_address_and_length("0xFF00_0000")
-            .map(|(a, l)| {
-                assert_eq!(0xFF00_0000, a);
-                assert_eq!(0, l);
-            })
-            .is_ok());
-        assert!(parse_address_and_length("0xFF00_0000:1024")
-            .map(|(a, l)| {
-                assert_eq!(0xFF00_0000, a);
-                assert_eq!(1024, l);
-            })
-            .is_ok());
-        assert!(parse_address_and_length("0xFF00_0000:0x1000").is_ok());
-        assert!(parse_address_and_length("0xZZ00_0000:0x1000").is_err());
-    }
-    #[test]
-    fn test_parse_address_and_length_as_some() {
-        use crate::*;
-        assert!(parse_address_and_length_as_some("0xFF00_0000")
-            .map(|(a, l)| {
-                assert_eq!(0xFF00_0000, a);
-                assert_eq!(None, l);
-            })
-            .is_ok());
-        assert!(parse_address_and_length_as_some("0xFF00_0000:1024")
-            .map(|(a, l)| {
-                assert_eq!(0xFF00_0000, a);
-                assert_eq!(Some(1024), l);
-            })
-            .is_ok());
-        assert!(parse_address_and_length("0xFF00_0000:0x1000").is_ok());
-        assert!(parse_address_and_length("0xZZ00_0000:0x1000").is_err());
-    }
+#[derive(clap::Args, PartialEq)]
+struct VWFlashArgs {
+    /// start address[:length], address may be relative like flash+0x4000 or end-0x800
+    #[arg(short = 's', long, default_value = "flash", value_parser = parse_address_and_length_as_some)]
+    address: (Address, Option<u32>),
+    /// Read firmware into <file>
+    #[arg(short = 'f', long, value_hint = ValueHint::FilePath)]
+    file_name: PathBuf,
+    /// Memory-map <file> instead of reading it chunk by chunk, for large images
+    #[arg(long)]
+    mmap: bool,
+    /// Write every mismatching region with its offset, expected and actual bytes to <file> as JSON
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    diff_out: Option<PathBuf>,
 }
 
-#[derive(StructOpt, PartialEq)]
-struct STMResetArgs {
-    #[structopt(short = "s", long, default_value = "0x08000000", parse(try_from_str=parse_int))]
-    address: u32,
+/// Where to continue an interrupted write
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resume {
+    Offset(u32),
+    /// First page differing from the file
+    Auto,
 }
 
-#[derive(StructOpt, PartialEq)]
-struct AddressArgs {
-    /// start_address:num_pages
-    #[structopt(short = "s", long, parse(try_from_str=parse_address_and_length))]
-    address: (u32, u32),
+impl FromStr for Resume {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "auto" => Ok(Resume::Auto),
+            s => address::parse_int(s)
+                .map(Resume::Offset)
+                .map_err(|e| format!("'{}': {}", s, e)),
+        }
+    }
 }
 
-#[derive(StructOpt, PartialEq)]
-struct VWFlashArgs {
-    /// start address[:length]
-    #[structopt(short = "s", long, default_value = "0x08000000", parse(try_from_str=parse_address_and_length_as_some))]
-    address: (u32, Option<u32>),
-    /// Read firmware into <file>
-    #[structopt(short = "f", long)]
-    file_name: PathBuf,
+#[derive(clap::Args, PartialEq)]
+struct WriteArgs {
+    #[command(flatten)]
+    flash: VWFlashArgs,
+    /// Leave DFU mode after a successful write and start the application at [address], default flash
+    #[arg(long, num_args = 0..=1, require_equals = true)]
+    reset: Option<Option<Address>>,
+    /// Read back and compare with the file after writing
+    #[arg(long)]
+    verify: bool,
+    /// Continue an interrupted write <offset> bytes into the file, `auto` verifies up to the first mismatch
+    #[arg(long)]
+    resume_from: Option<Resume>,
+    /// Fill the image with <byte>, e.g. 0xFF, up to the end of the page it ends in
+    #[arg(long, value_parser = parse_pad, conflicts_with = "resume_from")]
+    pad: Option<u8>,
+    /// Also write <file> at address[:length] in the same session, may be given repeatedly
+    #[arg(long = "write", value_name = "ADDRESS=FILE", value_parser = parse_write, conflicts_with = "resume_from")]
+    more: Vec<((Address, Option<u32>), PathBuf)>,
+}
+
+fn parse_pad(s: &str) -> Result<u8, String> {
+    address::parse_int(s)
+        .map_err(|e| e.to_string())
+        .and_then(|b| u8::try_from(b).map_err(|e| e.to_string()))
+        .map_err(|e| format!("'{}': {}", s, e))
 }
 
-#[derive(StructOpt, PartialEq)]
+#[derive(clap::Args, PartialEq)]
 struct ReadFlashArgs {
-    /// start address[:length]
-    #[structopt(short = "s", long, default_value = "0x08000000", parse(try_from_str=parse_address_and_length))]
-    address: (u32, u32),
+    /// start address[:length], address may be relative like flash+0x4000 or end-0x800
+    #[arg(short = 's', long, default_value = "flash", value_parser = parse_address_and_length)]
+    address: (Address, u32),
     /// Read firmware into <file>
-    #[structopt(short = "f", long)]
+    #[arg(short = 'f', long, value_hint = ValueHint::FilePath)]
     file_name: PathBuf,
-    #[structopt(short = "F", long)]
+    #[arg(short = 'F', long)]
     overwrite: bool,
+    /// Read up to the end of the memory layout, set by -U without a length
+    #[arg(skip)]
+    to_end: bool,
 }
 
-#[derive(StructOpt, PartialEq)]
+#[derive(Subcommand, PartialEq)]
 enum Action {
-    SupportedCommands,
+    /// Show the USB descriptors and identify the chip by its DBGMCU IDCODE
+    Info(InfoArgs),
+    /// List the DfuSe commands of the device, including bytes unknown to this tool
+    SupportedCommands(SupportedCommandsArgs),
     Reset(STMResetArgs),
-    EraseAll,
+    EraseAll(EraseAllArgs),
     Erase(AddressArgs),
+    #[command(visible_alias = "r")]
     Read(ReadFlashArgs),
-    Write(VWFlashArgs),
+    #[command(visible_alias = "w")]
+    Write(WriteArgs),
+    #[command(visible_alias = "v")]
     Verify(VWFlashArgs),
+    /// Read the whole memory layout into one file per region with an index.json of addresses and hashes
+    Dump(DumpArgs),
     Detach,
     SetAddress(STMResetArgs),
-    MemoryLayout,
-    ReadAddress(AddressArgs),
+    MemoryLayout(MemoryLayoutArgs),
+    ReadAddress(ReadAddressArgs),
+    /// Extract the elements of a DfuSe file
+    Unpack(UnpackArgs),
+    /// List where two images, e.g. device readouts, differ in target address space
+    Bindiff(BindiffArgs),
+    /// Patch per-device values from a CSV row into the image and write it
+    Provision(ProvisionArgs),
+    /// Print or install a udev rule giving access to the device
+    GenUdevRule(UdevRuleArgs),
+    /// List connected devices with a DFU interface
+    List(ListArgs),
+    /// Measure read and write throughput on a scratch region at several transfer sizes
+    Benchmark(BenchmarkArgs),
+    /// Check why a device can not be used and bring it back to dfuIDLE where possible
+    Doctor(DoctorArgs),
+    /// Expert: send an arbitrary DFU class request and show the response
+    Raw(RawArgs),
+    /// Write protect flash sectors through the option bytes (STM32F2/F4)
+    Protect(ProtectArgs),
+    /// Lift the write protection of flash sectors through the option bytes (STM32F2/F4)
+    Unprotect(UnprotectArgs),
+    /// Print the option bytes with their fields decoded (STM32F2/F4)
+    ReadOptionBytes,
+    /// Report or set the readout protection level through the option bytes (STM32F2/F4)
+    SetRdp(SetRdpArgs),
+    /// Detach the running application, flash, verify and wait for the application to return
+    Update(UpdateArgs),
+    /// Serve a REST API to list devices, upload firmware and run flash jobs
+    #[cfg(feature = "serve")]
+    Serve(ServeArgs),
+}
+
+impl Action {
+    fn needs_device(&self) -> bool {
+        match self {
+            Action::Unpack(_) | Action::Bindiff(_) | Action::GenUdevRule(_) | Action::List(_) | Action::Update(_) => false,
+            #[cfg(feature = "serve")]
+            Action::Serve(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Works on the option bytes alt setting, opened by default
+    fn uses_option_bytes(&self) -> bool {
+        matches!(
+            self,
+            Action::Protect(_) | Action::Unprotect(_) | Action::SetRdp(_) | Action::ReadOptionBytes
+        )
+    }
 }
 
 impl fmt::Display for Action {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use crate::Action::*;
         match self {
-            SupportedCommands => write!(f, "List supported commands"),
-            Reset(a) => write!(f, "Reset STM32 vector start address: 0x{:04X}", a.address),
-            EraseAll => write!(f, "Erase all"),
+            Info(_) => write!(f, "Device info"),
+            SupportedCommands(_) => write!(f, "List supported commands"),
+            Reset(a) => write!(f, "Reset STM32 vector start address: {}", a.address),
+            EraseAll(a) => {
+                write!(f, "Erase all")?;
+                for (address, length) in &a.keep {
+                    write!(f, ", keep {} length: {} bytes", address, length)?;
+                }
+                Ok(())
+            }
             Erase(a) => write!(
                 f,
-                "Erase area start address: 0x{:04X} number of pages: {}.",
+                "Erase area start address: {} number of pages: {}.",
                 a.address.0, a.address.1
             ),
             Read(a) => write!(
                 f,
-                "Read flash from start address: 0x{:04X} length: {} bytes and save to file: '{:?}'",
+                "Read flash from start address: {} length: {} bytes and save to file: '{:?}'",
                 a.address.0, a.address.1, a.file_name
             ),
-            Write(a) => write!(
-                f,
-                "Write file: '{:?}' to flash at start address: 0x{:04X} length: {:?} bytes.",
-                a.file_name, a.address.0, a.address.1
-            ),
+            Write(a) => {
+                write!(
+                    f,
+                    "Write file: '{:?}' to flash at start address: {} length: {:?} bytes.",
+                    a.flash.file_name, a.flash.address.0, a.flash.address.1
+                )?;
+                for ((address, length), file_name) in &a.more {
+                    write!(f, " Write file: '{:?}' at start address: {} length: {:?} bytes.", file_name, address, length)?;
+                }
+                Ok(())
+            }
             Verify(a) => write!(
                 f,
-                "Read flash from start address: 0x{:08X} length: {:?} bytes and verify using file '{:?}'",
+                "Read flash from start address: {} length: {:?} bytes and verify using file '{:?}'",
                 a.address.0, a.address.1, a.file_name
             ),
-            SetAddress(a) => write!(f, "Set address 0x{:08X}", a.address),
+            Dump(a) => write!(f, "Dump flash to directory: '{:?}'", a.dir),
+            SetAddress(a) => write!(f, "Set address {}", a.address),
             Detach => write!(f, "Detach"),
-            MemoryLayout => write!(f, "Memory layout"),
-            ReadAddress(a) => write!(f, "Read address 0x{:08X} length: {} bytes", a.address.0, a.address.1),
+            MemoryLayout(_) => write!(f, "Memory layout"),
+            ReadAddress(a) => write!(
+                f,
+                "Read address {} length: {} bytes",
+                a.region.address.0, a.region.address.1
+            ),
+            Unpack(a) => write!(f, "Unpack DfuSe file '{:?}'", a.file_name),
+            Bindiff(a) => write!(f, "Compare '{:?}' with '{:?}'", a.a, a.b),
+            GenUdevRule(_) => write!(f, "Generate udev rule"),
+            List(_) => write!(f, "List DFU devices"),
+            Doctor(_) => write!(f, "Diagnose device"),
+            Update(a) => write!(f, "Update {} with file: '{:?}'", a.runtime, a.file_name),
+            #[cfg(feature = "serve")]
+            Serve(a) => write!(f, "Serve on {}", a.listen),
+            Raw(a) => write!(f, "Raw request 0x{:02X} value 0x{:04X}", a.request, a.value),
+            Protect(a) => write!(f, "Write protect sectors {:?}", a.sectors),
+            Unprotect(a) if a.sectors.is_empty() => write!(f, "Unprotect all sectors"),
+            Unprotect(a) => write!(f, "Unprotect sectors {:?}", a.sectors),
+            ReadOptionBytes => write!(f, "Read option bytes"),
+            SetRdp(a) => match a.level {
+                Some(level) if !a.check => write!(f, "Set RDP level {}", level),
+                _ => write!(f, "Check RDP level"),
+            },
+            Benchmark(a) => write!(f, "Benchmark {} bytes at {}", a.length, a.address),
+            Provision(a) => write!(
+                f,
+                "Provision file: '{:?}' with {:?} to flash at start address: {}",
+                a.file_name, a.csv, a.address
+            ),
         }
     }
 }
 
-#[derive(StructOpt)]
+#[derive(Parser)]
+#[command(version, about)]
 struct Args {
     /// vendor_id:product_id example 0470:df00
-    #[structopt(short, long)]
+    #[arg(short, long, help_heading = "Device selection")]
     dev: Option<String>,
-    #[structopt(skip)]
-    id_vendor: u16,
-    #[structopt(skip)]
-    id_product: u16,
-    #[structopt(short, long)]
+    #[arg(short, long, help_heading = "Device selection")]
     bus_device: Option<String>,
-    /// Specify the DFU interface
-    #[structopt(short, long, default_value = "0")]
-    intf: u8,
-    /// Specify Alt setting of the DFU interface by number
-    #[structopt(short, long, default_value = "0")]
-    alt: u8,
-    #[structopt(skip)]
-    bus: u8,
-    #[structopt(skip)]
-    device: u8,
-    #[structopt(subcommand)]
-    action: Action,
-    #[structopt(short, long, parse(from_occurrences))]
-    verbose: usize,
+    /// USB serial number of the device
+    #[arg(long, help_heading = "Device selection")]
+    serial: Option<String>,
+    /// Physical port path like 1-4.2, unlike --bus-device stable across replugs
+    #[arg(long, help_heading = "Device selection", conflicts_with_all = ["dev", "bus_device"])]
+    port: Option<String>,
+    /// Operating system identifier, the sysfs path on Linux or the instance ID on Windows
+    #[arg(
+        long,
+        visible_alias = "sysfs-path",
+        help_heading = "Device selection",
+        conflicts_with_all = ["dev", "bus_device", "port"]
+    )]
+    platform_id: Option<String>,
+    #[arg(skip)]
+    filter: DeviceFilter,
+    /// Specify the DFU interface [default: 0]
+    #[arg(short, long, help_heading = "Device selection")]
+    intf: Option<u8>,
+    /// Specify Alt setting of the DFU interface by number or name [default: 0]
+    #[arg(short, long, help_heading = "Device selection")]
+    alt: Option<AltSetting>,
+    /// Override the transfer size in bytes advertised by the device
+    #[arg(long, help_heading = "Transfer")]
+    transfer_size: Option<u16>,
+    /// Timeout of each control transfer in milliseconds
+    #[arg(long, help_heading = "Transfer")]
+    timeout: Option<u64>,
+    /// Number of times a failing GET_STATUS is retried
+    #[arg(long, help_heading = "Transfer")]
+    retries: Option<u8>,
+    /// Read string descriptors in this LANGID, e.g. 0x0407 [default: first supported by the device]
+    #[arg(long, value_parser = parse_language, help_heading = "Transfer")]
+    language: Option<u16>,
+    /// Use the named [profile.<name>] of the config file
+    #[arg(long, help_heading = "Config")]
+    profile: Option<String>,
+    /// Use the device defined as [device.<name>] in the config file
+    #[arg(long, help_heading = "Config")]
+    device: Option<String>,
+    /// Ignore config files
+    #[arg(long, help_heading = "Config")]
+    no_config: bool,
+    #[arg(skip)]
+    settings: Settings,
+    /// dfu-util compatible: write <file> to the device
+    #[arg(short = 'D', long, conflicts_with = "upload", value_hint = ValueHint::FilePath, help_heading = "dfu-util compatibility")]
+    download: Option<PathBuf>,
+    /// dfu-util compatible: read the device into <file>
+    #[arg(short = 'U', long, value_hint = ValueHint::FilePath, help_heading = "dfu-util compatibility")]
+    upload: Option<PathBuf>,
+    /// dfu-util compatible: leave DFU mode and start the application when done
+    #[arg(short = 'R', long, help_heading = "dfu-util compatibility")]
+    reset: bool,
+    /// dfu-util compatible: address[:length][:leave] used with -D/-U
+    #[arg(short = 's', long, value_parser = parse_dfuse_address, help_heading = "dfu-util compatibility")]
+    dfuse_address: Option<DfuseAddress>,
+    #[arg(skip)]
+    leave: Option<Address>,
+    /// Save the flash pages about to be erased by a write to <dir> first
+    #[arg(long, value_hint = ValueHint::DirPath)]
+    backup: Option<PathBuf>,
+    /// Refuse to erase or write address:length, e.g. the bootloader, may be given repeatedly
+    #[arg(long, value_parser = parse_address_and_length)]
+    protect: Vec<(Address, u32)>,
+    /// Erase and write --protect ranges anyway
+    #[arg(long)]
+    force: bool,
+    /// alias=target[:length], e.g. 0x0=flash to use images linked for the flash mirror at 0
+    #[arg(long, value_parser = parse_alias)]
+    alias: Vec<(Address, (Address, Option<u32>))>,
+    /// Append a record for every written unit to <file>, JSON lines for .jsonl, CSV otherwise
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    result_log: Option<PathBuf>,
+    /// Read the 96 bit unique device ID at <address> for the result log, e.g. 0x1FFF7A10 on STM32F4
+    #[arg(long)]
+    uid_address: Option<Address>,
+    /// Run <cmd> through the shell after the action succeeded, DFU_HOOK_* variables describe device and result
+    #[arg(long, value_name = "CMD")]
+    on_success: Option<String>,
+    /// Run <cmd> through the shell after the action failed
+    #[arg(long, value_name = "CMD")]
+    on_failure: Option<String>,
+    /// Print transfers, retries, stalls, throughput and time waiting on the device after the action
+    #[arg(long)]
+    stats: bool,
+    /// Stream the serial <port> to the console after starting the application, until Ctrl-C
+    #[cfg(feature = "monitor")]
+    #[arg(long, value_name = "PORT")]
+    monitor: Option<String>,
+    /// Baud rate of --monitor
+    #[cfg(feature = "monitor")]
+    #[arg(long, default_value_t = 115200, requires = "monitor")]
+    baud: u32,
+    #[command(subcommand)]
+    action: Option<Action>,
+    /// More logging, -vv for trace, -vvv adds a hex dump of every control transfer
+    #[arg(short, long, action = ArgAction::Count, global = true, help_heading = "Logging")]
+    verbose: u8,
+    /// Only log errors to the console
+    #[arg(short, long, conflicts_with = "verbose", global = true, help_heading = "Logging")]
+    quiet: bool,
+    /// Also write trace level logs to <file>
+    #[arg(long, value_hint = ValueHint::FilePath, global = true, help_heading = "Logging")]
+    log_file: Option<PathBuf>,
+    /// text or json, the latter with the device, action and address range of the current operation
+    #[arg(long, default_value = "text", global = true, help_heading = "Logging")]
+    log_format: LogFormat,
+    /// json writes progress events to stderr and moves the console log to stdout
+    #[arg(long, global = true, help_heading = "Logging")]
+    progress: Option<ProgressFormat>,
+    /// No colors, also set by a NO_COLOR variable and when output is not a terminal
+    #[arg(long, global = true, help_heading = "Logging")]
+    no_color: bool,
 }
 
 impl Args {
     fn new() -> Result<Self, Error> {
-        let mut args = Self::from_args();
-        env_logger_init("dfu-flasher", args.verbose);
+        let mut args = Self::parse();
+        color::init(args.no_color);
+        logging::init(
+            args.verbose as usize,
+            args.quiet,
+            args.log_format,
+            args.log_file.as_deref(),
+            args.progress.is_some(),
+        )?;
         if args.dev.is_some() && args.bus_device.is_some() {
             return Err(Error::Argument(
                 "Both vendor:product and bus:address cannot be specified at once!".into(),
             ));
-        } else if let Some(dp) = &args.dev {
-            let mut dp = dp.split(':');
-            args.id_vendor = u16::from_str_radix(dp.next().unwrap_or(""), 16).unwrap_or(0);
-            args.id_product = u16::from_str_radix(dp.next().unwrap_or(""), 16).unwrap_or(0);
-            if args.id_vendor == 0 || args.id_product == 0 {
-                return Err(Error::Argument("Expect a device:product as hex".into()));
+        }
+        args.apply_config()?;
+        args.dfu_util_compat()?;
+        let update = matches!(args.action, Some(Action::Update(_)));
+        if args.action.as_ref().is_some_and(Action::needs_device) || update && args.settings.selects_device() {
+            args.select_device()?;
+        }
+        if let Some(Action::Write(w)) = &mut args.action {
+            if w.reset.is_none() {
+                if let Some(reset) = &args.settings.reset {
+                    w.reset = Some(Some(Address::from_str(reset).map_err(Error::Argument)?));
+                }
             }
-        } else if let Some(dp) = &args.bus_device {
+            w.verify |= args.settings.verify.unwrap_or(false);
+        }
+        Ok(args)
+    }
+
+    fn select_device(&mut self) -> Result<(), Error> {
+        if let Some(dp) = &self.settings.dev {
+            let (id_vendor, id_product) = parse_vid_pid(dp)?;
+            self.filter = DeviceFilter::vid_pid(id_vendor, id_product);
+        } else if let Some(dp) = &self.settings.bus_device {
             let mut dp = dp.split(':');
-            args.bus = dp.next().unwrap_or("").parse::<u8>().unwrap_or(0);
-            args.device = dp.next().unwrap_or("").parse::<u8>().unwrap_or(0);
-            if args.bus == 0 || args.device == 0 {
+            let bus = dp.next().unwrap_or("").parse::<u8>().unwrap_or(0);
+            let device = dp.next().unwrap_or("").parse::<u8>().unwrap_or(0);
+            if bus == 0 || device == 0 {
                 return Err(Error::Argument("expect bus:device".into()));
             }
-        } else {
-            let e = nusb::list_devices()?;
+            self.filter = DeviceFilter::bus_device(bus, device);
+        } else if let Some(port) = &self.settings.port {
+            self.filter = DeviceFilter::port(port);
+        } else if let Some(id) = &self.settings.platform_id {
+            self.filter = DeviceFilter::platform_id(id);
+        } else if self.settings.serial.is_none() {
             let mut msg =
-                String::from("Missing --bus-device or --dev! List of possible USB devices:\n\n");
-            for (bus, dev) in e.filter(|dev| dev.product_id() == 0xdf11).map(|dev| {
-                (
-                    format!("{:04X}:{:04X}", dev.bus_number(), dev.device_address()),
-                    dev,
-                )
-            }) {
-                msg += &format!(
-                    "--bus-device {} or -d {:04X}:{:04X}\n",
-                    bus,
-                    dev.vendor_id(),
-                    dev.product_id(),
-                );
+                String::from("Missing --bus-device, --port or --dev! List of possible DFU devices:\n\n");
+            for dev in list::devices(false)? {
+                msg += &list::describe(&dev);
+                msg.push('\n');
             }
             return Err(Error::Argument(msg));
         }
 
-        Ok(args)
+        self.filter.serial = self.settings.serial.clone();
+        Ok(())
+    }
+
+    /// Layer command line options over the config files
+    fn apply_config(&mut self) -> Result<(), Error> {
+        let cli = Settings {
+            dev: self.dev.clone(),
+            bus_device: self.bus_device.clone(),
+            serial: self.serial.clone(),
+            port: self.port.clone(),
+            platform_id: self.platform_id.clone(),
+            intf: self.intf,
+            alt: self.alt.clone(),
+            transfer_size: self.transfer_size,
+            timeout: self.timeout,
+            retries: self.retries,
+            language: self.language,
+            ..Default::default()
+        };
+        let config = if self.no_config {
+            Config::default()
+        } else {
+            Config::load()?
+        };
+        let device = self
+            .device
+            .clone()
+            .or_else(|| std::env::var("DFU_FLASHER_DEVICE").ok());
+        let cli = match device {
+            Some(name) => cli.or(config.device(&name)?),
+            None => cli,
+        };
+        let profile = self
+            .profile
+            .clone()
+            .or_else(|| std::env::var("DFU_FLASHER_PROFILE").ok());
+        self.settings = cli
+            .or(Settings::from_env()?)
+            .or(config.settings(profile.as_deref())?);
+        Ok(())
+    }
+
+    /// Translate dfu-util style -D/-U/-R/-s into an action
+    fn dfu_util_compat(&mut self) -> Result<(), Error> {
+        let dfuse_address = self.dfuse_address.unwrap_or(DfuseAddress {
+            address: Address::flash(),
+            length: None,
+            leave: false,
+        });
+        let compat = self.download.is_some() || self.upload.is_some();
+        if self.action.is_some() && (compat || self.dfuse_address.is_some()) {
+            return Err(Error::Argument(
+                "-D/-U/-s cannot be combined with a subcommand".into(),
+            ));
+        }
+        let leave = self.reset || dfuse_address.leave;
+        if let Some(file_name) = self.download.take() {
+            self.action = Some(Action::Write(WriteArgs {
+                flash: VWFlashArgs {
+                    address: (dfuse_address.address, dfuse_address.length),
+                    file_name,
+                    mmap: false,
+                    diff_out: None,
+                },
+                reset: leave.then_some(Some(dfuse_address.address)),
+                verify: false,
+                resume_from: None,
+                pad: None,
+                more: Vec::new(),
+            }));
+            return Ok(());
+        } else if let Some(file_name) = self.upload.take() {
+            self.action = Some(Action::Read(ReadFlashArgs {
+                address: (dfuse_address.address, dfuse_address.length.unwrap_or(0)),
+                file_name,
+                overwrite: false,
+                to_end: dfuse_address.length.is_none(),
+            }));
+        }
+        if leave {
+            if self.action.is_none() {
+                self.action = Some(Action::Reset(STMResetArgs {
+                    address: dfuse_address.address,
+                }));
+            } else {
+                self.leave = Some(dfuse_address.address);
+            }
+        }
+        if self.action.is_none() {
+            return Err(Error::Argument(
+                "Missing subcommand or -D/-U, see --help".into(),
+            ));
+        }
+        Ok(())
     }
 }
 
@@ -262,90 +632,574 @@ fn get_length_from_file(file: &File, length: Option<u32>) -> Result<u32, Error>
     })
 }
 
-async fn run_main() -> Result<(), Error> {
-    let args = Args::new()?;
-    let mut dfu = if args.id_vendor != 0 && args.id_product != 0 {
-        Dfu::from_vid_pid(args.id_vendor, args.id_product, args.intf, args.alt).await?
-    } else {
-        Dfu::from_bus_device(args.bus, args.device, args.intf, args.alt).await?
+/// Map `file` when `mmap` is set
+fn map_file(file: &File, mmap: bool) -> Result<Option<memmap2::Mmap>, Error> {
+    if !mmap {
+        return Ok(None);
+    }
+    // SAFETY: the map is only read, an image changed by another process while flashing is
+    // caught by verify like one changed between two reads
+    Ok(Some(unsafe { memmap2::Mmap::map(file)? }))
+}
+
+/// `data` written at `address` filled with `byte` up to the end of the page holding its last byte
+fn pad_to_page(mut data: Vec<u8>, address: u32, byte: u8, layout: &dfu_nusb::MemoryLayout) -> Result<Vec<u8>, Error> {
+    let last = u32::try_from(data.len().saturating_sub(1))
+        .ok()
+        .and_then(|len| address.checked_add(len))
+        .ok_or_else(|| Error::Argument(format!("{} bytes at 0x{:08X} do not fit in the address space", data.len(), address)))?;
+    let page = layout.address(last)?;
+    data.resize((page.address as u64 + page.size as u64 - address as u64) as usize, byte);
+    Ok(data)
+}
+
+/// Print where flash differs from `file` after verify failed at `at`, and with `diff_out` write
+/// every mismatch up to the end of the range there
+async fn show_verify_diff(
+    dfu: &mut Dfu,
+    file: &mut File,
+    address: u32,
+    length: u32,
+    at: u32,
+    diff_out: Option<&Path>,
+) {
+    let start = (at & !0xF).max(address);
+    let len = match diff_out {
+        Some(_) => address + length - start,
+        None => (address + length - start).min(4096),
     };
-    dfu.status_wait_for(0, Some(State::DfuIdle)).await?;
-    log::info!("Execute action: {}", args.action);
-    match args.action {
-        Action::SupportedCommands => {
-            let supported_cmds = dfu.dfuse_get_commands().await?;
-            println!("Supported commands:");
-            for cmd in supported_cmds {
-                println!("{}", cmd);
+    let mut expected = vec![0; len as usize];
+    let mut actual = vec![0; len as usize];
+    let read = async {
+        file.seek(SeekFrom::Start((start - address) as u64))?;
+        file.read_exact(&mut expected)?;
+        dfu.abort_to_idle_clear_once().await?;
+        dfu.read_flash_to_slice(start, &mut actual).await
+    };
+    match read.await {
+        Ok(n) => {
+            actual.truncate(n);
+            let color = color::Theme::stdout().enabled();
+            let shown = expected.len().min(4096);
+            print!(
+                "{}",
+                verify_diff::render(start, &expected[..shown], &actual[..n.min(shown)], 4, color)
+            );
+            if let Some(path) = diff_out {
+                let diff = verify_diff::Diff {
+                    address,
+                    length,
+                    mismatches: verify_diff::mismatches(address, start, &expected, &actual),
+                };
+                match verify_diff::write_json(path, &diff) {
+                    Ok(()) => info!("Wrote {} mismatching regions to {:?}", diff.mismatches.len(), path),
+                    Err(e) => log::warn!("Could not write the diff to {:?}: {}", path, e),
+                }
             }
-            Ok(())
         }
-        Action::Reset(a) => dfu.reset_stm32(a.address).await,
-        Action::Read(a) => dfu.upload(
-            &mut OpenOptions::new()
-                .write(true)
-                .create(a.overwrite)
-                .truncate(a.overwrite)
-                .create_new(!a.overwrite)
-                .open(a.file_name)?,
-            a.address.0,
-            a.address.1,
-        ).await,
-        Action::Write(a) => {
-            let f = &mut OpenOptions::new().read(true).open(a.file_name)?;
-            let len = get_length_from_file(f, a.address.1).unwrap();
-            dfu.download_raw(f, a.address.0, len).await
+        Err(e) => log::warn!("Could not read back the mismatch: {}", e),
+    }
+}
+
+/// Add the address range of the operation to the current span
+fn record_range(address: u32, length: u32) {
+    tracing::Span::current()
+        .record("address", format!("0x{:08X}", address))
+        .record("length", length);
+}
+
+/// Restore the backups taken before a failed write, the one of the failing image first
+async fn rollback(dfu: &mut Dfu, written: &[Backup], err: Error) -> Error {
+    let mut backups: Vec<_> = dfu.last_backup().cloned().into_iter().collect();
+    // Images written earlier in the session, newest first
+    for backup in written.iter().rev() {
+        if !backups.contains(backup) {
+            backups.push(backup.clone());
         }
-        Action::Verify(a) => {
-            let f = &mut OpenOptions::new().read(true).open(a.file_name)?;
-            let len = get_length_from_file(f, a.address.1).unwrap();
-            dfu.verify(f, a.address.0, len).await?;
-            info!("Verify done");
-            Ok(())
+    }
+    for backup in backups {
+        log::warn!("Write failed: {}, restoring backup {:?}", err, backup.path);
+        match dfu.restore(&backup).await {
+            Ok(()) => info!("Rollback succeeded"),
+            Err(e) => log::error!("Rollback failed: {}", e),
         }
-        Action::EraseAll => dfu.mass_erase().await,
-        Action::Erase(a) => dfu.erase_pages(a.address.0, a.address.1).await,
-        Action::Detach => dfu.detach().await,
-        Action::ReadAddress(a) => {
-            let mut buf = vec![0; a.address.1 as usize];
-            let len = dfu.read_flash_to_slice(a.address.0, &mut buf).await?;
-            // let mut address = a.address.0;
-            //print!("0x{:08X} ", address);
-            //address += 16;
-            println!("{:?}", buf[0..len].hex_dump());
-            Ok(())
+    }
+    err
+}
+
+/// Mass erase, reading the `keep` ranges first and writing them back afterwards. Kept data that
+/// can not be written back is saved to `keep-0x<address>.bin`.
+async fn erase_all(dfu: &mut Dfu, keep: &[(u32, u32)]) -> Result<(), Error> {
+    let mut kept = Vec::new();
+    for &(address, length) in keep {
+        let mut data = vec![0; length as usize];
+        if dfu.read_flash_to_slice(address, &mut data).await? < data.len() {
+            return Err(Error::InvalidControlResponse(format!("Short read of 0x{:08X}", address)));
+        }
+        info!("Keep 0x{:08X} {} bytes", address, length);
+        kept.push((address, data));
+    }
+    dfu.mass_erase().await?;
+    for (address, data) in &kept {
+        let restored = async {
+            dfu.download_slice(data, *address).await?;
+            dfu.verify_slice(data, *address).await
         }
-        Action::SetAddress(a) => dfu.set_address(a.address).await,
-        Action::MemoryLayout => {
-            dfu.memory_layout().pages().iter().for_each(|p| {
-                println!("Start: 0x{:08X} Size: {} bytes", p.address, p.size)
-            });
-            Ok(())
+        .await;
+        if let Err(e) = restored {
+            let path = PathBuf::from(format!("keep-0x{:08X}.bin", address));
+            match std::fs::write(&path, data) {
+                Ok(()) => log::error!("Could not restore 0x{:08X}, saved to {:?}", address, path),
+                Err(w) => log::error!("Could not restore 0x{:08X} nor save it: {}", address, w),
+            }
+            return Err(e);
         }
+        info!("Restored 0x{:08X} {} bytes", address, data.len());
     }
+    Ok(())
 }
 
-fn env_logger_init(_appname: &str, verbose: usize) {
-    use env_logger::Builder;
-    use log::LevelFilter;
-    match verbose {
-        0 => Builder::from_default_env()
-            .filter(None, LevelFilter::Info)
-            .format_timestamp_millis()
-            .init(),
-        1 => Builder::from_default_env()
-            .filter(None, LevelFilter::Debug)
-            .init(),
-        _ => Builder::from_default_env()
-            .filter(None, LevelFilter::Trace)
-            .init(),
+/// Bring the device back to dfuIDLE after Ctrl-C dropped the running transfer
+async fn interrupted(dfu: &mut Dfu) {
+    log::warn!(
+        "Interrupted after {} bytes of the current transfer, abort to idle",
+        dfu.progress()
+    );
+    match dfu.abort_to_idle_clear_once().await {
+        Ok(()) => info!("Device is idle"),
+        Err(e) => log::error!("Abort to idle failed {}", e),
     }
 }
 
+async fn run_main() -> Result<(), Error> {
+    let args = Args::new()?;
+    if let Some(Action::Unpack(a)) = &args.action {
+        return unpack::unpack(a);
+    }
+    if let Some(Action::Bindiff(a)) = &args.action {
+        return bindiff::bindiff(a);
+    }
+    if let Some(Action::List(a)) = &args.action {
+        return list::list(a);
+    }
+    if let Some(Action::GenUdevRule(a)) = &args.action {
+        return udev::gen_udev_rule(a, args.settings.dev.as_deref());
+    }
+    let settings = &args.settings;
+    let setup = Setup {
+        transfer_size: settings.transfer_size,
+        timeout: settings.timeout,
+        retries: settings.retries,
+        language: settings.language,
+        backup: args.backup.clone(),
+        protect: args.protect.clone(),
+        force: args.force,
+        alias: args.alias.clone(),
+    };
+    if let Some(Action::Update(a)) = &args.action {
+        let alt = settings.alt.clone().unwrap_or(AltSetting::Number(0));
+        return update::update(a, &args.filter, settings.intf.unwrap_or(0), alt, &setup).await;
+    }
+    #[cfg(feature = "serve")]
+    if let Some(Action::Serve(a)) = &args.action {
+        let alt = settings.alt.clone().unwrap_or(AltSetting::Number(0));
+        return serve::serve(a, settings.intf.unwrap_or(0), alt, setup).await;
+    }
+    if let Some(Action::Doctor(a)) = &args.action {
+        let alt = settings.alt.clone().unwrap_or(AltSetting::Number(0));
+        return doctor::doctor(a, &args.filter, settings.intf.unwrap_or(0), &alt, settings.language).await;
+    }
+    let alt = match (&settings.alt, &args.action) {
+        (Some(alt), _) => alt.clone(),
+        (None, Some(action)) if action.uses_option_bytes() => AltSetting::Name(option_bytes::ALT_NAME.into()),
+        (None, _) => AltSetting::Number(0),
+    };
+    let mut dfu = Dfu::open_with_language(
+        &args.filter,
+        settings.intf.unwrap_or(0),
+        &alt,
+        setup.language,
+    )
+    .await?;
+    setup.apply(&mut dfu)?;
+    if let Some(ProgressFormat::Json) = args.progress {
+        let layout = dfu.memory_layout().clone();
+        dfu.set_on_progress(move |p| eprintln!("{}", progress::event(p, &layout)));
+    }
+    log::info!(
+        "Device: {} {} serial {} release 0x{:04X}",
+        dfu.manufacturer_string().unwrap_or("-"),
+        dfu.product_string().unwrap_or("-"),
+        dfu.serial_number().unwrap_or("-"),
+        dfu.bcd_device().unwrap_or(0)
+    );
+    dfu.status_wait_for(0, Some(State::DfuIdle)).await?;
+    let action = args
+        .action
+        .ok_or_else(|| Error::Argument("Missing action".into()))?;
+    log::info!("Execute action: {}", action);
+    let logged = matches!(action, Action::Write(_) | Action::Provision(_));
+    let hooked = args.on_success.is_some() || args.on_failure.is_some();
+    let mut record = Record::new(SystemTime::now());
+    record.serial = dfu.serial_number().map(String::from);
+    if let (true, Some(uid)) = (logged && args.result_log.is_some() || hooked, args.uid_address) {
+        let mut buf = [0; 12];
+        let address = uid.resolve(dfu.memory_layout())?;
+        let len = dfu.read_flash_to_slice(address, &mut buf).await?;
+        record.chip_id = Some(dfu_nusb::hex::to_hex(&buf[..len]));
+    }
+    dfu.reset_stats();
+    let started = Instant::now();
+    let action_name = action.to_string();
+    #[cfg(feature = "monitor")]
+    let starts_app = args.leave.is_some() || matches!(&action, Action::Reset(_) | Action::Write(WriteArgs { reset: Some(_), .. }));
+    let span = tracing::info_span!(
+        "action",
+        device = %args.filter,
+        action = %action_name,
+        address = Empty,
+        length = Empty
+    );
+    let run = async {
+        match action {
+            Action::Info(a) => info::info(&mut dfu, &a).await,
+            Action::SupportedCommands(a) => supported_commands::supported_commands(&mut dfu, &a).await,
+            Action::Reset(a) => {
+                let address = a.address.resolve(dfu.memory_layout())?;
+                dfu.reset_stm32(address).await
+            }
+            Action::Read(a) => {
+                let address = dfu.canonical_address(a.address.0.resolve(dfu.memory_layout())?);
+                let length = match a.to_end {
+                    true => dfu.memory_layout().end_address()?.saturating_sub(address),
+                    false => a.address.1,
+                };
+                record_range(address, length);
+                dfu.upload(
+                    &mut OpenOptions::new()
+                        .write(true)
+                        .create(a.overwrite)
+                        .truncate(a.overwrite)
+                        .create_new(!a.overwrite)
+                        .open(a.file_name)?,
+                    address,
+                    length,
+                ).await
+            }
+            Action::Write(a) => {
+                let mut images = vec![Image::open(&dfu, &a.flash.file_name, a.flash.address, a.flash.mmap, a.pad)?];
+                for (address, file_name) in &a.more {
+                    images.push(Image::open(&dfu, file_name, *address, a.flash.mmap, a.pad)?);
+                }
+                let ranges: Vec<_> = images.iter().map(|i| (dfu.canonical_address(i.address), i.len)).collect();
+                images::check_disjoint("Images", &ranges, dfu.memory_layout())?;
+                if args.result_log.is_some() || hooked {
+                    let contents = images.iter().map(Image::contents).collect::<Result<Vec<_>, _>>()?;
+                    record.sha256 = Some(sha256_hex(&contents.concat()));
+                }
+                let start = images.iter().map(|i| i.address).min().unwrap_or(0);
+                let end = images.iter().map(|i| i.address + i.len).max().unwrap_or(0);
+                record_range(start, end - start);
+                let several = images.len() > 1;
+                let mut backups = Vec::new();
+                let written = async {
+                    for image in &mut images {
+                        if several {
+                            info!("Write {:?} at 0x{:08X}, {} bytes", image.file_name, image.address, image.len);
+                        }
+                        let (address, len) = (image.address, image.len);
+                        match a.resume_from {
+                            None => match image.data() {
+                                Some(data) => dfu.download_slice(data, address).await?,
+                                None => dfu.download_raw(&mut image.file, address, len).await?,
+                            },
+                            Some(resume) => {
+                                let f = &mut image.file;
+                                let offset = match resume {
+                                    Resume::Offset(offset) => offset,
+                                    Resume::Auto => {
+                                        let offset = dfu.resume_offset(f, address, len).await?;
+                                        f.seek(SeekFrom::Start(0))?;
+                                        offset
+                                    }
+                                };
+                                dfu.download_raw_resume(f, address, len, offset).await?
+                            }
+                        }
+                        backups.extend(dfu.last_backup().cloned());
+                    }
+                    if a.verify {
+                        for image in &mut images {
+                            let (address, len) = (image.address, image.len);
+                            image.file.seek(SeekFrom::Start(0))?;
+                            let verified = match image.data() {
+                                Some(data) => dfu.verify_slice(data, address).await,
+                                None => dfu.verify(&mut image.file, address, len).await,
+                            };
+                            if let Err(e) = verified {
+                                if let Error::Verify(at) = e {
+                                    let diff_out = a.flash.diff_out.as_deref();
+                                    show_verify_diff(&mut dfu, &mut image.file, address, len, at, diff_out).await;
+                                }
+                                return Err(e);
+                            }
+                        }
+                        match several {
+                            true => info!("Verify done, {} images", images.len()),
+                            false => info!("Verify done"),
+                        }
+                    }
+                    Ok(())
+                }
+                .await;
+                if let Err(e) = written {
+                    return Err(rollback(&mut dfu, &backups, e).await);
+                }
+                if let Some(reset) = a.reset {
+                    let address = reset
+                        .unwrap_or(Address::flash())
+                        .resolve(dfu.memory_layout())?;
+                    info!("Write done, leave DFU mode and start application at 0x{:08X}", address);
+                    dfu.reset_stm32(address).await?;
+                }
+                Ok(())
+            }
+            Action::Verify(a) => {
+                let f = &mut OpenOptions::new().read(true).open(a.file_name)?;
+                let len = get_length_from_file(f, a.address.1)?;
+                let address = a.address.0.resolve(dfu.memory_layout())?;
+                record_range(address, len);
+                let verified = match map_file(f, a.mmap)? {
+                    Some(map) => dfu.verify_slice(&map[..len as usize], address).await,
+                    None => dfu.verify(f, address, len).await,
+                };
+                if let Err(e) = verified {
+                    if let Error::Verify(at) = e {
+                        show_verify_diff(&mut dfu, f, address, len, at, a.diff_out.as_deref()).await;
+                    }
+                    return Err(e);
+                }
+                info!("Verify done");
+                Ok(())
+            }
+            Action::EraseAll(a) => {
+                let mut keep = Vec::new();
+                for (address, length) in &a.keep {
+                    keep.push((address.resolve(dfu.memory_layout())?, *length));
+                }
+                images::check_disjoint("Kept ranges", &keep, dfu.memory_layout())?;
+                erase_all(&mut dfu, &keep).await
+            }
+            Action::Erase(a) => {
+                let address = a.address.0.resolve(dfu.memory_layout())?;
+                dfu.erase_pages(address, a.address.1).await
+            }
+            Action::Detach => dfu.detach().await,
+            Action::ReadAddress(a) => {
+                let address = a.region.address.0.resolve(dfu.memory_layout())?;
+                let mut buf = vec![0; a.region.address.1 as usize];
+                let len = dfu.read_flash_to_slice(address, &mut buf).await?;
+                match a.raw {
+                    Some(Some(path)) => std::fs::write(path, &buf[..len])?,
+                    Some(None) => std::io::stdout().write_all(&buf[..len])?,
+                    None => print!("{}", hexdump::hex_dump(address, &buf[..len], a.width)),
+                }
+                Ok(())
+            }
+            Action::SetAddress(a) => {
+                let address = a.address.resolve(dfu.memory_layout())?;
+                dfu.set_address(address).await
+            }
+            Action::MemoryLayout(a) => {
+                print!("{}", layout::render(dfu.memory_layout(), a.format));
+                Ok(())
+            }
+            Action::Provision(a) => {
+                let (row, image) = provision::prepare(&a)?;
+                record.sha256 = Some(sha256_hex(&image));
+                if let Err(e) = provision::provision(&mut dfu, &a, &image).await {
+                    return Err(rollback(&mut dfu, &[], e).await);
+                }
+                provision::record_used(&a, row)
+            }
+            Action::Dump(a) => dump::dump(&mut dfu, &a).await,
+            Action::Benchmark(a) => benchmark::benchmark(&mut dfu, &a).await,
+            Action::Raw(a) => raw::raw(&mut dfu, &a).await,
+            Action::Protect(a) => option_bytes::write_protect(&mut dfu, &a.sectors, true).await,
+            Action::Unprotect(a) => option_bytes::write_protect(&mut dfu, &a.sectors, false).await,
+            Action::ReadOptionBytes => option_bytes::read_option_bytes(&mut dfu).await,
+            Action::SetRdp(a) => option_bytes::set_rdp(&mut dfu, &a).await,
+            Action::Unpack(_) | Action::Bindiff(_) | Action::GenUdevRule(_) | Action::List(_) => {
+                unreachable!("handled without a device")
+            }
+            Action::Doctor(_) | Action::Update(_) => unreachable!("handled before opening"),
+            #[cfg(feature = "serve")]
+            Action::Serve(_) => unreachable!("handled before opening"),
+        }
+    };
+    let result = tokio::select! {
+        result = run.instrument(span) => result,
+        _ = tokio::signal::ctrl_c() => Err(Error::Interrupted),
+    };
+    if let Err(Error::Interrupted) = result {
+        interrupted(&mut dfu).await;
+    }
+    let summary = OperationSummary::new(dfu.stats().clone(), started.elapsed());
+    info!("{}", summary);
+    if args.stats {
+        print!("{}", stats::render(&summary));
+    }
+    record.finish(started.elapsed(), &result);
+    if let (true, Some(path)) = (logged, &args.result_log) {
+        ResultLog::new(path).append(&record)?;
+    }
+    let hook = match &result {
+        Ok(()) => &args.on_success,
+        Err(_) => &args.on_failure,
+    };
+    if let Some(command) = hook {
+        hook::run(command, &hook::environment(&action_name, &record, &result));
+    }
+    result?;
+    if let Some(address) = args.leave {
+        let address = address.resolve(dfu.memory_layout())?;
+        log::info!("Leave DFU mode and start application at 0x{:08X}", address);
+        dfu.reset_stm32(address).await?;
+    }
+    #[cfg(feature = "monitor")]
+    if let (true, Some(port)) = (starts_app, &args.monitor) {
+        drop(dfu);
+        monitor::monitor(port, args.baud).await?;
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
     if let Err(err) = run_main().await {
         log::error!("{}", err);
-        std::process::exit(i32::from(err));
+        match &err {
+            Error::PermissionDenied(dev) => {
+                log::error!("Run `dfu-flasher gen-udev-rule -d {}` to print one", dev)
+            }
+            Error::DriverNotBound(_) => {
+                log::error!("Install WinUSB for the device, e.g. with Zadig from https://zadig.akeo.ie")
+            }
+            Error::ExclusiveAccess(_) => {
+                log::error!("Quit the program holding the device, e.g. a VM with USB passthrough")
+            }
+            Error::AccessRestricted(_) => {
+                log::error!("Sandboxed apps need the com.apple.security.device.usb entitlement")
+            }
+            _ => {}
+        }
+        std::process::exit(err.exit_code().into());
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_cli() {
+        use crate::*;
+        use clap::CommandFactory;
+        Args::command().debug_assert();
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "w", "-f", "fw.bin", "-vv"]).unwrap();
+        assert_eq!(2, args.verbose);
+        assert!(matches!(args.action, Some(Action::Write(_))));
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "verify", "-f", "fw.bin", "--mmap"]).unwrap();
+        assert!(matches!(args.action, Some(Action::Verify(VWFlashArgs { mmap: true, .. }))));
+    }
+
+    #[test]
+    fn test_parse_protect() {
+        use crate::*;
+        let args = Args::try_parse_from([
+            "dfu-flasher-nusb", "--protect", "flash:0x4000", "--protect", "0x1FFF0000:0x7800", "w", "-f", "fw.bin",
+        ])
+        .unwrap();
+        assert_eq!(vec![0x4000, 0x7800], args.protect.iter().map(|p| p.1).collect::<Vec<_>>());
+        assert!(!args.force);
+    }
+
+    #[test]
+    fn test_parse_alias() {
+        use crate::*;
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "--alias", "0x0=flash:0x100000", "r", "-f", "fw.bin"]).unwrap();
+        assert_eq!(Some(0x10_0000), args.alias[0].1 .1);
+    }
+
+    #[test]
+    fn test_parse_hooks() {
+        use crate::*;
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "--on-failure", "notify-send failed", "w", "-f", "fw.bin"]).unwrap();
+        assert_eq!((None, Some("notify-send failed")), (args.on_success.as_deref(), args.on_failure.as_deref()));
+    }
+
+    #[test]
+    fn test_parse_output() {
+        use crate::*;
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "list", "--no-color"]).unwrap();
+        assert!(args.no_color);
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "w", "-f", "fw.bin", "--progress", "json"]).unwrap();
+        assert_eq!(Some(ProgressFormat::Json), args.progress);
+    }
+
+    #[test]
+    fn test_parse_write() {
+        use crate::*;
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "w", "-f", "fw.bin", "--pad", "0xFF"]).unwrap();
+        assert!(matches!(args.action, Some(Action::Write(WriteArgs { pad: Some(0xFF), .. }))));
+        assert!(Args::try_parse_from(["dfu-flasher-nusb", "w", "-f", "fw.bin", "--pad", "0x100"]).is_err());
+        let args = Args::try_parse_from([
+            "dfu-flasher-nusb", "write", "-s", "0x08000000", "-f", "boot.bin", "--write", "flash+0x8000=app.bin", "--verify",
+        ])
+        .unwrap();
+        let Some(Action::Write(w)) = args.action else { panic!("not a write") };
+        assert_eq!(1, w.more.len());
+        assert_eq!(PathBuf::from("app.bin"), w.more[0].1);
+        assert!(Args::try_parse_from([
+            "dfu-flasher-nusb", "write", "-f", "boot.bin", "--write", "flash+0x8000=app.bin", "--resume-from", "auto",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_erase_all() {
+        use crate::*;
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "erase-all", "--keep", "0x0800C000:0x4000"]).unwrap();
+        let Some(Action::EraseAll(e)) = args.action else { panic!("not erase-all") };
+        assert_eq!(vec![(Address::from(0x0800_C000), 0x4000)], e.keep);
+    }
+
+    #[test]
+    fn test_parse_read() {
+        use crate::*;
+        // Only -U without a length reads to the end of the layout
+        let mut args = Args::try_parse_from(["dfu-flasher-nusb", "-U", "fw.bin"]).unwrap();
+        args.dfu_util_compat().unwrap();
+        assert!(matches!(args.action, Some(Action::Read(ReadFlashArgs { to_end: true, .. }))));
+        let mut args = Args::try_parse_from(["dfu-flasher-nusb", "-s", "0x08000000:0x100", "-U", "fw.bin"]).unwrap();
+        args.dfu_util_compat().unwrap();
+        assert!(matches!(args.action, Some(Action::Read(ReadFlashArgs { address: (_, 0x100), to_end: false, .. }))));
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "r", "-s", "0x08000000:0", "-f", "fw.bin"]).unwrap();
+        assert!(matches!(args.action, Some(Action::Read(ReadFlashArgs { address: (_, 0), to_end: false, .. }))));
+    }
+
+    #[test]
+    fn test_pad_to_page() {
+        use crate::*;
+        let layout = dfu_nusb::MemoryLayout::from_str("@Internal Flash  /0x08000000/04*016Kg,01*064Kg").unwrap();
+        let padded = pad_to_page(vec![1; 100], 0x0800_0000, 0xFF, &layout).unwrap();
+        assert_eq!(0x4000, padded.len());
+        assert_eq!((1, 0xFF), (padded[99], padded[100]));
+        // Ending on a page boundary adds nothing
+        assert_eq!(0x4000, pad_to_page(vec![0; 0x4000], 0x0800_0000, 0, &layout).unwrap().len());
+        // From the middle of the 16K sector 3 into the 64K sector
+        assert_eq!(0x1_2000, pad_to_page(vec![0; 0x3000], 0x0800_E000, 0, &layout).unwrap().len());
+        assert!(pad_to_page(vec![0; 4], 0x0900_0000, 0, &layout).is_err());
+        // Near the top of the address space, never past it
+        let top = dfu_nusb::MemoryLayout::from_str("/0xFFFF0000/03*016Kg").unwrap();
+        assert_eq!(0x100, pad_to_page(vec![0; 4], 0xFFFF_BF00, 0, &top).unwrap().len());
+        assert!(matches!(pad_to_page(vec![0; 0x101], 0xFFFF_FF00, 0, &top), Err(Error::Argument(_))));
     }
 }
@@ -0,0 +1,71 @@
+use dfu_nusb::error::Error;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long the port of a freshly started application may take to appear
+const OPEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Open `port`, retrying while the device re-enumerates after leaving DFU mode
+fn open(port: &str, baud: u32) -> Result<Box<dyn serialport::SerialPort>, Error> {
+    let started = Instant::now();
+    loop {
+        match serialport::new(port, baud).timeout(Duration::from_millis(100)).open() {
+            Ok(serial) => return Ok(serial),
+            Err(e) if started.elapsed() < OPEN_TIMEOUT => log::debug!("Open {}: {}, retrying", port, e),
+            Err(e) => return Err(Error::Argument(format!("Could not open {}: {}", port, e))),
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Copy everything `serial` sends to `out` until `stop` is set or the port goes away
+fn copy(serial: &mut (impl Read + ?Sized), out: &mut impl Write, stop: &AtomicBool) -> Result<(), Error> {
+    let mut buf = [0; 1024];
+    while !stop.load(Ordering::Relaxed) {
+        match serial.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                out.write_all(&buf[..n])?;
+                out.flush()?;
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Stream the output of the started application on `port` to stdout until Ctrl-C
+pub async fn monitor(port: &str, baud: u32) -> Result<(), Error> {
+    let (port, stop) = (port.to_string(), Arc::new(AtomicBool::new(false)));
+    let reader = {
+        let stop = stop.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut serial = open(&port, baud)?;
+            log::info!("Monitoring {} at {} baud, Ctrl-C to quit", port, baud);
+            copy(serial.as_mut(), &mut std::io::stdout().lock(), &stop)
+        })
+    };
+    tokio::select! {
+        result = reader => result.map_err(|e| Error::Argument(format!("Monitor failed: {}", e)))?,
+        _ = tokio::signal::ctrl_c() => {
+            stop.store(true, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_copy() {
+        use crate::monitor::*;
+        let mut out = Vec::new();
+        copy(&mut "Hello from the app\n".as_bytes(), &mut out, &AtomicBool::new(false)).unwrap();
+        assert_eq!(b"Hello from the app\n", out.as_slice());
+        let mut out = Vec::new();
+        copy(&mut "unread".as_bytes(), &mut out, &AtomicBool::new(true)).unwrap();
+        assert!(out.is_empty());
+    }
+}
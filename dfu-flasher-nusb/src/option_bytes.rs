@@ -0,0 +1,166 @@
+use crate::color::Theme;
+use crate::hexdump::hex_dump;
+use dfu_nusb::error::Error;
+use dfu_nusb::{Dfu, OptionBytes};
+use std::fmt::Write;
+use std::io::BufRead;
+
+/// Name of the alt setting the STM32 bootloader exposes the option bytes on
+pub const ALT_NAME: &str = "Option Bytes";
+
+#[derive(clap::Args, PartialEq)]
+pub struct ProtectArgs {
+    /// Flash sectors to protect, e.g. 0 1 for a bootloader in the first 32K of an STM32F4
+    #[arg(required = true, num_args = 1..)]
+    pub sectors: Vec<u8>,
+}
+
+#[derive(clap::Args, PartialEq)]
+pub struct UnprotectArgs {
+    /// Flash sectors to unprotect, all of them when none are given
+    pub sectors: Vec<u8>,
+}
+
+/// Print the option bytes as a hex dump and a table of their decoded fields
+pub async fn read_option_bytes(dfu: &mut Dfu) -> Result<(), Error> {
+    let option_bytes = dfu.read_option_bytes().await?;
+    print!("{}", render(&option_bytes));
+    Ok(())
+}
+
+fn render(option_bytes: &OptionBytes) -> String {
+    let mut out = format!("{} option bytes\n", option_bytes.family.name);
+    out.push_str(&hex_dump(option_bytes.family.address, &option_bytes.bytes, 16));
+    let _ = writeln!(out, "{:<10} {:>6} Meaning", "Field", "Value");
+    for d in option_bytes.decode() {
+        let _ = writeln!(out, "{:<10} {:>6} {}", d.name, format!("0x{:X}", d.value), d.meaning);
+    }
+    out
+}
+
+/// What has to be typed to set RDP level 2
+const CONFIRM_LEVEL_2: &str = "LOCK FOREVER";
+
+#[derive(clap::Args, PartialEq)]
+pub struct SetRdpArgs {
+    /// Readout protection level: 0 none, 1 no debug access to flash, 2 permanently locked
+    #[arg(value_parser = clap::value_parser!(u8).range(0..=2), required_unless_present = "check")]
+    pub level: Option<u8>,
+    /// Only report the current level
+    #[arg(long, conflicts_with = "level")]
+    pub check: bool,
+    /// Allow level 2, which disables debug access and DFU for good. It still has to be confirmed on stdin.
+    #[arg(long)]
+    pub permanent: bool,
+}
+
+/// Report the readout protection level and change it to `a.level`
+pub async fn set_rdp(dfu: &mut Dfu, a: &SetRdpArgs) -> Result<(), Error> {
+    let mut option_bytes = dfu.read_option_bytes().await?;
+    let current = option_bytes.rdp_level();
+    log::info!("{}: RDP level {}", option_bytes.family.name, current);
+    let level = match a.level {
+        Some(level) if !a.check => level,
+        _ => return Ok(()),
+    };
+    if level == current {
+        log::info!("RDP level unchanged");
+        return Ok(());
+    }
+    if level == 2 {
+        if !a.permanent {
+            return Err(Error::Argument(
+                "RDP level 2 can never be undone, the device can not be debugged or reflashed afterwards. Add --permanent to go ahead".into(),
+            ));
+        }
+        let prompt = format!("Type {} to set RDP level 2 on this device:", CONFIRM_LEVEL_2);
+        eprint!("{} ", Theme::stderr().warning(prompt));
+        if !confirmed(&mut std::io::stdin().lock())? {
+            return Err(Error::Argument("RDP level 2 not confirmed".into()));
+        }
+    }
+    if current == 1 && level == 0 {
+        log::warn!("Going back to RDP level 0 mass erases the flash");
+    }
+    option_bytes.set_rdp_level(level)?;
+    dfu.write_option_bytes(&option_bytes).await?;
+    log::info!("RDP level {} written, the device resets to load it", level);
+    Ok(())
+}
+
+fn confirmed(input: &mut impl BufRead) -> Result<bool, Error> {
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    Ok(line.trim() == CONFIRM_LEVEL_2)
+}
+
+/// Set or clear the write protection of `sectors`, all sectors when empty, and program the
+/// option bytes if that changes them
+pub async fn write_protect(dfu: &mut Dfu, sectors: &[u8], protect: bool) -> Result<(), Error> {
+    let mut option_bytes = dfu.read_option_bytes().await?;
+    let before = option_bytes.clone();
+    let all: Vec<u8> = (0..option_bytes.family.sectors).collect();
+    for sector in if sectors.is_empty() { &all } else { sectors } {
+        option_bytes.set_write_protect(*sector, protect)?;
+    }
+    if option_bytes == before {
+        log::info!("Option bytes unchanged, write protected sectors: {:?}", before.write_protected());
+        return Ok(());
+    }
+    log::info!(
+        "{}: write protected sectors {:?} -> {:?}",
+        option_bytes.family.name,
+        before.write_protected(),
+        option_bytes.write_protected()
+    );
+    dfu.write_option_bytes(&option_bytes).await?;
+    log::info!("Option bytes written, the device resets to load them");
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_confirmed() {
+        use crate::option_bytes::*;
+        assert!(confirmed(&mut "LOCK FOREVER\n".as_bytes()).unwrap());
+        assert!(!confirmed(&mut "y\n".as_bytes()).unwrap());
+        assert!(!confirmed(&mut "".as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn test_render() {
+        use crate::option_bytes::*;
+        let mut bytes = vec![0xFF; 16];
+        bytes[1] = 0xAA;
+        let ob = OptionBytes::new(&dfu_nusb::option_bytes::FAMILIES[0], bytes).unwrap();
+        let out = render(&ob);
+        assert!(out.starts_with("STM32F2/F4 option bytes\n0x1FFFC000: FF AA FF"));
+        assert!(out.contains("RDP          0xAA level 0\n"));
+        assert!(out.contains("BOR_LEV       0x3 off\n"));
+        assert!(out.contains("nWRP       0xFFFF no sector write protected\n"));
+    }
+
+    #[test]
+    fn test_parse_protect() {
+        use crate::option_bytes::*;
+        use crate::{Action, Args};
+        use clap::Parser;
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "protect", "0", "1"]).unwrap();
+        assert!(matches!(args.action, Some(Action::Protect(ProtectArgs { sectors })) if sectors == [0, 1]));
+        assert!(Args::try_parse_from(["dfu-flasher-nusb", "protect"]).is_err());
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "unprotect"]).unwrap();
+        assert!(matches!(args.action, Some(Action::Unprotect(UnprotectArgs { sectors })) if sectors.is_empty()));
+    }
+
+    #[test]
+    fn test_parse_set_rdp() {
+        use crate::option_bytes::*;
+        use crate::{Action, Args};
+        use clap::Parser;
+        let args = Args::try_parse_from(["dfu-flasher-nusb", "set-rdp", "--check"]).unwrap();
+        assert!(matches!(args.action, Some(Action::SetRdp(SetRdpArgs { level: None, check: true, .. }))));
+        assert!(Args::try_parse_from(["dfu-flasher-nusb", "set-rdp"]).is_err());
+        assert!(Args::try_parse_from(["dfu-flasher-nusb", "set-rdp", "3"]).is_err());
+        assert!(Args::try_parse_from(["dfu-flasher-nusb", "set-rdp", "1", "--check"]).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use dfu_nusb::{MemoryLayout, Progress};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressFormat {
+    /// One JSON object per erased page and transferred block on stderr
+    Json,
+}
+
+impl FromStr for ProgressFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "json" => Ok(ProgressFormat::Json),
+            s => Err(format!("unsupported progress format '{}', expect json", s)),
+        }
+    }
+}
+
+/// `progress` as a JSON line, with the index of its page in `layout`
+pub fn event(progress: &Progress, layout: &MemoryLayout) -> String {
+    let page = layout
+        .pages()
+        .iter()
+        .position(|p| p.address <= progress.address && progress.address - p.address < p.size);
+    serde_json::json!({
+        "phase": progress.activity.to_string(),
+        "done": progress.done,
+        "total": progress.total,
+        "address": format!("0x{:08X}", progress.address),
+        "page": page,
+        "percent": (progress.percent() * 10.0).round() / 10.0,
+    })
+    .to_string()
+}
+
+mod tests {
+    #[test]
+    fn test_event() {
+        use crate::progress::*;
+        use dfu_nusb::Activity;
+        let layout = MemoryLayout::from_str("@Internal Flash  /0x08000000/04*016Kg,01*064Kg").unwrap();
+        let progress = Progress {
+            activity: Activity::Write,
+            done: 0x4800,
+            total: 0x6000,
+            address: 0x0800_4000,
+        };
+        let event: serde_json::Value = serde_json::from_str(&event(&progress, &layout)).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "phase": "write", "done": 0x4800, "total": 0x6000, "address": "0x08004000", "page": 1, "percent": 75.0
+            }),
+            event
+        );
+        assert_eq!(Ok(ProgressFormat::Json), ProgressFormat::from_str("json"));
+        assert!(ProgressFormat::from_str("bar").is_err());
+    }
+}
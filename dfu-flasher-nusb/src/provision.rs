@@ -0,0 +1,315 @@
+use crate::address::{parse_int, Address};
+use dfu_nusb::error::Error;
+use dfu_nusb::hex::parse_hex;
+use dfu_nusb::Dfu;
+use log::info;
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Where a placeholder is located in the image
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    Offset(usize),
+    /// First occurrence of these bytes
+    Marker(Vec<u8>),
+}
+
+/// How a CSV value is turned into bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// Raw text, zero padded to the placeholder length
+    Ascii,
+    /// Hex digits, `:` and `-` are ignored so MAC addresses can be used as is
+    Hex,
+}
+
+/// `COLUMN@OFFSET:LEN[:ascii|hex]` or `COLUMN@marker=HEX:LEN[:ascii|hex]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patch {
+    pub column: String,
+    pub location: Location,
+    pub length: usize,
+    pub encoding: Encoding,
+}
+
+impl FromStr for Patch {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (column, rest) = s
+            .split_once('@')
+            .ok_or_else(|| format!("'{}': expect COLUMN@OFFSET:LEN", s))?;
+        let mut sp = rest.split(':');
+        let location = sp.next().unwrap_or("");
+        let location = match location.strip_prefix("marker=") {
+            Some(marker) => Location::Marker(parse_bytes(marker)?),
+            None => Location::Offset(
+                parse_int(location).map_err(|e| format!("'{}': {}", location, e))? as usize,
+            ),
+        };
+        let length = sp
+            .next()
+            .ok_or_else(|| format!("'{}': missing length", s))?;
+        let length = parse_int(length).map_err(|e| format!("'{}': {}", length, e))? as usize;
+        let encoding = match sp.next() {
+            None | Some("ascii") => Encoding::Ascii,
+            Some("hex") => Encoding::Hex,
+            Some(e) => return Err(format!("unsupported encoding '{}'", e)),
+        };
+        if column.is_empty() || length == 0 || sp.next().is_some() {
+            return Err(format!("'{}': expect COLUMN@OFFSET:LEN[:ascii|hex]", s));
+        }
+        Ok(Patch {
+            column: column.into(),
+            location,
+            length,
+            encoding,
+        })
+    }
+}
+
+/// Hex of a marker or value, at least one byte
+fn parse_bytes(s: &str) -> Result<Vec<u8>, String> {
+    match parse_hex(s)? {
+        bytes if bytes.is_empty() => Err(format!("'{}': expect hex bytes", s)),
+        bytes => Ok(bytes),
+    }
+}
+
+impl Patch {
+    /// Write `value` into the placeholder of `image`
+    pub fn apply(&self, image: &mut [u8], value: &str) -> Result<(), Error> {
+        let offset = match &self.location {
+            Location::Offset(o) => *o,
+            Location::Marker(m) => image
+                .windows(m.len())
+                .position(|w| w == m.as_slice())
+                .ok_or_else(|| Error::Argument(format!("marker for '{}' not found", self.column)))?,
+        };
+        let bytes = match self.encoding {
+            Encoding::Ascii => value.as_bytes().to_vec(),
+            Encoding::Hex => parse_bytes(value).map_err(Error::Argument)?,
+        };
+        if bytes.len() > self.length {
+            return Err(Error::Argument(format!(
+                "'{}' value '{}' is {} bytes, placeholder is {} bytes",
+                self.column,
+                value,
+                bytes.len(),
+                self.length
+            )));
+        }
+        let dst = image
+            .get_mut(offset..offset + self.length)
+            .ok_or_else(|| {
+                Error::Argument(format!(
+                    "placeholder for '{}' at 0x{:X} is outside the image",
+                    self.column, offset
+                ))
+            })?;
+        dst.fill(0);
+        dst[..bytes.len()].copy_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+/// Split CSV text into rows, supporting quoted fields with `""` escapes
+pub fn parse_csv(s: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\r' if !quoted => {}
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                if row.iter().any(|f| !f.is_empty()) {
+                    rows.push(std::mem::take(&mut row));
+                }
+                row.clear();
+            }
+            c => field.push(c),
+        }
+    }
+    row.push(field);
+    if row.iter().any(|f| !f.is_empty()) {
+        rows.push(row);
+    }
+    rows
+}
+
+#[derive(clap::Args, PartialEq)]
+pub struct ProvisionArgs {
+    /// start address, may be relative like flash+0x4000
+    #[arg(short = 's', long, default_value = "flash")]
+    pub address: Address,
+    /// Image containing the placeholders
+    #[arg(short = 'f', long, value_hint = clap::ValueHint::FilePath)]
+    pub file_name: PathBuf,
+    /// CSV with one device per row, the first row names the columns
+    #[arg(long, value_hint = clap::ValueHint::FilePath)]
+    pub csv: PathBuf,
+    /// Use data row <row> of the CSV, starting at 1
+    #[arg(long, required_unless_present = "next", conflicts_with = "next")]
+    pub row: Option<usize>,
+    /// Use the first row not yet listed in <csv>.used and record it there after flashing
+    #[arg(long)]
+    pub next: bool,
+    /// COLUMN@OFFSET:LEN[:ascii|hex] or COLUMN@marker=HEX:LEN[:ascii|hex], may be repeated
+    #[arg(long, required = true)]
+    pub patch: Vec<Patch>,
+    /// Read back and compare with the patched image after writing
+    #[arg(long)]
+    pub verify: bool,
+}
+
+fn used_path(csv: &Path) -> PathBuf {
+    let mut p = csv.as_os_str().to_owned();
+    p.push(".used");
+    PathBuf::from(p)
+}
+
+/// Row numbers already recorded in the `.used` file
+fn used_rows(path: &Path) -> Result<Vec<usize>, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(s) => Ok(s.lines().filter_map(|l| l.trim().parse().ok()).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Patch the image with the selected CSV row
+pub fn patch_image(
+    image: &mut [u8],
+    header: &[String],
+    row: &[String],
+    patches: &[Patch],
+) -> Result<(), Error> {
+    let record: HashMap<&str, &str> = header
+        .iter()
+        .map(String::as_str)
+        .zip(row.iter().map(String::as_str))
+        .collect();
+    for p in patches {
+        let value = record
+            .get(p.column.as_str())
+            .ok_or_else(|| Error::Argument(format!("no column '{}' in CSV", p.column)))?;
+        p.apply(image, value)?;
+    }
+    Ok(())
+}
+
+/// Select the CSV row and return its number together with the patched image
+pub fn prepare(a: &ProvisionArgs) -> Result<(usize, Vec<u8>), Error> {
+    let csv = parse_csv(&std::fs::read_to_string(&a.csv)?);
+    let (header, rows) = csv
+        .split_first()
+        .ok_or_else(|| Error::Argument(format!("CSV {:?} is empty", a.csv)))?;
+    let used = used_path(&a.csv);
+    let row = match a.row {
+        Some(row) => row,
+        None => {
+            let used_rows = used_rows(&used)?;
+            (1..=rows.len())
+                .find(|r| !used_rows.contains(r))
+                .ok_or_else(|| Error::Argument(format!("All rows of {:?} are used", a.csv)))?
+        }
+    };
+    let values = row
+        .checked_sub(1)
+        .and_then(|r| rows.get(r))
+        .ok_or_else(|| Error::Argument(format!("CSV {:?} has no row {}", a.csv, row)))?;
+
+    let mut image = std::fs::read(&a.file_name)?;
+    if image.is_empty() {
+        return Err(Error::Argument(format!("File '{:?}' is empty", a.file_name)));
+    }
+    patch_image(&mut image, header, values, &a.patch)?;
+    info!("Provision row {} of {:?}", row, a.csv);
+    Ok((row, image))
+}
+
+pub async fn provision(dfu: &mut Dfu, a: &ProvisionArgs, image: &[u8]) -> Result<(), Error> {
+    let address = a.address.resolve(dfu.memory_layout())?;
+    let len = image.len() as u32;
+    dfu.download_raw(&mut Cursor::new(image), address, len)
+        .await?;
+    if a.verify {
+        dfu.verify(&mut Cursor::new(image), address, len).await?;
+        info!("Verify done");
+    }
+    Ok(())
+}
+
+/// Mark `row` as used when it was picked with --next
+pub fn record_used(a: &ProvisionArgs, row: usize) -> Result<(), Error> {
+    if a.next {
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(used_path(&a.csv))?;
+        writeln!(f, "{}", row)?;
+    }
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_parse_patch() {
+        use crate::provision::*;
+        let p = Patch::from_str("serial@0x100:16").unwrap();
+        assert_eq!("serial", p.column);
+        assert_eq!(Location::Offset(0x100), p.location);
+        assert_eq!(16, p.length);
+        assert_eq!(Encoding::Ascii, p.encoding);
+        let p = Patch::from_str("mac@marker=DEADBEEF:6:hex").unwrap();
+        assert_eq!(Location::Marker(vec![0xDE, 0xAD, 0xBE, 0xEF]), p.location);
+        assert_eq!(Encoding::Hex, p.encoding);
+        assert!(Patch::from_str("serial@0x100").is_err());
+        assert!(Patch::from_str("serial@0x100:16:base64").is_err());
+        assert!(Patch::from_str("@0x100:16").is_err());
+        assert!(Patch::from_str("mac@marker=ABC:6").is_err());
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        use crate::provision::*;
+        let rows = parse_csv("serial,mac\r\nA1,\"00:11:22:33:44:55\"\n\"x,\"\"y\"\"\",\n\n");
+        assert_eq!(3, rows.len());
+        assert_eq!(vec!["serial", "mac"], rows[0]);
+        assert_eq!(vec!["A1", "00:11:22:33:44:55"], rows[1]);
+        assert_eq!(vec!["x,\"y\"", ""], rows[2]);
+    }
+
+    #[test]
+    fn test_patch_image() {
+        use crate::provision::*;
+        let mut image = vec![0xFF_u8; 32];
+        image[20..24].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        let patches = vec![
+            Patch::from_str("serial@2:6").unwrap(),
+            Patch::from_str("mac@marker=DEADBEEF:6:hex").unwrap(),
+        ];
+        let header = vec!["serial".to_string(), "mac".to_string()];
+        let row = vec!["SN1".to_string(), "00-11-22-33-44-55".to_string()];
+        patch_image(&mut image, &header, &row, &patches).unwrap();
+        assert_eq!(b"SN1\0\0\0", &image[2..8]);
+        assert_eq!([0x00, 0x11, 0x22, 0x33, 0x44, 0x55], image[20..26]);
+        assert_eq!(0xFF, image[26]);
+
+        let row = vec!["SERIAL-TOO-LONG".to_string(), "00".to_string()];
+        assert!(patch_image(&mut image, &header, &row, &patches).is_err());
+        let row = vec!["A".to_string(), "00".to_string()];
+        let patches = vec![Patch::from_str("serial@0x1F:2").unwrap()];
+        assert!(patch_image(&mut image, &header, &row, &patches).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use crate::address::parse_int;
+use crate::hexdump::hex_dump;
+use dfu_nusb::error::Error;
+use dfu_nusb::hex::parse_hex;
+use dfu_nusb::Dfu;
+use std::path::PathBuf;
+
+#[derive(clap::Args, PartialEq)]
+pub struct RawArgs {
+    /// bRequest, e.g. 0x02 for UPLOAD
+    #[arg(short, long, value_parser = parse_bounded::<u8>)]
+    pub request: u8,
+    /// wValue
+    #[arg(long, default_value = "0", value_parser = parse_bounded::<u16>)]
+    pub value: u16,
+    /// Send an OUT request with these hex bytes, e.g. "41 00 00 00 08"
+    #[arg(long, value_parser = parse_hex, conflicts_with_all = ["data_file", "length"])]
+    pub data: Option<Vec<u8>>,
+    /// Send an OUT request with the content of <file>
+    #[arg(long, value_hint = clap::ValueHint::FilePath, conflicts_with = "length")]
+    pub data_file: Option<PathBuf>,
+    /// Send an IN request reading up to <length> bytes
+    #[arg(short, long, value_parser = parse_bounded::<u16>, required_unless_present_any = ["data", "data_file"])]
+    pub length: Option<u16>,
+    /// Save the response to <file> instead of printing a hex dump
+    #[arg(short, long, value_hint = clap::ValueHint::FilePath)]
+    pub output: Option<PathBuf>,
+}
+
+fn parse_bounded<T: TryFrom<u32>>(s: &str) -> Result<T, String> {
+    let n = parse_int(s).map_err(|e| format!("'{}': {}", s, e))?;
+    T::try_from(n).map_err(|_| format!("'{}' is out of range", s))
+}
+
+pub async fn raw(dfu: &mut Dfu, a: &RawArgs) -> Result<(), Error> {
+    let data = match (&a.data, &a.data_file) {
+        (Some(d), _) => Some(d.clone()),
+        (None, Some(path)) => Some(std::fs::read(path)?),
+        (None, None) => None,
+    };
+    let response = dfu
+        .raw_request(a.request, a.value, data.as_deref(), a.length.unwrap_or(0))
+        .await?;
+    match dfu.get_status(0).await {
+        Ok(status) => log::info!("Status after request:\n{}", status),
+        Err(e) => log::warn!("GET_STATUS after request failed: {}", e),
+    }
+    if data.is_some() {
+        println!("Sent {} bytes", data.map_or(0, |d| d.len()));
+        return Ok(());
+    }
+    match &a.output {
+        Some(path) => {
+            std::fs::write(path, &response)?;
+            println!("Saved {} bytes to {:?}", response.len(), path);
+        }
+        None => print!("{}", hex_dump(0, &response, 16)),
+    }
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_parse_bounded() {
+        use crate::raw::*;
+        assert_eq!(Ok(0x21_u8), parse_bounded::<u8>("0x21"));
+        assert!(parse_bounded::<u8>("256").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use dfu_nusb::error::Error;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CSV_HEADER: &str = "timestamp,serial,chip_id,sha256,result,duration_ms";
+
+/// One flashed unit
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Record {
+    pub timestamp: String,
+    pub serial: Option<String>,
+    pub chip_id: Option<String>,
+    pub sha256: Option<String>,
+    pub result: String,
+    pub duration_ms: u128,
+}
+
+impl Record {
+    pub fn new(started: SystemTime) -> Self {
+        Record {
+            timestamp: rfc3339(started),
+            serial: None,
+            chip_id: None,
+            sha256: None,
+            result: String::new(),
+            duration_ms: 0,
+        }
+    }
+
+    pub fn finish(&mut self, duration: Duration, result: &Result<(), Error>) {
+        self.result = match result {
+            Ok(()) => "ok".into(),
+            Err(e) => e.to_string(),
+        };
+        self.duration_ms = duration.as_millis();
+    }
+
+    fn to_csv(&self) -> String {
+        let field = |s: &str| {
+            if s.contains([',', '"', '\n']) {
+                format!("\"{}\"", s.replace('"', "\"\""))
+            } else {
+                s.to_string()
+            }
+        };
+        format!(
+            "{},{},{},{},{},{}",
+            self.timestamp,
+            field(self.serial.as_deref().unwrap_or("")),
+            self.chip_id.as_deref().unwrap_or(""),
+            self.sha256.as_deref().unwrap_or(""),
+            field(&self.result),
+            self.duration_ms
+        )
+    }
+}
+
+/// Append-only log of flashed units, JSON lines for `.jsonl`/`.json` files and CSV otherwise
+pub struct ResultLog {
+    path: PathBuf,
+}
+
+impl ResultLog {
+    pub fn new(path: &Path) -> Self {
+        ResultLog { path: path.into() }
+    }
+
+    fn is_json(&self) -> bool {
+        self.path
+            .extension()
+            .is_some_and(|e| e == "jsonl" || e == "json")
+    }
+
+    pub fn append(&self, record: &Record) -> Result<(), Error> {
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if self.is_json() {
+            let line = serde_json::to_string(record)
+                .map_err(|e| Error::Argument(format!("result log: {}", e)))?;
+            writeln!(f, "{}", line)?;
+        } else {
+            if f.metadata()?.len() == 0 {
+                writeln!(f, "{}", CSV_HEADER)?;
+            }
+            writeln!(f, "{}", record.to_csv())?;
+        }
+        Ok(())
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    dfu_nusb::hex::to_hex(&Sha256::digest(data)).to_ascii_lowercase()
+}
+
+/// Format as `YYYY-MM-DDTHH:MM:SS.mmmZ`
+pub fn rfc3339(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs();
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Civil date from days since 1970-01-01, see Howard Hinnant's days_from_civil
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        d.subsec_millis()
+    )
+}
+
+mod tests {
+    #[test]
+    fn test_rfc3339() {
+        use crate::result_log::*;
+        assert_eq!("1970-01-01T00:00:00.000Z", rfc3339(UNIX_EPOCH));
+        let t = UNIX_EPOCH + Duration::from_millis(1_709_210_096_123);
+        assert_eq!("2024-02-29T12:34:56.123Z", rfc3339(t));
+    }
+
+    #[test]
+    fn test_record_csv() {
+        use crate::result_log::*;
+        let mut r = Record::new(UNIX_EPOCH);
+        r.finish(Duration::from_millis(1500), &Ok(()));
+        r.serial = Some("3574364C3034".into());
+        r.sha256 = Some(sha256_hex(b"abc"));
+        assert_eq!(
+            "1970-01-01T00:00:00.000Z,3574364C3034,,\
+             ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad,ok,1500",
+            r.to_csv()
+        );
+        let mut r = Record::new(UNIX_EPOCH);
+        r.finish(Duration::ZERO, &Err(Error::Argument("a, \"b\"".into())));
+        assert!(r.to_csv().contains(",\"Argument a, \"\"b\"\"\",0"));
+    }
+}
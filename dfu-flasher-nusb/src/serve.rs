@@ -0,0 +1,516 @@
+use crate::address::Address;
+use crate::config::parse_vid_pid;
+use crate::list;
+use crate::result_log::sha256_hex;
+use crate::setup::Setup;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use dfu_nusb::error::Error;
+use dfu_nusb::{port_path, AltSetting, DeviceFilter, Dfu, DfuTransport};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::Cursor;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// Largest image `POST /firmware` takes
+const MAX_FIRMWARE_SIZE: usize = 16 * 1024 * 1024;
+/// Uploaded images kept, the oldest is dropped for a new one
+const MAX_FIRMWARE_COUNT: usize = 16;
+/// Jobs kept for status queries, the oldest finished one is dropped for a new one
+const MAX_JOB_COUNT: usize = 64;
+
+#[derive(clap::Args, PartialEq)]
+pub struct ServeArgs {
+    /// Address and port to listen on, the API has no authentication
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub listen: String,
+    /// Also serve the gRPC API on this address, e.g. 127.0.0.1:50051
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    pub grpc: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DeviceInfo {
+    bus: u8,
+    address: u8,
+    vendor_id: String,
+    product_id: String,
+    serial: Option<String>,
+    port: Option<String>,
+    description: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Firmware {
+    id: u64,
+    size: usize,
+    sha256: String,
+}
+
+/// Body of `POST /jobs`, the device fields combine like the command line options
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlashRequest {
+    pub firmware: u64,
+    /// vendor_id:product_id
+    pub dev: Option<String>,
+    pub serial: Option<String>,
+    pub port: Option<String>,
+    /// Start address, may be relative like flash+0x4000 [default: flash]
+    pub address: Option<String>,
+    #[serde(default = "yes")]
+    pub verify: bool,
+    /// Leave DFU mode and start the application at the written address
+    pub reset: bool,
+}
+
+fn yes() -> bool {
+    true
+}
+
+impl FlashRequest {
+    pub fn filter(&self) -> Result<DeviceFilter, Error> {
+        let mut filter = match &self.dev {
+            Some(dev) => {
+                let (vendor_id, product_id) = parse_vid_pid(dev)?;
+                DeviceFilter::vid_pid(vendor_id, product_id)
+            }
+            None => DeviceFilter::default(),
+        };
+        filter.serial = self.serial.clone();
+        filter.port = self.port.clone();
+        Ok(filter)
+    }
+
+    pub fn address(&self) -> Result<Address, Error> {
+        match &self.address {
+            Some(a) => Address::from_str(a).map_err(Error::Argument),
+            None => Ok(Address::flash()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub state: JobState,
+    pub written: usize,
+    pub total: usize,
+    pub serial: Option<String>,
+    pub error: Option<String>,
+    /// Exit code the command line would have returned for the error
+    pub exit_code: Option<i32>,
+}
+
+impl Job {
+    pub fn finished(&self) -> bool {
+        matches!(self.state, JobState::Done | JobState::Failed)
+    }
+}
+
+pub(crate) struct Server {
+    iface_index: u8,
+    alt: AltSetting,
+    /// Protected ranges and aliases set on every device a job opens
+    setup: Setup,
+    next_id: AtomicU64,
+    firmware: Mutex<HashMap<u64, Arc<Vec<u8>>>>,
+    jobs: Mutex<HashMap<u64, watch::Receiver<Job>>>,
+}
+
+impl Server {
+    pub(crate) fn new(iface_index: u8, alt: AltSetting, setup: Setup) -> Arc<Server> {
+        Arc::new(Server {
+            iface_index,
+            alt,
+            setup,
+            next_id: AtomicU64::new(0),
+            firmware: Mutex::new(HashMap::new()),
+            jobs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Keep `image` for later jobs, dropping the oldest one when there are too many. Jobs
+    /// already started hold their own reference.
+    fn store(&self, image: Vec<u8>) -> Firmware {
+        let firmware = Firmware {
+            id: self.id(),
+            size: image.len(),
+            sha256: sha256_hex(&image),
+        };
+        let mut stored = self.firmware.lock().unwrap();
+        while stored.len() >= MAX_FIRMWARE_COUNT {
+            let oldest = *stored.keys().min().unwrap();
+            log::info!("Dropping firmware {}", oldest);
+            stored.remove(&oldest);
+        }
+        stored.insert(firmware.id, Arc::new(image));
+        firmware
+    }
+
+    /// Keep the receiver of job `id`, dropping finished jobs when there are too many. Running
+    /// jobs are always kept.
+    fn track(&self, id: u64, rx: watch::Receiver<Job>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        while jobs.len() >= MAX_JOB_COUNT {
+            let finished = jobs.iter().filter(|(_, rx)| rx.borrow().finished()).map(|(id, _)| *id).min();
+            let Some(oldest) = finished else {
+                break;
+            };
+            jobs.remove(&oldest);
+        }
+        jobs.insert(id, rx);
+    }
+
+    /// Flash `image` in the background, the receiver sees every state change of the job
+    pub(crate) fn start_job(&self, req: FlashRequest, image: Arc<Vec<u8>>) -> Result<watch::Receiver<Job>, Error> {
+        let filter = req.filter()?;
+        let address = req.address()?;
+        let job = Job {
+            id: self.id(),
+            state: JobState::Queued,
+            written: 0,
+            total: image.len(),
+            serial: None,
+            error: None,
+            exit_code: None,
+        };
+        let (tx, rx) = watch::channel(job.clone());
+        self.track(job.id, rx.clone());
+        let (iface_index, alt, setup) = (self.iface_index, self.alt.clone(), self.setup.clone());
+        tokio::spawn(async move {
+            let res = flash(&tx, &filter, iface_index, &alt, &setup, &image, address, &req).await;
+            tx.send_modify(|job| match res {
+                Ok(()) => job.state = JobState::Done,
+                Err(e) => {
+                    log::error!("Job {} failed: {}", job.id, e);
+                    job.state = JobState::Failed;
+                    job.error = Some(e.to_string());
+                    job.exit_code = Some(e.exit_code().into());
+                }
+            });
+        });
+        Ok(rx)
+    }
+}
+
+/// Failures as `{"error": "..", "exit_code": n}` with a status fitting the error
+struct ApiError(StatusCode, Error);
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        let status = match e {
+            Error::Argument(_) => StatusCode::BAD_REQUEST,
+            Error::DeviceNotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError(status, e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({
+            "error": self.1.to_string(),
+            "exit_code": i32::from(self.1.exit_code()),
+        });
+        (self.0, Json(body)).into_response()
+    }
+}
+
+fn not_found(what: &str, id: u64) -> ApiError {
+    ApiError(StatusCode::NOT_FOUND, Error::Argument(format!("no {} {}", what, id)))
+}
+
+async fn devices() -> Result<Json<Vec<DeviceInfo>>, ApiError> {
+    let devices = list::devices(false)?
+        .iter()
+        .map(|dev| DeviceInfo {
+            bus: dev.bus_number(),
+            address: dev.device_address(),
+            vendor_id: format!("{:04x}", dev.vendor_id()),
+            product_id: format!("{:04x}", dev.product_id()),
+            serial: dev.serial_number().map(String::from),
+            port: port_path(dev),
+            description: list::describe(dev),
+        })
+        .collect();
+    Ok(Json(devices))
+}
+
+async fn upload(State(server): State<Arc<Server>>, body: Bytes) -> Json<Firmware> {
+    let firmware = server.store(body.to_vec());
+    log::info!("Firmware {} uploaded, {} bytes", firmware.id, firmware.size);
+    Json(firmware)
+}
+
+async fn remove_firmware(State(server): State<Arc<Server>>, Path(id): Path<u64>) -> Result<StatusCode, ApiError> {
+    server
+        .firmware
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .ok_or_else(|| not_found("firmware", id))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn start(State(server): State<Arc<Server>>, Json(req): Json<FlashRequest>) -> Result<Json<Job>, ApiError> {
+    let image = server
+        .firmware
+        .lock()
+        .unwrap()
+        .get(&req.firmware)
+        .cloned()
+        .ok_or_else(|| not_found("firmware", req.firmware))?;
+    let job = server.start_job(req, image)?.borrow().clone();
+    Ok(Json(job))
+}
+
+/// Address right after `len` bytes written at `address`, an error past 4 GiB
+fn image_end(address: u32, len: usize) -> Result<u32, Error> {
+    u32::try_from(len)
+        .ok()
+        .and_then(|len| address.checked_add(len))
+        .ok_or_else(|| Error::Argument(format!("{} bytes at 0x{:08X} do not fit in the address space", len, address)))
+}
+
+/// Open the device for a job and write `image` to it
+#[allow(clippy::too_many_arguments)]
+async fn flash(
+    tx: &watch::Sender<Job>,
+    filter: &DeviceFilter,
+    iface_index: u8,
+    alt: &AltSetting,
+    setup: &Setup,
+    image: &[u8],
+    address: Address,
+    req: &FlashRequest,
+) -> Result<(), Error> {
+    let mut dfu = Dfu::open_with_language(filter, iface_index, alt, setup.language).await?;
+    setup.apply(&mut dfu)?;
+    write_job(&mut dfu, tx, image, address, req).await
+}
+
+/// Write `image` page by page so progress can be reported between pages
+async fn write_job<T: DfuTransport>(
+    dfu: &mut Dfu<T>,
+    tx: &watch::Sender<Job>,
+    image: &[u8],
+    address: Address,
+    req: &FlashRequest,
+) -> Result<(), Error> {
+    let serial = dfu.serial_number().map(String::from);
+    tx.send_modify(|job| {
+        job.state = JobState::Running;
+        job.serial = serial;
+    });
+    let address = dfu.canonical_address(address.resolve(dfu.memory_layout())?);
+    let end = image_end(address, image.len())?;
+    let mut at = address;
+    while at < end {
+        let page = dfu.memory_layout().address(at)?;
+        let len = (page.address + page.size).min(end) - at;
+        let offset = (at - address) as usize;
+        let chunk = &image[offset..offset + len as usize];
+        dfu.download_raw(&mut Cursor::new(chunk), at, len).await?;
+        at += len;
+        tx.send_modify(|job| job.written = (at - address) as usize);
+    }
+    if req.verify {
+        dfu.verify(&mut Cursor::new(image), address, image.len() as u32).await?;
+    }
+    if req.reset {
+        dfu.reset_stm32(address).await?;
+    }
+    Ok(())
+}
+
+async fn job(State(server): State<Arc<Server>>, Path(id): Path<u64>) -> Result<Json<Job>, ApiError> {
+    let jobs = server.jobs.lock().unwrap();
+    let job = jobs.get(&id).ok_or_else(|| not_found("job", id))?.borrow().clone();
+    Ok(Json(job))
+}
+
+/// Server-sent events with the job state, one per change until it is done or failed
+async fn events(State(server): State<Arc<Server>>, Path(id): Path<u64>) -> Result<impl IntoResponse, ApiError> {
+    let rx = server
+        .jobs
+        .lock()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| not_found("job", id))?;
+    let stream = futures_lite::stream::unfold((rx, true, false), |(mut rx, first, done)| async move {
+        if done || (!first && rx.changed().await.is_err()) {
+            return None;
+        }
+        let job = rx.borrow_and_update().clone();
+        let event = Event::default().json_data(&job).unwrap_or_default();
+        Some((Ok::<_, Infallible>(event), (rx, false, job.finished())))
+    });
+    Ok(Sse::new(stream))
+}
+
+fn router(server: Arc<Server>) -> Router {
+    Router::new()
+        .route("/devices", get(devices))
+        .route("/firmware", post(upload).layer(DefaultBodyLimit::max(MAX_FIRMWARE_SIZE)))
+        .route("/firmware/{id}", delete(remove_firmware))
+        .route("/jobs", post(start))
+        .route("/jobs/{id}", get(job))
+        .route("/jobs/{id}/events", get(events))
+        .with_state(server)
+}
+
+pub async fn serve(a: &ServeArgs, iface_index: u8, alt: AltSetting, setup: Setup) -> Result<(), Error> {
+    let server = Server::new(iface_index, alt, setup);
+    let listener = tokio::net::TcpListener::bind(&a.listen).await?;
+    log::info!("Listening on http://{}", listener.local_addr()?);
+    let rest = async { axum::serve(listener, router(server.clone())).await.map_err(Error::from) };
+    #[cfg(feature = "grpc")]
+    if let Some(addr) = &a.grpc {
+        let grpc = crate::grpc::serve(addr, server.clone());
+        return tokio::try_join!(rest, grpc).map(|_| ());
+    }
+    rest.await
+}
+
+mod tests {
+    #[test]
+    fn test_flash_request() {
+        use crate::serve::*;
+        let req: FlashRequest =
+            serde_json::from_str(r#"{"firmware": 3, "dev": "0483:df11", "serial": "ABC"}"#).unwrap();
+        assert_eq!(3, req.firmware);
+        assert!(req.verify);
+        assert!(!req.reset);
+        let filter = req.filter().unwrap();
+        assert_eq!(Some(0x0483), filter.vendor_id);
+        assert_eq!(Some("ABC".to_string()), filter.serial);
+        assert_eq!(Address::flash(), req.address().unwrap());
+        assert!(serde_json::from_str::<FlashRequest>(r#"{"firmware": 1, "size": 2}"#).is_err());
+        let req = FlashRequest {
+            address: Some("nowhere".into()),
+            ..Default::default()
+        };
+        assert!(req.address().is_err());
+    }
+
+    #[test]
+    fn test_store_firmware() {
+        use crate::serve::*;
+        let server = Server::new(0, AltSetting::Number(0), Setup::default());
+        let first = server.store(vec![1, 2, 3]);
+        assert_eq!(3, first.size);
+        assert_eq!(sha256_hex(&[1, 2, 3]), first.sha256);
+        let kept = server.firmware.lock().unwrap().get(&first.id).cloned().unwrap();
+        for _ in 0..MAX_FIRMWARE_COUNT {
+            server.store(vec![0]);
+        }
+        // The oldest image is dropped, a job holding it still has it
+        let stored = server.firmware.lock().unwrap();
+        assert_eq!(MAX_FIRMWARE_COUNT, stored.len());
+        assert!(!stored.contains_key(&first.id));
+        assert_eq!(vec![1, 2, 3], *kept);
+    }
+
+    #[test]
+    fn test_protected_job() {
+        use crate::serve::*;
+        use dfu_nusb::DfuseEmulator;
+        use futures_lite::future::block_on;
+        let setup = Setup {
+            protect: vec![(Address::flash(), 0x4000)],
+            ..Setup::default()
+        };
+        let mut dfu = DfuseEmulator::stm32f4().into_dfu();
+        setup.apply(&mut dfu).unwrap();
+        let job = Job {
+            id: 1,
+            state: JobState::Queued,
+            written: 0,
+            total: 0x100,
+            serial: None,
+            error: None,
+            exit_code: None,
+        };
+        let (tx, _rx) = watch::channel(job);
+        let req = FlashRequest {
+            verify: false,
+            ..Default::default()
+        };
+        let res = block_on(write_job(&mut dfu, &tx, &[0; 0x100], Address::flash(), &req));
+        assert!(matches!(res, Err(Error::Protected(_))));
+        assert_eq!(0, tx.borrow().written);
+        // Past the protected range the job goes through
+        let res = block_on(write_job(&mut dfu, &tx, &[0; 0x100], Address::from(0x0800_4000), &req));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_track_jobs() {
+        use crate::serve::*;
+        let server = Server::new(0, AltSetting::Number(0), Setup::default());
+        let job = |id, state| {
+            let job = Job {
+                id,
+                state,
+                written: 0,
+                total: 0,
+                serial: None,
+                error: None,
+                exit_code: None,
+            };
+            watch::channel(job).1
+        };
+        server.track(1, job(1, JobState::Running));
+        for id in 2..=MAX_JOB_COUNT as u64 {
+            server.track(id, job(id, JobState::Done));
+        }
+        server.track(100, job(100, JobState::Queued));
+        // The oldest finished job made room, the running one is kept
+        let jobs = server.jobs.lock().unwrap();
+        assert_eq!(MAX_JOB_COUNT, jobs.len());
+        assert!(jobs.contains_key(&1));
+        assert!(!jobs.contains_key(&2));
+        assert!(jobs.contains_key(&100));
+        drop(jobs);
+        // With nothing finished the list grows
+        let server = Server::new(0, AltSetting::Number(0), Setup::default());
+        for id in 0..=MAX_JOB_COUNT as u64 {
+            server.track(id, job(id, JobState::Running));
+        }
+        assert_eq!(MAX_JOB_COUNT + 1, server.jobs.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_image_end() {
+        use crate::serve::*;
+        assert_eq!(0x0800_0100, image_end(0x0800_0000, 0x100).unwrap());
+        assert_eq!(u32::MAX, image_end(0xFFFF_FF00, 0xFF).unwrap());
+        assert!(image_end(0xFFFF_FF00, 0x100).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use crate::address::Address;
+use dfu_nusb::error::Error;
+use dfu_nusb::{Alias, Dfu, DfuTransport, RetryPolicy};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Options applied to every device once it is open, for the actions as well as `update` and
+/// the jobs of `serve`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Setup {
+    pub transfer_size: Option<u16>,
+    /// Control transfer timeout in milliseconds
+    pub timeout: Option<u64>,
+    pub retries: Option<u8>,
+    /// LANGID devices are opened with, applied when opening rather than here
+    pub language: Option<u16>,
+    /// `--backup` directory
+    pub backup: Option<PathBuf>,
+    /// `--protect` ranges
+    pub protect: Vec<(Address, u32)>,
+    /// `--force`, erase and write the protected ranges anyway
+    pub force: bool,
+    /// `--alias` mirrors
+    pub alias: Vec<(Address, (Address, Option<u32>))>,
+}
+
+impl Setup {
+    pub fn apply<T: DfuTransport>(&self, dfu: &mut Dfu<T>) -> Result<(), Error> {
+        if let Some(transfer_size) = self.transfer_size {
+            dfu.set_transfer_size(transfer_size)?;
+        }
+        if let Some(timeout) = self.timeout {
+            dfu.set_timeout(Duration::from_millis(timeout));
+        }
+        if let Some(retries) = self.retries {
+            dfu.set_retry_policy(RetryPolicy {
+                retries,
+                ..dfu.retry_policy().clone()
+            });
+        }
+        dfu.set_backup_dir(self.backup.clone());
+        let protected = self
+            .protect
+            .iter()
+            .map(|(a, len)| a.resolve(dfu.memory_layout()).map(|start| start..start.saturating_add(*len)))
+            .collect::<Result<_, _>>()?;
+        dfu.set_protected(protected);
+        dfu.allow_protected(self.force);
+        let layout = dfu.memory_layout();
+        let aliases = self
+            .alias
+            .iter()
+            .map(|(alias, (target, length))| {
+                let target = target.resolve(layout)?;
+                let length = length.unwrap_or_else(|| layout.end_address().unwrap_or(target).saturating_sub(target));
+                Ok(Alias {
+                    address: alias.resolve(layout)?,
+                    length,
+                    target,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        dfu.set_aliases(aliases);
+        Ok(())
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_apply() {
+        use crate::setup::*;
+        use dfu_nusb::DfuseEmulator;
+        let mut dfu = DfuseEmulator::stm32f4().into_dfu();
+        let setup = Setup {
+            retries: Some(2),
+            protect: vec![(Address::flash(), 0x4000)],
+            alias: vec![(Address::from(0), (Address::flash(), Some(0x10_0000)))],
+            ..Setup::default()
+        };
+        setup.apply(&mut dfu).unwrap();
+        assert_eq!(2, dfu.retry_policy().retries);
+        assert_eq!(vec![(0x0800_0000, 0x0800_4000)], dfu.protected().iter().map(|r| (r.start, r.end)).collect::<Vec<_>>());
+        assert_eq!(0x0800_0100, dfu.canonical_address(0x100));
+    }
+}
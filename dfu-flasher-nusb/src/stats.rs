@@ -0,0 +1,45 @@
+use dfu_nusb::OperationSummary;
+use std::fmt::Write;
+
+/// End of run report of `--stats`
+pub fn render(summary: &OperationSummary) -> String {
+    let s = &summary.stats;
+    let rows = [
+        ("Control transfers", s.control_transfers.to_string()),
+        ("Retries", s.retries.to_string()),
+        ("Stalls", s.stalls.to_string()),
+        ("Bytes read", s.bytes_read.to_string()),
+        ("Bytes written", s.bytes_written.to_string()),
+        ("Average throughput", format!("{:.1} KB/s", summary.average_rate())),
+        ("Peak throughput", format!("{:.1} KB/s", s.peak_rate)),
+        ("Waiting on busy", format!("{:.2} s", s.busy_time.as_secs_f64())),
+        ("Transferring", format!("{:.2} s", summary.transfer_time().as_secs_f64())),
+        ("Total", format!("{:.2} s", summary.elapsed.as_secs_f64())),
+    ];
+    let mut out = String::new();
+    for (name, value) in rows {
+        let _ = writeln!(out, "{:<20} {:>12}", name, value);
+    }
+    out
+}
+
+mod tests {
+    #[test]
+    fn test_render() {
+        use crate::stats::*;
+        use dfu_nusb::TransferStats;
+        use std::time::Duration;
+        let stats = TransferStats {
+            control_transfers: 40,
+            bytes_written: 8192,
+            busy_time: Duration::from_millis(1500),
+            peak_rate: 12.5,
+            ..TransferStats::default()
+        };
+        let out = render(&OperationSummary::new(stats, Duration::from_secs(2)));
+        assert!(out.starts_with("Control transfers              40\n"));
+        assert!(out.contains("Average throughput       4.0 KB/s\n"));
+        assert!(out.contains("Peak throughput         12.5 KB/s\n"));
+        assert!(out.contains("Waiting on busy            1.50 s\nTransferring               0.50 s\nTotal                      2.00 s\n"));
+    }
+}
@@ -0,0 +1,62 @@
+use dfu_nusb::error::Error;
+use dfu_nusb::{Dfu, DfuseCommand};
+
+#[derive(clap::Args, PartialEq)]
+pub struct SupportedCommandsArgs {
+    /// Print a JSON object instead of text
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Each command byte with its name, `None` for bytes this tool does not know
+pub fn decode(bytes: &[u8]) -> Vec<(u8, Option<String>)> {
+    bytes
+        .iter()
+        .map(|b| match DfuseCommand::from_byte(*b) {
+            DfuseCommand::Unknown(_) => (*b, None),
+            command => (*b, Some(command.to_string())),
+        })
+        .collect()
+}
+
+pub fn to_text(bytes: &[u8]) -> String {
+    let mut out = String::from("Supported commands:\n");
+    for (b, name) in decode(bytes) {
+        out += &format!("0x{:02X} {}\n", b, name.as_deref().unwrap_or("unknown"));
+    }
+    out
+}
+
+pub fn to_json(bytes: &[u8]) -> String {
+    let commands: Vec<_> = decode(bytes)
+        .into_iter()
+        .map(|(b, name)| serde_json::json!({ "byte": b, "name": name }))
+        .collect();
+    serde_json::json!({ "raw": bytes, "commands": commands }).to_string()
+}
+
+pub async fn supported_commands(dfu: &mut Dfu, a: &SupportedCommandsArgs) -> Result<(), Error> {
+    let bytes = dfu.dfuse_get_command_bytes().await?;
+    if a.json {
+        println!("{}", to_json(&bytes));
+    } else {
+        print!("{}", to_text(&bytes));
+    }
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_supported_commands() {
+        use crate::supported_commands::*;
+        let bytes = [0x21, 0x41, 0x92, 0xA5];
+        assert_eq!(
+            "Supported commands:\n0x21 Set address\n0x41 Page/Mass erase\n0x92 Read unprotected\n0xA5 unknown\n",
+            to_text(&bytes)
+        );
+        assert_eq!(
+            "{\"commands\":[{\"byte\":33,\"name\":\"Set address\"},{\"byte\":165,\"name\":null}],\"raw\":[33,165]}",
+            to_json(&[0x21, 0xA5])
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use crate::config::parse_vid_pid;
+use dfu_nusb::error::Error;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const RULES_FILE: &str = "/etc/udev/rules.d/50-dfu-flasher.rules";
+
+#[derive(clap::Args, PartialEq)]
+pub struct UdevRuleArgs {
+    /// vendor_id:product_id the rule matches [default: --dev or 0483:df11]
+    #[arg(short, long)]
+    pub dev: Option<String>,
+    /// Write the rule to /etc/udev/rules.d using sudo and reload udev
+    #[arg(long)]
+    pub install: bool,
+}
+
+/// Rule giving the logged in user and the plugdev group access to the device
+pub fn rule(vendor_id: u16, product_id: u16) -> String {
+    format!(
+        "SUBSYSTEMS==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", \
+         MODE=\"0660\", GROUP=\"plugdev\", TAG+=\"uaccess\"\n",
+        vendor_id, product_id
+    )
+}
+
+fn sudo(args: &[&str], input: Option<&str>) -> Result<(), Error> {
+    let mut child = Command::new("sudo")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::Argument(format!("sudo {} failed with {}", args.join(" "), status)));
+    }
+    Ok(())
+}
+
+pub fn gen_udev_rule(a: &UdevRuleArgs, dev: Option<&str>) -> Result<(), Error> {
+    let (vendor_id, product_id) = parse_vid_pid(a.dev.as_deref().or(dev).unwrap_or("0483:df11"))?;
+    let rule = rule(vendor_id, product_id);
+    if !a.install {
+        print!("{}", rule);
+        return Ok(());
+    }
+    sudo(&["tee", "-a", RULES_FILE], Some(&rule))?;
+    sudo(&["udevadm", "control", "--reload-rules"], None)?;
+    sudo(&["udevadm", "trigger"], None)?;
+    println!("Installed rule in {}, replug the device", RULES_FILE);
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_udev_rule() {
+        use crate::udev::*;
+        assert_eq!(
+            "SUBSYSTEMS==\"usb\", ATTRS{idVendor}==\"0483\", ATTRS{idProduct}==\"df11\", \
+             MODE=\"0660\", GROUP=\"plugdev\", TAG+=\"uaccess\"\n",
+            rule(0x0483, 0xdf11)
+        );
+    }
+}
@@ -0,0 +1,40 @@
+use dfu_nusb::error::Error;
+use dfu_nusb::DfuseFile;
+use std::path::PathBuf;
+
+#[derive(clap::Args, PartialEq)]
+pub struct UnpackArgs {
+    /// DfuSe file to unpack
+    #[arg(short = 'f', long, value_hint = clap::ValueHint::FilePath)]
+    pub file_name: PathBuf,
+    /// Directory to write the elements to
+    #[arg(short = 'o', long, default_value = ".", value_hint = clap::ValueHint::DirPath)]
+    pub output_dir: PathBuf,
+    /// Only print the metadata of the file
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+}
+
+/// Extract every element of a `.dfu` file to `<stem>-alt<n>-0x<address>.bin`
+pub fn unpack(a: &UnpackArgs) -> Result<(), Error> {
+    let file = DfuseFile::parse(&std::fs::read(&a.file_name)?)?;
+    print!("{}", file);
+    if a.dry_run {
+        return Ok(());
+    }
+    let stem = a
+        .file_name
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "image".into());
+    for t in &file.targets {
+        for e in &t.elements {
+            let path = a
+                .output_dir
+                .join(format!("{}-alt{}-0x{:08X}.bin", stem, t.alt, e.address));
+            std::fs::write(&path, &e.data)?;
+            println!("Wrote {} bytes to {:?}", e.data.len(), path);
+        }
+    }
+    Ok(())
+}
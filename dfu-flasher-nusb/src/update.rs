@@ -0,0 +1,112 @@
+use crate::address::{parse_int, Address};
+use crate::config::parse_vid_pid;
+use crate::setup::Setup;
+use dfu_nusb::error::Error;
+use dfu_nusb::{AltSetting, DeviceFilter, Update};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(clap::Args, PartialEq)]
+pub struct UpdateArgs {
+    /// vendor_id:product_id of the running application
+    #[arg(long)]
+    pub runtime: String,
+    /// vendor_id:product_id in DFU mode [default: --dev or 0483:df11]
+    #[arg(long)]
+    pub dfu: Option<String>,
+    /// Firmware to write
+    #[arg(short = 'f', long, value_hint = clap::ValueHint::FilePath)]
+    pub file_name: PathBuf,
+    /// start address, may be relative like flash+0x4000
+    #[arg(short = 's', long, default_value = "flash")]
+    pub address: Address,
+    /// Skip reading back the written image
+    #[arg(long)]
+    pub no_verify: bool,
+    /// Seconds to wait for each re-enumeration
+    #[arg(long, default_value = "10", value_parser = parse_int)]
+    pub wait: u32,
+}
+
+/// The application keeps the serial number and port of the selected device, bus addresses and
+/// platform ids change when it re-enumerates
+fn runtime_filter(a: &UpdateArgs, filter: &DeviceFilter) -> Result<DeviceFilter, Error> {
+    let (vendor_id, product_id) = parse_vid_pid(&a.runtime)?;
+    Ok(DeviceFilter {
+        serial: filter.serial.clone(),
+        port: filter.port.clone(),
+        ..DeviceFilter::vid_pid(vendor_id, product_id)
+    })
+}
+
+/// The selected device, `--dfu` or 0483:df11 unless it has a vendor and product id
+fn dfu_filter(a: &UpdateArgs, filter: &DeviceFilter) -> Result<DeviceFilter, Error> {
+    let (vendor_id, product_id) = match (&a.dfu, filter.vendor_id, filter.product_id) {
+        (Some(dfu), _, _) => parse_vid_pid(dfu)?,
+        (None, Some(vendor_id), Some(product_id)) => (vendor_id, product_id),
+        (None, _, _) => (0x0483, 0xdf11),
+    };
+    Ok(DeviceFilter {
+        vendor_id: Some(vendor_id),
+        product_id: Some(product_id),
+        ..filter.clone()
+    })
+}
+
+pub async fn update(
+    a: &UpdateArgs,
+    filter: &DeviceFilter,
+    intf: u8,
+    alt: AltSetting,
+    setup: &Setup,
+) -> Result<(), Error> {
+    let u = Update {
+        runtime: runtime_filter(a, filter)?,
+        dfu: dfu_filter(a, filter)?,
+        iface_index: intf,
+        alt,
+        timeout: Duration::from_secs(a.wait as u64),
+        verify: !a.no_verify,
+        language: setup.language,
+    };
+    let image = std::fs::read(&a.file_name)?;
+    let address = &a.address;
+    let prepare = |dfu: &mut dfu_nusb::Dfu| {
+        setup.apply(dfu)?;
+        Ok(dfu.canonical_address(address.resolve(dfu.memory_layout())?))
+    };
+    dfu_nusb::update(&u, &image, prepare, |phase| log::info!("{}", phase)).await
+}
+
+mod tests {
+    #[test]
+    fn test_update_filters() {
+        use crate::update::*;
+        let a = UpdateArgs {
+            runtime: "1234:5678".into(),
+            dfu: None,
+            file_name: PathBuf::from("app.bin"),
+            address: Address::flash(),
+            no_verify: false,
+            wait: 10,
+        };
+        let filter = DeviceFilter {
+            serial: Some("ABC".into()),
+            ..DeviceFilter::port("1-4.2")
+        };
+        let runtime = runtime_filter(&a, &filter).unwrap();
+        assert_eq!((Some(0x1234), Some(0x5678)), (runtime.vendor_id, runtime.product_id));
+        assert_eq!(Some("1-4.2".to_string()), runtime.port);
+        let dfu = dfu_filter(&a, &filter).unwrap();
+        assert_eq!((Some(0x0483), Some(0xdf11)), (dfu.vendor_id, dfu.product_id));
+        assert_eq!(Some("ABC".to_string()), dfu.serial);
+        assert_eq!(Some("1-4.2".to_string()), dfu.port);
+        let dfu = dfu_filter(&a, &DeviceFilter::vid_pid(0x0483, 0xdf12)).unwrap();
+        assert_eq!(Some(0xdf12), dfu.product_id);
+        let a = UpdateArgs {
+            dfu: Some("1234:df11".into()),
+            ..a
+        };
+        assert_eq!(Some(0x1234), dfu_filter(&a, &DeviceFilter::vid_pid(0x0483, 0xdf12)).unwrap().vendor_id);
+    }
+}
@@ -0,0 +1,208 @@
+use dfu_nusb::error::Error;
+use dfu_nusb::hex::to_hex;
+use serde::Serialize;
+use std::fmt::Write;
+use std::fs::File;
+use std::path::Path;
+
+const ROW: usize = 16;
+/// Rows shown per differing region
+const MAX_REGION_ROWS: usize = 8;
+
+/// Run of consecutive rows containing differences, as row indexes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub first_row: usize,
+    pub rows: usize,
+}
+
+/// Up to `max` regions of rows where `expected` and `actual` differ
+pub fn regions(expected: &[u8], actual: &[u8], max: usize) -> Vec<Region> {
+    let mut regions: Vec<Region> = Vec::new();
+    let rows = expected.len().max(actual.len()).div_ceil(ROW);
+    for row in 0..rows {
+        let differs = (row * ROW..(row + 1) * ROW).any(|i| expected.get(i) != actual.get(i));
+        if !differs {
+            continue;
+        }
+        if let Some(r) = regions.last_mut().filter(|r| r.first_row + r.rows == row) {
+            r.rows += 1;
+        } else if regions.len() == max {
+            break;
+        } else {
+            regions.push(Region {
+                first_row: row,
+                rows: 1,
+            });
+        }
+    }
+    regions
+}
+
+fn hex_row(out: &mut String, bytes: &[u8], other: &[u8], offset: usize, color: bool) {
+    for i in offset..offset + ROW {
+        match bytes.get(i) {
+            Some(b) if color && other.get(i) != Some(b) => {
+                let _ = write!(out, "\x1b[1;31m{:02X}\x1b[0m ", b);
+            }
+            Some(b) => {
+                let _ = write!(out, "{:02X} ", b);
+            }
+            None => out.push_str("   "),
+        }
+    }
+    out.push('|');
+    for (i, &b) in bytes.iter().enumerate().skip(offset).take(ROW) {
+        let c = if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' };
+        if color && other.get(i) != Some(&b) {
+            let _ = write!(out, "\x1b[1;31m{}\x1b[0m", c);
+        } else {
+            out.push(c);
+        }
+    }
+    out.push_str("|\n");
+}
+
+/// Hex view of the differing regions, expected and actual row by row with an ASCII gutter.
+/// `address` is the flash address of the first byte of both buffers.
+pub fn render(address: u32, expected: &[u8], actual: &[u8], max_regions: usize, color: bool) -> String {
+    let mut out = String::new();
+    for r in regions(expected, actual, max_regions) {
+        for row in r.first_row..r.first_row + r.rows.min(MAX_REGION_ROWS) {
+            let offset = row * ROW;
+            let _ = write!(out, "0x{:08X} expected ", address as usize + offset);
+            hex_row(&mut out, expected, actual, offset, color);
+            out.push_str("           actual   ");
+            hex_row(&mut out, actual, expected, offset, color);
+        }
+        if r.rows > MAX_REGION_ROWS {
+            let _ = writeln!(out, "           ... {} more rows", r.rows - MAX_REGION_ROWS);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// One run of differing bytes in a diff file, the bytes as hex
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Mismatch {
+    /// Offset into the file
+    pub offset: u32,
+    pub address: u32,
+    pub expected: String,
+    /// Shorter than `expected` where the device returned less
+    pub actual: String,
+}
+
+/// Machine-readable result of a failed verify, written by `--diff-out`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diff {
+    /// Flash address of the start of the file
+    pub address: u32,
+    /// Bytes verified
+    pub length: u32,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Every run of bytes where `expected` and `actual` differ, bytes missing from `actual` included.
+/// `start` is the flash address of the first byte of both buffers, `address` that of the file.
+pub fn mismatches(address: u32, start: u32, expected: &[u8], actual: &[u8]) -> Vec<Mismatch> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < expected.len() {
+        if actual.get(i) == Some(&expected[i]) {
+            i += 1;
+            continue;
+        }
+        let end = (i..expected.len())
+            .find(|&j| actual.get(j) == Some(&expected[j]))
+            .unwrap_or(expected.len());
+        out.push(Mismatch {
+            offset: start - address + i as u32,
+            address: start + i as u32,
+            expected: to_hex(&expected[i..end]),
+            actual: to_hex(&actual[i.min(actual.len())..end.min(actual.len())]),
+        });
+        i = end;
+    }
+    out
+}
+
+/// Write `diff` to `path` as JSON
+pub fn write_json(path: &Path, diff: &Diff) -> Result<(), Error> {
+    serde_json::to_writer_pretty(File::create(path)?, diff).map_err(std::io::Error::from)?;
+    Ok(())
+}
+
+mod tests {
+    #[test]
+    fn test_regions() {
+        use crate::verify_diff::*;
+        let expected = vec![0_u8; 120];
+        let mut actual = expected.clone();
+        actual[3] = 1;
+        actual[17] = 1;
+        actual[50] = 1;
+        actual[100] = 1;
+        assert_eq!(
+            vec![
+                Region {
+                    first_row: 0,
+                    rows: 2
+                },
+                Region {
+                    first_row: 3,
+                    rows: 1
+                }
+            ],
+            regions(&expected, &actual, 2)
+        );
+        assert_eq!(3, regions(&expected, &actual, 10).len());
+        assert!(regions(&expected, &expected, 10).is_empty());
+        // Missing bytes count as differences
+        assert_eq!(2, regions(&expected, &actual[..104], 10).last().unwrap().rows);
+    }
+
+    #[test]
+    fn test_render() {
+        use crate::verify_diff::*;
+        let expected = b"0123456789abcdefXYZ".to_vec();
+        let mut actual = expected.clone();
+        actual[17] = b'!';
+        assert_eq!(
+            "0x08000010 expected 58 59 5A                                        |XYZ|\n           \
+             actual   58 21 5A                                        |X!Z|\n\n",
+            render(0x0800_0000, &expected, &actual, 4, false)
+        );
+        assert!(render(0, &expected, &actual, 4, true).contains("\x1b[1;31m21\x1b[0m"));
+    }
+
+    #[test]
+    fn test_mismatches() {
+        use crate::verify_diff::*;
+        let expected = vec![0_u8, 1, 2, 3, 4, 5, 6, 7];
+        let mut actual = expected.clone();
+        actual[1] = 0xFF;
+        actual[2] = 0xFE;
+        actual.truncate(6);
+        let found = mismatches(0x0800_0000, 0x0800_0010, &expected, &actual);
+        assert_eq!(
+            vec![
+                Mismatch {
+                    offset: 0x11,
+                    address: 0x0800_0011,
+                    expected: "0102".into(),
+                    actual: "FFFE".into()
+                },
+                Mismatch {
+                    offset: 0x16,
+                    address: 0x0800_0016,
+                    expected: "0607".into(),
+                    actual: "".into()
+                }
+            ],
+            found
+        );
+        assert!(mismatches(0, 0, &expected, &expected).is_empty());
+    }
+}
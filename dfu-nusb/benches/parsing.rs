@@ -0,0 +1,47 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use dfu_nusb::dfuse_file::{Element, Target};
+use dfu_nusb::{DfuseFile, MemoryLayout, Transaction};
+use std::str::FromStr;
+
+/// 32 MiB of QSPI flash in 4K sectors as an external loader reports it
+const EXTERNAL: &str = "@External Flash /0x90000000/8192*004Kg";
+const STM32F4: &str = "@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg";
+
+fn memory_layout(c: &mut Criterion) {
+    c.bench_function("layout parse stm32f4", |b| b.iter(|| MemoryLayout::from_str(black_box(STM32F4))));
+    c.bench_function("layout parse 8192 pages", |b| b.iter(|| MemoryLayout::from_str(black_box(EXTERNAL))));
+    let m = MemoryLayout::from_str(EXTERNAL).unwrap();
+    c.bench_function("layout last page lookup", |b| b.iter(|| m.address(black_box(0x91FF_F000))));
+    c.bench_function("layout num_pages 32 MiB", |b| b.iter(|| m.num_pages(black_box(0x9000_0000), 32 << 20)));
+}
+
+fn planner(c: &mut Criterion) {
+    c.bench_function("plan 16 MiB in 2048 byte chunks", |b| {
+        b.iter(|| Transaction::new(black_box(0x9000_0000), 16 << 20, 2048).map(|c| c.length as u64).sum::<u64>())
+    });
+}
+
+fn dfuse_file(c: &mut Criterion) {
+    let file = DfuseFile {
+        device_version: 0x2200,
+        product_id: 0xdf11,
+        vendor_id: 0x0483,
+        dfu_version: 0x011a,
+        targets: vec![Target {
+            alt: 0,
+            name: Some("Internal Flash".into()),
+            elements: (0..16)
+                .map(|i| Element {
+                    address: 0x0800_0000 + i * 0x1_0000,
+                    data: vec![i as u8; 0x1_0000],
+                })
+                .collect(),
+        }],
+    };
+    let buf = file.to_bytes();
+    c.bench_function("dfuse file parse 1 MiB", |b| b.iter(|| DfuseFile::parse(black_box(&buf))));
+    c.bench_function("dfuse file serialize 1 MiB", |b| b.iter(|| black_box(&file).to_bytes()));
+}
+
+criterion_group!(benches, memory_layout, planner, dfuse_file);
+criterion_main!(benches);
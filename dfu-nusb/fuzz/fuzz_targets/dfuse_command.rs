@@ -0,0 +1,13 @@
+#![no_main]
+
+use dfu_nusb::DfuseCommand;
+use libfuzzer_sys::fuzz_target;
+
+// Get Commands answers are a list of command bytes
+fuzz_target!(|data: &[u8]| {
+    for b in data {
+        let cmd = DfuseCommand::from_byte(*b);
+        let _ = cmd.to_string();
+        assert_eq!(*b, Vec::from(cmd)[0]);
+    }
+});
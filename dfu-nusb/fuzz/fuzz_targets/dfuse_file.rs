@@ -0,0 +1,12 @@
+#![no_main]
+
+use dfu_nusb::DfuseFile;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(file) = DfuseFile::parse(data) {
+        let _ = file.to_string();
+        // Whatever parses has to serialize into a container that parses again
+        assert!(DfuseFile::parse(&file.to_bytes()).is_ok());
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use dfu_nusb::fuzzing::functional_descriptor;
+use dfu_nusb::DfuDescriptor;
+use libfuzzer_sys::fuzz_target;
+
+// Raw configuration descriptors, as WebUSB hands them over
+fuzz_target!(|data: &[u8]| {
+    let _ = DfuDescriptor::from_bytes(data);
+    let _ = functional_descriptor(data);
+});
@@ -0,0 +1,26 @@
+#![no_main]
+
+use dfu_nusb::MemoryLayout;
+use libfuzzer_sys::fuzz_target;
+use std::str::FromStr;
+
+// Alt setting strings come straight from the device
+fuzz_target!(|data: &[u8]| {
+    let Ok(s) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(m) = MemoryLayout::from_str(s) else {
+        return;
+    };
+    let (Some(start), Ok(end)) = (m.start_address(), m.end_address()) else {
+        return;
+    };
+    for p in m.pages().iter().take(64) {
+        assert_eq!(p.address, m.address(p.address).unwrap().address);
+        let _ = m.num_pages(p.address, end - p.address);
+        let _ = m.page_range(p.address + p.size / 2, p.size);
+    }
+    let _ = m.num_pages(start, u32::MAX);
+    let _ = m.page_range(end.wrapping_sub(1), u32::MAX);
+    let _ = m.to_string();
+});
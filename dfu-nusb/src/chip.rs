@@ -0,0 +1,216 @@
+use crate::core::Dfu;
+use crate::error::Error;
+use crate::transport::DfuTransport;
+use std::fmt;
+
+/// DBGMCU_IDCODE of the Cortex-M3/M4/M7 STM32 families, DEV_ID in bits 11:0, REV_ID in 31:16
+pub const DBGMCU_IDCODE: u32 = 0xE004_2000;
+
+/// An STM32 line as identified by its DEV_ID
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chip {
+    pub name: &'static str,
+    pub dev_id: u16,
+    /// Part number prefixes sharing the DEV_ID, matched against `--chip`
+    pub parts: &'static [&'static str],
+    /// Flash size register, the size in KB as a little endian half word
+    pub flash_size_address: u32,
+    /// REV_ID with the silicon revision it stands for
+    pub revisions: &'static [(u16, &'static str)],
+}
+
+pub const CHIPS: &[Chip] = &[
+    Chip {
+        name: "STM32F2xx",
+        dev_id: 0x411,
+        parts: &["STM32F205", "STM32F207", "STM32F215", "STM32F217"],
+        flash_size_address: 0x1FFF_7A22,
+        revisions: &[(0x1000, "A"), (0x2000, "B"), (0x1001, "Z"), (0x2001, "Y"), (0x2003, "X")],
+    },
+    Chip {
+        name: "STM32F405xx/F407xx/F415xx/F417xx",
+        dev_id: 0x413,
+        parts: &["STM32F405", "STM32F407", "STM32F415", "STM32F417"],
+        flash_size_address: 0x1FFF_7A22,
+        revisions: &[(0x1000, "A"), (0x1001, "Z"), (0x1003, "1"), (0x1007, "2"), (0x100F, "Y")],
+    },
+    Chip {
+        name: "STM32F42xxx/F43xxx",
+        dev_id: 0x419,
+        parts: &["STM32F427", "STM32F429", "STM32F437", "STM32F439"],
+        flash_size_address: 0x1FFF_7A22,
+        revisions: &[(0x1000, "A"), (0x1003, "Y"), (0x1007, "1"), (0x2001, "3")],
+    },
+    Chip {
+        name: "STM32F401xB/C",
+        dev_id: 0x423,
+        parts: &["STM32F401"],
+        flash_size_address: 0x1FFF_7A22,
+        revisions: &[(0x1000, "Z"), (0x1001, "A")],
+    },
+    Chip {
+        name: "STM32F401xD/E",
+        dev_id: 0x433,
+        parts: &["STM32F401"],
+        flash_size_address: 0x1FFF_7A22,
+        revisions: &[(0x1000, "A"), (0x1001, "Z")],
+    },
+    Chip {
+        name: "STM32F411xC/E",
+        dev_id: 0x431,
+        parts: &["STM32F411"],
+        flash_size_address: 0x1FFF_7A22,
+        revisions: &[(0x1000, "A")],
+    },
+    Chip {
+        name: "STM32F446xx",
+        dev_id: 0x421,
+        parts: &["STM32F446"],
+        flash_size_address: 0x1FFF_7A22,
+        revisions: &[(0x1000, "A")],
+    },
+    Chip {
+        name: "STM32F74xxx/F75xxx",
+        dev_id: 0x449,
+        parts: &["STM32F745", "STM32F746", "STM32F756"],
+        flash_size_address: 0x1FF0_F442,
+        revisions: &[(0x1000, "A"), (0x1001, "Z")],
+    },
+    Chip {
+        name: "STM32F76xxx/F77xxx",
+        dev_id: 0x451,
+        parts: &["STM32F765", "STM32F767", "STM32F769", "STM32F777", "STM32F779"],
+        flash_size_address: 0x1FF0_F442,
+        revisions: &[(0x1000, "A"), (0x1001, "Z")],
+    },
+    Chip {
+        name: "STM32L47x/L48x",
+        dev_id: 0x415,
+        parts: &["STM32L475", "STM32L476", "STM32L486"],
+        flash_size_address: 0x1FFF_75E0,
+        revisions: &[(0x1000, "1"), (0x1001, "2"), (0x1003, "3"), (0x1007, "4")],
+    },
+];
+
+/// The chip with `dev_id`
+pub fn chip(dev_id: u16) -> Option<&'static Chip> {
+    CHIPS.iter().find(|c| c.dev_id == dev_id)
+}
+
+impl Chip {
+    /// Silicon revision of `rev_id`, the raw REV_ID when it is not known
+    pub fn revision(&self, rev_id: u16) -> String {
+        match self.revisions.iter().find(|(id, _)| *id == rev_id) {
+            Some((_, rev)) => rev.to_string(),
+            None => format!("0x{:04X}", rev_id),
+        }
+    }
+
+    /// Whether `part`, e.g. STM32F405RG or STM32F4, can be this chip
+    pub fn matches(&self, part: &str) -> bool {
+        let part = part.to_ascii_uppercase();
+        self.parts.iter().any(|p| part.starts_with(p) || p.starts_with(&part))
+    }
+}
+
+/// What DBGMCU_IDCODE and the flash size register say about the connected chip
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub idcode: u32,
+    pub chip: Option<&'static Chip>,
+    /// Flash size in KB, `None` when the chip is unknown or the register could not be read
+    pub flash_kb: Option<u16>,
+}
+
+impl Identity {
+    pub fn dev_id(&self) -> u16 {
+        (self.idcode & 0xFFF) as u16
+    }
+
+    pub fn rev_id(&self) -> u16 {
+        (self.idcode >> 16) as u16
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.chip {
+            Some(chip) => write!(f, "{} rev {}", chip.name, chip.revision(self.rev_id()))?,
+            None => write!(f, "Unknown chip DEV_ID 0x{:03X} REV_ID 0x{:04X}", self.dev_id(), self.rev_id())?,
+        }
+        match self.flash_kb {
+            Some(kb) => write!(f, ", {} KB flash", kb),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: DfuTransport> Dfu<T> {
+    /// Read DBGMCU_IDCODE and, for a known chip, its flash size register by UPLOAD. Bootloaders
+    /// only allowing their memory layout to be read refuse it.
+    pub async fn identify(&mut self) -> Result<Identity, Error> {
+        let mut idcode = [0; 4];
+        if self.read_flash_to_slice(DBGMCU_IDCODE, &mut idcode).await? < 4 {
+            return Err(Error::InvalidControlResponse("Short read of DBGMCU_IDCODE".into()));
+        }
+        let idcode = u32::from_le_bytes(idcode);
+        let chip = chip((idcode & 0xFFF) as u16);
+        let mut flash_kb = None;
+        if let Some(chip) = chip {
+            let mut size = [0; 2];
+            match self.read_flash_to_slice(chip.flash_size_address, &mut size).await {
+                Ok(2) => flash_kb = Some(u16::from_le_bytes(size)),
+                Ok(_) => log::warn!("Short read of the flash size register"),
+                Err(e) => log::warn!("Could not read the flash size register: {}", e),
+            }
+        }
+        Ok(Identity { idcode, chip, flash_kb })
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_chip() {
+        use crate::chip::*;
+        let f4 = chip(0x413).unwrap();
+        assert!(f4.matches("STM32F405RG"));
+        assert!(f4.matches("stm32f407"));
+        assert!(f4.matches("STM32F4"));
+        assert!(!f4.matches("STM32F411CE"));
+        assert_eq!("Z", f4.revision(0x1001));
+        assert_eq!("0x2222", f4.revision(0x2222));
+        assert!(chip(0x999).is_none());
+        let identity = Identity {
+            idcode: 0x1001_6413,
+            chip: Some(f4),
+            flash_kb: Some(1024),
+        };
+        assert_eq!("STM32F405xx/F407xx/F415xx/F417xx rev Z, 1024 KB flash", identity.to_string());
+        let unknown = Identity {
+            idcode: 0x1000_0999,
+            chip: None,
+            flash_kb: None,
+        };
+        assert_eq!("Unknown chip DEV_ID 0x999 REV_ID 0x1000", unknown.to_string());
+    }
+
+    #[test]
+    fn test_identify() {
+        use crate::mock::{MockTransport, Reply};
+        use crate::status::State;
+        use futures_lite::future::block_on;
+        let mock = MockTransport::new();
+        // SET_ADDRESS, ABORT and the ABORT closing the session for each read
+        for _ in 0..2 {
+            mock.push_status(0, State::DfuDownloadBusy)
+                .push_status(0, State::DfuIdle)
+                .push_status(0, State::DfuIdle);
+        }
+        mock.push(2, Reply::Data(vec![0x13, 0x64, 0x01, 0x10]))
+            .push(2, Reply::Data(vec![0x00, 0x04]));
+        let mut dfu = mock.into_dfu(2048, "@Internal Flash  /0x08000000/04*016Kg");
+        let identity = block_on(dfu.identify()).unwrap();
+        assert_eq!((0x413, 0x1001, Some(1024)), (identity.dev_id(), identity.rev_id(), identity.flash_kb));
+        assert_eq!("STM32F405xx/F407xx/F415xx/F417xx", identity.chip.unwrap().name);
+    }
+}
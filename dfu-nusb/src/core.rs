@@ -1,116 +1,459 @@
+#[cfg(not(target_arch = "wasm32"))]
+use crate::device_filter::{DeviceFilter, DfuMode};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::device_lock::DeviceLock;
 use crate::dfuse_command::DfuseCommand;
 use crate::error::Error;
-use crate::memory_layout::MemoryLayout;
-use crate::status::{State, Status};
+use crate::memory_layout::{Alias, MemoryLayout};
+use crate::progress::{Activity, OnProgress, Progress};
+use crate::stats::TransferStats;
+use crate::status::{status_name, State, Status};
+use crate::transaction::{Chunk, Transaction};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::{default_language, NusbTransport};
+use crate::transport::{DefaultTransport, DfuTransport};
 use std::convert::TryFrom;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::fmt;
+use std::io::{BufReader, Cursor, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::time::Duration;
-use futures_lite::future::block_on;
-use nusb;
-use nusb::descriptors::language_id::US_ENGLISH;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use futures_lite::future::{block_on, zip};
+use serde::Deserialize;
+#[cfg(not(target_arch = "wasm32"))]
 use nusb::descriptors::Descriptor;
-use nusb::transfer::{ControlIn, ControlOut, ControlType, Recipient};
-#[allow(dead_code)]
-const DFU_DETACH: u8 = 0;
-const DFU_DNLOAD: u8 = 1;
-#[allow(dead_code)]
-const DFU_UPLOAD: u8 = 2;
+/// Id for the spans of one public operation, so the logs of several devices can be told apart
+fn next_operation_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+pub(crate) const DFU_DETACH: u8 = 0;
+pub(crate) const DFU_DNLOAD: u8 = 1;
+pub(crate) const DFU_UPLOAD: u8 = 2;
 pub(crate) const DFU_GET_STATUS: u8 = 3;
-const DFU_CLRSTATUS: u8 = 4;
+pub(crate) const DFU_CLRSTATUS: u8 = 4;
 #[allow(dead_code)]
-const DFU_GETSTATE: u8 = 5;
-#[allow(dead_code)]
-const DFU_ABORT: u8 = 6;
+pub(crate) const DFU_GETSTATE: u8 = 5;
+pub(crate) const DFU_ABORT: u8 = 6;
+
+/// Largest control transfer usbfs accepts on Linux (one page)
+#[cfg(target_os = "linux")]
+pub const MAX_TRANSFER_SIZE: u16 = 4096;
+/// Largest control transfer wLength can express
+#[cfg(not(target_os = "linux"))]
+pub const MAX_TRANSFER_SIZE: u16 = u16::MAX;
+
+/// Bytes read from a file at once by verify and download, independent of the transfer size
+pub const INPUT_BUFFER_SIZE: usize = 64 * 1024;
 
-#[derive(Debug)]
-struct Transaction {
-    transaction: u16,
-    address: u32,
-    pending: u32,
-    xfer: u16,
-    xfer_max: u16,
+/// wTransferSize assumed when a device has no usable DFU functional descriptor
+pub const FALLBACK_TRANSFER_SIZE: u16 = 1024;
+
+/// Shortest sleep between two polls of [`Dfu::wait_until`], so a zero poll interval still
+/// reaches the deadline
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DfuDescriptor {
+    pub attributes: u8,
+    pub detach_timeout: u16,
+    pub transfer_size: u16,
+    /// bcdDFUVersion, 0x0100 for a DFU 1.0 descriptor that has none
+    pub dfu_version: u16,
 }
 
-impl Transaction {
-    fn new(address: u32, pending: u32, xfer_max: u16) -> Self {
-        let mut t = Transaction {
-            transaction: 2,
-            address,
-            pending,
-            xfer: xfer_max,
-            xfer_max,
-        };
-        t.set_xfer();
-        t
-    }
+/// Why the DFU functional descriptor of a device was not used and a stand-in was assumed
+#[derive(Debug, Clone, PartialEq)]
+pub enum DescriptorWarning {
+    /// The configuration has no descriptor of type 0x21
+    Missing,
+    /// A functional descriptor with this bLength, too short for wTransferSize
+    Malformed(u8),
+}
 
-    fn set_xfer(&mut self) {
-        if self.pending >= self.xfer_max as u32 {
-            self.xfer = self.xfer_max;
-            self.pending -= self.xfer_max as u32;
-        } else {
-            self.xfer = (self.pending % self.xfer_max as u32) as u16;
-            self.pending = 0;
+impl fmt::Display for DescriptorWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DescriptorWarning::Missing => write!(f, "No DFU functional descriptor"),
+            DescriptorWarning::Malformed(len) => write!(f, "DFU functional descriptor of {} bytes", len),
         }
     }
 }
 
-impl Iterator for Transaction {
-    type Item = ();
-    fn next(&mut self) -> Option<()> {
-        if self.pending == 0 {
-            self.xfer = 0;
+impl DfuDescriptor {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn new(desc: &Descriptor) -> Result<Self, DescriptorWarning> {
+        DfuDescriptor::from_bytes(desc).ok_or(DescriptorWarning::Malformed(desc[0]))
+    }
+
+    /// Parse the DFU functional descriptor, type 0x21. DFU 1.0 devices send 7 bytes without
+    /// bcdDFUVersion, bytes past the 9 of DFU 1.1 are ignored.
+    pub fn from_bytes(desc: &[u8]) -> Option<Self> {
+        let len = *desc.first()? as usize;
+        if len < 7 || desc.len() < len || desc[1] != 33 {
             return None;
         }
-        self.set_xfer();
-        self.address += self.xfer as u32;
-        self.transaction += 1;
-        Some(())
+        let u16_at = |i: usize| u16::from_le_bytes([desc[i], desc[i + 1]]);
+        Some(DfuDescriptor {
+            attributes: desc[2],
+            detach_timeout: u16_at(3),
+            transfer_size: u16_at(5),
+            dfu_version: if len >= 9 { u16_at(7) } else { 0x0100 },
+        })
+    }
+
+    /// bitCanDnload
+    pub fn can_download(&self) -> bool {
+        self.attributes & 1 != 0
+    }
+
+    /// bitCanUpload
+    pub fn can_upload(&self) -> bool {
+        self.attributes & 1 << 1 != 0
+    }
+
+    /// bitManifestationTolerant, the device still answers after manifesting and returns to
+    /// dfuIDLE
+    pub fn manifestation_tolerant(&self) -> bool {
+        self.attributes & 1 << 2 != 0
+    }
+
+    /// bitWillDetach, the device leaves on its own instead of waiting for a bus reset
+    pub fn will_detach(&self) -> bool {
+        self.attributes & 1 << 3 != 0
+    }
+
+    /// `desc`, or when the device has no usable one a download and upload capable stand-in with
+    /// [`FALLBACK_TRANSFER_SIZE`] along with what was wrong
+    pub(crate) fn or_fallback(desc: Result<Self, DescriptorWarning>) -> (Self, Option<DescriptorWarning>) {
+        match desc {
+            Ok(desc) => (desc, None),
+            Err(warning) => {
+                log::warn!("{}, assuming a transfer size of {} bytes", warning, FALLBACK_TRANSFER_SIZE);
+                let desc = DfuDescriptor {
+                    attributes: 0x03,
+                    detach_timeout: 255,
+                    transfer_size: FALLBACK_TRANSFER_SIZE,
+                    dfu_version: 0x011A,
+                };
+                (desc, Some(warning))
+            }
+        }
     }
 }
 
-pub struct DfuDescriptor {
-    pub attributes: u8,
-    pub detach_timeout: u16,
-    pub transfer_size: u16,
-    pub dfu_version: u8,
+/// The DFU functional descriptor among the descriptors of a configuration descriptor
+#[cfg_attr(not(feature = "fuzzing"), allow(dead_code))]
+pub(crate) fn functional_descriptor(config: &[u8]) -> Option<DfuDescriptor> {
+    parse_functional_descriptor(config).ok()
 }
 
-impl DfuDescriptor {
-    fn new(desc: Descriptor) -> Option<Self> {
-        let mut iter = desc.iter();
-        // length
-        if *iter.next()? != 9 {
-            return None;
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+pub(crate) fn parse_functional_descriptor(config: &[u8]) -> Result<DfuDescriptor, DescriptorWarning> {
+    let mut rest = config;
+    while rest.len() >= 2 {
+        let len = rest[0] as usize;
+        if len < 2 || len > rest.len() {
+            break;
         }
+        if rest[1] == 33 {
+            return DfuDescriptor::from_bytes(&rest[..len]).ok_or(DescriptorWarning::Malformed(rest[0]));
+        }
+        rest = &rest[len..];
+    }
+    Err(DescriptorWarning::Missing)
+}
 
-        // type
-        if *iter.next()? != 33 {
-            return None;
+/// Offset of the first byte of `flash` differing from `expected`, a short read mismatches at
+/// its end
+fn first_mismatch(flash: &[u8], expected: &[u8]) -> Option<usize> {
+    flash
+        .iter()
+        .zip(expected)
+        .position(|(a, b)| a != b)
+        .or((flash.len() < expected.len()).then_some(flash.len()))
+}
+
+/// Compare a chunk uploaded by verify, `chunk` being its address, length and bytes read
+fn check_chunk(chunk: Option<(u32, usize, usize)>, flash: &[u8], expected: &[u8]) -> Result<(), Error> {
+    match chunk {
+        Some((at, len, read)) => match first_mismatch(&flash[..read], &expected[..len]) {
+            Some(i) => Err(Error::Verify(at + i as u32)),
+            None => Ok(()),
+        },
+        None => Ok(()),
+    }
+}
+
+fn slice_length(data: &[u8]) -> Result<u32, Error> {
+    u32::try_from(data.len()).map_err(|_| Error::Argument(format!("{} bytes do not fit in the address space", data.len())))
+}
+
+/// A transfer that timed out or was interrupted, as opposed to one the device answered with an
+/// error
+fn transient(err: &Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(err, Error::USB(_, e) if matches!(e.kind(), TimedOut | Interrupted))
+        || disconnected(err)
+}
+
+/// The device dropped off the bus
+fn disconnected(err: &Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(err, Error::USB(_, e) if matches!(e.kind(), NotConnected | ConnectionAborted))
+}
+
+/// The request a dfuERROR is blamed on, the last DNLOAD or UPLOAD sent
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    Start,
+    Command(DfuseCommand),
+    /// A block 0 DNLOAD that is not a known DfuSe command
+    Vendor,
+    Write(u16),
+    Read(u16),
+    Manifest,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::Start => write!(f, "the first request"),
+            Phase::Command(DfuseCommand::SetAddress(a)) => write!(f, "set address 0x{:08X}", a),
+            Phase::Command(DfuseCommand::ErasePage(a)) => write!(f, "erase page 0x{:08X}", a),
+            Phase::Command(DfuseCommand::MassErase) => write!(f, "mass erase"),
+            Phase::Command(DfuseCommand::ReadUnprotected) => write!(f, "read unprotect"),
+            Phase::Command(DfuseCommand::Unknown(cmd)) => write!(f, "command 0x{:02X}", cmd),
+            Phase::Vendor => write!(f, "a DfuSe command"),
+            Phase::Write(block) => write!(f, "write of block {}", block),
+            Phase::Read(block) => write!(f, "read of block {}", block),
+            Phase::Manifest => write!(f, "manifestation"),
         }
+    }
+}
 
-        Some(DfuDescriptor {
-            attributes: *iter.next()?,
-            detach_timeout: *iter.next()? as u16 | (*iter.next()? as u16) << 8,
-            transfer_size: *iter.next()? as u16 | (*iter.next()? as u16) << 8,
-            dfu_version: *iter.next()?,
+/// How GET_STATUS polling, stalled requests and failed blocks are retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Attempts to repeat a failing GET_STATUS
+    pub retries: u8,
+    /// Delay between polls while waiting for a state
+    pub poll_interval: Duration,
+    /// Delay before the first retry after a stall, doubled for every further one
+    pub stall_delay: Duration,
+    /// Upper bound of the doubled stall delay
+    pub max_stall_delay: Duration,
+    /// Attempts to resend a DNLOAD the device stalled, after clearing the stall
+    pub stall_retries: u8,
+    /// Attempts to send a whole block again after it failed with a timeout
+    pub block_retries: u8,
+    /// How long the device may take to manifest the firmware after the download
+    pub manifest_timeout: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt`, counted from 0
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.stall_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_stall_delay)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 10,
+            poll_interval: Duration::from_millis(100),
+            stall_delay: Duration::from_millis(10),
+            max_stall_delay: Duration::from_millis(1000),
+            stall_retries: 3,
+            block_retries: 2,
+            manifest_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Alt setting of the DFU interface, by number or by its string descriptor
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum AltSetting {
+    Number(u8),
+    Name(String),
+}
+
+impl From<u8> for AltSetting {
+    fn from(alt: u8) -> Self {
+        AltSetting::Number(alt)
+    }
+}
+
+impl FromStr for AltSetting {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s.parse::<u8>() {
+            Ok(n) => AltSetting::Number(n),
+            Err(_) => AltSetting::Name(s.into()),
         })
     }
 }
 
-pub struct Dfu {
-    usb: nusb::Device,
-    interface: nusb::Interface,
+impl fmt::Display for AltSetting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AltSetting::Number(n) => write!(f, "{}", n),
+            AltSetting::Name(name) => write!(f, "'{}'", name),
+        }
+    }
+}
+
+/// Name part of a DfuSe alt string such as `@Internal Flash  /0x08000000/04*016Kg`
+pub fn alt_name(alt: &str) -> &str {
+    alt.trim_start_matches('@')
+        .split('/')
+        .next()
+        .unwrap_or("")
+        .trim()
+}
+
+/// Flash contents saved before a download erased them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backup {
+    pub path: PathBuf,
+    pub address: u32,
+    pub length: u32,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Map a failed open to the variant telling the user what to fix
+pub(crate) fn open_error(device: &nusb::DeviceInfo, e: std::io::Error) -> Error {
+    let id = format!("{:04x}:{:04x}", device.vendor_id(), device.product_id());
+    #[cfg(target_os = "windows")]
+    {
+        // Composite devices report usbccgp for the device, the interface driver is not known here
+        let driver = device.driver().unwrap_or("none");
+        if !driver.eq_ignore_ascii_case("winusb") && !driver.eq_ignore_ascii_case("usbccgp") {
+            return Error::DriverNotBound(format!(
+                "{} ({}) uses driver {}",
+                id,
+                device.instance_id().to_string_lossy(),
+                driver
+            ));
+        }
+    }
+    #[cfg(target_os = "macos")]
+    if is_exclusive_access(&e) {
+        let owners = std::process::Command::new("ioreg")
+            .args(["-l", "-w0", "-r", "-c", "IOUSBHostDevice"])
+            .output()
+            .map(|o| ioreg_clients(&String::from_utf8_lossy(&o.stdout), device.location_id()))
+            .unwrap_or_default();
+        if owners.is_empty() {
+            return Error::ExclusiveAccess(id);
+        }
+        return Error::ExclusiveAccess(format!("{} by {}", id, owners.join(", ")));
+    }
+    #[cfg(target_os = "macos")]
+    if is_not_permitted(&e) {
+        return Error::AccessRestricted(id);
+    }
+    match e.kind() {
+        std::io::ErrorKind::PermissionDenied => Error::PermissionDenied(id),
+        _ => Error::USB("open".into(), e),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Map a failed interface claim, on macOS another process may hold the interface
+fn claim_error(iface_index: u8, e: std::io::Error) -> Error {
+    #[cfg(target_os = "macos")]
+    if is_exclusive_access(&e) {
+        return Error::ExclusiveAccess(format!("interface {}", iface_index));
+    }
+    #[cfg(target_os = "macos")]
+    if is_not_permitted(&e) {
+        return Error::AccessRestricted(format!("interface {}", iface_index));
+    }
+    log::error!("Claim interface {} failed with {}", iface_index, e);
+    Error::USB("Claim interface failed".into(), e)
+}
+
+/// kIOReturnExclusiveAccess, nusb turns it into an `Other` error with this message
+#[cfg(target_os = "macos")]
+fn is_exclusive_access(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(0xe00002c5_u32 as i32) || e.to_string().contains("exclusive access")
+}
+
+/// kIOReturnNotPermitted or kIOReturnNotPrivileged, e.g. an App Sandbox without the USB entitlement
+#[cfg(target_os = "macos")]
+fn is_not_permitted(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(r) if r == 0xe00002e2_u32 as i32 || r == 0xe00002c1_u32 as i32)
+}
+
+/// Processes holding a user client on the device at `location_id`, from `ioreg -l -r -c IOUSBHostDevice`.
+/// Each device is a block at column 0, its own properties come before those of its children.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+pub(crate) fn ioreg_clients(ioreg: &str, location_id: u32) -> Vec<String> {
+    let location = location_id.to_string();
+    ioreg
+        .split("\n+-o ")
+        .filter(|block| {
+            block
+                .lines()
+                .find_map(|l| l.split_once("\"locationID\" = ").map(|(_, v)| v.trim()))
+                .is_some_and(|v| v == location)
+        })
+        .flat_map(|block| block.lines())
+        .filter_map(|l| l.split_once("\"IOUserClientCreator\" = ").map(|(_, v)| v.trim().trim_matches('"')))
+        .filter(|creator| !creator.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Default timeout of a single control transfer
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// DFU protocol on top of a [`DfuTransport`], nusb unless built for the browser
+pub struct Dfu<T: DfuTransport = DefaultTransport> {
+    transport: T,
     detached: bool,
     dfu_descriptor: DfuDescriptor,
+    transfer_size: u16,
     mem_layout: MemoryLayout,
+    retry_policy: RetryPolicy,
+    timeout: Duration,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial_number: Option<String>,
+    bcd_device: Option<u16>,
+    backup_dir: Option<PathBuf>,
+    last_backup: Option<Backup>,
+    progress: u32,
+    /// Activity and total bytes of the operation `progress` counts for
+    progress_of: (Activity, u32),
+    on_progress: Option<OnProgress>,
+    stats: TransferStats,
+    /// When the last data block went through, for [`TransferStats::peak_rate`]
+    #[cfg(not(target_arch = "wasm32"))]
+    last_block: Option<std::time::Instant>,
+    /// Address of the Set Address UPLOAD blocks count from, while an upload session is open
+    upload_base: Option<u32>,
+    /// Ranges erasing and writing refuse to touch unless `allow_protected`
+    protected: Vec<Range<u32>>,
+    allow_protected: bool,
+    aliases: Vec<Alias>,
+    phase: Phase,
+    descriptor_warning: Option<DescriptorWarning>,
+    alt_setting: u8,
 }
 
-impl Drop for Dfu {
+impl<T: DfuTransport> Drop for Dfu<T> {
     fn drop(&mut self) {
-        if self.detached {
+        // A browser can not block on a transfer, web callers abort to idle themselves
+        if self.detached || cfg!(target_arch = "wasm32") {
             return;
         }
         if block_on(self.status_wait_for(0, Some(State::DfuIdle))).is_err() {
@@ -127,12 +470,16 @@ impl Drop for Dfu {
     }
 }
 
-impl Dfu {
-    fn setup(usb: nusb::Device, iface_index: u8, alt_index: u8) -> Result<Self, Error> {
-        let interface = usb.claim_interface(iface_index).map_err(|e| {
-            log::error!("Claim interface failed with {}", e);
-            Error::USB("Claim interface failed".into(), e)
-        })?;
+#[cfg(not(target_arch = "wasm32"))]
+impl Dfu<NusbTransport> {
+    fn setup(
+        usb: nusb::Device,
+        iface_index: u8,
+        alt_index: u8,
+        language: u16,
+        mem_layout: Option<MemoryLayout>,
+    ) -> Result<Self, Error> {
+        let interface = usb.claim_interface(iface_index).map_err(|e| claim_error(iface_index, e))?;
 
         let conf = usb.active_configuration().map_err(|_| {
             Error::DeviceNotFound("Missing active configuration".to_string())
@@ -143,75 +490,217 @@ impl Dfu {
         }).ok_or_else(|| {
             Error::DeviceNotFound("Missing configuration alt setting".to_string())
         })?;
+        if DfuMode::from_interface(alt.class(), alt.subclass(), alt.protocol()) == Some(DfuMode::Runtime) {
+            return Err(Error::RuntimeMode(format!("Interface {}", iface_index)));
+        }
 
-        let mem_layout = MemoryLayout::from_str(
-            &alt.string_index().map(|i| usb.get_string_descriptor(i, US_ENGLISH, Duration::from_secs(1)).unwrap()).ok_or_else(|| {
-                Error::DeviceNotFound("Missing configuration descriptor".to_string())
-            })?
-        )?;
+        let mem_layout = match mem_layout {
+            Some(layout) => layout,
+            None => {
+                let alt_string = alt.string_index().ok_or_else(|| {
+                    Error::DeviceNotFound("Missing configuration descriptor".to_string())
+                })?;
+                let alt_string = usb
+                    .get_string_descriptor(alt_string, language, Duration::from_secs(1))
+                    .map_err(|e| Error::USB(format!("read alt setting string in language 0x{:04X}", language), e))?;
+                MemoryLayout::from_str(&alt_string)?
+            }
+        };
         
-        let dfu_descriptor = conf.descriptors()
-        .find(|desc| desc.descriptor_type() == 33)
-        .map(|desc| DfuDescriptor::new(desc.clone())).ok_or_else(|| {
-            Error::DeviceNotFound("Missing configuration dfu transfer descriptor".to_string())
-        })?.unwrap();
-
-        interface.set_alt_setting(alt_index).unwrap();
-
-        log::debug!("Transfer size: {} bytes", dfu_descriptor.transfer_size);
-        Ok(Self {
-            usb,
-            interface,
-            dfu_descriptor,
-            detached: false,
-            mem_layout,
-        })
+        let (dfu_descriptor, warning) = DfuDescriptor::or_fallback(
+            conf.descriptors()
+                .find(|desc| desc.descriptor_type() == 33)
+                .map_or(Err(DescriptorWarning::Missing), |desc| DfuDescriptor::new(&desc)),
+        );
+
+        interface
+            .set_alt_setting(alt_index)
+            .map_err(|e| Error::USB(format!("Set alt setting {}", alt_index), e))?;
+
+        let transport = NusbTransport::new(usb, interface, Some(language));
+        let mut dfu = Dfu::with_transport(transport, dfu_descriptor, mem_layout);
+        dfu.set_descriptor_warning(warning);
+        dfu.set_alt_setting(alt_index);
+        Ok(dfu)
     }
 
-    pub async fn from_bus_device(bus: u8, dev_addr: u8, iface_index: u8, alt: u8) -> Result<Self, Error> {
-        
+    /// Find the alt setting of the interface whose string descriptor is `name`
+    pub(crate) fn find_alt(usb: &nusb::Device, iface_index: u8, name: &str, language: u16) -> Result<u8, Error> {
+        let conf = usb.active_configuration().map_err(|_| {
+            Error::DeviceNotFound("Missing active configuration".to_string())
+        })?;
+        conf.interface_alt_settings()
+            .filter(|s| s.interface_number() == iface_index)
+            .find(|s| {
+                s.string_index()
+                    .and_then(|i| usb.get_string_descriptor(i, language, Duration::from_secs(1)).ok())
+                    .is_some_and(|s| s == name || alt_name(&s) == name)
+            })
+            .map(|s| s.alternate_setting())
+            .ok_or_else(|| Error::DeviceNotFound(format!("Missing alt setting named '{}'", name)))
+    }
+
+    /// Open the first device matching `filter` and claim its DFU interface
+    pub async fn open(filter: &DeviceFilter, iface_index: u8, alt: &AltSetting) -> Result<Self, Error> {
+        Dfu::open_with_language(filter, iface_index, alt, None).await
+    }
+
+    /// [`open`](Dfu::open) reading string descriptors in `language`, a LANGID such as 0x0409,
+    /// instead of the first language the device supports
+    pub async fn open_with_language(
+        filter: &DeviceFilter,
+        iface_index: u8,
+        alt: &AltSetting,
+        language: Option<u16>,
+    ) -> Result<Self, Error> {
+        Dfu::open_device(filter, iface_index, alt, language, None).await
+    }
+
+    /// [`open`](Dfu::open) with a known `layout` instead of the one the alt setting string
+    /// describes, for devices whose string is missing or wrong
+    pub async fn with_memory_layout(
+        filter: &DeviceFilter,
+        iface_index: u8,
+        alt: &AltSetting,
+        layout: MemoryLayout,
+    ) -> Result<Self, Error> {
+        Dfu::open_device(filter, iface_index, alt, None, Some(layout)).await
+    }
+
+    async fn open_device(
+        filter: &DeviceFilter,
+        iface_index: u8,
+        alt: &AltSetting,
+        language: Option<u16>,
+        layout: Option<MemoryLayout>,
+    ) -> Result<Self, Error> {
         let device = nusb::list_devices()
-        .unwrap()
-        .find(|dev| dev.bus_number() == bus && dev.device_address() == dev_addr)
-        .expect("device not connected");
+            .map_err(|e| Error::USB("list devices".into(), e))?
+            .find(|dev| filter.matches(dev))
+            .ok_or_else(|| Error::DeviceNotFound(filter.to_string()))?;
 
-        let usb = device.open().map_err(|e| Error::USB("open".into(), e))?;
+        let lock = DeviceLock::acquire(device.bus_number(), device.device_address())?;
+        let usb = device.open().map_err(|e| open_error(&device, e))?;
+        let language = language.unwrap_or_else(|| default_language(&usb));
 
-        let mut dfu = Dfu::setup(usb, iface_index, alt)?;
+        let alt = match alt {
+            AltSetting::Number(n) => *n,
+            AltSetting::Name(name) => Dfu::find_alt(&usb, iface_index, name, language)?,
+        };
+        let mut dfu = Dfu::setup(usb, iface_index, alt, language, layout)?;
+        dfu.set_identity(
+            device.manufacturer_string().map(String::from),
+            device.product_string().map(String::from),
+            device.serial_number().map(String::from),
+            Some(device.device_version()),
+        );
+        dfu.transport.set_lock(lock);
+        dfu.transport.set_origin(&device, alt);
         dfu.abort_to_idle_clear_once().await?;
         Ok(dfu)
     }
 
+    pub async fn from_bus_device(bus: u8, dev_addr: u8, iface_index: u8, alt: u8) -> Result<Self, Error> {
+        Dfu::open(&DeviceFilter::bus_device(bus, dev_addr), iface_index, &alt.into()).await
+    }
+
+    /// Open the device on the physical port `port`, e.g. `1-4.2`, see [`port_path`](crate::port_path)
+    pub async fn from_port(port: &str, iface_index: u8, alt: u8) -> Result<Self, Error> {
+        Dfu::open(&DeviceFilter::port(port), iface_index, &alt.into()).await
+    }
+
+    /// Open the device by its operating system identifier, see [`platform_id`](crate::platform_id)
+    pub async fn from_platform_id(id: &str, iface_index: u8, alt: u8) -> Result<Self, Error> {
+        Dfu::open(&DeviceFilter::platform_id(id), iface_index, &alt.into()).await
+    }
+
     pub async fn from_vid_pid(vid: u16, pid: u16, iface_index: u8, alt: u8) -> Result<Self, Error> {
-        
-        let device = nusb::list_devices()
-        .unwrap()
-        .find(|dev| dev.vendor_id() == vid && dev.product_id() == pid)
-        .expect("device not connected");
+        Dfu::open(&DeviceFilter::vid_pid(vid, pid), iface_index, &alt.into()).await
+    }
 
-        let usb = device.open().map_err(|e| Error::USB("open".into(), e))?;
+    pub fn usb(&mut self) -> &mut nusb::Device {
+        self.transport.device_mut()
+    }
+}
 
-        let mut dfu = Dfu::setup(usb, iface_index, alt)?;
-        dfu.abort_to_idle_clear_once().await?;
-        Ok(dfu)
+impl<T: DfuTransport> Dfu<T> {
+    /// Run DFU over `transport` with the interface already claimed and its alt setting selected
+    pub fn with_transport(transport: T, dfu_descriptor: DfuDescriptor, mem_layout: MemoryLayout) -> Self {
+        let transfer_size = match dfu_descriptor.transfer_size {
+            0 => {
+                log::warn!("wTransferSize is 0, assuming {} bytes", FALLBACK_TRANSFER_SIZE);
+                FALLBACK_TRANSFER_SIZE
+            }
+            size if u32::from(size) > u32::from(MAX_TRANSFER_SIZE) => {
+                log::warn!("wTransferSize {} is more than the host takes, using {} bytes", size, MAX_TRANSFER_SIZE);
+                MAX_TRANSFER_SIZE
+            }
+            size => size,
+        };
+        log::debug!("Transfer size: {} bytes", transfer_size);
+        Dfu {
+            transport,
+            transfer_size,
+            dfu_descriptor,
+            detached: false,
+            mem_layout,
+            retry_policy: RetryPolicy::default(),
+            timeout: DEFAULT_TIMEOUT,
+            manufacturer: None,
+            product: None,
+            serial_number: None,
+            bcd_device: None,
+            backup_dir: None,
+            last_backup: None,
+            progress: 0,
+            progress_of: (Activity::Write, 0),
+            on_progress: None,
+            stats: TransferStats::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            last_block: None,
+            upload_base: None,
+            protected: Vec::new(),
+            allow_protected: false,
+            aliases: Vec::new(),
+            phase: Phase::Start,
+            descriptor_warning: None,
+            alt_setting: 0,
+        }
     }
 
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    #[tracing::instrument(level = "trace", skip_all)]
     pub async fn get_status(&mut self, mut retries: u8) -> Result<Status, Error> {
         let mut status = Err(Error::Argument("Get status retries failed".into()));
+        let mut stalls = 0;
         retries += 1;
         while retries > 0 {
             retries -= 1;
-            status = Status::get(&self.interface).await;
+            self.stats.control_transfers += 1;
+            status = Status::get(&self.transport, self.timeout).await;
             if let Err(e) = &status {
+                let retry = retries > 0;
                 if let Error::USB(_, e) = e {
-                    if e.kind() == std::io::ErrorKind::BrokenPipe {
-                        log::warn!("Epipe try again");
-                        tokio::time::sleep(std::time::Duration::from_millis(3000)).await;
+                    if e.kind() == std::io::ErrorKind::ConnectionReset {
+                        self.stats.stalls += 1;
+                        if !retry || stalls >= self.retry_policy.stall_retries {
+                            break;
+                        }
+                        log::warn!("Get status stalled, retry {}", stalls + 1);
+                        self.stats.retries += 1;
+                        self.transport.sleep(self.retry_policy.backoff(stalls as u32)).await;
+                        // Boxed, clearing the stall polls the status again
+                        Box::pin(self.clear_stall()).await?;
+                        stalls += 1;
                         continue;
                     }
                 } else if let Error::InvalidControlResponse(e) = e {
                     log::warn!("retries {} Get status error cause '{}'", retries, e);
-                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    self.stats.retries += retry as u32;
+                    self.transport.sleep(self.retry_policy.poll_interval).await;
                     continue;
                 }
             } else {
@@ -221,27 +710,33 @@ impl Dfu {
         status
     }
 
+    /// Text of the iString of `status`, None when it has none
+    pub async fn status_string(&self, status: &Status) -> Result<Option<String>, Error> {
+        if status.string_index == 0 {
+            return Ok(None);
+        }
+        self.transport
+            .string_descriptor(status.string_index, self.timeout)
+            .await
+            .map(Some)
+            .map_err(|e| Error::USB("Get status string".into(), e))
+    }
+
     pub async fn clear_status(&mut self) -> Result<(), Error> {
-        self.interface.control_out(ControlOut {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_CLRSTATUS,
-            value: 0,
-            index: self.interface.interface_number() as u16,
-            data: &[],
-        }).await.into_result().map_err(|e| Error::USB("Control transfer".into(), e.into()))?;
+        self.stats.control_transfers += 1;
+        self.transport
+            .control_out(DFU_CLRSTATUS, 0, &[], self.timeout)
+            .await
+            .map_err(|e| Error::USB("Control transfer".into(), e))?;
         Ok(())
     }
 
     pub async fn detach(&mut self) -> Result<(), Error> {
-        self.interface.control_out(ControlOut {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_DETACH,
-            value: 0,
-            index: self.interface.interface_number() as u16,
-            data: &[],
-        }).await.into_result().map_err(|e| Error::USB("Detach".into(), e.into()))?;
+        self.stats.control_transfers += 1;
+        self.transport
+            .control_out(DFU_DETACH, 0, &[], self.timeout)
+            .await
+            .map_err(|e| Error::USB("Detach".into(), e))?;
         Ok(())
     }
 
@@ -256,17 +751,24 @@ impl Dfu {
         } else {
             State::DfuDownloadBusy
         };
-        let mut s = self.get_status(10).await?;
+        let mut s = self.get_status(self.retry_policy.retries).await?;
         while retries > 0 {
             if s.state == u8::from(&wait_for_state) {
                 break;
             }
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            if s.state == u8::from(&State::DfuError) {
+                return Err(self.device_error(s).await);
+            }
+            self.stats.busy_time += self.retry_policy.poll_interval;
+            self.transport.sleep(self.retry_policy.poll_interval).await;
             retries -= 1;
-            s = self.get_status(10).await?;
+            s = self.get_status(self.retry_policy.retries).await?;
         }
 
         // check if expected state and return fail if not
+        if s.state == u8::from(&State::DfuError) && wait_for_state != State::DfuError {
+            return Err(self.device_error(s).await);
+        }
         if s.state != u8::from(&wait_for_state) {
             return Err(Error::InvalidState(s, wait_for_state));
         }
@@ -277,8 +779,49 @@ impl Dfu {
         Ok(s)
     }
 
+    /// What the device reported with dfuERROR status `s`, clearing it so the next request finds
+    /// the device in dfuIDLE
+    async fn device_error(&mut self, s: Status) -> Error {
+        let text = self.status_string(&s).await.unwrap_or_else(|e| {
+            log::debug!("{}", e);
+            None
+        });
+        if let Err(e) = self.clear_status().await {
+            log::warn!("Clear status after {} failed {}", status_name(s.status), e);
+        }
+        Error::Device(self.phase.to_string(), s, text)
+    }
+
+    /// Poll GET_STATUS until `done` accepts a status, e.g. "idle or error". Polls are spaced by
+    /// bwPollTimeout or else the poll interval, at least 1 ms, and give up once they slept
+    /// `deadline` in total.
+    pub async fn wait_until<F>(&mut self, mut done: F, deadline: Duration) -> Result<Status, Error>
+    where
+        F: FnMut(&Status) -> bool,
+    {
+        let mut waited = Duration::ZERO;
+        loop {
+            let s = self.get_status(self.retry_policy.retries).await?;
+            if done(&s) {
+                return Ok(s);
+            }
+            if waited >= deadline {
+                return Err(Error::WaitTimeout(s));
+            }
+            let wait = match s.poll_timeout() {
+                Duration::ZERO => self.retry_policy.poll_interval,
+                t => t,
+            };
+            let wait = wait.max(MIN_POLL_INTERVAL).min(deadline - waited);
+            self.stats.busy_time += wait;
+            self.transport.sleep(wait).await;
+            waited += wait;
+        }
+    }
+
     pub async fn set_address(&mut self, address: u32) -> Result<(), Error> {
-        self.dfuse_download(Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
+        let address = self.canonical_address(address);
+        self.dfuse_download(&Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
         self.status_wait_for(0, Some(State::DfuDownloadIdle)).await?;
         Ok(())
     }
@@ -289,179 +832,460 @@ impl Dfu {
         log::debug!("set done");
         //        self.abort_to_idle()?;
         //       log::debug!("abort done");
-        self.dfuse_download(Vec::new(), 2).await?;
-        log::debug!("dfuse None, 2 done");
-        self.get_status(0).await.unwrap_or_else(|e| {
-            log::warn!("get_status failed cause {}", e);
-            Status::default()
-        });
+        self.finish_download(2).await
+    }
+
+    /// End a download with the zero length DNLOAD as block `block` and poll the device through
+    /// dfuMANIFEST-SYNC and dfuMANIFEST, as its functional descriptor says it goes. A tolerant
+    /// device comes back to dfuIDLE, any other leaves the bus or waits in
+    /// dfuMANIFEST-WAIT-RESET, and unless it detaches by itself gets a bus reset. DfuSe leaves
+    /// with block 2 after a Set Address, a plain DFU 1.1 download with the block after its last.
+    pub async fn finish_download(&mut self, block: u16) -> Result<(), Error> {
+        self.dfuse_download(&[], block).await?;
+        let manifesting = |s: &Status| {
+            s.state == u8::from(&State::DfuManifestSync) || s.state == u8::from(&State::DfuManifest)
+        };
+        let timeout = self.retry_policy.manifest_timeout;
+        if self.dfu_descriptor.manifestation_tolerant() {
+            let s = self.wait_until(|s| !manifesting(s), timeout).await?;
+            if s.state == u8::from(&State::DfuError) {
+                return Err(self.device_error(s).await);
+            }
+            if s.state != u8::from(&State::DfuIdle) {
+                return Err(Error::InvalidState(s, State::DfuIdle));
+            }
+        } else {
+            match self.wait_until(|s| !manifesting(s), timeout).await {
+                Ok(s) if s.state == u8::from(&State::DfuManifestWaitReset) => {}
+                Ok(s) if s.state == u8::from(&State::DfuError) => return Err(self.device_error(s).await),
+                Ok(s) if s.status != 0 => return Err(Error::InvalidStatus(s, 0)),
+                Ok(s) => log::debug!("State {} after manifestation", s.state),
+                // Still reset below, the device may only start the firmware once it sees one
+                Err(Error::WaitTimeout(s)) => log::warn!("Manifestation not done in {:?}, state {}", timeout, s.state),
+                // The device dropped off the bus to run the new firmware
+                Err(e) => log::debug!("Device left during manifestation: {}", e),
+            }
+        }
+        if !self.dfu_descriptor.will_detach() {
+            log::debug!("Device does not detach by itself, resetting it");
+            if let Err(e) = self.transport.reset().await {
+                // Devices often drop off the bus while the reset is still being acknowledged
+                log::debug!("Reset after manifestation: {}", e);
+            }
+        }
         self.detached = true;
         Ok(())
     }
 
-    pub async fn dfuse_get_commands(&mut self) -> Result<Vec<DfuseCommand>, Error> {
+    /// Command bytes answered to DfuSe Get Commands, without the leading Get Commands byte 0x00
+    pub async fn dfuse_get_command_bytes(&mut self) -> Result<Vec<u8>, Error> {
         self.abort_to_idle().await?;
-        let mut v = Vec::new();
-        let cmds = &self.dfuse_upload(0, 1024).await?;
-        if let Some(cmd) = cmds.iter().next() {
-            if *cmd != 0 {
-                return Err(Error::InvalidControlResponse(format!(
-                    "Get command {:X} {:X?}",
-                    cmd, cmds
-                )));
-            }
-        }
-        for cmd in &cmds[1..] {
-            v.push(DfuseCommand::try_from(*cmd)?)
+        let cmds = self.dfuse_upload(0, 1024).await?;
+        match cmds.first() {
+            Some(0) => Ok(cmds[1..].to_vec()),
+            Some(cmd) => Err(Error::InvalidControlResponse(format!(
+                "Get command {:X} {:X?}",
+                cmd, cmds
+            ))),
+            None => Ok(Vec::new()),
         }
-        Ok(v)
+    }
+
+    /// Decoded DfuSe Get Commands, bytes unknown to [`DfuseCommand`] as
+    /// [`Unknown`](DfuseCommand::Unknown)
+    pub async fn dfuse_get_commands(&mut self) -> Result<Vec<DfuseCommand>, Error> {
+        Ok(self
+            .dfuse_get_command_bytes()
+            .await?
+            .into_iter()
+            .map(DfuseCommand::from_byte)
+            .collect())
     }
 
     /// Verify flash using file
-    pub async fn verify(
+    #[tracing::instrument(
+        level = "info",
+        skip_all,
+        fields(
+            op = next_operation_id(),
+            serial = self.serial_number().unwrap_or(""),
+            address = format!("0x{:08X}", address),
+            length,
+        ),
+    )]
+    pub async fn verify<R: Read>(
         &mut self,
-        file: &mut File,
+        file: &mut R,
         address: u32,
         length: u32,
     ) -> Result<(), Error> {
-        self.dfuse_download(Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
-        self.status_wait_for(0, None).await?;
-        self.abort_to_idle().await?;
-        self.status_wait_for(0, Some(State::DfuIdle)).await?;
-        let mut t = Transaction::new(address, length, self.dfu_descriptor.transfer_size);
-        while t.xfer > 0 {
-            let address = t.address;
-            self.flash_read_chunk(&mut t, |v| {
-                let mut r = vec![0; v.len()];
-                file.read_exact(&mut r)?;
-                let mut i2 = v.iter();
-                for (i, byte) in r.iter().enumerate() {
-                    if let Some(byte2) = i2.next() {
-                        if byte == byte2 {
-                            continue;
-                        }
-                    }
-                    return Err(Error::Verify(address + i as u32));
-                }
-                if v.len() != r.len() {
-                    return Err(Error::Verify(address + v.len() as u32));
+        let address = self.canonical_address(address);
+        self.start_progress(Activity::Verify, length);
+        self.in_upload(address, async |dfu: &mut Self| {
+            // Never read past the range, the caller may go on reading the file
+            let file = &mut BufReader::with_capacity(INPUT_BUFFER_SIZE, file.take(length as u64));
+            let size = dfu.transfer_size as usize;
+            let (mut flash, mut expected) = (vec![0; size], vec![0; size]);
+            let (mut last_flash, mut last_expected) = (vec![0; size], vec![0; size]);
+            // Address, length and bytes read of the chunk compared while the next one is uploaded
+            let mut last: Option<(u32, usize, usize)> = None;
+            for chunk in Transaction::new(address, length, dfu.transfer_size) {
+                let len = chunk.length as usize;
+                let compare = async {
+                    check_chunk(last, &last_flash, &last_expected)?;
+                    file.read_exact(&mut expected[..len])?;
+                    Ok::<_, Error>(())
+                };
+                let (read, compared) = zip(dfu.read_chunk(chunk, &mut flash[..len]), compare).await;
+                compared?;
+                last = Some((chunk.address, len, read?));
+                std::mem::swap(&mut flash, &mut last_flash);
+                std::mem::swap(&mut expected, &mut last_expected);
+            }
+            check_chunk(last, &last_flash, &last_expected)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Verify flash against `data` in memory, compared in place without copying it
+    #[tracing::instrument(
+        level = "info",
+        skip_all,
+        fields(
+            op = next_operation_id(),
+            serial = self.serial_number().unwrap_or(""),
+            address = format!("0x{:08X}", address),
+            length = data.len(),
+        ),
+    )]
+    pub async fn verify_slice(&mut self, data: &[u8], address: u32) -> Result<(), Error> {
+        let address = self.canonical_address(address);
+        self.start_progress(Activity::Verify, slice_length(data)?);
+        self.in_upload(address, async |dfu: &mut Self| {
+            let mut flash = vec![0; dfu.transfer_size as usize];
+            for chunk in Transaction::new(address, slice_length(data)?, dfu.transfer_size) {
+                let start = chunk.offset as usize;
+                let read = dfu.read_chunk(chunk, &mut flash).await?;
+                if let Some(i) = first_mismatch(&flash[..read], &data[start..start + chunk.length as usize]) {
+                    return Err(Error::Verify(chunk.address + i as u32));
                 }
-                Ok(())
-            }).await?;
-        }
-        self.abort_to_idle().await?;
-        Ok(())
+            }
+            Ok(())
+        })
+        .await
     }
 
     /// Erase pages from start address + length
-    pub async fn erase_pages(&mut self, mut address: u32, length: u32) -> Result<(), Error> {
+    #[tracing::instrument(
+        level = "info",
+        skip_all,
+        fields(
+            op = next_operation_id(),
+            serial = self.serial_number().unwrap_or(""),
+            address = format!("0x{:08X}", address),
+            length,
+        ),
+    )]
+    pub async fn erase_pages(&mut self, address: u32, length: u32) -> Result<(), Error> {
+        let address = self.canonical_address(address);
+        self.end_session().await?;
         self.status_wait_for(0, Some(State::DfuIdle)).await?;
-        let mut pages = self.mem_layout.num_pages(address, length)?;
-        let page = self.mem_layout.address(address)?;
-        // realign to beginning of page
-        address = page.address;
-        while pages > 0 {
-            self.dfuse_download(Vec::from(DfuseCommand::ErasePage(address)), 0).await?;
-            self.status_wait_for(0, Some(State::DfuDownloadBusy)).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
-            pages -= 1;
-            address += page.size;
+        let pages = self.mem_layout.pages_in_range(address, length)?;
+        // Refuse before erasing anything
+        for page in &pages {
+            self.check_protected(page.address, page.size)?;
+        }
+        let total = pages.iter().map(|p| p.size).sum();
+        let mut done = 0;
+        for page in pages {
+            self.erase_page(page.address).await?;
+            done += page.size;
+            self.report(&Progress {
+                activity: Activity::Erase,
+                done,
+                total,
+                address: page.address,
+            });
         }
         Ok(())
     }
 
+    /// Erase the page at `address`, from dfuIDLE or dfuDNLOAD-IDLE
+    pub(crate) async fn erase_page(&mut self, address: u32) -> Result<(), Error> {
+        let page = self.mem_layout.address(address)?;
+        self.check_protected(page.address, page.size)?;
+        self.dfuse_download(&Vec::from(DfuseCommand::ErasePage(address)), 0).await?;
+        self.status_wait_for(0, Some(State::DfuDownloadBusy)).await?;
+        self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
+        Ok(())
+    }
+
     /// Do mass erase of flash
+    #[tracing::instrument(
+        level = "info",
+        skip_all,
+        fields(
+            op = next_operation_id(),
+            serial = self.serial_number().unwrap_or(""),
+        ),
+    )]
     pub async fn mass_erase(&mut self) -> Result<(), Error> {
+        if let Some(start) = self.mem_layout.start_address() {
+            self.check_protected(start, self.mem_layout.end_address()? - start)?;
+        }
+        self.end_session().await?;
         self.status_wait_for(0, Some(State::DfuIdle)).await?;
-        self.dfuse_download(Vec::from(DfuseCommand::MassErase), 0).await?;
+        self.dfuse_download(&Vec::from(DfuseCommand::MassErase), 0).await?;
         self.status_wait_for(0, Some(State::DfuDownloadBusy)).await?;
         self.status_wait_for(10, Some(State::DfuDownloadIdle)).await?;
         Ok(())
     }
 
-    async fn flash_read_chunk<F>(&mut self, t: &mut Transaction, mut f: F) -> Result<(), Error>
-    where
-        F: FnMut(Vec<u8>) -> Result<(), Error>,
-    {
-        log::debug!("{:X?}", t);
-        let v = self.dfuse_upload(t.transaction, t.xfer).await?;
-        f(v)?;
-        let _ = t.next().is_some();
+    /// Open an upload session reading from `address`. A session that is already open is kept
+    /// when `address` is a whole number of transfers past its base, otherwise it is aborted
+    /// and the device gets a new Set Address.
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            address = format!("0x{:08X}", address),
+        ),
+    )]
+    pub async fn begin_upload(&mut self, address: u32) -> Result<(), Error> {
+        let address = self.canonical_address(address);
+        match self.upload_base {
+            Some(base) if self.upload_block(base, address).is_some() => return Ok(()),
+            Some(_) => self.end_session().await?,
+            None => {}
+        }
+        self.dfuse_download(&Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
+        self.status_wait_for(0, None).await?;
+        self.abort_to_idle().await?;
+        self.upload_base = Some(address);
+        Ok(())
+    }
+
+    /// Run `f` in an upload session from `address`. A session opened here is closed again,
+    /// one the caller opened is left open for the next operation.
+    async fn in_upload<R>(
+        &mut self,
+        address: u32,
+        f: impl AsyncFnOnce(&mut Self) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        let shared = self.upload_base.is_some();
+        self.begin_upload(address).await?;
+        let res = f(self).await;
+        if !shared {
+            match res {
+                Ok(_) => self.end_session().await?,
+                // Leave the device as the error found it, without pretending a session is open
+                Err(_) => self.upload_base = None,
+            }
+        }
+        res
+    }
+
+    /// Close the upload session, if one is open, and return to dfuIDLE
+    pub async fn end_session(&mut self) -> Result<(), Error> {
+        if self.upload_base.take().is_some() {
+            self.abort_to_idle().await?;
+        }
         Ok(())
     }
 
+    /// UPLOAD block reading `address` in a session from `base`
+    fn upload_block(&self, base: u32, address: u32) -> Option<u16> {
+        let size = self.transfer_size as u32;
+        let offset = address.wrapping_sub(base);
+        if !offset.is_multiple_of(size) {
+            return None;
+        }
+        u16::try_from(offset / size + 2).ok()
+    }
+
+    /// UPLOAD at most one transfer from `address` into `buf`, opening or moving the session as
+    /// needed. Returns the number of bytes the device sent.
+    pub(crate) async fn read_at(&mut self, address: u32, buf: &mut [u8]) -> Result<usize, Error> {
+        self.begin_upload(address).await?;
+        let base = self.upload_base.unwrap_or(address);
+        let block = self.upload_block(base, address).ok_or(Error::Address(address))?;
+        let read = self.dfuse_upload_into(block, buf).await;
+        if read.is_err() {
+            // The device state is unknown, start over with the next read
+            self.upload_base = None;
+        }
+        read
+    }
+
+    /// UPLOAD `chunk` of a [`Transaction`] into `buf`, which must hold `chunk.length` bytes,
+    /// within the upload session. Returns the number of bytes the device sent.
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            address = format!("0x{:08X}", chunk.address),
+            length = chunk.length,
+        ),
+    )]
+    pub async fn read_chunk(&mut self, chunk: Chunk, buf: &mut [u8]) -> Result<usize, Error> {
+        log::debug!("{:X?}", chunk);
+        let len = self.read_at(chunk.address, &mut buf[..chunk.length as usize]).await?;
+        self.advance(len as u32, chunk.address);
+        Ok(len)
+    }
+
+    #[tracing::instrument(
+        level = "info",
+        skip_all,
+        fields(
+            op = next_operation_id(),
+            serial = self.serial_number().unwrap_or(""),
+            address = format!("0x{:08X}", address),
+            length = buf.len(),
+        ),
+    )]
     pub async fn write_flash_from_slice(&mut self, address: u32, buf: &[u8]) -> Result<usize, Error> {
-        let mut length = buf.len() as u32;
+        let address = self.canonical_address(address);
+        let length = slice_length(buf)?;
         self.erase_pages(address, length).await?;
         self.abort_to_idle().await?;
         self.status_wait_for(0, Some(State::DfuIdle)).await?;
-        let mut transaction = 2;
-        let mut xfer;
-        if length >= self.dfu_descriptor.transfer_size as u32 {
-            panic!(
-                "FIXME write_flash_from_slice only allow xfer size max {}",
-                self.dfu_descriptor.transfer_size
-            );
+        self.start_progress(Activity::Write, length);
+        for chunk in Transaction::new(address, length, self.transfer_size) {
+            let start = chunk.offset as usize;
+            self.write_chunk(chunk, &buf[start..start + chunk.length as usize]).await?;
+            self.advance(chunk.length as u32, chunk.address);
         }
-        while length != 0 {
-            if length >= self.dfu_descriptor.transfer_size as u32 {
-                xfer = self.dfu_descriptor.transfer_size;
-                length -= self.dfu_descriptor.transfer_size as u32;
-            } else {
-                xfer = length as u16;
-                length = 0;
+        self.abort_to_idle().await?;
+        Ok(buf.len())
+    }
+
+    pub(crate) async fn write_chunk(&mut self, chunk: Chunk, buf: &[u8]) -> Result<(), Error> {
+        match self.send_chunk(chunk, buf).await {
+            Err(e) => self.retry_chunk(chunk, buf, e).await,
+            Ok(()) => Ok(()),
+        }
+    }
+
+    async fn send_chunk(&mut self, chunk: Chunk, buf: &[u8]) -> Result<(), Error> {
+        let busy = self.start_chunk(chunk, buf).await?;
+        self.finish_chunk(&busy).await
+    }
+
+    /// Send `chunk` again after it failed with `err`, up to `block_retries` times. Only transfers
+    /// that timed out or broke are repeated, a device reporting an error is not asked twice. A
+    /// device that went away is reconnected first.
+    async fn retry_chunk(&mut self, chunk: Chunk, buf: &[u8], mut err: Error) -> Result<(), Error> {
+        for attempt in 0..self.retry_policy.block_retries {
+            if !transient(&err) {
+                break;
             }
-            log::debug!(
-                "{}: 0x{:4X} xfer: {} length: {}",
-                transaction,
-                address,
-                xfer,
-                length
+            log::warn!(
+                "block {} at 0x{:08X} failed: {}, retry {}",
+                chunk.block,
+                chunk.address,
+                err,
+                attempt + 1
             );
-            self.dfuse_download(Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
-            self.dfuse_download(buf.into(), transaction).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadBusy)).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
-            transaction += 1;
+            self.transport.sleep(self.retry_policy.backoff(attempt as u32)).await;
+            self.stats.retries += 1;
+            let recovered = if disconnected(&err) {
+                self.reconnect().await
+            } else {
+                self.clear_stall().await
+            };
+            let res = match recovered {
+                Ok(()) => self.send_chunk(chunk, buf).await,
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(()) => return Ok(()),
+                Err(e) => err = e,
+            }
         }
-        self.abort_to_idle().await?;
-        Ok(length as usize)
+        Err(err)
+    }
+
+    /// Send `buf` and return the dfuDNLOAD-BUSY status, the device is programming until the
+    /// chunk is finished with [`Self::finish_chunk`]
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(
+            address = format!("0x{:08X}", chunk.address),
+            length = chunk.length,
+        ),
+    )]
+    async fn start_chunk(&mut self, chunk: Chunk, buf: &[u8]) -> Result<Status, Error> {
+        log::debug!("{:X?}", chunk);
+        self.check_protected(chunk.address, chunk.length as u32)?;
+        self.dfuse_download(&Vec::from(DfuseCommand::SetAddress(chunk.base)), 0).await?;
+        self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
+        self.dfuse_download(buf, chunk.block).await?;
+        self.status_wait_for(100, Some(State::DfuDownloadBusy)).await
     }
 
+    /// Wait out bwPollTimeout of `busy` before polling for dfuDNLOAD-IDLE. The wait is capped
+    /// at the poll interval as not every bootloader reports a sensible timeout.
+    #[tracing::instrument(level = "debug", skip_all, fields(poll_timeout = busy.poll_timeout))]
+    async fn finish_chunk(&mut self, busy: &Status) -> Result<(), Error> {
+        let wait = busy.poll_timeout().min(self.retry_policy.poll_interval);
+        self.stats.busy_time += wait;
+        self.transport.sleep(wait).await;
+        self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        level = "info",
+        skip_all,
+        fields(
+            op = next_operation_id(),
+            serial = self.serial_number().unwrap_or(""),
+            address = format!("0x{:08X}", address),
+            length = buf.len(),
+        ),
+    )]
     pub async fn read_flash_to_slice(&mut self, address: u32, buf: &mut [u8]) -> Result<usize, Error> {
-        self.dfuse_download(Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
-        self.status_wait_for(0, None).await?;
-        self.abort_to_idle().await?;
-        self.status_wait_for(0, Some(State::DfuIdle)).await?;
-        let mut len = 0;
-        let size = buf.len();
-        let mut t = Transaction::new(address, size as u32, self.dfu_descriptor.transfer_size);
-        while t.xfer > 0 {
-            self.flash_read_chunk(&mut t, |v| {
-                for b in v {
-                    buf[len] = b;
-                    len += 1;
-                }
-                Ok(())
-            }).await?;
-        }
-        self.abort_to_idle().await?;
-        Ok(len)
+        let address = self.canonical_address(address);
+        let length = slice_length(buf)?;
+        self.start_progress(Activity::Read, length);
+        self.in_upload(address, async |dfu: &mut Self| {
+            let mut len = 0;
+            for chunk in Transaction::new(address, length, dfu.transfer_size) {
+                // Chunks are contiguous, a short UPLOAD leaves the rest of its chunk untouched
+                let start = chunk.offset as usize;
+                len = start + dfu.read_chunk(chunk, &mut buf[start..]).await?;
+            }
+            Ok(len)
+        })
+        .await
     }
 
     /// Upload read flash and store it in file.
-    pub async fn upload(&mut self, file: &mut File, address: u32, length: u32) -> Result<(), Error> {
-        self.dfuse_download(Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
-        self.status_wait_for(0, None).await?;
-        self.abort_to_idle().await?;
-        self.status_wait_for(0, Some(State::DfuIdle)).await?;
-        let mut t = Transaction::new(address, length, self.dfu_descriptor.transfer_size);
-        while t.xfer > 0 {
-            self.flash_read_chunk(&mut t, |v| Ok(file.write_all(&v)?)).await?;
-        }
-        self.abort_to_idle().await?;
-        Ok(())
+    #[tracing::instrument(
+        level = "info",
+        skip_all,
+        fields(
+            op = next_operation_id(),
+            serial = self.serial_number().unwrap_or(""),
+            address = format!("0x{:08X}", address),
+            length,
+        ),
+    )]
+    pub async fn upload<W: Write>(&mut self, file: &mut W, address: u32, length: u32) -> Result<(), Error> {
+        let address = self.canonical_address(address);
+        self.start_progress(Activity::Read, length);
+        self.in_upload(address, async |dfu: &mut Self| {
+            let mut buf = vec![0; dfu.transfer_size as usize];
+            for chunk in Transaction::new(address, length, dfu.transfer_size) {
+                let len = dfu.read_chunk(chunk, &mut buf).await?;
+                file.write_all(&buf[..len])?;
+            }
+            Ok(())
+        })
+        .await
     }
 
     pub async fn abort_to_idle_clear_once(&mut self) -> Result<(), Error> {
@@ -471,14 +1295,11 @@ impl Dfu {
             return Ok(());
         }
 
-        self.interface.control_out(ControlOut {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_ABORT,
-            value: 0,
-            index: self.interface.interface_number() as u16,
-            data: &[],
-        }).await.into_result().map_err(|e| Error::USB("Abort to idle".into(), e.into()))?;
+        self.stats.control_transfers += 1;
+        self.transport
+            .control_out(DFU_ABORT, 0, &[], self.timeout)
+            .await
+            .map_err(|e| Error::USB("Abort to idle".into(), e))?;
     
         let s = self.get_status(0).await?;
         // try clear and read again in case of wrong state
@@ -492,14 +1313,11 @@ impl Dfu {
     }
 
     pub async fn abort_to_idle(&mut self) -> Result<(), Error> {
-        self.interface.control_out(ControlOut {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_ABORT,
-            value: 0,
-            index: self.interface.interface_number() as u16,
-            data: &[],
-        }).await.into_result().map_err(|e| Error::USB("Abort to idle".into(), e.into()))?;
+        self.stats.control_transfers += 1;
+        self.transport
+            .control_out(DFU_ABORT, 0, &[], self.timeout)
+            .await
+            .map_err(|e| Error::USB("Abort to idle".into(), e))?;
 
         let s = self.get_status(0).await?;
         if s.state != u8::from(&State::DfuIdle) {
@@ -510,95 +1328,609 @@ impl Dfu {
 
     /// Download file to device using raw mode.
     /// If length is None it will read to file end.
-    pub async fn download_raw(
+    #[tracing::instrument(
+        level = "info",
+        skip_all,
+        fields(
+            op = next_operation_id(),
+            serial = self.serial_number().unwrap_or(""),
+            address = format!("0x{:08X}", address),
+            length,
+        ),
+    )]
+    pub async fn download_raw<R: Read>(
         &mut self,
-        file: &mut File,
+        file: &mut R,
         address: u32,
-        mut length: u32,
+        length: u32,
     ) -> Result<(), Error> {
+        let address = self.canonical_address(address);
+        if let Some(dir) = self.backup_dir.clone() {
+            self.backup(&dir, address, length).await?;
+        }
+        self.progress = 0;
         self.erase_pages(address, length).await?;
         self.abort_to_idle().await?;
         self.status_wait_for(0, Some(State::DfuIdle)).await?;
-        let mut transaction = 2;
-        let mut xfer;
-        while length != 0 {
-            if length >= self.dfu_descriptor.transfer_size as u32 {
-                xfer = self.dfu_descriptor.transfer_size;
-                length -= self.dfu_descriptor.transfer_size as u32;
-            } else {
-                xfer = length as u16;
-                length = 0;
+        self.start_progress(Activity::Write, length);
+        let file = &mut BufReader::with_capacity(INPUT_BUFFER_SIZE, file.take(length as u64));
+        let mut plan = Transaction::new(address, length, self.transfer_size).peekable();
+        // The chunk in flight stays in `buf` until the device finished it, a failed one is sent
+        // again from there while the file has already moved on into `next`
+        let mut buf = vec![0; self.transfer_size as usize];
+        let mut next = vec![0; self.transfer_size as usize];
+        if let Some(first) = plan.peek() {
+            file.read_exact(&mut buf[..first.length as usize])?;
+        }
+        while let Some(chunk) = plan.next() {
+            let data = &buf[..chunk.length as usize];
+            let started = self.start_chunk(chunk, data).await;
+            // Read the next chunk while the device is still programming this one
+            if let Some(n) = plan.peek() {
+                file.read_exact(&mut next[..n.length as usize])?;
             }
-            log::debug!(
-                "{}: 0x{:4X} xfer: {} length: {}",
-                transaction,
-                address,
-                xfer,
-                length
-            );
-            let mut buf = vec![0; xfer as usize];
-            file.read_exact(&mut buf)?;
-            self.dfuse_download(Vec::from(DfuseCommand::SetAddress(address)), 0).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
-            self.dfuse_download(buf, transaction).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadBusy)).await?;
-            self.status_wait_for(100, Some(State::DfuDownloadIdle)).await?;
-            transaction += 1;
+            let res = match started {
+                Ok(busy) => self.finish_chunk(&busy).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                self.retry_chunk(chunk, data, e).await?;
+            }
+            self.advance(chunk.length as u32, chunk.address);
+            std::mem::swap(&mut buf, &mut next);
         }
         self.abort_to_idle().await?;
         Ok(())
     }
 
-    async fn dfuse_download(&mut self, buf: Vec<u8>, transaction: u16) -> Result<(), Error> {
-        let res = self.interface.control_out(ControlOut {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_DNLOAD,
-            value: transaction,
-            index: self.interface.interface_number() as u16,
-            data: &buf,
-        }).await.into_result();
+    /// Like [`Self::download_raw`] with the image in memory, e.g. a memory-mapped file. Chunks
+    /// are sent straight from `data`.
+    #[tracing::instrument(
+        level = "info",
+        skip_all,
+        fields(
+            op = next_operation_id(),
+            serial = self.serial_number().unwrap_or(""),
+            address = format!("0x{:08X}", address),
+            length = data.len(),
+        ),
+    )]
+    pub async fn download_slice(&mut self, data: &[u8], address: u32) -> Result<(), Error> {
+        let address = self.canonical_address(address);
+        let length = slice_length(data)?;
+        if let Some(dir) = self.backup_dir.clone() {
+            self.backup(&dir, address, length).await?;
+        }
+        self.progress = 0;
+        self.write_flash_from_slice(address, data).await?;
+        Ok(())
+    }
 
-        match res
-        {
-            Err(e) => {
-                match e {
-                    nusb::transfer::TransferError::Stall => {
-                        log::warn!("stalled on transaction {}", transaction);
-                        self.abort_to_idle().await?;
-                        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
-                        Ok(())
+    /// Offset into the page holding `address + offset`, counted from `address`
+    fn page_offset(&self, address: u32, offset: u32) -> Result<u32, Error> {
+        let page = self.mem_layout.address(address + offset)?;
+        Ok(page.address.saturating_sub(address))
+    }
+
+    /// Continue an interrupted download `offset` bytes into `file`.
+    /// The offset is rounded down to the start of its page since that page is erased again.
+    pub async fn download_raw_resume<R: Read>(
+        &mut self,
+        file: &mut R,
+        address: u32,
+        length: u32,
+        offset: u32,
+    ) -> Result<(), Error> {
+        if offset >= length {
+            log::info!("Nothing left to write");
+            return Ok(());
+        }
+        let offset = self.page_offset(address, offset)?;
+        log::info!("Resume at 0x{:08X} skipping {} bytes", address + offset, offset);
+        std::io::copy(&mut file.take(offset as u64), &mut std::io::sink())?;
+        self.download_raw(file, address + offset, length - offset).await
+    }
+
+    /// Offset of the first page whose content differs from `file`, `length` when everything matches
+    pub async fn resume_offset<R: Read>(
+        &mut self,
+        file: &mut R,
+        address: u32,
+        length: u32,
+    ) -> Result<u32, Error> {
+        match self.verify(file, address, length).await {
+            Ok(()) => Ok(length),
+            Err(Error::Verify(a)) => {
+                self.abort_to_idle().await?;
+                self.page_offset(address, a - address)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Low level: a single DNLOAD of `buf` as block `transaction`, 0 being a DfuSe command and
+    /// an empty `buf` the end of the download. A stall is cleared and the block sent again up to
+    /// `stall_retries` times, backing off between attempts, but nothing else is done: the caller
+    /// sets the address, polls GET_STATUS until the block is taken and keeps the device in a
+    /// state the high level API expects, e.g. with [`Self::abort_to_idle`].
+    /// Meant for vendor extensions of a bootloader, prefer [`Self::download_raw`].
+    #[tracing::instrument(
+        level = "trace",
+        skip_all,
+        fields(
+            block = transaction,
+            length = buf.len(),
+        ),
+    )]
+    pub async fn dfuse_download(&mut self, buf: &[u8], transaction: u16) -> Result<(), Error> {
+        // Any DNLOAD leaves the address UPLOAD reads from behind
+        self.upload_base = None;
+        self.phase = match transaction {
+            0 => DfuseCommand::parse(buf).map_or(Phase::Vendor, Phase::Command),
+            _ if buf.is_empty() => Phase::Manifest,
+            block => Phase::Write(block),
+        };
+        let mut attempt = 0;
+        loop {
+            self.stats.control_transfers += 1;
+            let res = self.transport.control_out(DFU_DNLOAD, transaction, buf, self.timeout).await;
+            match res {
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => {
+                    self.stats.stalls += 1;
+                    if attempt >= self.retry_policy.stall_retries {
+                        return Err(Error::Stalled(format!("Dfuse download block {}", transaction), attempt + 1));
+                    }
+                    log::warn!("stalled on transaction {}, retry {}", transaction, attempt + 1);
+                    self.transport.sleep(self.retry_policy.backoff(attempt as u32)).await;
+                    self.clear_stall().await?;
+                    self.stats.retries += 1;
+                    attempt += 1;
+                }
+                Err(e) => return Err(Error::USB("Dfuse download".into(), e)),
+                Ok(_) => {
+                    if transaction >= 2 {
+                        self.stats.bytes_written += buf.len() as u64;
+                        self.block_done(buf.len());
                     }
-                    _ => Err(Error::USB("Dfuse download".into(), e.into())),
+                    return Ok(());
                 }
             }
-            Ok(_) => Ok(()),
         }
     }
 
+    /// Open the device again after it dropped off the bus, found by its port path or serial
+    /// number, and bring it to dfuIDLE. Layout, policies and [`Self::progress`] are kept, so an
+    /// interrupted download can go on with [`Self::download_raw_resume`] from there.
+    pub async fn reconnect(&mut self) -> Result<(), Error> {
+        log::warn!("Device went away, reconnecting");
+        self.transport
+            .reopen()
+            .await
+            .map_err(|e| Error::USB("Reconnect".into(), e))?;
+        self.detached = false;
+        self.upload_base = None;
+        self.abort_to_idle_clear_once().await
+    }
+
+    /// Get the device back to dfuIDLE after a stalled request, a stall leaves it in dfuERROR
+    /// which only CLRSTATUS leaves
+    async fn clear_stall(&mut self) -> Result<(), Error> {
+        let s = self.get_status(0).await?;
+        if s.state == u8::from(&State::DfuError) {
+            self.clear_status().await?;
+            self.status_wait_for(0, Some(State::DfuIdle)).await?;
+            return Ok(());
+        }
+        self.abort_to_idle().await
+    }
+
+
+    pub fn dfu_descriptor(&self) -> &DfuDescriptor {
+        &self.dfu_descriptor
+    }
+
+    /// Set when the device had no usable functional descriptor and a stand-in is used, see
+    /// [`Self::set_transfer_size`] to pick the transfer size for such a device
+    pub fn descriptor_warning(&self) -> Option<&DescriptorWarning> {
+        self.descriptor_warning.as_ref()
+    }
+
+    /// The device left DFU mode on its own, dropping `self` must not talk to it
+    pub(crate) fn set_detached(&mut self) {
+        self.detached = true;
+    }
+
+    pub(crate) fn set_descriptor_warning(&mut self, warning: Option<DescriptorWarning>) {
+        self.descriptor_warning = warning;
+    }
 
     pub fn memory_layout(&self) -> &MemoryLayout {
         &self.mem_layout
     }
 
-    async fn dfuse_upload(&mut self, transaction: u16, xfer: u16) -> Result<Vec<u8>, Error> {
-        let res = self.interface.control_in(ControlIn {
-            control_type: ControlType::Class,
-            recipient: Recipient::Interface,
-            request: DFU_UPLOAD,
-            value: transaction,
-            index: self.interface.interface_number() as u16,
-            length: xfer,
-        }).await.into_result();
+    /// Read the pages a write of `length` bytes at `address` erases into
+    /// `<dir>/backup-<unix time>-0x<page address>.bin`
+    pub async fn backup(&mut self, dir: &Path, address: u32, length: u32) -> Result<Backup, Error> {
+        let (start, len) = self.mem_layout.page_range(address, length)?;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("backup-{}-0x{:08X}.bin", time, start));
+        log::info!("Backup 0x{:08X} {} bytes to {:?}", start, len, path);
+        self.upload(&mut std::fs::File::create(&path)?, start, len).await?;
+        let backup = Backup {
+            path,
+            address: start,
+            length: len,
+        };
+        self.last_backup = Some(backup.clone());
+        Ok(backup)
+    }
+
+    /// Write a backup back and verify it, used to roll back a failed download
+    pub async fn restore(&mut self, backup: &Backup) -> Result<(), Error> {
+        let data = std::fs::read(&backup.path)?;
+        if data.len() != backup.length as usize {
+            return Err(Error::Argument(format!(
+                "backup {:?} is {} bytes, expected {}",
+                backup.path,
+                data.len(),
+                backup.length
+            )));
+        }
+        self.abort_to_idle_clear_once().await?;
+        let dir = self.backup_dir.take();
+        let mut res = self
+            .download_raw(&mut Cursor::new(&data), backup.address, backup.length)
+            .await;
+        if res.is_ok() {
+            res = self
+                .verify(&mut Cursor::new(&data), backup.address, backup.length)
+                .await;
+        }
+        self.backup_dir = dir;
+        res
+    }
+
+    /// Back up the region about to be erased before every download
+    pub fn set_backup_dir(&mut self, dir: Option<PathBuf>) {
+        self.backup_dir = dir;
+    }
+
+    /// Ranges of the memory erasing and writing refuse to touch, e.g. the bootloader itself
+    pub fn set_protected(&mut self, ranges: Vec<Range<u32>>) {
+        self.protected = ranges;
+    }
+
+    pub fn protected(&self) -> &[Range<u32>] {
+        &self.protected
+    }
+
+    /// Erase and write protected ranges anyway
+    pub fn allow_protected(&mut self, allow: bool) {
+        self.allow_protected = allow;
+    }
+
+    /// Mirrors of the memory, addresses in them are remapped before anything else
+    pub fn set_aliases(&mut self, aliases: Vec<Alias>) {
+        self.aliases = aliases;
+    }
+
+    pub fn aliases(&self) -> &[Alias] {
+        &self.aliases
+    }
+
+    /// `address` in the memory layout, following the first alias containing it
+    pub fn canonical_address(&self, address: u32) -> u32 {
+        self.aliases
+            .iter()
+            .find_map(|a| a.resolve(address))
+            .unwrap_or(address)
+    }
+
+    /// Fail with [`Error::Protected`] if `length` bytes at `address` overlap a protected range
+    fn check_protected(&self, address: u32, length: u32) -> Result<(), Error> {
+        if self.allow_protected {
+            return Ok(());
+        }
+        let end = address as u64 + length as u64;
+        match self
+            .protected
+            .iter()
+            .find(|r| (r.start as u64) < end && address < r.end)
+        {
+            Some(r) => Err(Error::Protected(address.max(r.start))),
+            None => Ok(()),
+        }
+    }
+
+    /// The most recent backup
+    pub fn last_backup(&self) -> Option<&Backup> {
+        self.last_backup.as_ref()
+    }
+
+    /// Bytes transferred by the current or last download, upload or verify
+    pub fn progress(&self) -> u32 {
+        self.progress
+    }
+
+    /// Call `on_progress` after every erased page and every block written, read or verified
+    pub fn set_on_progress(&mut self, on_progress: impl FnMut(&Progress) + Send + Sync + 'static) {
+        self.on_progress = Some(Box::new(on_progress));
+    }
+
+    fn start_progress(&mut self, activity: Activity, total: u32) {
+        self.progress = 0;
+        self.progress_of = (activity, total);
+    }
+
+    fn advance(&mut self, len: u32, address: u32) {
+        self.progress += len;
+        let (activity, total) = self.progress_of;
+        self.report(&Progress {
+            activity,
+            done: self.progress,
+            total,
+            address,
+        });
+    }
+
+    fn report(&mut self, progress: &Progress) {
+        if let Some(on_progress) = &mut self.on_progress {
+            on_progress(progress);
+        }
+    }
+
+    /// Transfers since the device was opened or [`Self::reset_stats`]
+    pub fn stats(&self) -> &TransferStats {
+        &self.stats
+    }
+
+    /// Start counting afresh, e.g. at the beginning of an operation
+    pub fn reset_stats(&mut self) {
+        self.stats = TransferStats::default();
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.last_block = None;
+        }
+    }
+
+    /// Raise the peak rate to that of a data block of `len` bytes done now, timed from the
+    /// previous one so the wait for the device in between counts
+    fn block_done(&mut self, len: usize) {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let now = std::time::Instant::now();
+            if let Some(last) = self.last_block.replace(now) {
+                let secs = (now - last).as_secs_f64();
+                if secs > 0.0 {
+                    self.stats.peak_rate = self.stats.peak_rate.max(len as f64 / 1024.0 / secs);
+                }
+            }
+        }
+        #[cfg(target_arch = "wasm32")]
+        let _ = len;
+    }
+
+    /// Remember the strings and release number of the device descriptor
+    pub(crate) fn set_identity(
+        &mut self,
+        manufacturer: Option<String>,
+        product: Option<String>,
+        serial_number: Option<String>,
+        bcd_device: Option<u16>,
+    ) {
+        self.manufacturer = manufacturer;
+        self.product = product;
+        self.serial_number = serial_number;
+        self.bcd_device = bcd_device;
+    }
+
+    /// USB manufacturer string of the opened device
+    pub fn manufacturer_string(&self) -> Option<&str> {
+        self.manufacturer.as_deref()
+    }
+
+    /// USB product string of the opened device
+    pub fn product_string(&self) -> Option<&str> {
+        self.product.as_deref()
+    }
+
+    /// USB serial number of the opened device
+    pub fn serial_number(&self) -> Option<&str> {
+        self.serial_number.as_deref()
+    }
+
+    /// Device release number in binary coded decimal, `None` over a transport without descriptors
+    pub fn bcd_device(&self) -> Option<u16> {
+        self.bcd_device
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Set timeout of each control transfer
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// bInterfaceNumber of the claimed DFU interface
+    pub fn interface_number(&self) -> u8 {
+        self.transport.interface_number()
+    }
+
+    /// bAlternateSetting selected on the DFU interface, 0 unless the constructor was told
+    pub fn alt_setting(&self) -> u8 {
+        self.alt_setting
+    }
+
+    pub(crate) fn set_alt_setting(&mut self, alt: u8) {
+        self.alt_setting = alt;
+    }
+
+    /// Bytes sent or requested per DNLOAD and UPLOAD block
+    pub fn transfer_size(&self) -> u16 {
+        self.transfer_size
+    }
+
+    /// Override the transfer size advertised by the DFU functional descriptor
+    pub fn set_transfer_size(&mut self, transfer_size: u16) -> Result<(), Error> {
+        if transfer_size == 0 || u32::from(transfer_size) > u32::from(MAX_TRANSFER_SIZE) {
+            return Err(Error::Argument(format!(
+                "transfer size {} must be between 1 and {} bytes",
+                transfer_size, MAX_TRANSFER_SIZE
+            )));
+        }
+        if transfer_size > self.dfu_descriptor.transfer_size && self.descriptor_warning.is_none() {
+            log::warn!(
+                "Transfer size {} exceeds {} bytes advertised by the device",
+                transfer_size,
+                self.dfu_descriptor.transfer_size
+            );
+        }
+        self.transfer_size = transfer_size;
+        Ok(())
+    }
+
+    /// UPLOAD block `transaction` into `buf`, returning how many bytes the device sent
+    #[tracing::instrument(
+        level = "trace",
+        skip_all,
+        fields(
+            block = transaction,
+            length = buf.len(),
+        ),
+    )]
+    pub(crate) async fn dfuse_upload_into(&mut self, transaction: u16, buf: &mut [u8]) -> Result<usize, Error> {
+        let data = self.dfuse_upload(transaction, buf.len() as u16).await?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    /// Low level: a single UPLOAD of up to `xfer` bytes of block `transaction`, 0 reading the
+    /// DfuSe commands. The caller sets the address and leaves dfuUPLOAD-IDLE again.
+    /// Meant for vendor extensions of a bootloader, prefer [`Self::upload`].
+    #[tracing::instrument(level = "trace", skip_all, fields(block = transaction, length = xfer))]
+    pub async fn dfuse_upload(&mut self, transaction: u16, xfer: u16) -> Result<Vec<u8>, Error> {
+        self.phase = Phase::Read(transaction);
+        self.stats.control_transfers += 1;
+        let res = self.transport.control_in(DFU_UPLOAD, transaction, xfer, self.timeout).await;
 
         match res
         {
-            Err(e) => Err(Error::USB("Dfuse upload".into(), e.into())),
-            Ok(buf) => Ok(buf),
+            Err(e) => Err(Error::USB("Dfuse upload".into(), e)),
+            Ok(buf) => {
+                if transaction >= 2 {
+                    self.stats.bytes_read += buf.len() as u64;
+                    self.block_done(buf.len());
+                }
+                Ok(buf)
+            }
         }
     }
 
-    pub fn usb(&mut self) -> &mut nusb::Device {
-        &mut self.usb
+    /// Send an arbitrary class request to the DFU interface, OUT with `data` or IN reading up
+    /// to `length` bytes otherwise. Meant for poking at vendor extensions of a bootloader.
+    pub async fn raw_request(
+        &mut self,
+        request: u8,
+        value: u16,
+        data: Option<&[u8]>,
+        length: u16,
+    ) -> Result<Vec<u8>, Error> {
+        let what = || format!("Raw request 0x{:02X}", request);
+        self.stats.control_transfers += 1;
+        match data {
+            Some(data) => {
+                self.transport
+                    .control_out(request, value, data, self.timeout)
+                    .await
+                    .map_err(|e| Error::USB(what(), e))?;
+                Ok(Vec::new())
+            }
+            None => self
+                .transport
+                .control_in(request, value, length, self.timeout)
+                .await
+                .map_err(|e| Error::USB(what(), e)),
+        }
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_alt_name() {
+        use crate::core::alt_name;
+        assert_eq!(
+            "Internal Flash",
+            alt_name("@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg")
+        );
+        assert_eq!("Option Bytes", alt_name("@Option Bytes  /0x1FFFC000/01*016 e"));
+        assert_eq!("plain", alt_name("plain"));
+    }
+
+    #[test]
+    fn test_functional_descriptor() {
+        use crate::core::*;
+        let config = [
+            9, 2, 36, 0, 1, 1, 0, 0xC0, 50, // configuration
+            9, 4, 0, 0, 0, 0xFE, 1, 2, 4, // interface
+            9, 33, 0x0B, 0xFF, 0, 0, 8, 0x1A, 1, // DFU functional
+        ];
+        let desc = functional_descriptor(&config).unwrap();
+        assert_eq!(0x0B, desc.attributes);
+        assert_eq!(255, desc.detach_timeout);
+        assert_eq!(2048, desc.transfer_size);
+        assert_eq!(0x011A, desc.dfu_version);
+        assert!(functional_descriptor(&config[..18]).is_none());
+        assert!(functional_descriptor(&[0, 2, 9]).is_none());
+        assert_eq!((2048, None), {
+            let (d, w) = DfuDescriptor::or_fallback(Ok(desc));
+            (d.transfer_size, w)
+        });
+        let (fallback, warning) = DfuDescriptor::or_fallback(parse_functional_descriptor(&config[..18]));
+        assert_eq!((FALLBACK_TRANSFER_SIZE, Some(DescriptorWarning::Missing)), (fallback.transfer_size, warning));
+
+        // DFU 1.0 without bcdDFUVersion, and one padded past 9 bytes
+        let desc = DfuDescriptor::from_bytes(&[7, 33, 0x03, 0xFF, 0, 0, 4]).unwrap();
+        assert_eq!((1024, 0x0100), (desc.transfer_size, desc.dfu_version));
+        let desc = DfuDescriptor::from_bytes(&[10, 33, 0x0B, 0xFF, 0, 0, 8, 0x1A, 1, 0]).unwrap();
+        assert_eq!((2048, 0x011A), (desc.transfer_size, desc.dfu_version));
+        let mut short = config;
+        short[18] = 5;
+        assert_eq!(Err(DescriptorWarning::Malformed(5)), parse_functional_descriptor(&short));
+    }
+
+    #[test]
+    fn test_ioreg_clients() {
+        use crate::core::ioreg_clients;
+        let ioreg = [
+            "+-o Root  <class IORegistryEntry>",
+            "+-o STM32  BOOTLOADER@14200000  <class IOUSBHostDevice>",
+            "  | {",
+            "  |   \"locationID\" = 337641472",
+            "  | }",
+            "  +-o AppleUSBHostDeviceUserClient  <class AppleUSBHostDeviceUserClient>",
+            "  |   {",
+            "  |     \"IOUserClientCreator\" = \"pid 4321, VirtualBoxVM\"",
+            "  |   }",
+            "+-o Hub@14100000  <class IOUSBHostDevice>",
+            "  | {",
+            "  |   \"locationID\" = 336592896",
+            "  | }",
+        ]
+        .join("\n");
+        let ioreg = ioreg.as_str();
+        assert_eq!(vec!["pid 4321, VirtualBoxVM".to_string()], ioreg_clients(ioreg, 337641472));
+        assert!(ioreg_clients(ioreg, 336592896).is_empty());
+        assert!(ioreg_clients(ioreg, 1).is_empty());
     }
 }
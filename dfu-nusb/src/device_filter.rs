@@ -0,0 +1,275 @@
+use crate::error::Error;
+use std::fmt;
+
+/// Select a USB device by any combination of its properties.
+/// Fields left as `None` match every device.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceFilter {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub bus: Option<u8>,
+    pub address: Option<u8>,
+    pub serial: Option<String>,
+    /// Physical port path as given by [`port_path`], stays the same across replugs
+    pub port: Option<String>,
+    /// Operating system identifier as given by [`platform_id`]
+    pub platform_id: Option<String>,
+}
+
+impl DeviceFilter {
+    pub fn vid_pid(vendor_id: u16, product_id: u16) -> Self {
+        DeviceFilter {
+            vendor_id: Some(vendor_id),
+            product_id: Some(product_id),
+            ..Default::default()
+        }
+    }
+
+    pub fn bus_device(bus: u8, address: u8) -> Self {
+        DeviceFilter {
+            bus: Some(bus),
+            address: Some(address),
+            ..Default::default()
+        }
+    }
+
+    pub fn port(port: &str) -> Self {
+        DeviceFilter {
+            port: Some(port.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn platform_id(id: &str) -> Self {
+        DeviceFilter {
+            platform_id: Some(id.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn matches(&self, dev: &nusb::DeviceInfo) -> bool {
+        self.vendor_id.is_none_or(|v| v == dev.vendor_id())
+            && self.product_id.is_none_or(|p| p == dev.product_id())
+            && self.bus.is_none_or(|b| b == dev.bus_number())
+            && self.address.is_none_or(|a| a == dev.device_address())
+            && self
+                .serial
+                .as_ref()
+                .is_none_or(|s| Some(s.as_str()) == dev.serial_number())
+            && self
+                .port
+                .as_ref()
+                .is_none_or(|p| Some(p) == port_path(dev).as_ref())
+            && self
+                .platform_id
+                .as_ref()
+                .is_none_or(|id| same_platform_id(dev, id))
+    }
+}
+
+/// The sysfs directory on Linux, e.g. `/sys/bus/usb/devices/1-4.2`, the device instance ID
+/// on Windows and the IORegistry entry ID on macOS
+pub fn platform_id(dev: &nusb::DeviceInfo) -> String {
+    #[cfg(target_os = "linux")]
+    {
+        dev.sysfs_path().display().to_string()
+    }
+    #[cfg(target_os = "windows")]
+    {
+        dev.instance_id().to_string_lossy().into_owned()
+    }
+    #[cfg(target_os = "macos")]
+    {
+        format!("0x{:x}", dev.registry_entry_id())
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+    {
+        format!("{}-{}", dev.bus_number(), dev.device_address())
+    }
+}
+
+/// Compare `id` to the device, on Linux after resolving symlinks so the
+/// `/sys/devices/...` path works as well
+fn same_platform_id(dev: &nusb::DeviceInfo, id: &str) -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        let canonical = |p: &std::path::Path| std::fs::canonicalize(p).unwrap_or_else(|_| p.into());
+        canonical(dev.sysfs_path()) == canonical(std::path::Path::new(id))
+    }
+    #[cfg(target_os = "windows")]
+    {
+        platform_id(dev).eq_ignore_ascii_case(id)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    {
+        platform_id(dev) == id
+    }
+}
+
+/// Bus and chain of hub ports leading to the device in the Linux sysfs notation `<bus>-<port>[.<port>]*`,
+/// e.g. `1-4.2`. `None` where the platform does not tell.
+pub fn port_path(dev: &nusb::DeviceInfo) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    {
+        dev.sysfs_path()
+            .file_name()
+            .and_then(|n| n.to_str())
+            .filter(|n| n.contains('-'))
+            .map(String::from)
+    }
+    #[cfg(target_os = "macos")]
+    {
+        Some(location_port_path(dev.location_id()))
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    {
+        let _ = dev;
+        None
+    }
+}
+
+/// macOS location IDs hold the bus in the top byte followed by one nibble per hub port
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn location_port_path(location_id: u32) -> String {
+    let ports: Vec<String> = (0..6)
+        .map(|i| (location_id >> (20 - 4 * i)) & 0xF)
+        .take_while(|p| *p != 0)
+        .map(|p| p.to_string())
+        .collect();
+    format!("{}-{}", location_id >> 24, ports.join("."))
+}
+
+/// USB interface class and subclass of DFU, "Application Specific" / "Device Firmware Upgrade"
+pub(crate) const DFU_CLASS: u8 = 0xFE;
+pub(crate) const DFU_SUBCLASS: u8 = 0x01;
+/// Interface protocols of the application and of the bootloader
+const RUNTIME_PROTOCOL: u8 = 0x01;
+const DFU_MODE_PROTOCOL: u8 = 0x02;
+
+/// Which side of DFU a DFU interface belongs to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DfuMode {
+    /// The application offers DFU_DETACH to enter the bootloader
+    Runtime,
+    /// The bootloader itself
+    Dfu,
+}
+
+impl DfuMode {
+    /// Mode of an interface, `None` for anything but DFU
+    pub fn from_interface(class: u8, subclass: u8, protocol: u8) -> Option<Self> {
+        match (class, subclass, protocol) {
+            (DFU_CLASS, DFU_SUBCLASS, RUNTIME_PROTOCOL) => Some(DfuMode::Runtime),
+            (DFU_CLASS, DFU_SUBCLASS, DFU_MODE_PROTOCOL) => Some(DfuMode::Dfu),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DfuMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DfuMode::Runtime => write!(f, "runtime"),
+            DfuMode::Dfu => write!(f, "dfu"),
+        }
+    }
+}
+
+/// Mode of the first DFU interface of the device
+pub fn dfu_mode(dev: &nusb::DeviceInfo) -> Option<DfuMode> {
+    dev.interfaces()
+        .find_map(|i| DfuMode::from_interface(i.class(), i.subclass(), i.protocol()))
+}
+
+/// Whether the device exposes a DFU interface, in runtime or DFU mode
+pub fn is_dfu(dev: &nusb::DeviceInfo) -> bool {
+    dev.interfaces()
+        .any(|i| i.class() == DFU_CLASS && i.subclass() == DFU_SUBCLASS)
+}
+
+/// Whether the device runs its DFU bootloader rather than exposing a runtime DFU interface
+pub fn is_dfu_mode(dev: &nusb::DeviceInfo) -> bool {
+    dev.interfaces()
+        .any(|i| DfuMode::from_interface(i.class(), i.subclass(), i.protocol()) == Some(DfuMode::Dfu))
+}
+
+/// All connected devices with a DFU interface matching `filter`, `DeviceFilter::default()` lists all of them
+pub fn list_dfu_devices(filter: &DeviceFilter) -> Result<Vec<nusb::DeviceInfo>, Error> {
+    Ok(nusb::list_devices()
+        .map_err(|e| Error::USB("list devices".into(), e))?
+        .filter(|d| is_dfu(d) && filter.matches(d))
+        .collect())
+}
+
+impl fmt::Display for DeviceFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sep = "";
+        if self.vendor_id.is_some() || self.product_id.is_some() {
+            write!(
+                f,
+                "{}:{}",
+                self.vendor_id.map_or("*".into(), |v| format!("{:04x}", v)),
+                self.product_id.map_or("*".into(), |p| format!("{:04x}", p))
+            )?;
+            sep = " ";
+        }
+        if self.bus.is_some() || self.address.is_some() {
+            write!(
+                f,
+                "{}bus {} device {}",
+                sep,
+                self.bus.map_or("*".into(), |b| b.to_string()),
+                self.address.map_or("*".into(), |a| a.to_string())
+            )?;
+            sep = " ";
+        }
+        if let Some(serial) = &self.serial {
+            write!(f, "{}serial {}", sep, serial)?;
+            sep = " ";
+        }
+        if let Some(port) = &self.port {
+            write!(f, "{}port {}", sep, port)?;
+            sep = " ";
+        }
+        if let Some(id) = &self.platform_id {
+            write!(f, "{}{}", sep, id)?;
+            sep = " ";
+        }
+        if sep.is_empty() {
+            write!(f, "any device")?;
+        }
+        Ok(())
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_device_filter_display() {
+        use crate::DeviceFilter;
+        assert_eq!("0483:df11", DeviceFilter::vid_pid(0x0483, 0xdf11).to_string());
+        assert_eq!("bus 1 device 7", DeviceFilter::bus_device(1, 7).to_string());
+        assert_eq!("any device", DeviceFilter::default().to_string());
+        let f = DeviceFilter {
+            serial: Some("3574364C3034".into()),
+            ..DeviceFilter::vid_pid(0x0483, 0xdf11)
+        };
+        assert_eq!("0483:df11 serial 3574364C3034", f.to_string());
+        assert_eq!("port 1-4.2", DeviceFilter::port("1-4.2").to_string());
+    }
+
+    #[test]
+    fn test_dfu_mode() {
+        use crate::device_filter::*;
+        assert_eq!(Some(DfuMode::Runtime), DfuMode::from_interface(0xFE, 0x01, 0x01));
+        assert_eq!(Some(DfuMode::Dfu), DfuMode::from_interface(0xFE, 0x01, 0x02));
+        assert_eq!(None, DfuMode::from_interface(0xFE, 0x02, 0x01));
+        assert_eq!(None, DfuMode::from_interface(0x08, 0x06, 0x50));
+    }
+
+    #[test]
+    fn test_location_port_path() {
+        use crate::device_filter::location_port_path;
+        assert_eq!("20-4.2", location_port_path(0x1442_0000));
+        assert_eq!("1-3", location_port_path(0x0130_0000));
+    }
+}
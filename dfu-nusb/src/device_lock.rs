@@ -0,0 +1,64 @@
+use crate::error::Error;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// Advisory lock on one USB device held for as long as it is open,
+/// so two processes never interleave control transfers on the same device.
+#[derive(Debug)]
+pub struct DeviceLock {
+    _file: File,
+}
+
+impl DeviceLock {
+    /// `<tmp>/dfu-nusb-<bus>-<address>.lock`
+    pub fn path(bus: u8, address: u8) -> PathBuf {
+        std::env::temp_dir().join(format!("dfu-nusb-{}-{}.lock", bus, address))
+    }
+
+    /// Take the lock or fail right away naming the process holding it
+    pub fn acquire(bus: u8, address: u8) -> Result<Self, Error> {
+        let path = DeviceLock::path(bus, address);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                return Err(Error::Busy(format!(
+                    "bus {} device {} by PID {}",
+                    bus,
+                    address,
+                    pid.trim()
+                )));
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        log::debug!("Locked {:?}", path);
+        Ok(DeviceLock { _file: file })
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_device_lock() {
+        use crate::device_lock::*;
+        let lock = DeviceLock::acquire(255, 254).unwrap();
+        let pid = std::fs::read_to_string(DeviceLock::path(255, 254)).unwrap();
+        assert_eq!(std::process::id().to_string(), pid);
+        match DeviceLock::acquire(255, 254) {
+            Err(Error::Busy(s)) => assert!(s.ends_with(&pid)),
+            _ => panic!("second lock must fail"),
+        }
+        drop(lock);
+        assert!(DeviceLock::acquire(255, 254).is_ok());
+    }
+}
@@ -1,11 +1,13 @@
 use std::convert::TryFrom;
 use std::fmt;
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DfuseCommand {
     SetAddress(u32),
     ErasePage(u32),
     MassErase,
     ReadUnprotected,
+    /// A Get Commands byte not known here, such as a vendor extension
+    Unknown(u8),
 }
 
 impl TryFrom<u8> for DfuseCommand {
@@ -21,6 +23,27 @@ impl TryFrom<u8> for DfuseCommand {
     }
 }
 
+impl DfuseCommand {
+    /// Command of a Get Commands byte, [`Unknown`](DfuseCommand::Unknown) where
+    /// [`try_from`](DfuseCommand::try_from) fails
+    pub fn from_byte(cmd: u8) -> Self {
+        DfuseCommand::try_from(cmd).unwrap_or(DfuseCommand::Unknown(cmd))
+    }
+
+    /// Command of a block 0 DNLOAD, None for a command byte or length not known here
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        use crate::DfuseCommand::*;
+        let address = |b: &[u8]| u32::from_le_bytes([b[1], b[2], b[3], b[4]]);
+        match buf {
+            [0x21, _, _, _, _] => Some(SetAddress(address(buf))),
+            [0x41, _, _, _, _] => Some(ErasePage(address(buf))),
+            [0x41] => Some(MassErase),
+            [0x92] => Some(ReadUnprotected),
+            _ => None,
+        }
+    }
+}
+
 impl From<DfuseCommand> for Vec<u8> {
     fn from(command: DfuseCommand) -> Vec<u8> {
         use crate::DfuseCommand::*;
@@ -42,6 +65,10 @@ impl From<DfuseCommand> for Vec<u8> {
                 buf.push(0x92);
                 None
             }
+            Unknown(cmd) => {
+                buf.push(cmd);
+                None
+            }
         };
 
         if let Some(address) = address {
@@ -61,6 +88,7 @@ impl fmt::Display for DfuseCommand {
             SetAddress(_) => write!(f, "Set address"),
             ErasePage(_) | MassErase => write!(f, "Page/Mass erase"),
             ReadUnprotected => write!(f, "Read unprotected"),
+            Unknown(cmd) => write!(f, "Unknown 0x{:02X}", cmd),
         }
     }
 }
@@ -84,5 +112,12 @@ mod tests {
         let vec = Vec::from(DfuseCommand::ErasePage(0x0801_0200));
         assert_eq!(5, vec.len());
         assert_eq!(&vec![0x41, 0x00, 0x02, 0x01, 0x08], &vec);
+        assert_eq!(Some(DfuseCommand::ErasePage(0x0801_0200)), DfuseCommand::parse(&vec));
+        assert_eq!(Some(DfuseCommand::MassErase), DfuseCommand::parse(&[0x41]));
+        assert_eq!(None, DfuseCommand::parse(&[0x21, 0x00]));
+        assert_eq!(DfuseCommand::MassErase, DfuseCommand::from_byte(0x41));
+        assert_eq!(DfuseCommand::Unknown(0xA5), DfuseCommand::from_byte(0xA5));
+        assert_eq!(vec![0xA5], Vec::from(DfuseCommand::Unknown(0xA5)));
+        assert_eq!("Unknown 0xA5", DfuseCommand::Unknown(0xA5).to_string());
     }
 }
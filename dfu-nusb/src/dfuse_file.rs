@@ -0,0 +1,276 @@
+use crate::error::Error;
+use std::fmt;
+
+const PREFIX_LEN: usize = 11;
+const TARGET_PREFIX_LEN: usize = 274;
+const SUFFIX_LEN: usize = 16;
+
+/// One contiguous block of data to be written at `address`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+/// Images for one alt setting
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub alt: u8,
+    pub name: Option<String>,
+    pub elements: Vec<Element>,
+}
+
+/// A DfuSe (`.dfu`) container as described by ST UM0391
+#[derive(Debug, Clone, PartialEq)]
+pub struct DfuseFile {
+    pub device_version: u16,
+    pub product_id: u16,
+    pub vendor_id: u16,
+    pub dfu_version: u16,
+    pub targets: Vec<Target>,
+}
+
+/// CRC used by the DFU suffix, CRC-32 without the final inversion
+pub fn dfu_crc(buf: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for b in buf {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize, what: &str) -> Result<&'a [u8], Error> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or_else(|| Error::DfuseFile(format!("truncated {} at offset {}", what, self.pos)))?;
+        let s = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(s)
+    }
+
+    fn u8(&mut self, what: &str) -> Result<u8, Error> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    fn u32(&mut self, what: &str) -> Result<u32, Error> {
+        let b = self.take(4, what)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+impl DfuseFile {
+    pub fn parse(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < PREFIX_LEN + SUFFIX_LEN {
+            return Err(Error::DfuseFile("file too short".into()));
+        }
+        let (body, suffix) = buf.split_at(buf.len() - SUFFIX_LEN);
+        if &suffix[8..11] != b"UFD" || suffix[11] as usize != SUFFIX_LEN {
+            return Err(Error::DfuseFile("missing DFU suffix".into()));
+        }
+        let crc = u32::from_le_bytes([suffix[12], suffix[13], suffix[14], suffix[15]]);
+        let expected = dfu_crc(&buf[..buf.len() - 4]);
+        // The fuzz targets would hardly ever get past a matching CRC
+        if crc != expected && !cfg!(feature = "fuzzing") {
+            return Err(Error::DfuseFile(format!(
+                "CRC 0x{:08X} does not match 0x{:08X}",
+                crc, expected
+            )));
+        }
+
+        let mut r = Reader { buf: body, pos: 0 };
+        if r.take(5, "signature")? != b"DfuSe" {
+            return Err(Error::DfuseFile("missing DfuSe signature".into()));
+        }
+        let version = r.u8("version")?;
+        if version != 1 {
+            return Err(Error::DfuseFile(format!("unsupported version {}", version)));
+        }
+        let image_size = r.u32("image size")? as usize;
+        if image_size != body.len() {
+            return Err(Error::DfuseFile(format!(
+                "image size {} does not match file size {}",
+                image_size,
+                body.len()
+            )));
+        }
+        let num_targets = r.u8("target count")?;
+        let mut targets = Vec::new();
+        for _ in 0..num_targets {
+            let start = r.pos;
+            if r.take(6, "target signature")? != b"Target" {
+                return Err(Error::DfuseFile(format!(
+                    "missing Target signature at offset {}",
+                    start
+                )));
+            }
+            let alt = r.u8("alt setting")?;
+            let named = r.u32("target named")? != 0;
+            let name = r.take(255, "target name")?;
+            let name = named.then(|| {
+                let end = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+                String::from_utf8_lossy(&name[..end]).into_owned()
+            });
+            let size = r.u32("target size")? as usize;
+            let num_elements = r.u32("element count")?;
+            let data_start = start + TARGET_PREFIX_LEN;
+            let mut elements = Vec::new();
+            for _ in 0..num_elements {
+                let address = r.u32("element address")?;
+                let len = r.u32("element size")? as usize;
+                let data = r.take(len, "element data")?.to_vec();
+                elements.push(Element { address, data });
+            }
+            if r.pos - data_start != size {
+                return Err(Error::DfuseFile(format!(
+                    "target {} size {} does not match its elements",
+                    alt, size
+                )));
+            }
+            targets.push(Target {
+                alt,
+                name,
+                elements,
+            });
+        }
+
+        Ok(DfuseFile {
+            device_version: u16::from_le_bytes([suffix[0], suffix[1]]),
+            product_id: u16::from_le_bytes([suffix[2], suffix[3]]),
+            vendor_id: u16::from_le_bytes([suffix[4], suffix[5]]),
+            dfu_version: u16::from_le_bytes([suffix[6], suffix[7]]),
+            targets,
+        })
+    }
+
+    /// Serialize back into a `.dfu` container including suffix and CRC
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(b"DfuSe");
+        buf.push(1);
+        buf.extend_from_slice(&[0; 4]);
+        buf.push(self.targets.len() as u8);
+        for t in &self.targets {
+            buf.extend_from_slice(b"Target");
+            buf.push(t.alt);
+            buf.extend_from_slice(&(t.name.is_some() as u32).to_le_bytes());
+            let mut name = [0_u8; 255];
+            if let Some(n) = &t.name {
+                let n = n.as_bytes();
+                let len = n.len().min(254);
+                name[..len].copy_from_slice(&n[..len]);
+            }
+            buf.extend_from_slice(&name);
+            let size: usize = t.elements.iter().map(|e| 8 + e.data.len()).sum();
+            buf.extend_from_slice(&(size as u32).to_le_bytes());
+            buf.extend_from_slice(&(t.elements.len() as u32).to_le_bytes());
+            for e in &t.elements {
+                buf.extend_from_slice(&e.address.to_le_bytes());
+                buf.extend_from_slice(&(e.data.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&e.data);
+            }
+        }
+        let image_size = buf.len() as u32;
+        buf[6..10].copy_from_slice(&image_size.to_le_bytes());
+        buf.extend_from_slice(&self.device_version.to_le_bytes());
+        buf.extend_from_slice(&self.product_id.to_le_bytes());
+        buf.extend_from_slice(&self.vendor_id.to_le_bytes());
+        buf.extend_from_slice(&self.dfu_version.to_le_bytes());
+        buf.extend_from_slice(b"UFD");
+        buf.push(SUFFIX_LEN as u8);
+        let crc = dfu_crc(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf
+    }
+}
+
+impl fmt::Display for DfuseFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "VID: 0x{:04X} PID: 0x{:04X} Version: 0x{:04X} DFU: 0x{:04X}",
+            self.vendor_id, self.product_id, self.device_version, self.dfu_version
+        )?;
+        for t in &self.targets {
+            writeln!(
+                f,
+                "Target alt: {} name: '{}' elements: {}",
+                t.alt,
+                t.name.as_deref().unwrap_or(""),
+                t.elements.len()
+            )?;
+            for e in &t.elements {
+                writeln!(f, "  Start: 0x{:08X} Size: {} bytes", e.address, e.data.len())?;
+            }
+        }
+        write!(f, "")
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_dfuse_file_round_trip() {
+        use crate::dfuse_file::*;
+        let file = DfuseFile {
+            device_version: 0x2200,
+            product_id: 0xdf11,
+            vendor_id: 0x0483,
+            dfu_version: 0x011a,
+            targets: vec![Target {
+                alt: 0,
+                name: Some("ST...".into()),
+                elements: vec![
+                    Element {
+                        address: 0x0800_0000,
+                        data: vec![1, 2, 3, 4],
+                    },
+                    Element {
+                        address: 0x0800_4000,
+                        data: vec![5, 6],
+                    },
+                ],
+            }],
+        };
+        let buf = file.to_bytes();
+        assert_eq!(11 + 274 + 12 + 10 + 16, buf.len());
+        assert_eq!(file, DfuseFile::parse(&buf).unwrap());
+    }
+
+    #[test]
+    fn test_dfuse_file_errors() {
+        use crate::dfuse_file::*;
+        assert!(DfuseFile::parse(&[]).is_err());
+        let file = DfuseFile {
+            device_version: 0,
+            product_id: 0xdf11,
+            vendor_id: 0x0483,
+            dfu_version: 0x011a,
+            targets: vec![],
+        };
+        let mut buf = file.to_bytes();
+        assert!(DfuseFile::parse(&buf).is_ok());
+        buf[0] = b'X';
+        assert!(DfuseFile::parse(&buf).is_err());
+    }
+
+    #[test]
+    fn test_dfu_crc() {
+        use crate::dfuse_file::*;
+        // CRC-32 of "123456789" is 0xCBF43926 after the final inversion
+        assert_eq!(!0xCBF4_3926, dfu_crc(b"123456789"));
+    }
+}
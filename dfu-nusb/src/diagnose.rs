@@ -0,0 +1,269 @@
+use crate::core::{alt_name, open_error, AltSetting, Dfu, DEFAULT_TIMEOUT, DFU_ABORT, DFU_CLRSTATUS};
+use crate::device_filter::DeviceFilter;
+use crate::device_lock::DeviceLock;
+use crate::memory_layout::MemoryLayout;
+use crate::status::{status_name, State, Status};
+use crate::transport::{default_language, DfuTransport, NusbTransport};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Ok,
+    /// Was wrong and has been repaired
+    Fixed,
+    Failed,
+}
+
+/// Result of one check run by [`diagnose`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub check: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    fn push(&mut self, check: &'static str, outcome: Outcome, detail: impl Into<String>) {
+        self.findings.push(Finding {
+            check,
+            outcome,
+            detail: detail.into(),
+        });
+    }
+
+    /// The check that stopped the diagnosis
+    pub fn failure(&self) -> Option<&Finding> {
+        self.findings.iter().find(|f| f.outcome == Outcome::Failed)
+    }
+
+    /// No check failed, possibly after fixing some
+    pub fn healthy(&self) -> bool {
+        !self.findings.is_empty() && self.findings.iter().all(|f| f.outcome != Outcome::Failed)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for finding in &self.findings {
+            let tag = match finding.outcome {
+                Outcome::Ok => " ok  ",
+                Outcome::Fixed => "fixed",
+                Outcome::Failed => "FAIL ",
+            };
+            writeln!(f, "[{}] {}: {}", tag, finding.check, finding.detail)?;
+        }
+        Ok(())
+    }
+}
+
+fn describe(s: &Status) -> String {
+    format!(
+        "state {} ({}) status {} ({})",
+        State::from(s.state),
+        s.state,
+        status_name(s.status),
+        s.status
+    )
+}
+
+/// Send the class `request` without data and read the status it left
+async fn request(transport: &NusbTransport, request: u8) -> Result<Status, String> {
+    transport
+        .control_out(request, 0, &[], DEFAULT_TIMEOUT)
+        .await
+        .map_err(|e| e.to_string())?;
+    status(transport).await
+}
+
+async fn status(transport: &NusbTransport) -> Result<Status, String> {
+    Status::get(transport, DEFAULT_TIMEOUT).await.map_err(|e| e.to_string())
+}
+
+/// Step through what `Dfu::open` needs, from finding the device to a dfuIDLE state,
+/// and apply the safe fixes on the way: CLRSTATUS on an error status, ABORT outside
+/// of dfuIDLE and, if `usb_reset`, a USB port reset when the device stops answering.
+/// Stops at the first check that can not be fixed.
+pub async fn diagnose(
+    filter: &DeviceFilter,
+    iface_index: u8,
+    alt: &AltSetting,
+    language: Option<u16>,
+    usb_reset: bool,
+) -> Report {
+    let mut report = Report::default();
+
+    let device = match nusb::list_devices().map(|mut l| l.find(|d| filter.matches(d))) {
+        Ok(Some(d)) => d,
+        Ok(None) => {
+            report.push("find", Outcome::Failed, format!("no device matches {}", filter));
+            return report;
+        }
+        Err(e) => {
+            report.push("find", Outcome::Failed, format!("listing devices failed: {}", e));
+            return report;
+        }
+    };
+    report.push(
+        "find",
+        Outcome::Ok,
+        format!(
+            "{:04x}:{:04x} on bus {} device {}",
+            device.vendor_id(),
+            device.product_id(),
+            device.bus_number(),
+            device.device_address()
+        ),
+    );
+
+    let _lock = match DeviceLock::acquire(device.bus_number(), device.device_address()) {
+        Ok(l) => l,
+        Err(e) => {
+            report.push("lock", Outcome::Failed, e.to_string());
+            return report;
+        }
+    };
+
+    let usb = match device.open() {
+        Ok(u) => u,
+        Err(e) => {
+            report.push("open", Outcome::Failed, open_error(&device, e).to_string());
+            return report;
+        }
+    };
+    let language = language.unwrap_or_else(|| default_language(&usb));
+    report.push("open", Outcome::Ok, format!("device opened, strings in language 0x{:04X}", language));
+
+    let alt = match alt {
+        AltSetting::Number(n) => *n,
+        AltSetting::Name(name) => match Dfu::find_alt(&usb, iface_index, name, language) {
+            Ok(n) => n,
+            Err(e) => {
+                report.push("claim", Outcome::Failed, e.to_string());
+                return report;
+            }
+        },
+    };
+    let interface = match usb.claim_interface(iface_index) {
+        Ok(i) => i,
+        Err(e) => {
+            report.push(
+                "claim",
+                Outcome::Failed,
+                format!("interface {}: {}, is another program or a kernel driver using it?", iface_index, e),
+            );
+            return report;
+        }
+    };
+    if let Err(e) = interface.set_alt_setting(alt) {
+        report.push("claim", Outcome::Failed, format!("alt setting {}: {}", alt, e));
+        return report;
+    }
+    report.push("claim", Outcome::Ok, format!("interface {} alt {}", iface_index, alt));
+    let transport = NusbTransport::new(usb, interface, Some(language));
+    let usb = transport.device();
+
+    let alt_string = usb.active_configuration().ok().and_then(|conf| {
+        conf.interface_alt_settings()
+            .find(|s| s.interface_number() == iface_index && s.alternate_setting() == alt)
+            .and_then(|s| s.string_index())
+            .and_then(|i| usb.get_string_descriptor(i, language, Duration::from_secs(1)).ok())
+    });
+    match alt_string.as_deref().map(|s| (s, MemoryLayout::from_str(s))) {
+        Some((s, Ok(layout))) => report.push(
+            "layout",
+            Outcome::Ok,
+            format!("{}, {} pages", alt_name(s), layout.pages().len()),
+        ),
+        Some((s, Err(e))) => report.push("layout", Outcome::Failed, format!("'{}': {}", s, e)),
+        None => report.push("layout", Outcome::Failed, "alt setting has no string descriptor"),
+    }
+
+    let mut s = match status(&transport).await {
+        Ok(s) => s,
+        Err(e) if usb_reset => {
+            match usb.reset() {
+                Ok(()) => report.push(
+                    "status",
+                    Outcome::Fixed,
+                    format!("{}, reset the device, run again once it is back", e),
+                ),
+                Err(r) => report.push("status", Outcome::Failed, format!("{}, USB reset failed: {}", e, r)),
+            }
+            return report;
+        }
+        Err(e) => {
+            report.push("status", Outcome::Failed, format!("{}, a USB reset may bring it back", e));
+            return report;
+        }
+    };
+    let mut detail = describe(&s);
+    if s.string_index != 0 {
+        if let Ok(text) = transport.string_descriptor(s.string_index, Duration::from_secs(1)).await {
+            detail.push_str(&format!(" '{}'", text));
+        }
+    }
+    if s.state == u8::from(&State::AppIdle) || s.state == u8::from(&State::AppDetach) {
+        report.push("status", Outcome::Failed, format!("{}, the application is running, detach first", detail));
+        return report;
+    }
+    report.push("status", Outcome::Ok, detail);
+
+    if s.status != 0 || s.state == u8::from(&State::DfuError) {
+        let before = describe(&s);
+        match request(&transport, DFU_CLRSTATUS).await {
+            Ok(after) if after.status == 0 => {
+                report.push("clear", Outcome::Fixed, format!("CLRSTATUS: {} -> {}", before, describe(&after)));
+                s = after;
+            }
+            Ok(after) => {
+                report.push("clear", Outcome::Failed, format!("CLRSTATUS left {}", describe(&after)));
+                return report;
+            }
+            Err(e) => {
+                report.push("clear", Outcome::Failed, format!("CLRSTATUS failed: {}", e));
+                return report;
+            }
+        }
+    }
+
+    if s.state != u8::from(&State::DfuIdle) {
+        let before = describe(&s);
+        match request(&transport, DFU_ABORT).await {
+            Ok(after) if after.state == u8::from(&State::DfuIdle) => {
+                report.push("abort", Outcome::Fixed, format!("ABORT: {} -> {}", before, describe(&after)));
+            }
+            Ok(after) => {
+                report.push("abort", Outcome::Failed, format!("ABORT left {}", describe(&after)));
+                return report;
+            }
+            Err(e) => {
+                report.push("abort", Outcome::Failed, format!("ABORT failed: {}", e));
+                return report;
+            }
+        }
+    }
+    report
+}
+
+mod tests {
+    #[test]
+    fn test_report() {
+        use crate::diagnose::*;
+        let mut report = Report::default();
+        assert!(!report.healthy());
+        report.push("open", Outcome::Ok, "device opened");
+        report.push("abort", Outcome::Fixed, "ABORT");
+        assert!(report.healthy());
+        assert_eq!("[ ok  ] open: device opened\n[fixed] abort: ABORT\n", report.to_string());
+        report.push("layout", Outcome::Failed, "bad");
+        assert!(!report.healthy());
+        assert_eq!("layout", report.failure().unwrap().check);
+    }
+}
@@ -0,0 +1,861 @@
+use crate::core::{
+    Dfu, DfuDescriptor, DFU_ABORT, DFU_CLRSTATUS, DFU_DNLOAD, DFU_GETSTATE, DFU_GET_STATUS, DFU_UPLOAD,
+};
+use crate::error::Error;
+use crate::memory_layout::{MemoryLayout, Page};
+use crate::status::State;
+use crate::transport::DfuTransport;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const ERR_TARGET: u8 = 0x01;
+const ERR_ADDRESS: u8 = 0x08;
+const ERR_UNKNOWN: u8 = 0x0E;
+const ERR_STALLEDPKT: u8 = 0x0F;
+
+/// bitManifestationTolerant and bitWillDetach of bmAttributes
+const TOLERANT: u8 = 1 << 2;
+const WILL_DETACH: u8 = 1 << 3;
+
+/// Command bytes answered to Get Commands
+const COMMANDS: [u8; 4] = [0x00, 0x21, 0x41, 0x92];
+
+/// Failure injected into the next matching request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The request stalls, the state is left as it was
+    Stall,
+    /// The request fails with `TimedOut`
+    Timeout,
+    /// The DNLOAD is accepted but executing it ends in dfuERROR with this bStatus
+    Status(u8),
+    /// The device drops off the bus, every request fails until it is reopened
+    Disconnect,
+}
+
+/// bwPollTimeout reported while the bootloader is busy, on the short side of STM32F4 figures
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub page_erase: Duration,
+    pub mass_erase: Duration,
+    pub write: Duration,
+    /// Time spent in dfuMANIFEST before moving on
+    pub manifest: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            page_erase: Duration::from_millis(250),
+            mass_erase: Duration::from_millis(800),
+            write: Duration::from_millis(10),
+            manifest: Duration::ZERO,
+        }
+    }
+}
+
+struct Device {
+    layout: MemoryLayout,
+    start: u32,
+    flash: Vec<u8>,
+    transfer_size: u16,
+    timing: Timing,
+    state: State,
+    status: u8,
+    address: u32,
+    /// DNLOAD waiting for the GET_STATUS that executes it
+    pending: Option<(u16, Vec<u8>)>,
+    fail: Option<u8>,
+    /// Virtual clock, advanced by sleeping
+    now: Duration,
+    busy_until: Duration,
+    /// Per bRequest, faults with the number of requests to let through first
+    faults: HashMap<u8, VecDeque<(usize, Fault)>>,
+    erased: Vec<u32>,
+    reset: Option<u32>,
+    gone: bool,
+    /// bmAttributes of the functional descriptor, deciding how manifestation goes
+    attributes: u8,
+    /// A tolerant device passed dfuMANIFEST and reports dfuIDLE next
+    manifested: bool,
+    bus_resets: usize,
+    reconnects: usize,
+}
+
+impl Device {
+    fn page(&self, address: u32) -> Result<&Page, u8> {
+        self.layout
+            .pages()
+            .iter()
+            .find(|p| p.address <= address && address - p.address < p.size)
+            .ok_or(ERR_ADDRESS)
+    }
+
+    /// Index into `flash` of `length` bytes at `address`, on pages allowing `access`
+    fn range(&self, address: u32, length: usize, access: fn(&Page) -> bool) -> Result<std::ops::Range<usize>, u8> {
+        let mut at = address;
+        let end = address as u64 + length as u64;
+        while (at as u64) < end {
+            let page = self.page(at)?;
+            if !access(page) {
+                return Err(ERR_TARGET);
+            }
+            at = page.address + page.size;
+        }
+        let offset = (address - self.start) as usize;
+        Ok(offset..offset + length)
+    }
+
+    fn erase(&mut self, page: Page) {
+        let offset = (page.address - self.start) as usize;
+        self.flash[offset..offset + page.size as usize].fill(0xFF);
+        self.erased.push(page.address);
+    }
+
+    fn erase_all(&mut self) {
+        let pages: Vec<Page> = self.layout.pages().iter().filter(|p| erasable(p)).cloned().collect();
+        for page in pages {
+            self.erase(page);
+        }
+    }
+
+    /// Carry out a DNLOAD, returning how long the bootloader stays busy
+    fn execute(&mut self, block: u16, data: &[u8]) -> Result<Duration, u8> {
+        if let Some(status) = self.fail.take() {
+            return Err(status);
+        }
+        let address = |a: &[u8]| u32::from_le_bytes([a[0], a[1], a[2], a[3]]);
+        match (block, data) {
+            (0, [0x21, a @ ..]) if a.len() == 4 => {
+                self.page(address(a))?;
+                self.address = address(a);
+                Ok(Duration::ZERO)
+            }
+            (0, [0x41]) | (0, [0x92]) => {
+                self.erase_all();
+                Ok(self.timing.mass_erase)
+            }
+            (0, [0x41, a @ ..]) if a.len() == 4 => {
+                let page = self.page(address(a))?.clone();
+                if !erasable(&page) {
+                    return Err(ERR_TARGET);
+                }
+                self.erase(page);
+                Ok(self.timing.page_erase)
+            }
+            (0, _) | (1, _) => Err(ERR_STALLEDPKT),
+            (block, data) => {
+                let at = self.address as u64 + (block as u64 - 2) * self.transfer_size as u64;
+                let at = u32::try_from(at).map_err(|_| ERR_ADDRESS)?;
+                let range = self.range(at, data.len(), writable)?;
+                // Programming only clears bits, as on NOR flash
+                for (byte, d) in self.flash[range].iter_mut().zip(data) {
+                    *byte &= d;
+                }
+                Ok(self.timing.write)
+            }
+        }
+    }
+
+    /// Host broke the protocol, stall and wait for CLRSTATUS
+    fn stall(&mut self, status: u8) -> io::Error {
+        self.state = State::DfuError;
+        self.status = status;
+        io::ErrorKind::ConnectionReset.into()
+    }
+
+    fn get_status(&mut self) -> Vec<u8> {
+        let mut poll_timeout = Duration::ZERO;
+        match self.state {
+            State::DfuDownloadSync => {
+                let (block, data) = self.pending.take().unwrap_or_default();
+                match self.execute(block, &data) {
+                    Ok(busy) => {
+                        self.state = State::DfuDownloadBusy;
+                        self.busy_until = self.now + busy;
+                        poll_timeout = busy;
+                    }
+                    Err(status) => {
+                        self.state = State::DfuError;
+                        self.status = status;
+                    }
+                }
+            }
+            State::DfuDownloadBusy if self.now >= self.busy_until => self.state = State::DfuDownloadIdle,
+            State::DfuDownloadBusy => poll_timeout = self.busy_until - self.now,
+            State::DfuManifestSync if self.manifested => {
+                self.state = State::DfuIdle;
+                self.manifested = false;
+            }
+            State::DfuManifestSync => {
+                // Reported once, then the bootloader starts the application
+                self.state = State::DfuManifest;
+                self.reset = Some(self.address);
+                self.busy_until = self.now + self.timing.manifest;
+            }
+            State::DfuManifest if self.now < self.busy_until => {}
+            State::DfuManifest if self.attributes & TOLERANT != 0 => {
+                self.state = State::DfuManifestSync;
+                self.manifested = true;
+            }
+            State::DfuManifest => self.state = State::DfuManifestWaitReset,
+            _ => {}
+        }
+        let ms = (poll_timeout.as_millis() as u32).to_le_bytes();
+        vec![self.status, ms[0], ms[1], ms[2], u8::from(&self.state), 0]
+    }
+
+    fn upload(&mut self, block: u16, length: u16) -> io::Result<Vec<u8>> {
+        if !matches!(self.state, State::DfuIdle | State::DfuUploadIdle) {
+            return Err(self.stall(ERR_STALLEDPKT));
+        }
+        self.state = State::DfuUploadIdle;
+        match block {
+            0 => Ok(COMMANDS.iter().copied().take(length as usize).collect()),
+            1 => Err(self.stall(ERR_STALLEDPKT)),
+            block => {
+                let at = self.address as u64 + (block as u64 - 2) * self.transfer_size as u64;
+                let range = u32::try_from(at)
+                    .map_err(|_| ERR_ADDRESS)
+                    .and_then(|at| self.range(at, length as usize, readable));
+                match range {
+                    Ok(range) => Ok(self.flash[range].to_vec()),
+                    Err(status) => Err(self.stall(status)),
+                }
+            }
+        }
+    }
+
+    fn download(&mut self, block: u16, data: &[u8]) -> io::Result<()> {
+        match (&self.state, data.is_empty()) {
+            (State::DfuIdle | State::DfuDownloadIdle, false) => {
+                self.pending = Some((block, data.to_vec()));
+                self.state = State::DfuDownloadSync;
+            }
+            (State::DfuDownloadIdle, true) => self.state = State::DfuManifestSync,
+            _ => return Err(self.stall(ERR_STALLEDPKT)),
+        }
+        Ok(())
+    }
+
+    fn abort(&mut self) {
+        // Ignored in dfuERROR and while manifesting like the ST bootloader does
+        if !matches!(
+            self.state,
+            State::DfuError | State::DfuManifest | State::DfuManifestWaitReset
+        ) {
+            self.state = State::DfuIdle;
+            self.pending = None;
+        }
+    }
+
+    fn clear_status(&mut self) {
+        if self.state == State::DfuError {
+            self.state = State::DfuIdle;
+            self.status = 0;
+        } else {
+            self.state = State::DfuError;
+            self.status = ERR_UNKNOWN;
+        }
+    }
+
+    /// Fail the request if the device went away or a fault is injected
+    fn fault(&mut self, request: u8) -> io::Result<()> {
+        if self.gone {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        if self.state == State::DfuManifest && self.attributes & (TOLERANT | WILL_DETACH) == WILL_DETACH {
+            self.gone = true;
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let fault = match self.faults.get_mut(&request).and_then(|q| q.front_mut()) {
+            Some((0, _)) => self.faults.get_mut(&request).and_then(|q| q.pop_front()).map(|(_, f)| f),
+            Some((after, _)) => {
+                *after -= 1;
+                None
+            }
+            None => None,
+        };
+        match fault {
+            Some(Fault::Stall) => Err(io::ErrorKind::ConnectionReset.into()),
+            Some(Fault::Timeout) => Err(io::ErrorKind::TimedOut.into()),
+            Some(Fault::Status(status)) => {
+                self.fail = Some(status);
+                Ok(())
+            }
+            Some(Fault::Disconnect) => {
+                self.gone = true;
+                Err(io::ErrorKind::NotConnected.into())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+fn readable(page: &Page) -> bool {
+    page.access.is_none_or(|a| a.readable())
+}
+
+fn erasable(page: &Page) -> bool {
+    page.access.is_none_or(|a| a.erasable())
+}
+
+fn writable(page: &Page) -> bool {
+    page.access.is_none_or(|a| a.writable())
+}
+
+/// In-process DfuSe bootloader: flash following a memory layout string, the DFU state machine,
+/// Get Commands, Set Address, page and mass erase and bwPollTimeout while busy. The clock only
+/// advances when the host sleeps, so tests run at full speed. Flash starts out erased.
+pub struct DfuseEmulator {
+    device: Mutex<Device>,
+    layout: String,
+}
+
+impl DfuseEmulator {
+    pub fn new(layout: &str, transfer_size: u16) -> Result<Self, Error> {
+        let mem_layout = MemoryLayout::from_str(layout)?;
+        let start = mem_layout
+            .start_address()
+            .ok_or_else(|| Error::MemoryLayout(format!("No pages in {}", layout)))?;
+        let end = mem_layout.end_address()?;
+        let device = Device {
+            layout: mem_layout,
+            start,
+            flash: vec![0xFF; (end - start) as usize],
+            transfer_size,
+            timing: Timing::default(),
+            state: State::DfuIdle,
+            status: 0,
+            address: start,
+            pending: None,
+            fail: None,
+            now: Duration::ZERO,
+            busy_until: Duration::ZERO,
+            faults: HashMap::new(),
+            erased: Vec::new(),
+            reset: None,
+            gone: false,
+            attributes: 0x0B,
+            manifested: false,
+            bus_resets: 0,
+            reconnects: 0,
+        };
+        Ok(DfuseEmulator {
+            device: Mutex::new(device),
+            layout: layout.into(),
+        })
+    }
+
+    /// Internal flash of an STM32F405 with its 2048 byte transfer size
+    pub fn stm32f4() -> Self {
+        DfuseEmulator::new("@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg", 2048)
+            .expect("STM32F4 memory layout")
+    }
+
+    /// bmAttributes of the functional descriptor, 0x0B like the ST bootloader by default. Bit 2
+    /// makes manifestation return to dfuIDLE, without bit 3 the device waits for a bus reset.
+    pub fn set_attributes(&mut self, attributes: u8) {
+        self.device.get_mut().unwrap().attributes = attributes;
+    }
+
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.device.get_mut().unwrap().timing = timing;
+    }
+
+    /// Fail request `request` (bRequest, e.g. 1 for DNLOAD) with `fault` once `after` more of
+    /// them went through. Faults for the same request apply one after the other.
+    pub fn inject(&self, request: u8, after: usize, fault: Fault) {
+        self.device
+            .lock()
+            .unwrap()
+            .faults
+            .entry(request)
+            .or_default()
+            .push_back((after, fault));
+    }
+
+    /// Copy of `length` bytes of flash at `address`
+    pub fn read(&self, address: u32, length: usize) -> Vec<u8> {
+        let device = self.device.lock().unwrap();
+        let offset = (address - device.start) as usize;
+        device.flash[offset..offset + length].to_vec()
+    }
+
+    pub fn state(&self) -> State {
+        self.device.lock().unwrap().state.clone()
+    }
+
+    /// Start address of every page erase so far, mass erase adds all erasable pages
+    pub fn erased_pages(&self) -> Vec<u32> {
+        self.device.lock().unwrap().erased.clone()
+    }
+
+    /// Address the application was started at by leaving DFU mode
+    pub fn reset_address(&self) -> Option<u32> {
+        self.device.lock().unwrap().reset
+    }
+
+    /// Bus resets the host issued
+    pub fn bus_resets(&self) -> usize {
+        self.device.lock().unwrap().bus_resets
+    }
+
+    /// Times the host opened the device again after it went away
+    pub fn reconnects(&self) -> usize {
+        self.device.lock().unwrap().reconnects
+    }
+
+    pub fn into_dfu(self) -> Dfu<DfuseEmulator> {
+        let (attributes, transfer_size) = {
+            let device = self.device.lock().unwrap();
+            (device.attributes, device.transfer_size)
+        };
+        let descriptor = DfuDescriptor {
+            attributes,
+            detach_timeout: 255,
+            transfer_size,
+            dfu_version: 0x011A,
+        };
+        let layout = MemoryLayout::from_str(&self.layout).expect("parsed in new");
+        Dfu::with_transport(self, descriptor, layout)
+    }
+}
+
+impl DfuTransport for DfuseEmulator {
+    fn interface_number(&self) -> u8 {
+        0
+    }
+
+    async fn control_in(&self, request: u8, value: u16, length: u16, _: Duration) -> io::Result<Vec<u8>> {
+        let mut device = self.device.lock().unwrap();
+        device.fault(request)?;
+        let mut data = match request {
+            DFU_GET_STATUS => device.get_status(),
+            DFU_GETSTATE => vec![u8::from(&device.state)],
+            DFU_UPLOAD => device.upload(value, length)?,
+            _ => return Err(device.stall(ERR_STALLEDPKT)),
+        };
+        data.truncate(length as usize);
+        Ok(data)
+    }
+
+    async fn control_out(&self, request: u8, value: u16, data: &[u8], _: Duration) -> io::Result<()> {
+        let mut device = self.device.lock().unwrap();
+        device.fault(request)?;
+        match request {
+            DFU_DNLOAD => device.download(value, data)?,
+            DFU_ABORT => device.abort(),
+            DFU_CLRSTATUS => device.clear_status(),
+            _ => return Err(device.stall(ERR_STALLEDPKT)),
+        }
+        Ok(())
+    }
+
+    async fn string_descriptor(&self, _: u8, _: Duration) -> io::Result<String> {
+        Err(io::ErrorKind::NotFound.into())
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        self.device.lock().unwrap().now += duration;
+    }
+
+    async fn reset(&self) -> io::Result<()> {
+        let mut device = self.device.lock().unwrap();
+        device.bus_resets += 1;
+        device.gone = true;
+        Ok(())
+    }
+
+    /// Coming back enumerates the bootloader afresh, flash kept
+    async fn reopen(&mut self) -> io::Result<()> {
+        let device = self.device.get_mut().unwrap();
+        device.gone = false;
+        device.state = State::DfuIdle;
+        device.status = 0;
+        device.address = device.start;
+        device.pending = None;
+        device.fail = None;
+        device.reconnects += 1;
+        Ok(())
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_download_verify_upload() {
+        use crate::emulator::*;
+        use futures_lite::future::block_on;
+        use std::io::Cursor;
+        let image: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        let mut dfu = DfuseEmulator::stm32f4().into_dfu();
+        block_on(dfu.download_raw(&mut Cursor::new(&image), 0x0800_4000, image.len() as u32)).unwrap();
+        block_on(dfu.verify(&mut Cursor::new(&image), 0x0800_4000, image.len() as u32)).unwrap();
+        block_on(dfu.verify_slice(&image, 0x0800_4000)).unwrap();
+        // A mismatch found while the next chunk is uploaded
+        let mut other = image.clone();
+        other[100] ^= 1;
+        match block_on(dfu.verify(&mut Cursor::new(&other), 0x0800_4000, image.len() as u32)) {
+            Err(Error::Verify(a)) => assert_eq!(0x0800_4000 + 100, a),
+            r => panic!("expected verify error, got {:?}", r.err()),
+        }
+        block_on(dfu.abort_to_idle()).unwrap();
+        // Mismatch in the short last chunk is reported at its own address
+        let mut other = image.clone();
+        other[19999] ^= 1;
+        match block_on(dfu.verify_slice(&other, 0x0800_4000)) {
+            Err(Error::Verify(a)) => assert_eq!(0x0800_4000 + 19999, a),
+            r => panic!("expected verify error, got {:?}", r.err()),
+        }
+        block_on(dfu.abort_to_idle()).unwrap();
+        match block_on(dfu.verify(&mut Cursor::new(&other), 0x0800_4000, image.len() as u32)) {
+            Err(Error::Verify(a)) => assert_eq!(0x0800_4000 + 19999, a),
+            r => panic!("expected verify error, got {:?}", r.err()),
+        }
+        block_on(dfu.abort_to_idle()).unwrap();
+        let mut out = Vec::new();
+        block_on(dfu.upload(&mut out, 0x0800_4000, image.len() as u32)).unwrap();
+        assert_eq!(image, out);
+        let mut slice = vec![0; image.len()];
+        assert_eq!(image.len(), block_on(dfu.read_flash_to_slice(0x0800_4000, &mut slice)).unwrap());
+        assert_eq!(image, slice);
+        // Callers can drive the transaction with their own buffer
+        slice.fill(0);
+        let mut buf = [0; 2048];
+        let mut i = 0;
+        block_on(async {
+            dfu.set_address(0x0800_4000).await?;
+            dfu.abort_to_idle().await?;
+            for chunk in crate::transaction::Transaction::new(0x0800_4000, 5000, 2048) {
+                let len = dfu.read_chunk(chunk, &mut buf).await?;
+                slice[i..i + len].copy_from_slice(&buf[..len]);
+                i += len;
+            }
+            dfu.abort_to_idle().await
+        })
+        .unwrap();
+        assert_eq!(image[..5000], slice[..i]);
+        let emu = dfu.transport();
+        assert_eq!(vec![0x0800_4000, 0x0800_8000], emu.erased_pages());
+        assert_eq!(image, emu.read(0x0800_4000, image.len()));
+        assert_eq!(vec![0xFF; 4], emu.read(0x0800_4000 + image.len() as u32, 4));
+        assert_eq!(State::DfuIdle, emu.state());
+    }
+
+    #[test]
+    fn test_buffered_input() {
+        use crate::emulator::*;
+        use futures_lite::future::block_on;
+        use std::io::{Cursor, Read};
+        struct Counted<'a>(Cursor<&'a [u8]>, usize);
+        impl Read for Counted<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.1 += 1;
+                self.0.read(buf)
+            }
+        }
+        // 64 byte transfers, still only a couple of reads of the file
+        let emu = DfuseEmulator::new("@Internal Flash  /0x08000000/04*016Kg", 64).unwrap();
+        let mut dfu = emu.into_dfu();
+        let image: Vec<u8> = (0..20000u32).map(|i| (i % 253) as u8).collect();
+        let mut file = Counted(Cursor::new(&image), 0);
+        block_on(dfu.download_raw(&mut file, 0x0800_0000, 10000)).unwrap();
+        assert!(file.1 <= 2, "{} reads", file.1);
+        // Nothing beyond the range is consumed
+        assert_eq!(10000, file.0.position());
+        let mut file = Counted(Cursor::new(&image[..10000]), 0);
+        block_on(dfu.verify(&mut file, 0x0800_0000, 10000)).unwrap();
+        assert!(file.1 <= 2, "{} reads", file.1);
+    }
+
+    #[test]
+    fn test_shared_upload_session() {
+        use crate::emulator::*;
+        use futures_lite::future::block_on;
+        let mut dfu = DfuseEmulator::stm32f4().into_dfu();
+        let image: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8).collect();
+        block_on(dfu.download_slice(&image, 0x0800_4000)).unwrap();
+        let mut front = vec![0; 4096];
+        let mut composite = |dfu: &mut Dfu<DfuseEmulator>, shared: bool| {
+            dfu.reset_stats();
+            block_on(async {
+                if shared {
+                    dfu.begin_upload(0x0800_4000).await?;
+                }
+                dfu.read_flash_to_slice(0x0800_4000, &mut front).await?;
+                dfu.verify_slice(&image[4096..], 0x0800_5000).await?;
+                dfu.end_session().await
+            })
+            .unwrap();
+            assert_eq!(image[..4096], front);
+            assert_eq!(State::DfuIdle, dfu.transport().state());
+            dfu.stats().control_transfers
+        };
+        let separate = composite(&mut dfu, false);
+        let shared = composite(&mut dfu, true);
+        // One Set Address and one abort instead of one of each per operation
+        assert!(shared < separate, "{} vs {}", shared, separate);
+        // Erasing closes a session left open
+        block_on(dfu.begin_upload(0x0800_4000)).unwrap();
+        block_on(dfu.erase_pages(0x0800_4000, 1)).unwrap();
+    }
+
+    #[test]
+    fn test_protected() {
+        use crate::emulator::*;
+        use futures_lite::future::block_on;
+        let mut dfu = DfuseEmulator::stm32f4().into_dfu();
+        // Bootloader in the first page and its settings in the last
+        dfu.set_protected(vec![0x0800_0000..0x0800_4000, 0x080E_0000..0x0810_0000]);
+        let image = vec![0x5A; 1024];
+        // The write ends in the protected page, nothing is erased
+        match block_on(dfu.download_slice(&vec![0x5A; 0x5000], 0x0800_0000)) {
+            Err(Error::Protected(a)) => assert_eq!(0x0800_0000, a),
+            r => panic!("expected protected, got {:?}", r.err()),
+        }
+        assert!(matches!(block_on(dfu.mass_erase()), Err(Error::Protected(_))));
+        assert!(dfu.transport().erased_pages().is_empty());
+        block_on(dfu.download_slice(&image, 0x0800_4000)).unwrap();
+        dfu.allow_protected(true);
+        block_on(dfu.download_slice(&image, 0x0800_3C00)).unwrap();
+        assert_eq!(vec![0x0800_4000, 0x0800_0000], dfu.transport().erased_pages());
+        assert_eq!(image, dfu.transport().read(0x0800_3C00, 1024));
+    }
+
+    #[test]
+    fn test_alias() {
+        use crate::emulator::*;
+        use crate::memory_layout::Alias;
+        use futures_lite::future::block_on;
+        let mut dfu = DfuseEmulator::stm32f4().into_dfu();
+        dfu.set_aliases(vec![Alias {
+            address: 0,
+            length: 0x10_0000,
+            target: 0x0800_0000,
+        }]);
+        let image: Vec<u8> = (0..3000u32).map(|i| (i % 7) as u8).collect();
+        block_on(dfu.download_slice(&image, 0x4000)).unwrap();
+        block_on(dfu.verify_slice(&image, 0x4000)).unwrap();
+        assert_eq!(vec![0x0800_4000], dfu.transport().erased_pages());
+        assert_eq!(image, dfu.transport().read(0x0800_4000, image.len()));
+    }
+
+    #[test]
+    fn test_low_level_blocks() {
+        use crate::emulator::*;
+        use crate::status::State;
+        use futures_lite::future::block_on;
+        let data: Vec<u8> = (0..64u8).collect();
+        let mut dfu = DfuseEmulator::stm32f4().into_dfu();
+        block_on(async {
+            dfu.erase_pages(0x0800_4000, 64).await?;
+            dfu.set_address(0x0800_4000).await?;
+            dfu.dfuse_download(&data, 2).await?;
+            dfu.status_wait_for(0, Some(State::DfuDownloadIdle)).await?;
+            dfu.abort_to_idle().await?;
+            dfu.set_address(0x0800_4000).await?;
+            dfu.abort_to_idle().await?;
+            assert_eq!(data[..16], dfu.dfuse_upload(2, 16).await?);
+            dfu.abort_to_idle().await
+        })
+        .unwrap();
+        assert_eq!(data, dfu.transport().read(0x0800_4000, 64));
+    }
+
+    #[test]
+    fn test_erase_mixed_sectors() {
+        use crate::emulator::*;
+        use futures_lite::future::block_on;
+        // From the middle of the last 16K sector across the 64K one into the first 128K sector
+        let image = vec![0x00; 0x1_4000];
+        let mut dfu = DfuseEmulator::stm32f4().into_dfu();
+        block_on(dfu.download_slice(&image, 0x0800_E000)).unwrap();
+        assert_eq!(image.len() as u32, dfu.progress());
+        let stats = dfu.stats().clone();
+        assert_eq!((image.len() as u64, 0, 0, 0), (stats.bytes_written, stats.bytes_read, stats.stalls, stats.retries));
+        // Set Address, data DNLOAD and three GET_STATUS for each of the 40 chunks at least
+        assert!(stats.control_transfers >= 40 * 5);
+        let emu = dfu.transport();
+        assert_eq!(vec![0x0800_C000, 0x0801_0000, 0x0802_0000], emu.erased_pages());
+        assert_eq!(image, emu.read(0x0800_E000, image.len()));
+        assert_eq!(vec![0xFF; 0x2000], emu.read(0x0800_C000, 0x2000));
+    }
+
+    #[test]
+    fn test_faults() {
+        use crate::emulator::*;
+        use futures_lite::future::block_on;
+        use std::io::Cursor;
+        let image = vec![0x5A; 4096];
+        let emu = DfuseEmulator::stm32f4();
+        // errERASE from the page erase
+        emu.inject(DFU_DNLOAD, 0, Fault::Status(0x04));
+        let mut dfu = emu.into_dfu();
+        match block_on(dfu.download_raw(&mut Cursor::new(&image), 0x0800_0000, 4096)) {
+            Err(Error::Device(phase, s, _)) => {
+                assert_eq!("erase page 0x08000000", phase);
+                assert_eq!((0x04, State::DfuError), (s.status, State::from(s.state)));
+            }
+            r => panic!("expected errERASE, got {:?}", r.err()),
+        }
+        // Cleared on the way out
+        assert_eq!(State::DfuIdle, dfu.transport().state());
+
+        // Erase, set address, first chunk, set address, then the second chunk stalls once and
+        // is sent again
+        dfu.transport().inject(DFU_DNLOAD, 4, Fault::Stall);
+        block_on(dfu.download_raw(&mut Cursor::new(&image), 0x0800_0000, 4096)).unwrap();
+        assert_eq!(image, dfu.transport().read(0x0800_0000, 4096));
+
+        // Stalling on every retry as well
+        dfu.transport().inject(DFU_DNLOAD, 4, Fault::Stall);
+        for _ in 0..dfu.retry_policy().stall_retries {
+            dfu.transport().inject(DFU_DNLOAD, 0, Fault::Stall);
+        }
+        match block_on(dfu.download_raw(&mut Cursor::new(&image), 0x0800_0000, 4096)) {
+            Err(Error::Stalled(_, n)) => assert_eq!(dfu.retry_policy().stall_retries + 1, n),
+            r => panic!("expected stall, got {:?}", r.err()),
+        }
+        assert_eq!(image[..2048], dfu.transport().read(0x0800_0000, 2048));
+        assert_eq!(vec![0xFF; 2048], dfu.transport().read(0x0800_0800, 2048));
+
+        // The second chunk is read while the first is programming, a short file fails there
+        block_on(dfu.abort_to_idle_clear_once()).unwrap();
+        match block_on(dfu.download_raw(&mut Cursor::new(&image[..3000]), 0x0800_4000, 4096)) {
+            Err(Error::FileIO(e)) => assert_eq!(std::io::ErrorKind::UnexpectedEof, e.kind()),
+            r => panic!("expected end of file, got {:?}", r.err()),
+        }
+        assert_eq!(image[..2048], dfu.transport().read(0x0800_4000, 2048));
+
+        // Read-only first sector
+        let emu = DfuseEmulator::new("@Internal Flash  /0x08000000/01*016Ka,03*016Kg", 2048).unwrap();
+        let mut dfu = emu.into_dfu();
+        match block_on(dfu.download_raw(&mut Cursor::new(&image), 0x0800_0000, 4096)) {
+            Err(Error::Device(_, s, _)) => assert_eq!(0x01, s.status),
+            r => panic!("expected errTARGET, got {:?}", r.err()),
+        }
+    }
+
+    #[test]
+    fn test_block_retry() {
+        use crate::emulator::*;
+        use futures_lite::future::block_on;
+        use std::io::Cursor;
+        let image: Vec<u8> = (0..8192u32).map(|i| (i % 253) as u8).collect();
+        let emu = DfuseEmulator::stm32f4();
+        // Erase, then set address and data per chunk: the third chunk times out once
+        emu.inject(DFU_DNLOAD, 5, Fault::Timeout);
+        let mut dfu = emu.into_dfu();
+        block_on(dfu.download_raw(&mut Cursor::new(&image), 0x0800_0000, 8192)).unwrap();
+        assert_eq!(1, dfu.stats().retries);
+        assert_eq!(image, dfu.transport().read(0x0800_0000, 8192));
+
+        // The block times out every time it is sent again, its set address going through
+        dfu.transport().inject(DFU_DNLOAD, 2, Fault::Timeout);
+        for _ in 0..dfu.retry_policy().block_retries {
+            dfu.transport().inject(DFU_DNLOAD, 1, Fault::Timeout);
+        }
+        match block_on(dfu.write_flash_from_slice(0x0800_4000, &image)) {
+            Err(Error::USB(_, e)) => assert_eq!(std::io::ErrorKind::TimedOut, e.kind()),
+            r => panic!("expected a timeout, got {:?}", r),
+        }
+
+        // An error status is not worth repeating
+        block_on(dfu.abort_to_idle_clear_once()).unwrap();
+        dfu.reset_stats();
+        dfu.transport().inject(DFU_DNLOAD, 2, Fault::Status(0x03));
+        assert!(block_on(dfu.download_raw(&mut Cursor::new(&image), 0x0800_4000, 8192)).is_err());
+        assert_eq!(0, dfu.stats().retries);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        use crate::emulator::*;
+        use futures_lite::future::block_on;
+        use std::io::Cursor;
+        let image: Vec<u8> = (0..8192u32).map(|i| (i % 247) as u8).collect();
+        let emu = DfuseEmulator::stm32f4();
+        // Gone while the third chunk is sent, the download picks up at that chunk
+        emu.inject(DFU_DNLOAD, 5, Fault::Disconnect);
+        let mut dfu = emu.into_dfu();
+        block_on(dfu.download_raw(&mut Cursor::new(&image), 0x0800_0000, 8192)).unwrap();
+        assert_eq!(1, dfu.transport().reconnects());
+        assert_eq!(8192, dfu.progress());
+        assert_eq!(image, dfu.transport().read(0x0800_0000, 8192));
+        assert_eq!(vec![0x0800_0000], dfu.transport().erased_pages());
+
+        block_on(dfu.reconnect()).unwrap();
+        assert_eq!(2, dfu.transport().reconnects());
+        assert_eq!(State::DfuIdle, dfu.transport().state());
+    }
+
+    #[test]
+    fn test_transfer_sizes() {
+        use crate::emulator::*;
+        use futures_lite::future::block_on;
+        use std::io::Cursor;
+        let image: Vec<u8> = (0..10000u32).map(|i| (i % 239) as u8).collect();
+        for size in [64, 1000, 1023, 1024, 2048] {
+            let emu = DfuseEmulator::new("@Internal Flash  /0x08000000/04*016Kg", size).unwrap();
+            let mut dfu = emu.into_dfu();
+            // Starting off a transfer boundary and crossing a page
+            block_on(dfu.download_raw(&mut Cursor::new(&image), 0x0800_3000, 10000)).unwrap();
+            block_on(dfu.verify(&mut Cursor::new(&image), 0x0800_3000, 10000)).unwrap();
+            let mut out = vec![0; image.len()];
+            assert_eq!(image.len(), block_on(dfu.read_flash_to_slice(0x0800_3000, &mut out)).unwrap());
+            assert_eq!(image, out, "transfer size {}", size);
+        }
+
+        // A descriptor claiming no transfer size at all
+        let dfu = DfuseEmulator::new("@Internal Flash  /0x08000000/04*016Kg", 0).unwrap().into_dfu();
+        assert_eq!(crate::core::FALLBACK_TRANSFER_SIZE, dfu.transfer_size());
+    }
+
+    #[test]
+    fn test_commands_and_leave() {
+        use crate::emulator::*;
+        use futures_lite::future::block_on;
+        let mut dfu = DfuseEmulator::stm32f4().into_dfu();
+        assert_eq!(vec![0x21, 0x41, 0x92], block_on(dfu.dfuse_get_command_bytes()).unwrap());
+        block_on(dfu.abort_to_idle()).unwrap();
+        block_on(dfu.mass_erase()).unwrap();
+        assert_eq!(12, dfu.transport().erased_pages().len());
+        block_on(dfu.abort_to_idle()).unwrap();
+        block_on(dfu.reset_stm32(0x0800_0000)).unwrap();
+        assert_eq!(Some(0x0800_0000), dfu.transport().reset_address());
+        assert_eq!(0, dfu.transport().bus_resets());
+    }
+
+    #[test]
+    fn test_manifestation() {
+        use crate::emulator::*;
+        use futures_lite::future::block_on;
+        let leave = |attributes: u8| {
+            let mut emu = DfuseEmulator::stm32f4();
+            emu.set_attributes(attributes);
+            let mut dfu = emu.into_dfu();
+            block_on(dfu.reset_stm32(0x0800_0000)).unwrap();
+            assert_eq!(Some(0x0800_0000), dfu.transport().reset_address());
+            (dfu.transport().state(), dfu.transport().bus_resets())
+        };
+        // Tolerant, polled through dfuMANIFEST-SYNC back to dfuIDLE
+        assert_eq!((State::DfuIdle, 0), leave(0x0F));
+        assert_eq!((State::DfuIdle, 1), leave(0x07));
+        // Intolerant without bitWillDetach waits for the reset
+        assert_eq!((State::DfuManifestWaitReset, 1), leave(0x03));
+        // Stuck in dfuMANIFEST past the deadline, still reset
+        let mut emu = DfuseEmulator::stm32f4();
+        emu.set_attributes(0x03);
+        emu.set_timing(Timing {
+            manifest: Duration::from_secs(3600),
+            ..Timing::default()
+        });
+        let mut dfu = emu.into_dfu();
+        block_on(dfu.reset_stm32(0x0800_0000)).unwrap();
+        assert_eq!(State::DfuManifest, dfu.transport().state());
+        assert_eq!(1, dfu.transport().bus_resets());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::status::{State, Status};
+use crate::status::{status_name, State, Status};
 use std::fmt;
 #[derive(Debug)]
 pub enum Error {
@@ -7,12 +7,33 @@ pub enum Error {
     InvalidControlResponse(String),
     InvalidState(Status, State),
     InvalidStatus(Status, u8),
+    /// The device went to dfuERROR during the phase named, with the status it reported and the
+    /// text of its iString. The error status has been cleared again.
+    Device(String, Status, Option<String>),
+    /// No status accepted by [`Dfu::wait_until`](crate::Dfu::wait_until) in time, with the last one
+    WaitTimeout(Status),
     USB(String, std::io::Error),
+    /// A request kept stalling after clearing the stall, with the number of attempts
+    Stalled(String, u8),
     FileIO(std::io::Error),
     UnknownCommandByte(u8),
     Address(u32),
     Verify(u32),
+    /// Erasing or writing would touch this address of a protected range
+    Protected(u32),
     MemoryLayout(String),
+    DfuseFile(String),
+    Interrupted,
+    Busy(String),
+    PermissionDenied(String),
+    /// The interface is the runtime DFU interface of an application
+    RuntimeMode(String),
+    /// Windows: WinUSB is not bound to the device
+    DriverNotBound(String),
+    /// macOS: another process opened the device or interface for exclusive access
+    ExclusiveAccess(String),
+    /// macOS: IOKit refused access, e.g. from an App Sandbox without the USB entitlement
+    AccessRestricted(String),
 }
 
 impl From<std::io::Error> for Error {
@@ -21,25 +42,77 @@ impl From<std::io::Error> for Error {
     }
 }
 
-impl From<Error> for i32 {
-    fn from(err: Error) -> Self {
+/// Process exit code for each class of [Error], stable across releases so scripts can branch on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    DeviceNotFound = 64,
+    Argument = 65,
+    Usb = 66,
+    InvalidControlResponse = 68,
+    InvalidState = 69,
+    InvalidStatus = 70,
+    FileIO = 71,
+    UnknownCommandByte = 72,
+    Address = 73,
+    Verify = 74,
+    MemoryLayout = 75,
+    DfuseFile = 76,
+    Busy = 77,
+    PermissionDenied = 78,
+    RuntimeMode = 79,
+    DriverNotBound = 80,
+    ExclusiveAccess = 81,
+    AccessRestricted = 82,
+    Stalled = 83,
+    Protected = 84,
+    Device = 85,
+    /// 128 + SIGINT like a shell
+    Interrupted = 130,
+}
+
+impl From<ExitCode> for i32 {
+    fn from(code: ExitCode) -> Self {
+        code as i32
+    }
+}
+
+impl Error {
+    pub fn exit_code(&self) -> ExitCode {
         use Error::*;
-        match err {
-            DeviceNotFound(_) => 64,
-            Argument(_) => 65,
-            USB(_, _) => 66,
-            InvalidControlResponse(_) => 68,
-            InvalidState(_, _) => 69,
-            InvalidStatus(_, _) => 70,
-            FileIO(_) => 71,
-            UnknownCommandByte(_) => 72,
-            Address(_) => 73,
-            Verify(_) => 74,
-            MemoryLayout(_) => 75,
+        match self {
+            DeviceNotFound(_) => ExitCode::DeviceNotFound,
+            Argument(_) => ExitCode::Argument,
+            USB(_, _) => ExitCode::Usb,
+            Stalled(_, _) => ExitCode::Stalled,
+            InvalidControlResponse(_) => ExitCode::InvalidControlResponse,
+            InvalidState(_, _) => ExitCode::InvalidState,
+            InvalidStatus(_, _) => ExitCode::InvalidStatus,
+            Device(_, _, _) => ExitCode::Device,
+            WaitTimeout(_) => ExitCode::InvalidState,
+            FileIO(_) => ExitCode::FileIO,
+            UnknownCommandByte(_) => ExitCode::UnknownCommandByte,
+            Address(_) => ExitCode::Address,
+            Verify(_) => ExitCode::Verify,
+            Protected(_) => ExitCode::Protected,
+            MemoryLayout(_) => ExitCode::MemoryLayout,
+            DfuseFile(_) => ExitCode::DfuseFile,
+            Interrupted => ExitCode::Interrupted,
+            Busy(_) => ExitCode::Busy,
+            PermissionDenied(_) => ExitCode::PermissionDenied,
+            RuntimeMode(_) => ExitCode::RuntimeMode,
+            DriverNotBound(_) => ExitCode::DriverNotBound,
+            ExclusiveAccess(_) => ExitCode::ExclusiveAccess,
+            AccessRestricted(_) => ExitCode::AccessRestricted,
         }
     }
 }
 
+impl From<Error> for i32 {
+    fn from(err: Error) -> Self {
+        err.exit_code().into()
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use Error::*;
@@ -47,6 +120,7 @@ impl fmt::Display for Error {
             DeviceNotFound(d) => write!(f, "Device not found: {}", d),
             Argument(d) => write!(f, "Argument {}", d),
             USB(e, io) => write!(f, "USB {} failed cause {}", e, io),
+            Stalled(e, n) => write!(f, "USB {} stalled {} times", e, n),
             InvalidControlResponse(w) => write!(f, "Invalid control response on {}", w),
             InvalidState(s, expect) => write!(
                 f,
@@ -58,11 +132,56 @@ impl fmt::Display for Error {
                 "Invalid state Get status gave:\n{}\nExpected status: {}",
                 s, expect
             ),
+            Device(phase, s, text) => {
+                write!(f, "Device reported {} (0x{:02X}) during {}", status_name(s.status), s.status, phase)?;
+                match text {
+                    Some(text) => write!(f, ": {}", text),
+                    None => Ok(()),
+                }
+            }
+            WaitTimeout(s) => write!(f, "Timed out waiting, Get status gave:\n{}", s),
             FileIO(io) => write!(f, "IO error {}", io),
             UnknownCommandByte(b) => write!(f, "Unknown command byte: 0x{:X}", b),
             Address(a) => write!(f, "Address: 0x{:08X} not supported", a),
             Verify(a) => write!(f, "Verify failed at address: 0x{:08X}", a),
+            Protected(a) => write!(f, "Address: 0x{:08X} is protected", a),
             MemoryLayout(s) => write!(f, "Could not get memory layout from '{}'", s),
+            DfuseFile(s) => write!(f, "Invalid DfuSe file: {}", s),
+            Interrupted => write!(f, "Interrupted"),
+            Busy(d) => write!(f, "Device busy: {}", d),
+            PermissionDenied(d) => write!(
+                f,
+                "Permission denied opening {}, on Linux a udev rule is needed to grant access",
+                d
+            ),
+            RuntimeMode(d) => write!(
+                f,
+                "{} is in runtime mode, detach it into its bootloader first",
+                d
+            ),
+            DriverNotBound(d) => write!(f, "Device {}, WinUSB must be bound to open it", d),
+            ExclusiveAccess(d) => write!(f, "{} is opened for exclusive access", d),
+            AccessRestricted(d) => write!(f, "Access to {} not permitted by macOS", d),
         }
     }
 }
+
+impl std::error::Error for Error {}
+
+mod tests {
+    #[test]
+    fn test_exit_code() {
+        use crate::error::*;
+        assert_eq!(ExitCode::Verify, Error::Verify(0).exit_code());
+        assert_eq!(74, i32::from(Error::Verify(0)));
+        assert_eq!(130, i32::from(ExitCode::Interrupted));
+        let s = Status {
+            status: 0x04,
+            state: 10,
+            ..Default::default()
+        };
+        let e = Error::Device("erase page 0x08004000".into(), s, Some("sector locked".into()));
+        assert_eq!(ExitCode::Device, e.exit_code());
+        assert_eq!("Device reported errERASE (0x04) during erase page 0x08004000: sector locked", e.to_string());
+    }
+}
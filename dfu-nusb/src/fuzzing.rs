@@ -0,0 +1,4 @@
+/// DFU functional descriptor among the descriptors of a raw configuration descriptor
+pub fn functional_descriptor(config: &[u8]) -> Option<crate::DfuDescriptor> {
+    crate::core::functional_descriptor(config)
+}
@@ -0,0 +1,42 @@
+/// Hex bytes, optionally prefixed with 0x and separated by spaces, commas, colons or dashes
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits: String = s
+        .chars()
+        .filter(|c| !matches!(c, ' ' | ',' | ':' | '-'))
+        .collect();
+    let digits = digits.strip_prefix("0x").unwrap_or(&digits);
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("'{}' has an odd number of hex digits", s));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            digits
+                .get(i..i + 2)
+                .and_then(|d| u8::from_str_radix(d, 16).ok())
+                .ok_or_else(|| format!("'{}' is not hex", s))
+        })
+        .collect()
+}
+
+/// Bytes as upper case hex digits without separators
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+mod tests {
+    #[test]
+    fn test_parse_hex() {
+        use crate::hex::*;
+        assert_eq!(Ok(vec![0x41, 0x00, 0xFF]), parse_hex("41 00 ff"));
+        assert_eq!(Ok(vec![0x41, 0x00, 0xFF]), parse_hex("0x4100FF"));
+        assert_eq!(Ok(vec![0xDE, 0xAD]), parse_hex("de:ad"));
+        assert_eq!(Ok(vec![0xDE, 0xAD]), parse_hex("DE-AD"));
+        assert_eq!(Ok(vec![]), parse_hex(""));
+        assert!(parse_hex("123").is_err());
+        assert!(parse_hex("zz").is_err());
+        assert!(parse_hex("\u{e9}0").is_err());
+        assert_eq!("00417F", to_hex(&[0x00, 0x41, 0x7F]));
+        assert_eq!("", to_hex(&[]));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::core::{AltSetting, Dfu};
+use crate::device_filter::{is_dfu, is_dfu_mode, DeviceFilter};
+use crate::error::Error;
+use futures_lite::stream::{self, Stream, StreamExt};
+use nusb::hotplug::HotplugEvent;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Interval of the enumeration fallback where hotplug is not available
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Arrival or removal of a DFU capable device
+#[derive(Debug, Clone)]
+pub enum DfuEvent {
+    Arrived(nusb::DeviceInfo),
+    /// Carries the information seen on arrival as the device is gone
+    Removed(nusb::DeviceInfo),
+}
+
+/// Stream of DFU devices matching `filter` coming and going.
+/// Devices already connected are reported as arrivals first.
+pub fn watch_dfu_devices(filter: DeviceFilter) -> Result<impl Stream<Item = DfuEvent>, Error> {
+    // Watch before listing so nothing plugged in between is missed
+    let watch = nusb::watch_devices().map_err(|e| Error::USB("watch devices".into(), e))?;
+    let mut known: HashMap<nusb::DeviceId, nusb::DeviceInfo> = nusb::list_devices()
+        .map_err(|e| Error::USB("list devices".into(), e))?
+        .filter(|d| is_dfu(d) && filter.matches(d))
+        .map(|d| (d.id(), d))
+        .collect();
+    let present: Vec<DfuEvent> = known.values().cloned().map(DfuEvent::Arrived).collect();
+    let events = watch.filter_map(move |event| match event {
+        HotplugEvent::Connected(d) if is_dfu(&d) && filter.matches(&d) => {
+            if known.insert(d.id(), d.clone()).is_some() {
+                // Already reported from the initial listing
+                return None;
+            }
+            Some(DfuEvent::Arrived(d))
+        }
+        HotplugEvent::Connected(_) => None,
+        HotplugEvent::Disconnected(id) => known.remove(&id).map(DfuEvent::Removed),
+    });
+    Ok(stream::iter(present).chain(events))
+}
+
+/// Filter for exactly `dev` on top of what `filter` asks for
+fn this_device(filter: &DeviceFilter, dev: &nusb::DeviceInfo) -> DeviceFilter {
+    DeviceFilter {
+        bus: Some(dev.bus_number()),
+        address: Some(dev.device_address()),
+        ..filter.clone()
+    }
+}
+
+async fn next_dfu_mode_device(filter: &DeviceFilter) -> Result<nusb::DeviceInfo, Error> {
+    match watch_dfu_devices(filter.clone()) {
+        Ok(events) => {
+            let mut events = std::pin::pin!(events);
+            while let Some(event) = events.next().await {
+                if let DfuEvent::Arrived(d) = event {
+                    if is_dfu_mode(&d) {
+                        return Ok(d);
+                    }
+                }
+            }
+            Err(Error::DeviceNotFound(format!("{}, hotplug watch ended", filter)))
+        }
+        Err(e) => {
+            log::debug!("Hotplug not available, polling instead: {}", e);
+            loop {
+                if let Some(d) = nusb::list_devices()
+                    .map_err(|e| Error::USB("list devices".into(), e))?
+                    .find(|d| is_dfu_mode(d) && filter.matches(d))
+                {
+                    return Ok(d);
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Wait up to `timeout` for a device matching `filter` to enumerate in DFU mode and open it.
+/// Opening is retried while the device is still settling, e.g. before udev applied its permissions.
+pub async fn wait_for_dfu_device(
+    filter: &DeviceFilter,
+    timeout: Duration,
+    iface_index: u8,
+    alt: &AltSetting,
+) -> Result<Dfu, Error> {
+    wait_for_dfu_device_with_language(filter, timeout, iface_index, alt, None).await
+}
+
+/// [`wait_for_dfu_device`] reading string descriptors in `language`, see
+/// [`Dfu::open_with_language`]
+pub async fn wait_for_dfu_device_with_language(
+    filter: &DeviceFilter,
+    timeout: Duration,
+    iface_index: u8,
+    alt: &AltSetting,
+    language: Option<u16>,
+) -> Result<Dfu, Error> {
+    let wait = async {
+        let dev = next_dfu_mode_device(filter).await?;
+        log::debug!("{} arrived on bus {} device {}", filter, dev.bus_number(), dev.device_address());
+        let filter = this_device(filter, &dev);
+        loop {
+            match Dfu::open_with_language(&filter, iface_index, alt, language).await {
+                Err(e @ (Error::PermissionDenied(_) | Error::USB(..))) => {
+                    log::debug!("Open failed, retrying: {}", e);
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+                r => return r,
+            }
+        }
+    };
+    tokio::time::timeout(timeout, wait)
+        .await
+        .map_err(|_| Error::DeviceNotFound(format!("{} not in DFU mode within {:?}", filter, timeout)))?
+}
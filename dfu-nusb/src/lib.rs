@@ -1,11 +1,71 @@
+#[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
+compile_error!("dfu-nusb needs the wasm feature on wasm32, nusb has no browser backend");
+
+pub mod chip;
 pub mod core;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod device_filter;
+pub mod device_lock;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod diagnose;
 pub mod dfuse_command;
+pub mod dfuse_file;
+#[cfg(any(test, feature = "test-util"))]
+pub mod emulator;
 pub mod error;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+pub mod hex;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod hotplug;
 pub mod memory_layout;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+pub mod option_bytes;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pool;
+pub mod progress;
+pub mod record;
+pub mod session;
+pub mod stats;
 pub mod status;
+pub mod transaction;
+pub mod transport;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod update;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub mod webusb;
 
-pub use crate::core::Dfu;
+pub use crate::chip::{Chip, Identity};
+pub use crate::core::{AltSetting, Backup, DescriptorWarning, Dfu, DfuDescriptor, RetryPolicy};
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::device_filter::{
+    dfu_mode, is_dfu, is_dfu_mode, list_dfu_devices, platform_id, port_path, DeviceFilter, DfuMode,
+};
+pub use crate::device_lock::DeviceLock;
 pub use crate::dfuse_command::DfuseCommand;
-pub use crate::error::Error;
+pub use crate::dfuse_file::DfuseFile;
+#[cfg(any(test, feature = "test-util"))]
+pub use crate::emulator::DfuseEmulator;
+pub use crate::error::{Error, ExitCode};
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::hotplug::{wait_for_dfu_device, wait_for_dfu_device_with_language, watch_dfu_devices, DfuEvent};
+pub use crate::record::{Recorder, Replay};
+pub use crate::session::{DownloadSession, IdleSession, UploadSession, WriteSession};
+pub use crate::stats::{OperationSummary, TransferStats};
 pub use crate::status::{State, Status};
-pub use memory_layout::MemoryLayout;
+pub use crate::transaction::{Chunk, Transaction};
+pub use crate::transport::DfuTransport;
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::transport::NusbTransport;
+pub use memory_layout::{Alias, MemoryLayout};
+#[cfg(any(test, feature = "test-util"))]
+pub use mock::MockTransport;
+pub use option_bytes::OptionBytes;
+#[cfg(not(target_arch = "wasm32"))]
+pub use pool::DfuPool;
+pub use progress::{Activity, Progress};
+#[cfg(not(target_arch = "wasm32"))]
+pub use update::{update, Phase, Update};
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub use webusb::WebUsbTransport;
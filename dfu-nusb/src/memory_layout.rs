@@ -2,11 +2,59 @@ use crate::error::Error;
 use serde::{Serialize, Serializer};
 use std::fmt;
 use std::str::FromStr;
-#[derive(Debug, Serialize)]
+/// DfuSe sector type, the letter a to g after the size with bit 0 readable,
+/// bit 1 erasable and bit 2 writable
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Access(u8);
+
+impl Access {
+    fn from_letter(c: char) -> Option<Self> {
+        match c {
+            'a'..='g' => Some(Access(c as u8 - b'a' + 1)),
+            _ => None,
+        }
+    }
+
+    pub fn readable(&self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn erasable(&self) -> bool {
+        self.0 & 2 != 0
+    }
+
+    pub fn writable(&self) -> bool {
+        self.0 & 4 != 0
+    }
+}
+
+/// `rew` with `-` for each missing permission
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set, c| if set { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.readable(), 'r'),
+            flag(self.erasable(), 'e'),
+            flag(self.writable(), 'w')
+        )
+    }
+}
+
+impl Serialize for Access {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Page {
     #[serde(serialize_with = "to_hex32_string")]
     pub address: u32,
     pub size: u32,
+    /// `None` when the layout string gives no sector type
+    pub access: Option<Access>,
 }
 
 fn to_hex32_string<S>(value: &u32, s: S) -> Result<S::Ok, S::Error>
@@ -16,7 +64,26 @@ where
     s.serialize_str(&format!("0x{:08X}", value))
 }
 
-#[derive(Debug, Serialize)]
+/// `length` bytes at `address` mirroring the memory at `target`, like flash at 0x00000000 on
+/// Cortex-M parts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Alias {
+    pub address: u32,
+    pub length: u32,
+    pub target: u32,
+}
+
+impl Alias {
+    /// Where `address` is in the mirrored memory, `None` outside of the alias
+    pub fn resolve(&self, address: u32) -> Option<u32> {
+        address
+            .checked_sub(self.address)
+            .filter(|offset| *offset < self.length)
+            .and_then(|offset| self.target.checked_add(offset))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct MemoryLayout {
     pages: Vec<Page>,
 }
@@ -42,19 +109,33 @@ impl FromStr for MemoryLayout {
                 .parse()
                 .map_err(|_| Error::MemoryLayout(p.into()))?;
             let valprefix = keyval.next().ok_or_else(|| Error::MemoryLayout(p.into()))?;
-            let size = valprefix.trim_matches(char::is_alphabetic);
-            let prefix = valprefix.trim_matches(char::is_numeric);
-            let mut size: u32 = size.parse().map_err(|_| Error::MemoryLayout(size.into()))?;
-            match &prefix[0..1] {
-                "K" => size *= 1024,
-                "M" => size *= 1024 * 1024,
+            let size = valprefix.trim_end_matches(|c: char| !c.is_ascii_digit());
+            let prefix = valprefix.trim_start_matches(|c: char| c.is_ascii_digit());
+            let size: u32 = size.parse().map_err(|_| Error::MemoryLayout(size.into()))?;
+            let access = prefix.chars().nth(1).and_then(Access::from_letter);
+            let multiplier = match prefix.chars().next() {
+                // A space for plain bytes, as in the option bytes of ST bootloaders
+                Some(' ') => 1,
+                Some('K') => 1024,
+                Some('M') => 1024 * 1024,
                 _ => {
                     return Err(Error::MemoryLayout(format!("Invalid prefix {}", prefix)));
                 }
-            }
+            };
+            let size = size
+                .checked_mul(multiplier)
+                .filter(|size| *size > 0)
+                .ok_or_else(|| Error::MemoryLayout(format!("Invalid page size {}", p)))?;
             for _ in 0..page_count {
-                pages.push(Page { address, size });
-                address += size;
+                pages.push(Page {
+                    address,
+                    size,
+                    access,
+                });
+                // The end of the last page must still be a u32 for the lookups below
+                address = address
+                    .checked_add(size)
+                    .ok_or_else(|| Error::MemoryLayout(format!("Pages beyond 4 GiB in {}", s)))?;
             }
         }
         Ok(Self { pages })
@@ -82,31 +163,57 @@ impl MemoryLayout {
         &self.pages
     }
 
+    /// Return address of the first page
+    pub fn start_address(&self) -> Option<u32> {
+        self.pages.first().map(|p| p.address)
+    }
+
+    /// Return address right after the last page, an error when there are no pages or the last
+    /// one ends at the top of the address space
+    pub fn end_address(&self) -> Result<u32, Error> {
+        let last = self.pages.last().ok_or_else(|| Error::MemoryLayout("no pages".into()))?;
+        last.address.checked_add(last.size).ok_or(Error::Address(last.address))
+    }
+
     /// Return num_pages in region specified
-    pub fn num_pages(&self, mut address: u32, length: u32) -> Result<usize, Error> {
-        let mut pages = 0;
-        let end = address + length;
-        while address < end {
-            let p = self.address(address)?;
-            address += p.size;
-            pages += 1;
+    pub fn num_pages(&self, address: u32, length: u32) -> Result<usize, Error> {
+        Ok(self.pages_in_range(address, length)?.len())
+    }
+
+    /// Pages touched by the region, each with its own address and size. Fails when part of the
+    /// region is not covered by the layout.
+    pub fn pages_in_range(&self, address: u32, length: u32) -> Result<Vec<Page>, Error> {
+        let mut pages = Vec::new();
+        let end = address as u64 + length as u64;
+        let mut at = address;
+        while (at as u64) < end {
+            let p = self.address(at)?;
+            at = p.address.checked_add(p.size).ok_or(Error::Address(at))?;
+            pages.push(p);
         }
         Ok(pages)
     }
 
+    /// Return start address and length of the pages touched by the region
+    pub fn page_range(&self, address: u32, length: u32) -> Result<(u32, u32), Error> {
+        let first = self.address(address)?;
+        let end = address.checked_add(length.max(1) - 1).ok_or(Error::Address(address))?;
+        let last = self.address(end)?;
+        let last_end = last.address.checked_add(last.size).ok_or(Error::Address(last.address))?;
+        Ok((first.address, last_end - first.address))
+    }
+
     pub fn address(&self, address: u32) -> Result<Page, Error> {
         for p in &self.pages {
-            if address >= p.address && address < p.address + p.size {
-                return Ok(Page {
-                    address: p.address,
-                    size: p.size,
-                });
+            if address.checked_sub(p.address).is_some_and(|offset| offset < p.size) {
+                return Ok(p.clone());
             }
         }
         Err(Error::Address(address))
     }
 }
 
+#[allow(clippy::bool_assert_comparison, clippy::iter_nth, clippy::iter_nth_zero)]
 mod tests {
     #[test]
     fn test_memory_address() {
@@ -157,6 +264,28 @@ mod tests {
 
         let n = m.num_pages(0x0801_4000, 0x8000).unwrap();
         assert_eq!(2, n);
+
+        // Unaligned start reaching just into the next page
+        let n = m.num_pages(0x0801_2000, 0x2001).unwrap();
+        assert_eq!(2, n);
+    }
+    #[test]
+    fn test_memory_pages_in_range() {
+        use super::MemoryLayout;
+        use std::str::FromStr;
+        let m = MemoryLayout::from_str("/0x08000000/04*016Kg,01*064Kg,07*128Kg").unwrap();
+        let pages = |address, length| -> Vec<(u32, u32)> {
+            m.pages_in_range(address, length).unwrap().iter().map(|p| (p.address, p.size)).collect()
+        };
+        assert_eq!(Vec::<(u32, u32)>::new(), pages(0x0800_0000, 0));
+        // Last 16K sector, the 64K sector and the first 128K sector
+        assert_eq!(
+            vec![(0x0800_C000, 0x4000), (0x0801_0000, 0x1_0000), (0x0802_0000, 0x2_0000)],
+            pages(0x0800_C000, 0x1_4001)
+        );
+        assert_eq!(vec![(0x0801_0000, 0x1_0000)], pages(0x0801_FFFF, 1));
+        assert_eq!(12, pages(0x0800_0000, 0x10_0000).len());
+        assert!(m.pages_in_range(0x080F_0000, 0x2_0000).is_err());
     }
     #[test]
     fn test_memory_from() {
@@ -174,6 +303,16 @@ mod tests {
         assert_eq!(16384, p.iter().nth(0).unwrap().size);
         assert_eq!(16384, p.iter().nth(1).unwrap().size);
 
+        // Missing prefix, overflowing, empty and wrapping page sizes
+        assert!(MemoryLayout::from_str("/0x08000000/02*16").is_err());
+        assert!(MemoryLayout::from_str("/0x08000000/02*16\u{e9}").is_err());
+        assert!(MemoryLayout::from_str("/0x08000000/01*4096M").is_err());
+        assert!(MemoryLayout::from_str("/0x08000000/01*0K").is_err());
+        assert!(MemoryLayout::from_str("/0xFFFF0000/02*64K").is_err());
+        let m = MemoryLayout::from_str("/0xFFFE0000/01*64K").unwrap();
+        assert!(m.page_range(0xFFFE_0000, u32::MAX).is_err());
+        assert!(m.num_pages(0xFFFE_8000, u32::MAX).is_err());
+
         let m = MemoryLayout::from_str("/0x08010000/02*16K,01*64K");
         assert_eq!(true, m.is_ok());
         let m = m.unwrap();
@@ -183,4 +322,83 @@ mod tests {
         assert_eq!(16384, p.iter().nth(1).unwrap().size);
         assert_eq!(65536, p.iter().nth(2).unwrap().size);
     }
+    #[test]
+    fn test_alias() {
+        use crate::memory_layout::*;
+        let alias = Alias {
+            address: 0,
+            length: 0x10_0000,
+            target: 0x0800_0000,
+        };
+        assert_eq!(Some(0x0800_0000), alias.resolve(0));
+        assert_eq!(Some(0x080F_FFFF), alias.resolve(0xF_FFFF));
+        assert_eq!(None, alias.resolve(0x10_0000));
+        let high = Alias {
+            address: 0x1000,
+            length: 0x100,
+            target: u32::MAX - 0x10,
+        };
+        assert_eq!(None, high.resolve(0x1000 + 0x20));
+        assert_eq!(None, high.resolve(0xFFF));
+    }
+
+    #[test]
+    fn test_memory_start_end() {
+        use super::MemoryLayout;
+        use std::str::FromStr;
+        let m = MemoryLayout::from_str("/0x08010000/02*16K,01*64K").unwrap();
+        assert_eq!(Some(0x0801_0000), m.start_address());
+        assert_eq!(0x0802_8000, m.end_address().unwrap());
+    }
+    #[test]
+    fn test_memory_end_overflow() {
+        use crate::memory_layout::*;
+        // The parser rejects such a layout, the lookups do not rely on it
+        let m = MemoryLayout {
+            pages: vec![Page {
+                address: 0xFFFF_0000,
+                size: 0x1_0000,
+                access: None,
+            }],
+        };
+        assert_eq!(Some(0xFFFF_0000), m.start_address());
+        assert!(matches!(m.end_address(), Err(Error::Address(0xFFFF_0000))));
+        assert!(MemoryLayout { pages: Vec::new() }.end_address().is_err());
+    }
+    #[test]
+    fn test_memory_page_range() {
+        use super::MemoryLayout;
+        use std::str::FromStr;
+        let m = MemoryLayout::from_str("/0x08010000/02*16K,01*64K").unwrap();
+        assert_eq!((0x0801_0000, 0x4000), m.page_range(0x0801_0100, 0x100).unwrap());
+        assert_eq!((0x0801_0000, 0x8000), m.page_range(0x0801_0000, 0x4001).unwrap());
+        assert_eq!((0x0801_4000, 0x14000), m.page_range(0x0801_7000, 0x2000).unwrap());
+        assert!(m.page_range(0x0802_0000, 0x10000).is_err());
+        // Regions wrapping past 4 GiB and pages ending there
+        assert!(matches!(m.page_range(0x0801_0000, u32::MAX), Err(super::Error::Address(0x0801_0000))));
+        let m = MemoryLayout {
+            pages: vec![super::Page {
+                address: 0xFFFF_0000,
+                size: 0x1_0000,
+                access: None,
+            }],
+        };
+        assert!(matches!(m.page_range(0xFFFF_0000, 0x10), Err(super::Error::Address(0xFFFF_0000))));
+    }
+    #[test]
+    fn test_memory_access() {
+        use super::MemoryLayout;
+        use std::str::FromStr;
+        let m = MemoryLayout::from_str("@Internal Flash  /0x08000000/04*016Kg,01*064Ka").unwrap();
+        let a = m.pages()[0].access.unwrap();
+        assert!(a.readable() && a.erasable() && a.writable());
+        assert_eq!("rew", a.to_string());
+        assert_eq!("r--", m.pages()[4].access.unwrap().to_string());
+        let m = MemoryLayout::from_str("/0x08010000/02*16K").unwrap();
+        assert_eq!(None, m.pages()[0].access);
+        // Sizes in plain bytes have a space for the multiplier
+        let m = MemoryLayout::from_str("@Option Bytes  /0x1FFFC000/01*016 e").unwrap();
+        assert_eq!((0x1FFF_C000, 16), (m.pages()[0].address, m.pages()[0].size));
+        assert_eq!("r-w", m.pages()[0].access.unwrap().to_string());
+    }
 }
@@ -0,0 +1,398 @@
+use crate::core::{Dfu, DfuDescriptor, DFU_GET_STATUS};
+use crate::memory_layout::MemoryLayout;
+use crate::status::State;
+use crate::transport::DfuTransport;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Scripted answer to one request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    /// OUT accepted, or IN answered with no data
+    Ok,
+    /// IN answered with these bytes, shorter than requested for a partial read
+    Data(Vec<u8>),
+    /// Fails with `ConnectionReset` like a stalled request
+    Stall,
+    /// Fails with `TimedOut`
+    Timeout,
+}
+
+impl Reply {
+    /// GET_STATUS answer with bStatus `status`, bwPollTimeout 0 and `state`
+    pub fn status(status: u8, state: State) -> Reply {
+        Reply::Data(vec![status, 0, 0, 0, u8::from(&state), 0])
+    }
+}
+
+/// A request seen by the mock
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transfer {
+    pub request: u8,
+    pub value: u16,
+    /// wIndex, the interface number of the mock
+    pub index: u16,
+    /// Data of an OUT request
+    pub data: Option<Vec<u8>>,
+    /// wLength of an IN request
+    pub length: u16,
+}
+
+/// [`DfuTransport`] answering from per request queues of [`Reply`], for testing without hardware.
+/// A request with an empty queue succeeds, GET_STATUS then reports OK in dfuIDLE.
+/// Sleeps return at once and are only recorded.
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    replies: Mutex<HashMap<u8, VecDeque<Reply>>>,
+    transfers: Mutex<Vec<Transfer>>,
+    sleeps: Mutex<Vec<Duration>>,
+    strings: HashMap<u8, String>,
+    interface_number: u8,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        MockTransport::default()
+    }
+
+    /// Queue `reply` for the next unanswered `request`
+    pub fn push(&self, request: u8, reply: Reply) -> &Self {
+        self.replies
+            .lock()
+            .unwrap()
+            .entry(request)
+            .or_default()
+            .push_back(reply);
+        self
+    }
+
+    /// Queue GET_STATUS answers
+    pub fn push_status(&self, status: u8, state: State) -> &Self {
+        self.push(DFU_GET_STATUS, Reply::status(status, state))
+    }
+
+    /// Pose as DFU interface `interface_number` of a composite device
+    pub fn set_interface_number(&mut self, interface_number: u8) {
+        self.interface_number = interface_number;
+    }
+
+    pub fn set_string(&mut self, index: u8, s: &str) {
+        self.strings.insert(index, s.into());
+    }
+
+    /// Requests in the order they were made
+    pub fn transfers(&self) -> Vec<Transfer> {
+        self.transfers.lock().unwrap().clone()
+    }
+
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.sleeps.lock().unwrap().clone()
+    }
+
+    /// Wrap in a [`Dfu`] with `transfer_size` and the DfuSe memory layout `layout`
+    pub fn into_dfu(self, transfer_size: u16, layout: &str) -> Dfu<MockTransport> {
+        let descriptor = DfuDescriptor {
+            attributes: 0x0B,
+            detach_timeout: 255,
+            transfer_size,
+            dfu_version: 0x011A,
+        };
+        let layout = MemoryLayout::from_str(layout).expect("mock memory layout");
+        Dfu::with_transport(self, descriptor, layout)
+    }
+
+    fn reply(&self, transfer: Transfer) -> Option<Reply> {
+        let request = transfer.request;
+        self.transfers.lock().unwrap().push(transfer);
+        self.replies
+            .lock()
+            .unwrap()
+            .get_mut(&request)
+            .and_then(|q| q.pop_front())
+    }
+}
+
+fn fail(reply: &Reply) -> io::Result<()> {
+    match reply {
+        Reply::Stall => Err(io::ErrorKind::ConnectionReset.into()),
+        Reply::Timeout => Err(io::ErrorKind::TimedOut.into()),
+        Reply::Ok | Reply::Data(_) => Ok(()),
+    }
+}
+
+impl DfuTransport for MockTransport {
+    fn interface_number(&self) -> u8 {
+        self.interface_number
+    }
+
+    async fn control_in(&self, request: u8, value: u16, length: u16, _: Duration) -> io::Result<Vec<u8>> {
+        let transfer = Transfer {
+            request,
+            value,
+            index: self.interface_number as u16,
+            data: None,
+            length,
+        };
+        match self.reply(transfer) {
+            Some(Reply::Data(mut data)) => {
+                data.truncate(length as usize);
+                Ok(data)
+            }
+            Some(reply) => fail(&reply).map(|_| Vec::new()),
+            None if request == DFU_GET_STATUS => Ok(vec![0, 0, 0, 0, u8::from(&State::DfuIdle), 0]),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn control_out(&self, request: u8, value: u16, data: &[u8], _: Duration) -> io::Result<()> {
+        let transfer = Transfer {
+            request,
+            value,
+            index: self.interface_number as u16,
+            data: Some(data.to_vec()),
+            length: 0,
+        };
+        match self.reply(transfer) {
+            Some(reply) => fail(&reply),
+            None => Ok(()),
+        }
+    }
+
+    async fn string_descriptor(&self, index: u8, _: Duration) -> io::Result<String> {
+        self.strings
+            .get(&index)
+            .cloned()
+            .ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        self.sleeps.lock().unwrap().push(duration);
+    }
+}
+
+mod tests {
+    #[allow(dead_code)]
+    const LAYOUT: &str = "@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg";
+
+    #[test]
+    fn test_get_status_retries() {
+        use crate::mock::*;
+        use futures_lite::future::block_on;
+        let mock = MockTransport::new();
+        // Partial read, then a valid answer
+        mock.push(DFU_GET_STATUS, Reply::Data(vec![0, 0]))
+            .push_status(0, State::DfuDownloadIdle);
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        let s = block_on(dfu.get_status(3)).unwrap();
+        assert_eq!(u8::from(&State::DfuDownloadIdle), s.state);
+        let policy = dfu.retry_policy().clone();
+        assert_eq!(vec![policy.poll_interval], dfu.transport().sleeps());
+
+        // A stall is cleared from dfuERROR and GET_STATUS sent again
+        let mock = MockTransport::new();
+        mock.push(DFU_GET_STATUS, Reply::Stall)
+            .push_status(0x0F, State::DfuError)
+            .push_status(0, State::DfuIdle)
+            .push_status(0, State::DfuDownloadIdle);
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        let s = block_on(dfu.get_status(3)).unwrap();
+        assert_eq!(u8::from(&State::DfuDownloadIdle), s.state);
+        assert_eq!(vec![policy.backoff(0)], dfu.transport().sleeps());
+        assert_eq!((1, 1), (dfu.stats().stalls, dfu.stats().retries));
+        let requests: Vec<u8> = dfu.transport().transfers().iter().map(|t| t.request).collect();
+        let clear = [DFU_GET_STATUS, crate::core::DFU_CLRSTATUS, DFU_GET_STATUS];
+        assert_eq!([&[DFU_GET_STATUS][..], &clear, &[DFU_GET_STATUS]].concat(), requests);
+
+        // Without retries left the stall is returned
+        let mock = MockTransport::new();
+        mock.push(DFU_GET_STATUS, Reply::Stall);
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        match block_on(dfu.get_status(0)) {
+            Err(crate::Error::USB(_, e)) => assert_eq!(io::ErrorKind::ConnectionReset, e.kind()),
+            r => panic!("expected a stall, got {:?}", r),
+        }
+
+        let mock = MockTransport::new();
+        mock.push(DFU_GET_STATUS, Reply::Timeout);
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        assert!(matches!(block_on(dfu.get_status(0)), Err(crate::Error::USB(_, _))));
+    }
+
+    #[test]
+    fn test_interface_addressing() {
+        use crate::mock::*;
+        use futures_lite::future::block_on;
+        let mut mock = MockTransport::new();
+        mock.set_interface_number(2);
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        assert_eq!(2, dfu.interface_number());
+        block_on(dfu.abort_to_idle()).unwrap();
+        let transfers = dfu.transport().transfers();
+        assert!(transfers.len() >= 2);
+        assert!(transfers.iter().all(|t| t.index == 2));
+    }
+
+    #[test]
+    fn test_stall_backoff() {
+        use crate::core::{DFU_CLRSTATUS, DFU_DNLOAD};
+        use crate::mock::*;
+        use futures_lite::future::block_on;
+        let mock = MockTransport::new();
+        // Two stalls, each cleared from dfuERROR, then Set Address goes through
+        mock.push(DFU_DNLOAD, Reply::Stall).push(DFU_DNLOAD, Reply::Stall);
+        for _ in 0..2 {
+            mock.push_status(0x0F, State::DfuError).push_status(0, State::DfuIdle);
+        }
+        mock.push_status(0, State::DfuDownloadIdle);
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        block_on(dfu.set_address(0x0800_0000)).unwrap();
+        let policy = dfu.retry_policy().clone();
+        assert_eq!(vec![policy.backoff(0), policy.backoff(1)], dfu.transport().sleeps());
+        assert_eq!(policy.stall_delay * 2, policy.backoff(1));
+        assert_eq!(policy.max_stall_delay, policy.backoff(30));
+        let requests: Vec<u8> = dfu.transport().transfers().iter().map(|t| t.request).collect();
+        assert_eq!((2, 2), (dfu.stats().stalls, dfu.stats().retries));
+        assert_eq!(dfu.transport().transfers().len() as u64, dfu.stats().control_transfers);
+        let clear = [DFU_GET_STATUS, DFU_CLRSTATUS, DFU_GET_STATUS];
+        let expected: Vec<u8> = [&[DFU_DNLOAD][..], &clear, &[DFU_DNLOAD], &clear, &[DFU_DNLOAD, DFU_GET_STATUS]].concat();
+        assert_eq!(expected, requests);
+
+        let mock = MockTransport::new();
+        for _ in 0..4 {
+            mock.push(DFU_DNLOAD, Reply::Stall);
+        }
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        assert!(matches!(block_on(dfu.set_address(0x0800_0000)), Err(crate::Error::Stalled(_, 4))));
+    }
+
+    #[test]
+    fn test_wait_until() {
+        use crate::mock::*;
+        use futures_lite::future::block_on;
+        let busy = State::DfuDownloadBusy;
+        let mock = MockTransport::new();
+        mock.push_status(0, busy.clone())
+            .push(DFU_GET_STATUS, Reply::Data(vec![0, 20, 0, 0, u8::from(&busy), 0]))
+            .push_status(0x04, State::DfuError);
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        let idle_or_error = |s: &crate::Status| matches!(State::from(s.state), State::DfuIdle | State::DfuError);
+        let s = block_on(dfu.wait_until(idle_or_error, Duration::from_secs(1))).unwrap();
+        assert_eq!((0x04, State::DfuError), (s.status, State::from(s.state)));
+        // Poll interval without a bwPollTimeout, then the 20 ms reported
+        let interval = dfu.retry_policy().poll_interval;
+        assert_eq!(vec![interval, Duration::from_millis(20)], dfu.transport().sleeps());
+        assert_eq!(interval + Duration::from_millis(20), dfu.stats().busy_time);
+
+        let mock = MockTransport::new();
+        for _ in 0..5 {
+            mock.push_status(0, busy.clone());
+        }
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        let deadline = interval * 5 / 2;
+        match block_on(dfu.wait_until(|s| s.state != u8::from(&busy), deadline)) {
+            Err(crate::Error::WaitTimeout(s)) => assert_eq!(u8::from(&busy), s.state),
+            r => panic!("expected timeout, got {:?}", r),
+        }
+        assert_eq!(vec![interval, interval, interval / 2], dfu.transport().sleeps());
+
+        // A zero poll interval and bwPollTimeout still run into the deadline
+        let mut dfu = MockTransport::new().into_dfu(2048, LAYOUT);
+        dfu.set_retry_policy(crate::RetryPolicy {
+            poll_interval: Duration::ZERO,
+            ..dfu.retry_policy().clone()
+        });
+        let r = block_on(dfu.wait_until(|_| false, Duration::from_millis(3)));
+        assert!(matches!(r, Err(crate::Error::WaitTimeout(_))));
+        assert_eq!(vec![Duration::from_millis(1); 3], dfu.transport().sleeps());
+    }
+
+    #[test]
+    fn test_finish_download() {
+        use crate::core::{DFU_CLRSTATUS, DFU_DNLOAD};
+        use crate::mock::*;
+        use futures_lite::future::block_on;
+        // bitWillDetach without bitManifestationTolerant, manifesting takes 30 ms
+        let mock = MockTransport::new();
+        mock.push_status(0, State::DfuManifestSync)
+            .push(DFU_GET_STATUS, Reply::Data(vec![0, 30, 0, 0, u8::from(&State::DfuManifest), 0]))
+            .push_status(0, State::DfuManifestWaitReset);
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        block_on(dfu.finish_download(5)).unwrap();
+        let transfers = dfu.transport().transfers();
+        assert_eq!((DFU_DNLOAD, 5, Some(vec![])), (transfers[0].request, transfers[0].value, transfers[0].data.clone()));
+        assert!(transfers[1..].iter().all(|t| t.request == DFU_GET_STATUS));
+        assert_eq!(vec![dfu.retry_policy().poll_interval, Duration::from_millis(30)], dfu.transport().sleeps());
+
+        // The image is rejected while manifesting
+        let mut mock = MockTransport::new();
+        mock.set_string(4, "signature mismatch");
+        mock.push_status(0, State::DfuManifest)
+            .push(DFU_GET_STATUS, Reply::Data(vec![0x07, 0, 0, 0, u8::from(&State::DfuError), 4]));
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        match block_on(dfu.finish_download(5)) {
+            Err(e @ crate::Error::Device(..)) => assert_eq!(
+                "Device reported errVERIFY (0x07) during manifestation: signature mismatch",
+                e.to_string()
+            ),
+            r => panic!("expected errVERIFY, got {:?}", r),
+        }
+        // The error status is cleared for the next operation
+        assert_eq!(Some(DFU_CLRSTATUS), dfu.transport().transfers().last().map(|t| t.request));
+    }
+
+    #[test]
+    fn test_status_wait_for() {
+        use crate::mock::*;
+        use futures_lite::future::block_on;
+        let mock = MockTransport::new();
+        mock.push_status(0, State::DfuDownloadBusy)
+            .push_status(0, State::DfuDownloadBusy)
+            .push_status(0, State::DfuDownloadIdle);
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        assert!(block_on(dfu.status_wait_for(2, Some(State::DfuDownloadIdle))).is_ok());
+
+        let mock = MockTransport::new();
+        mock.push_status(0, State::DfuDownloadBusy)
+            .push_status(0, State::DfuDownloadBusy);
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        match block_on(dfu.status_wait_for(1, Some(State::DfuDownloadIdle))) {
+            Err(crate::Error::InvalidState(_, State::DfuDownloadIdle)) => {}
+            r => panic!("expected invalid state, got {:?}", r.map(|s| s.state)),
+        }
+
+        let mock = MockTransport::new();
+        mock.push_status(0x03, State::DfuDownloadIdle);
+        let mut dfu = mock.into_dfu(2048, LAYOUT);
+        assert!(matches!(
+            block_on(dfu.status_wait_for(0, Some(State::DfuDownloadIdle))),
+            Err(crate::Error::InvalidStatus(_, 0))
+        ));
+    }
+
+    #[test]
+    fn test_upload() {
+        use crate::mock::*;
+        use futures_lite::future::block_on;
+        let mock = MockTransport::new();
+        // SET_ADDRESS leaves dfuDNLOAD-IDLE, then ABORT to dfuIDLE
+        mock.push_status(0, State::DfuDownloadBusy)
+            .push(2, Reply::Data(vec![1; 4]))
+            .push(2, Reply::Data(vec![2; 4]))
+            .push(2, Reply::Data(vec![3; 4]));
+        let mut dfu = mock.into_dfu(4, LAYOUT);
+        let mut out = Vec::new();
+        block_on(dfu.upload(&mut out, 0x0800_0000, 10)).unwrap();
+        assert_eq!([vec![1; 4], vec![2; 4], vec![3; 2]].concat(), out);
+        let uploads: Vec<(u16, u16)> = dfu
+            .transport()
+            .transfers()
+            .iter()
+            .filter(|t| t.request == 2)
+            .map(|t| (t.value, t.length))
+            .collect();
+        assert_eq!(vec![(2, 4), (3, 4), (4, 2)], uploads);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::core::Dfu;
+use crate::error::Error;
+use crate::status::State;
+use crate::transport::DfuTransport;
+
+/// Where an STM32 family keeps its option bytes, as read through the DfuSe `@Option Bytes` alt
+/// setting, and the offsets of the fields this crate knows
+#[derive(Debug, Clone, PartialEq)]
+pub struct Family {
+    pub name: &'static str,
+    /// Start of the option bytes
+    pub address: u32,
+    /// Bytes read and written at once
+    pub length: usize,
+    /// Offset of the USER byte
+    pub user: usize,
+    /// Offset of the RDP byte
+    pub rdp: usize,
+    /// Offset of the little endian nWRP half word, a cleared bit protects its sector
+    pub wrp: usize,
+    /// Sectors with a nWRP bit
+    pub sectors: u8,
+    /// Bit fields of the USER byte
+    pub fields: &'static [Field],
+}
+
+/// A bit field of the USER byte, `values` naming each of its values
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: &'static str,
+    pub shift: u8,
+    pub width: u8,
+    pub values: &'static [&'static str],
+}
+
+/// A field of the option bytes with its raw value and what that means
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decoded {
+    pub name: &'static str,
+    pub value: u32,
+    pub meaning: String,
+}
+
+/// Families whose option bytes can be changed, F2 and F4 share the layout of the first bank
+pub const FAMILIES: &[Family] = &[
+    Family {
+        name: "STM32F2/F4",
+        address: 0x1FFF_C000,
+        length: 16,
+        user: 0,
+        rdp: 1,
+        wrp: 8,
+        sectors: 12,
+        fields: &[
+            Field {
+                name: "BOR_LEV",
+                shift: 2,
+                width: 2,
+                values: &["level 3, 2.70 to 3.60 V", "level 2, 2.40 to 2.70 V", "level 1, 2.10 to 2.40 V", "off"],
+            },
+            Field {
+                name: "BFB2",
+                shift: 4,
+                width: 1,
+                values: &["boot from bank 1", "boot from bank 2 (STM32F42x/F43x)"],
+            },
+            Field {
+                name: "WDG_SW",
+                shift: 5,
+                width: 1,
+                values: &["hardware watchdog", "software watchdog"],
+            },
+            Field {
+                name: "nRST_STOP",
+                shift: 6,
+                width: 1,
+                values: &["reset when entering Stop", "no reset"],
+            },
+            Field {
+                name: "nRST_STDBY",
+                shift: 7,
+                width: 1,
+                values: &["reset when entering Standby", "no reset"],
+            },
+        ],
+    },
+];
+
+/// The family whose option bytes start at `address`, the start of the option bytes alt setting
+pub fn family_at(address: u32) -> Option<&'static Family> {
+    FAMILIES.iter().find(|f| f.address == address)
+}
+
+/// Option bytes as read from the device, with the family that says what they mean
+#[derive(Debug, Clone, PartialEq)]
+pub struct OptionBytes {
+    pub family: &'static Family,
+    pub bytes: Vec<u8>,
+}
+
+impl OptionBytes {
+    pub fn new(family: &'static Family, bytes: Vec<u8>) -> Result<Self, Error> {
+        if bytes.len() < family.length {
+            return Err(Error::InvalidControlResponse(format!(
+                "{} bytes of {} option bytes",
+                bytes.len(),
+                family.length
+            )));
+        }
+        Ok(OptionBytes { family, bytes })
+    }
+
+    fn wrp(&self) -> u16 {
+        u16::from_le_bytes([self.bytes[self.family.wrp], self.bytes[self.family.wrp + 1]])
+    }
+
+    /// Readout protection level, 0xAA is level 0, 0xCC level 2 and anything else level 1
+    pub fn rdp_level(&self) -> u8 {
+        match self.bytes[self.family.rdp] {
+            0xAA => 0,
+            0xCC => 2,
+            _ => 1,
+        }
+    }
+
+    /// Every field this crate knows for the family: RDP, the USER byte fields and nWRP
+    pub fn decode(&self) -> Vec<Decoded> {
+        let rdp = self.bytes[self.family.rdp];
+        let mut decoded = vec![Decoded {
+            name: "RDP",
+            value: rdp.into(),
+            meaning: format!("level {}", self.rdp_level()),
+        }];
+        let user = self.bytes[self.family.user];
+        decoded.extend(self.family.fields.iter().map(|f| {
+            let value = (user >> f.shift) & ((1 << f.width) - 1);
+            Decoded {
+                name: f.name,
+                value: value.into(),
+                meaning: f.values.get(value as usize).unwrap_or(&"?").to_string(),
+            }
+        }));
+        let protected = self.write_protected();
+        decoded.push(Decoded {
+            name: "nWRP",
+            value: self.wrp().into(),
+            meaning: match protected.is_empty() {
+                true => "no sector write protected".into(),
+                false => format!("sectors {:?} write protected", protected),
+            },
+        });
+        decoded
+    }
+
+    /// Change the readout protection level. Level 2 can not be left again, going from level 1 to 0
+    /// makes the device mass erase its flash.
+    pub fn set_rdp_level(&mut self, level: u8) -> Result<(), Error> {
+        if self.rdp_level() == 2 {
+            return Err(Error::Argument("RDP level 2 can not be changed".into()));
+        }
+        self.bytes[self.family.rdp] = match level {
+            0 => 0xAA,
+            1 => 0xBB,
+            2 => 0xCC,
+            _ => return Err(Error::Argument(format!("RDP level {} is not 0, 1 or 2", level))),
+        };
+        Ok(())
+    }
+
+    /// Sectors whose nWRP bit is cleared
+    pub fn write_protected(&self) -> Vec<u8> {
+        let wrp = self.wrp();
+        (0..self.family.sectors).filter(|s| wrp & 1 << s == 0).collect()
+    }
+
+    /// Protect `sector` from being erased or written, or lift the protection
+    pub fn set_write_protect(&mut self, sector: u8, protect: bool) -> Result<(), Error> {
+        if sector >= self.family.sectors {
+            return Err(Error::Argument(format!(
+                "{} has sectors 0 to {}, not {}",
+                self.family.name,
+                self.family.sectors - 1,
+                sector
+            )));
+        }
+        let wrp = match protect {
+            true => self.wrp() & !(1 << sector),
+            false => self.wrp() | 1 << sector,
+        };
+        self.bytes[self.family.wrp..self.family.wrp + 2].copy_from_slice(&wrp.to_le_bytes());
+        Ok(())
+    }
+}
+
+impl<T: DfuTransport> Dfu<T> {
+    /// Read the option bytes, the device having been opened on its option bytes alt setting
+    pub async fn read_option_bytes(&mut self) -> Result<OptionBytes, Error> {
+        let address = self.memory_layout().start_address().unwrap_or(0);
+        let family = family_at(address)
+            .ok_or_else(|| Error::Argument(format!("No known option bytes at 0x{:08X}", address)))?;
+        let mut bytes = vec![0; family.length];
+        let len = self.read_flash_to_slice(family.address, &mut bytes).await?;
+        bytes.truncate(len);
+        OptionBytes::new(family, bytes)
+    }
+
+    /// Program `option_bytes`. The bootloader resets to load them and leaves the bus, so the
+    /// device has to be opened again for anything else.
+    pub async fn write_option_bytes(&mut self, option_bytes: &OptionBytes) -> Result<(), Error> {
+        self.abort_to_idle().await?;
+        self.set_address(option_bytes.family.address).await?;
+        self.dfuse_download(&option_bytes.bytes[..option_bytes.family.length], 2).await?;
+        match self.status_wait_for(100, Some(State::DfuDownloadIdle)).await {
+            Ok(_) => {}
+            Err(Error::USB(_, e)) => log::debug!("Device reset to load the option bytes: {}", e),
+            Err(e) => return Err(e),
+        }
+        self.set_detached();
+        Ok(())
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_write_protect() {
+        use crate::option_bytes::*;
+        let family = family_at(0x1FFF_C000).unwrap();
+        let mut bytes = vec![0xFF; 16];
+        bytes[0] = 0xEC;
+        bytes[1] = 0xAA;
+        bytes[8] = 0xFF;
+        bytes[9] = 0x0F;
+        let mut ob = OptionBytes::new(family, bytes).unwrap();
+        assert!(ob.write_protected().is_empty());
+        ob.set_write_protect(0, true).unwrap();
+        ob.set_write_protect(11, true).unwrap();
+        assert_eq!(vec![0, 11], ob.write_protected());
+        assert_eq!((0xFE, 0x07), (ob.bytes[8], ob.bytes[9]));
+        ob.set_write_protect(0, false).unwrap();
+        assert_eq!(vec![11], ob.write_protected());
+        // USER and RDP are left alone
+        assert_eq!((0xEC, 0xAA), (ob.bytes[0], ob.bytes[1]));
+        assert!(ob.set_write_protect(12, true).is_err());
+        assert!(OptionBytes::new(family, vec![0; 8]).is_err());
+        assert!(family_at(0x0800_0000).is_none());
+    }
+
+    #[test]
+    fn test_rdp_level() {
+        use crate::option_bytes::*;
+        let mut bytes = vec![0xFF; 16];
+        bytes[1] = 0xAA;
+        let mut ob = OptionBytes::new(&FAMILIES[0], bytes).unwrap();
+        assert_eq!(0, ob.rdp_level());
+        ob.set_rdp_level(1).unwrap();
+        assert_eq!((1, 0xBB), (ob.rdp_level(), ob.bytes[1]));
+        ob.bytes[1] = 0x00;
+        assert_eq!(1, ob.rdp_level());
+        assert!(ob.set_rdp_level(3).is_err());
+        ob.set_rdp_level(2).unwrap();
+        assert_eq!((2, 0xCC), (ob.rdp_level(), ob.bytes[1]));
+        assert!(ob.set_rdp_level(0).is_err());
+    }
+
+    #[test]
+    fn test_decode() {
+        use crate::option_bytes::*;
+        let mut bytes = vec![0xFF; 16];
+        bytes[0] = 0xE4;
+        bytes[1] = 0xAA;
+        bytes[8] = 0xFC;
+        bytes[9] = 0x0F;
+        let decoded = OptionBytes::new(&FAMILIES[0], bytes).unwrap().decode();
+        let find = |name| decoded.iter().find(|d| d.name == name).unwrap();
+        assert_eq!((0xAA, "level 0"), (find("RDP").value, find("RDP").meaning.as_str()));
+        assert_eq!((1, "level 2, 2.40 to 2.70 V"), (find("BOR_LEV").value, find("BOR_LEV").meaning.as_str()));
+        assert_eq!(1, find("WDG_SW").value);
+        assert_eq!(0, find("BFB2").value);
+        assert_eq!((0x0FFC, "sectors [0, 1] write protected"), (find("nWRP").value, find("nWRP").meaning.as_str()));
+    }
+
+    #[test]
+    fn test_protect_over_dfu() {
+        use crate::DfuseEmulator;
+        use futures_lite::future::block_on;
+        let mut dfu = DfuseEmulator::new("@Option Bytes  /0x1FFFC000/01*016 e", 2048).unwrap().into_dfu();
+        let mut ob = block_on(dfu.read_option_bytes()).unwrap();
+        assert!(ob.write_protected().is_empty());
+        ob.set_write_protect(1, true).unwrap();
+        block_on(dfu.write_option_bytes(&ob)).unwrap();
+        assert_eq!(0xFD, dfu.transport().read(0x1FFF_C008, 1)[0]);
+    }
+}
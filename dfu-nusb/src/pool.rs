@@ -0,0 +1,187 @@
+use crate::core::{AltSetting, Dfu};
+use crate::device_filter::{is_dfu_mode, platform_id, port_path, DeviceFilter};
+use crate::error::Error;
+use crate::transport::{DfuTransport, NusbTransport};
+use futures_lite::FutureExt;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Key of a device in a [`DfuPool`], the serial number if it has one,
+/// otherwise the port path or the platform identifier
+pub fn device_key(dev: &nusb::DeviceInfo) -> String {
+    dev.serial_number()
+        .map(String::from)
+        .or_else(|| port_path(dev))
+        .unwrap_or_else(|| platform_id(dev))
+}
+
+/// Text of a panic payload
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Set of open devices running the same operation with bounded concurrency
+pub struct DfuPool<T: DfuTransport = NusbTransport> {
+    devices: BTreeMap<String, Dfu<T>>,
+    concurrency: usize,
+}
+
+impl<T: DfuTransport> DfuPool<T> {
+    /// Pool running at most `concurrency` operations at a time
+    pub fn new(concurrency: usize) -> Self {
+        DfuPool {
+            devices: BTreeMap::new(),
+            concurrency: concurrency.max(1),
+        }
+    }
+
+    pub fn insert(&mut self, key: String, dfu: Dfu<T>) -> Option<Dfu<T>> {
+        self.devices.insert(key, dfu)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Dfu<T>> {
+        self.devices.remove(key)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Dfu<T>> {
+        self.devices.get_mut(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.devices.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Run `op` on every device, at most `concurrency` at a time, and return the result
+    /// of each device in key order. Devices stay in the pool whatever the outcome, an `op`
+    /// that panics is an error of its device.
+    pub async fn run<R, F>(&mut self, op: F) -> Vec<(String, Result<R, Error>)>
+    where
+        T: Send + 'static,
+        R: Send + 'static,
+        F: for<'a> Fn(&'a mut Dfu<T>) -> BoxFuture<'a, Result<R, Error>> + Send + Sync + 'static,
+    {
+        let op = Arc::new(op);
+        let permits = Arc::new(Semaphore::new(self.concurrency));
+        let tasks: Vec<_> = std::mem::take(&mut self.devices)
+            .into_iter()
+            .map(|(key, mut dfu)| {
+                let (op, permits) = (op.clone(), permits.clone());
+                let task_key = key.clone();
+                let task = tokio::spawn(async move {
+                    let _permit = permits.acquire_owned().await;
+                    let result = AssertUnwindSafe(op(&mut dfu))
+                        .catch_unwind()
+                        .await
+                        .unwrap_or_else(|panic| {
+                            Err(Error::Argument(format!("Task panicked: {}", panic_message(panic.as_ref()))))
+                        });
+                    (task_key, dfu, result)
+                });
+                (key, task)
+            })
+            .collect();
+        let mut results = Vec::with_capacity(tasks.len());
+        for (key, task) in tasks {
+            match task.await {
+                Ok((key, dfu, result)) => {
+                    self.devices.insert(key.clone(), dfu);
+                    results.push((key, result));
+                }
+                Err(e) => {
+                    log::error!("Pool task of {} failed: {}", key, e);
+                    results.push((key, Err(Error::Argument(format!("Pool task failed: {}", e)))));
+                }
+            }
+        }
+        results
+    }
+}
+
+impl DfuPool {
+    /// Open `dev`, e.g. from a [`DfuEvent::Arrived`](crate::DfuEvent), and add it.
+    /// Returns its key, a device already in the pool is replaced.
+    pub async fn add(&mut self, dev: &nusb::DeviceInfo, iface_index: u8, alt: &AltSetting) -> Result<String, Error> {
+        let filter = DeviceFilter::bus_device(dev.bus_number(), dev.device_address());
+        let dfu = Dfu::open(&filter, iface_index, alt).await?;
+        let key = device_key(dev);
+        self.insert(key.clone(), dfu);
+        Ok(key)
+    }
+
+    /// Add every device in DFU mode matching `filter`, returning the keys and errors of
+    /// the ones that could not be opened
+    pub async fn add_all(
+        &mut self,
+        filter: &DeviceFilter,
+        iface_index: u8,
+        alt: &AltSetting,
+    ) -> Result<Vec<(String, Error)>, Error> {
+        let mut failed = Vec::new();
+        for dev in crate::list_dfu_devices(filter)?.iter().filter(|d| is_dfu_mode(d)) {
+            if let Err(e) = self.add(dev, iface_index, alt).await {
+                failed.push((device_key(dev), e));
+            }
+        }
+        Ok(failed)
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_pool_run_empty() {
+        use crate::pool::*;
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let mut pool: DfuPool = DfuPool::new(0);
+        assert!(pool.is_empty());
+        let results = rt.block_on(pool.run(|dfu| Box::pin(dfu.mass_erase())));
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn test_pool_run_panic() {
+        use crate::mock::MockTransport;
+        use crate::pool::*;
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let mut pool = DfuPool::new(2);
+        for interface_number in 0..2 {
+            let mut mock = MockTransport::new();
+            mock.set_interface_number(interface_number);
+            pool.insert(format!("dev{}", interface_number), mock.into_dfu(2048, "/0x08000000/04*016Kg"));
+        }
+        let results = rt.block_on(pool.run(|dfu| {
+            Box::pin(async move {
+                if dfu.interface_number() == 1 {
+                    panic!("lost the device");
+                }
+                Ok(dfu.interface_number())
+            })
+        }));
+        assert_eq!(2, results.len());
+        assert_eq!(("dev0", 0), (results[0].0.as_str(), *results[0].1.as_ref().unwrap()));
+        match &results[1] {
+            (key, Err(Error::Argument(e))) => assert_eq!(("dev1", "Task panicked: lost the device"), (key.as_str(), e.as_str())),
+            (_, r) => panic!("expected a panic, got {:?}", r.as_ref().map(|_| ())),
+        }
+        // Both devices are still in the pool
+        assert_eq!(vec!["dev0", "dev1"], pool.keys().collect::<Vec<_>>());
+    }
+}
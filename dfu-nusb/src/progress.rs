@@ -0,0 +1,87 @@
+use std::fmt;
+
+/// What an operation reporting [`Progress`] is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    Erase,
+    Write,
+    Read,
+    Verify,
+}
+
+impl fmt::Display for Activity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Activity::Erase => write!(f, "erase"),
+            Activity::Write => write!(f, "write"),
+            Activity::Read => write!(f, "read"),
+            Activity::Verify => write!(f, "verify"),
+        }
+    }
+}
+
+/// Passed to the callback of [`Dfu::set_on_progress`](crate::Dfu::set_on_progress) after every
+/// erased page and every block written, read or verified
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub activity: Activity,
+    /// Bytes done of `total`, for erasing the size of the pages erased so far
+    pub done: u32,
+    pub total: u32,
+    /// Start of the page or block just done
+    pub address: u32,
+}
+
+impl Progress {
+    pub fn percent(&self) -> f64 {
+        match self.total {
+            0 => 100.0,
+            total => self.done as f64 * 100.0 / total as f64,
+        }
+    }
+}
+
+/// Callback of [`Dfu::set_on_progress`](crate::Dfu::set_on_progress)
+pub type OnProgress = Box<dyn FnMut(&Progress) + Send + Sync>;
+
+mod tests {
+    #[test]
+    fn test_percent() {
+        use crate::progress::*;
+        let p = Progress {
+            activity: Activity::Write,
+            done: 512,
+            total: 2048,
+            address: 0x0800_0000,
+        };
+        assert_eq!(25.0, p.percent());
+        assert_eq!(100.0, Progress { total: 0, ..p }.percent());
+        assert_eq!("verify", Activity::Verify.to_string());
+    }
+
+    #[test]
+    fn test_progress_events() {
+        use crate::progress::*;
+        use crate::DfuseEmulator;
+        use futures_lite::future::block_on;
+        use std::sync::{Arc, Mutex};
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut dfu = DfuseEmulator::stm32f4().into_dfu();
+        let seen = events.clone();
+        dfu.set_on_progress(move |p| seen.lock().unwrap().push((p.activity, p.done, p.total, p.address)));
+        let data = vec![0x5A; 0x4800];
+        block_on(dfu.download_slice(&data, 0x0800_0000)).unwrap();
+        block_on(dfu.verify_slice(&data, 0x0800_0000)).unwrap();
+        let events = events.lock().unwrap();
+        let of = |activity| events.iter().filter(|e| e.0 == activity).cloned().collect::<Vec<_>>();
+        // Two 16K pages, then 2048 byte blocks
+        assert_eq!(
+            vec![(Activity::Erase, 0x4000, 0x8000, 0x0800_0000), (Activity::Erase, 0x8000, 0x8000, 0x0800_4000)],
+            of(Activity::Erase)
+        );
+        let writes = of(Activity::Write);
+        assert_eq!(9, writes.len());
+        assert_eq!((Activity::Write, 0x4800, 0x4800, 0x0800_4000), writes[8]);
+        assert_eq!(Some(&(Activity::Verify, 0x4800, 0x4800, 0x0800_4000)), of(Activity::Verify).last());
+    }
+}
@@ -0,0 +1,358 @@
+use crate::error::Error;
+use crate::hex::{parse_hex, to_hex};
+use crate::transport::DfuTransport;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+    /// String descriptor, `request` holds its index
+    String,
+}
+
+/// One control transfer and how it ended, a line of a capture such as
+/// `out 01 0000 2100400008 ok` or `in 03 0000 6 !pipe`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exchange {
+    pub direction: Direction,
+    pub request: u8,
+    pub value: u16,
+    /// Data sent by an OUT request
+    pub data: Vec<u8>,
+    /// wLength of an IN request
+    pub length: u16,
+    /// Data received, empty for OUT, or the kind of error
+    pub reply: Result<Vec<u8>, io::ErrorKind>,
+}
+
+/// Data of a capture line, `-` when there is none
+fn format_data(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "-".into();
+    }
+    to_hex(data).to_ascii_lowercase()
+}
+
+fn parse_data(s: &str) -> Option<Vec<u8>> {
+    match s {
+        "-" => Some(Vec::new()),
+        s => parse_hex(s).ok(),
+    }
+}
+
+/// Error kinds a capture can tell apart, everything else is saved as `!error`
+const KINDS: &[(io::ErrorKind, &str)] = &[
+    (io::ErrorKind::ConnectionReset, "stall"),
+    (io::ErrorKind::BrokenPipe, "pipe"),
+    (io::ErrorKind::TimedOut, "timeout"),
+    (io::ErrorKind::NotConnected, "gone"),
+    (io::ErrorKind::Other, "error"),
+];
+
+impl fmt::Display for Exchange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.direction {
+            Direction::In => write!(f, "in {:02x} {:04x} {}", self.request, self.value, self.length)?,
+            Direction::Out => write!(f, "out {:02x} {:04x} {}", self.request, self.value, format_data(&self.data))?,
+            Direction::String => write!(f, "str {:02x}", self.request)?,
+        }
+        match &self.reply {
+            Ok(_) if self.direction == Direction::Out => write!(f, " ok"),
+            Ok(data) => write!(f, " {}", format_data(data)),
+            Err(kind) => {
+                let name = KINDS.iter().find(|(k, _)| k == kind).map_or("error", |(_, n)| n);
+                write!(f, " !{}", name)
+            }
+        }
+    }
+}
+
+impl FromStr for Exchange {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let bad = || Error::Argument(format!("Invalid capture line '{}'", s));
+        let mut sp = s.split_whitespace();
+        let direction = match sp.next() {
+            Some("in") => Direction::In,
+            Some("out") => Direction::Out,
+            Some("str") => Direction::String,
+            _ => return Err(bad()),
+        };
+        let request = sp.next().and_then(|r| u8::from_str_radix(r, 16).ok()).ok_or_else(bad)?;
+        let (mut value, mut data, mut length) = (0, Vec::new(), 0);
+        if direction != Direction::String {
+            value = sp.next().and_then(|v| u16::from_str_radix(v, 16).ok()).ok_or_else(bad)?;
+        }
+        match direction {
+            Direction::In => length = sp.next().and_then(|l| l.parse().ok()).ok_or_else(bad)?,
+            Direction::Out => data = sp.next().and_then(parse_data).ok_or_else(bad)?,
+            Direction::String => {}
+        }
+        let reply = match sp.next().ok_or_else(bad)? {
+            "ok" if direction == Direction::Out => Ok(Vec::new()),
+            r if r.starts_with('!') => Err(KINDS
+                .iter()
+                .find(|(_, n)| *n == &r[1..])
+                .map_or(io::ErrorKind::Other, |(k, _)| *k)),
+            r => Ok(parse_data(r).ok_or_else(bad)?),
+        };
+        if sp.next().is_some() {
+            return Err(bad());
+        }
+        Ok(Exchange {
+            direction,
+            request,
+            value,
+            data,
+            length,
+            reply,
+        })
+    }
+}
+
+/// Passes everything on to `T` and writes each transfer to a capture, one [`Exchange`] per line.
+/// Lines are flushed as they are written so a capture survives a crash.
+pub struct Recorder<T: DfuTransport> {
+    inner: T,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl<T: DfuTransport> Recorder<T> {
+    pub fn new(inner: T, out: impl Write + Send + 'static) -> Self {
+        Recorder {
+            inner,
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    /// Record into a new file at `path`
+    pub fn create(inner: T, path: &Path) -> Result<Self, Error> {
+        let mut file = LineWriter::new(File::create(path)?);
+        writeln!(file, "# dfu-nusb capture, interface {}", inner.interface_number())?;
+        Ok(Recorder::new(inner, file))
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn record<D>(&self, exchange: Exchange, reply: io::Result<D>) -> io::Result<D> {
+        if let Err(e) = writeln!(self.out.lock().unwrap(), "{}", exchange) {
+            log::warn!("Recording transfer failed: {}", e);
+        }
+        reply
+    }
+}
+
+fn outcome(reply: Result<&[u8], &io::Error>) -> Result<Vec<u8>, io::ErrorKind> {
+    match reply {
+        Ok(data) => Ok(data.to_vec()),
+        Err(e) => Err(e.kind()),
+    }
+}
+
+impl<T: DfuTransport> DfuTransport for Recorder<T> {
+    fn interface_number(&self) -> u8 {
+        self.inner.interface_number()
+    }
+
+    async fn control_in(&self, request: u8, value: u16, length: u16, timeout: Duration) -> io::Result<Vec<u8>> {
+        let reply = self.inner.control_in(request, value, length, timeout).await;
+        let exchange = Exchange {
+            direction: Direction::In,
+            request,
+            value,
+            data: Vec::new(),
+            length,
+            reply: outcome(reply.as_ref().map(|d| d.as_slice())),
+        };
+        self.record(exchange, reply)
+    }
+
+    async fn control_out(&self, request: u8, value: u16, data: &[u8], timeout: Duration) -> io::Result<()> {
+        let reply = self.inner.control_out(request, value, data, timeout).await;
+        let exchange = Exchange {
+            direction: Direction::Out,
+            request,
+            value,
+            data: data.to_vec(),
+            length: 0,
+            reply: outcome(reply.as_ref().map(|_| &[][..])),
+        };
+        self.record(exchange, reply)
+    }
+
+    async fn string_descriptor(&self, index: u8, timeout: Duration) -> io::Result<String> {
+        let reply = self.inner.string_descriptor(index, timeout).await;
+        let exchange = Exchange {
+            direction: Direction::String,
+            request: index,
+            value: 0,
+            data: Vec::new(),
+            length: 0,
+            reply: outcome(reply.as_ref().map(|s| s.as_bytes())),
+        };
+        self.record(exchange, reply)
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        self.inner.sleep(duration).await
+    }
+
+    async fn reset(&self) -> io::Result<()> {
+        self.inner.reset().await
+    }
+
+    async fn reopen(&mut self) -> io::Result<()> {
+        self.inner.reopen().await
+    }
+}
+
+/// Answers transfers from a capture made by [`Recorder`], in order. A transfer that does not
+/// match the next line, or comes after the last one, fails so a changed flow shows up.
+/// Sleeps return at once.
+pub struct Replay {
+    interface_number: u8,
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+impl FromStr for Replay {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let exchanges = s
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(Exchange::from_str)
+            .collect::<Result<VecDeque<_>, _>>()?;
+        let interface_number = s
+            .lines()
+            .find_map(|l| l.strip_prefix("# dfu-nusb capture, interface "))
+            .and_then(|i| i.trim().parse().ok())
+            .unwrap_or(0);
+        Ok(Replay {
+            interface_number,
+            exchanges: Mutex::new(exchanges),
+        })
+    }
+}
+
+impl Replay {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Replay::from_str(&std::fs::read_to_string(path)?)
+    }
+
+    /// Lines of the capture not replayed yet
+    pub fn remaining(&self) -> usize {
+        self.exchanges.lock().unwrap().len()
+    }
+
+    fn next(&self, direction: Direction, request: u8, value: u16, data: &[u8], length: u16) -> io::Result<Vec<u8>> {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let got = Exchange {
+            direction,
+            request,
+            value,
+            data: data.to_vec(),
+            length,
+            reply: Ok(Vec::new()),
+        };
+        let Some(expected) = exchanges.front() else {
+            return Err(io::Error::other(format!("Replay ended before '{}'", got)));
+        };
+        if (expected.direction, expected.request, expected.value, &expected.data, expected.length)
+            != (direction, request, value, &got.data, length)
+        {
+            return Err(io::Error::other(format!(
+                "Replay diverged, expected '{}' got '{}'",
+                expected, got
+            )));
+        }
+        let expected = exchanges.pop_front().unwrap_or(got);
+        expected.reply.map_err(io::Error::from)
+    }
+}
+
+impl DfuTransport for Replay {
+    fn interface_number(&self) -> u8 {
+        self.interface_number
+    }
+
+    async fn control_in(&self, request: u8, value: u16, length: u16, _: Duration) -> io::Result<Vec<u8>> {
+        self.next(Direction::In, request, value, &[], length)
+    }
+
+    async fn control_out(&self, request: u8, value: u16, data: &[u8], _: Duration) -> io::Result<()> {
+        self.next(Direction::Out, request, value, data, 0).map(|_| ())
+    }
+
+    async fn string_descriptor(&self, index: u8, _: Duration) -> io::Result<String> {
+        let data = self.next(Direction::String, index, 0, &[], 0)?;
+        String::from_utf8(data).map_err(io::Error::other)
+    }
+
+    async fn sleep(&self, _: Duration) {}
+}
+
+mod tests {
+    #[test]
+    fn test_exchange() {
+        use crate::record::*;
+        for line in [
+            "out 01 0000 2100400008 ok",
+            "in 03 0000 6 000000000500",
+            "in 02 0002 2048 !pipe",
+            "out 06 0000 - !stall",
+            "str 05 !error",
+            "in 02 0000 1024 -",
+        ] {
+            assert_eq!(line, Exchange::from_str(line).unwrap().to_string());
+        }
+        let e = Exchange::from_str("in 03 0000 6 !timeout").unwrap();
+        assert_eq!(Err(io::ErrorKind::TimedOut), e.reply);
+        assert!(Exchange::from_str("in 03 0000 6").is_err());
+        assert!(Exchange::from_str("out 01 0000 210 ok").is_err());
+    }
+
+    #[test]
+    fn test_record_replay() {
+        use crate::record::*;
+        use crate::{DfuDescriptor, DfuseEmulator, Dfu, MemoryLayout};
+        use futures_lite::future::block_on;
+        use std::io::Cursor;
+        let layout = "@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg";
+        let path = std::env::temp_dir().join(format!("dfu-nusb-capture-{}.txt", std::process::id()));
+        let image: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        {
+            let recorder = Recorder::create(DfuseEmulator::stm32f4(), &path).unwrap();
+            let descriptor = DfuDescriptor::from_bytes(&[9, 0x21, 0x0B, 255, 0, 0, 8, 0x1A, 0x01]).unwrap();
+            let mut dfu = Dfu::with_transport(recorder, descriptor, MemoryLayout::from_str(layout).unwrap());
+            block_on(dfu.download_raw(&mut Cursor::new(&image), 0x0800_0000, image.len() as u32)).unwrap();
+        }
+        let replay = || {
+            let descriptor = DfuDescriptor::from_bytes(&[9, 0x21, 0x0B, 255, 0, 0, 8, 0x1A, 0x01]).unwrap();
+            let replay = Replay::open(&path).unwrap();
+            Dfu::with_transport(replay, descriptor, MemoryLayout::from_str(layout).unwrap())
+        };
+        let mut dfu = replay();
+        assert_eq!((0, 0, 2048), (dfu.interface_number(), dfu.alt_setting(), dfu.transfer_size()));
+        block_on(dfu.download_raw(&mut Cursor::new(&image), 0x0800_0000, image.len() as u32)).unwrap();
+
+        // Other data than captured
+        let mut dfu = replay();
+        let other = vec![0; image.len()];
+        match block_on(dfu.download_raw(&mut Cursor::new(&other), 0x0800_0000, other.len() as u32)) {
+            Err(Error::USB(_, e)) => assert!(e.to_string().contains("diverged")),
+            r => panic!("expected divergence, got {:?}", r.err()),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
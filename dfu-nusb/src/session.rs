@@ -0,0 +1,252 @@
+//! Typed sessions following the DfuSe state machine. Only an [`IdleSession`] can start a
+//! download or an upload, and getting one back means finishing the running one, so the
+//! compiler rejects starting an upload while a download is in flight.
+
+use crate::core::Dfu;
+use crate::error::Error;
+use crate::status::Status;
+use crate::transaction::Transaction;
+use crate::transport::DfuTransport;
+
+/// The device is in dfuIDLE
+pub struct IdleSession<'a, T: DfuTransport> {
+    dfu: &'a mut Dfu<T>,
+}
+
+/// DNLOADs at a running address, the device is in dfuIDLE or dfuDNLOAD-IDLE
+pub struct DownloadSession<'a, T: DfuTransport> {
+    dfu: &'a mut Dfu<T>,
+    address: u32,
+}
+
+/// DNLOADs data as it comes in, erasing the pages ahead of it. Writes are collected into whole
+/// transfers, so the pieces can have any size.
+pub struct WriteSession<'a, T: DfuTransport> {
+    dfu: &'a mut Dfu<T>,
+    /// Where `pending` goes
+    address: u32,
+    pending: Vec<u8>,
+    /// End of the pages erased so far
+    erased_to: u32,
+}
+
+/// UPLOADs from a running address, the device is in dfuIDLE or dfuUPLOAD-IDLE
+pub struct UploadSession<'a, T: DfuTransport> {
+    dfu: &'a mut Dfu<T>,
+    address: u32,
+}
+
+impl<T: DfuTransport> Dfu<T> {
+    /// Bring the device to dfuIDLE and start a session there
+    pub async fn session(&mut self) -> Result<IdleSession<'_, T>, Error> {
+        self.abort_to_idle_clear_once().await?;
+        Ok(IdleSession { dfu: self })
+    }
+
+    /// Write data of a size not known up front from `address` on, see [`WriteSession`]
+    pub async fn open_write_session(&mut self, address: u32) -> Result<WriteSession<'_, T>, Error> {
+        let address = self.canonical_address(address);
+        self.memory_layout().address(address)?;
+        let dfu = self.session().await?.dfu;
+        Ok(WriteSession {
+            dfu,
+            address,
+            pending: Vec::new(),
+            erased_to: address,
+        })
+    }
+
+    /// Read from `address` on as far as the caller wants
+    pub async fn open_read_session(&mut self, address: u32) -> Result<UploadSession<'_, T>, Error> {
+        self.session().await?.upload(address).await
+    }
+}
+
+impl<'a, T: DfuTransport> IdleSession<'a, T> {
+    pub async fn status(&mut self) -> Result<Status, Error> {
+        self.dfu.get_status(0).await
+    }
+
+    /// Erase the pages touched by `length` bytes at `address`
+    pub async fn erase(&mut self, address: u32, length: u32) -> Result<(), Error> {
+        self.dfu.erase_pages(address, length).await?;
+        self.dfu.abort_to_idle().await
+    }
+
+    pub async fn mass_erase(&mut self) -> Result<(), Error> {
+        self.dfu.mass_erase().await?;
+        self.dfu.abort_to_idle().await
+    }
+
+    /// Write from `address` on, the pages have to be erased already
+    pub fn download(self, address: u32) -> Result<DownloadSession<'a, T>, Error> {
+        let address = self.dfu.canonical_address(address);
+        self.dfu.memory_layout().address(address)?;
+        Ok(DownloadSession { dfu: self.dfu, address })
+    }
+
+    /// Read from `address` on
+    pub async fn upload(self, address: u32) -> Result<UploadSession<'a, T>, Error> {
+        self.dfu.begin_upload(address).await?;
+        Ok(UploadSession { dfu: self.dfu, address })
+    }
+}
+
+impl<'a, T: DfuTransport> DownloadSession<'a, T> {
+    /// Where the next write goes
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+
+    /// Write `data` at [`Self::address`] and move past it
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        let length = u32::try_from(data.len()).map_err(|_| Error::Address(self.address))?;
+        for chunk in Transaction::new(self.address, length, self.dfu.transfer_size()) {
+            let start = chunk.offset as usize;
+            self.dfu.write_chunk(chunk, &data[start..start + chunk.length as usize]).await?;
+        }
+        self.address = self.address.wrapping_add(length);
+        Ok(())
+    }
+
+    /// Return to dfuIDLE
+    pub async fn finish(self) -> Result<IdleSession<'a, T>, Error> {
+        self.dfu.abort_to_idle().await?;
+        Ok(IdleSession { dfu: self.dfu })
+    }
+}
+
+impl<'a, T: DfuTransport> WriteSession<'a, T> {
+    /// Where the next piece goes, past the ones still collected
+    pub fn address(&self) -> u32 {
+        self.address.wrapping_add(self.pending.len() as u32)
+    }
+
+    /// Take `data`, all full transfers collected so far are written right away
+    pub async fn write_chunk(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.pending.extend_from_slice(data);
+        let size = self.dfu.transfer_size() as usize;
+        let full = self.pending.len() / size * size;
+        if full > 0 {
+            self.flush(full).await?;
+        }
+        Ok(())
+    }
+
+    /// Write the first `length` bytes of `pending`
+    async fn flush(&mut self, length: usize) -> Result<(), Error> {
+        let size = u32::try_from(length).map_err(|_| Error::Address(self.address))?;
+        let end = self.address.checked_add(size).ok_or(Error::Address(self.address))?;
+        if end > self.erased_to {
+            let pages = self.dfu.memory_layout().pages_in_range(self.erased_to, end - self.erased_to)?;
+            for page in pages {
+                self.dfu.erase_page(page.address).await?;
+                self.erased_to = page.address + page.size;
+            }
+        }
+        for chunk in Transaction::new(self.address, size, self.dfu.transfer_size()) {
+            let start = chunk.offset as usize;
+            self.dfu.write_chunk(chunk, &self.pending[start..start + chunk.length as usize]).await?;
+        }
+        self.pending.drain(..length);
+        self.address = end;
+        Ok(())
+    }
+
+    /// Write what is left and return to dfuIDLE
+    pub async fn finish(mut self) -> Result<IdleSession<'a, T>, Error> {
+        if !self.pending.is_empty() {
+            self.flush(self.pending.len()).await?;
+        }
+        self.dfu.abort_to_idle().await?;
+        Ok(IdleSession { dfu: self.dfu })
+    }
+}
+
+impl<'a, T: DfuTransport> UploadSession<'a, T> {
+    /// Where the next read starts
+    pub fn address(&self) -> u32 {
+        self.address
+    }
+
+    /// Fill `buf` from [`Self::address`] and move past it. Returns fewer bytes only when the
+    /// device sent a short UPLOAD, e.g. at the end of its memory.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let size = self.dfu.transfer_size() as usize;
+        let mut done = 0;
+        while done < buf.len() {
+            let len = (buf.len() - done).min(size);
+            let read = self.dfu.read_at(self.address, &mut buf[done..done + len]).await?;
+            done += read;
+            self.address = self.address.wrapping_add(read as u32);
+            if read < len {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// Return to dfuIDLE
+    pub async fn finish(self) -> Result<IdleSession<'a, T>, Error> {
+        self.dfu.end_session().await?;
+        Ok(IdleSession { dfu: self.dfu })
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_sessions() {
+        use crate::emulator::DfuseEmulator;
+        use futures_lite::future::block_on;
+        let image: Vec<u8> = (0..10000u32).map(|i| (i % 249) as u8).collect();
+        let mut dfu = DfuseEmulator::stm32f4().into_dfu();
+        block_on(async {
+            let mut idle = dfu.session().await?;
+            idle.erase(0x0800_4000, image.len() as u32).await?;
+            let mut download = idle.download(0x0800_4000)?;
+            // Pieces that do not line up with the 2048 byte transfers
+            download.write(&image[..3000]).await?;
+            download.write(&image[3000..]).await?;
+            assert_eq!(0x0800_4000 + 10000, download.address());
+            let idle = download.finish().await?;
+
+            let mut upload = idle.upload(0x0800_4000).await?;
+            let mut out = vec![0; image.len()];
+            assert_eq!(4096, upload.read(&mut out[..4096]).await?);
+            assert_eq!(1000, upload.read(&mut out[4096..5096]).await?);
+            assert_eq!(4904, upload.read(&mut out[5096..]).await?);
+            assert_eq!(image, out);
+            upload.finish().await?.status().await
+        })
+        .unwrap();
+        assert_eq!(image, dfu.transport().read(0x0800_4000, image.len()));
+        assert_eq!(crate::State::DfuIdle, dfu.transport().state());
+    }
+
+    #[test]
+    fn test_write_session() {
+        use crate::emulator::DfuseEmulator;
+        use futures_lite::future::block_on;
+        let image: Vec<u8> = (0..20000u32).map(|i| (i % 241) as u8).collect();
+        let mut dfu = DfuseEmulator::stm32f4().into_dfu();
+        block_on(async {
+            let mut write = dfu.open_write_session(0x0800_4000).await?;
+            // A producer handing out odd sized pieces, crossing into the second 16K page
+            for piece in image.chunks(700) {
+                write.write_chunk(piece).await?;
+            }
+            assert_eq!(0x0800_4000 + 20000, write.address());
+            write.finish().await?;
+
+            let mut read = dfu.open_read_session(0x0800_4000).await?;
+            let mut out = vec![0; image.len()];
+            assert_eq!(image.len(), read.read(&mut out).await?);
+            assert_eq!(image, out);
+            read.finish().await.map(|_| ())
+        })
+        .unwrap();
+        let emu = dfu.transport();
+        assert_eq!(vec![0x0800_4000, 0x0800_8000], emu.erased_pages());
+        assert_eq!(crate::State::DfuIdle, emu.state());
+    }
+}
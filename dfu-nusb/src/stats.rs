@@ -0,0 +1,109 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Counters of a [`Dfu`](crate::Dfu) since it was opened or its stats were reset
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransferStats {
+    /// Requests sent to the DFU interface, GET_STATUS polls included
+    pub control_transfers: u64,
+    /// Bytes received by UPLOAD
+    pub bytes_read: u64,
+    /// Firmware bytes sent by DNLOAD, DfuSe commands not counted
+    pub bytes_written: u64,
+    /// Requests the device stalled
+    pub stalls: u32,
+    /// Requests repeated after a stall or a failed GET_STATUS
+    pub retries: u32,
+    /// Slept waiting for a busy device, bwPollTimeout and poll intervals between GET_STATUS
+    pub busy_time: Duration,
+    /// Fastest data block in KB/s, timed from the block before it, 0 on wasm32
+    pub peak_rate: f64,
+}
+
+/// What one operation transferred and how long it took
+#[derive(Debug, Clone, PartialEq)]
+pub struct OperationSummary {
+    pub elapsed: Duration,
+    pub stats: TransferStats,
+}
+
+fn rate(bytes: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        bytes as f64 / 1024.0 / secs
+    } else {
+        0.0
+    }
+}
+
+impl OperationSummary {
+    pub fn new(stats: TransferStats, elapsed: Duration) -> Self {
+        OperationSummary { elapsed, stats }
+    }
+
+    /// Effective read speed in KB/s over the whole operation
+    pub fn read_rate(&self) -> f64 {
+        rate(self.stats.bytes_read, self.elapsed)
+    }
+
+    /// Effective write speed in KB/s over the whole operation, erasing included
+    pub fn write_rate(&self) -> f64 {
+        rate(self.stats.bytes_written, self.elapsed)
+    }
+
+    /// Bytes read and written per second in KB/s over the whole operation
+    pub fn average_rate(&self) -> f64 {
+        rate(self.stats.bytes_read + self.stats.bytes_written, self.elapsed)
+    }
+
+    /// Time not spent waiting for a busy device
+    pub fn transfer_time(&self) -> Duration {
+        self.elapsed.saturating_sub(self.stats.busy_time)
+    }
+}
+
+impl fmt::Display for OperationSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = &self.stats;
+        if s.bytes_read > 0 {
+            write!(f, "Read {} bytes at {:.1} KB/s, ", s.bytes_read, self.read_rate())?;
+        }
+        if s.bytes_written > 0 {
+            write!(f, "Wrote {} bytes at {:.1} KB/s, ", s.bytes_written, self.write_rate())?;
+        }
+        write!(
+            f,
+            "{} control transfers, {} stalls, {} retries in {:.2} s",
+            s.control_transfers,
+            s.stalls,
+            s.retries,
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_operation_summary() {
+        use crate::stats::*;
+        let stats = TransferStats {
+            control_transfers: 12,
+            bytes_read: 2048,
+            bytes_written: 0,
+            stalls: 1,
+            retries: 2,
+            busy_time: Duration::from_millis(200),
+            peak_rate: 8.0,
+        };
+        let summary = OperationSummary::new(stats, Duration::from_millis(500));
+        assert_eq!(4.0, summary.read_rate());
+        assert_eq!(0.0, summary.write_rate());
+        assert_eq!(4.0, summary.average_rate());
+        assert_eq!(Duration::from_millis(300), summary.transfer_time());
+        assert_eq!(
+            "Read 2048 bytes at 4.0 KB/s, 12 control transfers, 1 stalls, 2 retries in 0.50 s",
+            summary.to_string()
+        );
+        assert_eq!(0.0, OperationSummary::new(TransferStats::default(), Duration::ZERO).read_rate());
+    }
+}
@@ -1,8 +1,12 @@
 use crate::core::*;
 use crate::error::Error;
-use std::fmt;
-use nusb;
+use crate::transport::DfuTransport;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::timed;
+#[cfg(not(target_arch = "wasm32"))]
 use nusb::transfer::{ControlIn, ControlType, Recipient};
+use std::fmt;
+use std::time::Duration;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum State {
@@ -79,9 +83,33 @@ impl fmt::Display for State {
         }
     }
 }
+/// Name of a bStatus value as given in the DFU 1.1 specification
+pub fn status_name(status: u8) -> &'static str {
+    match status {
+        0x00 => "OK",
+        0x01 => "errTARGET",
+        0x02 => "errFILE",
+        0x03 => "errWRITE",
+        0x04 => "errERASE",
+        0x05 => "errCHECK_ERASED",
+        0x06 => "errPROG",
+        0x07 => "errVERIFY",
+        0x08 => "errADDRESS",
+        0x09 => "errNOTDONE",
+        0x0A => "errFIRMWARE",
+        0x0B => "errVENDOR",
+        0x0C => "errUSBR",
+        0x0D => "errPOR",
+        0x0E => "errUNKNOWN",
+        0x0F => "errSTALLEDPKT",
+        _ => "reserved",
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Status {
     pub status: u8,
+    /// bwPollTimeout in milliseconds
     pub poll_timeout: usize,
     pub state: u8,
     pub string_index: u8,
@@ -96,31 +124,80 @@ impl fmt::Display for Status {
 }
 
 impl Status {
-    pub async fn get(interface: &nusb::Interface) -> Result<Self, Error> {
-        let mut s = Self::default();
-        let data: Vec<u8> = interface.control_in(ControlIn {
+    /// GET_STATUS over `transport`, addressed to its claimed interface
+    pub async fn get<T: DfuTransport>(transport: &T, timeout: Duration) -> Result<Self, Error> {
+        let data: Vec<u8> = transport
+            .control_in(DFU_GET_STATUS, 0, 6, timeout)
+            .await
+            .map_err(|e| Error::USB("Control transfer: DFU_GET_STATUS".into(), e))?;
+        Self::from_bytes(&data)
+    }
+
+    /// GET_STATUS of the DFU interface `interface` without a [`Dfu`], e.g. one of several
+    /// interfaces of a composite device. wIndex is its bInterfaceNumber like for every request
+    /// a [`DfuTransport`] sends, so another interface than 0 answers.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn get_interface(interface: &nusb::Interface, timeout: Duration) -> Result<Self, Error> {
+        let transfer = interface.control_in(ControlIn {
             control_type: ControlType::Class,
             recipient: Recipient::Interface,
             request: DFU_GET_STATUS,
             value: 0,
-            index: 0,
+            index: interface.interface_number() as u16,
             length: 6,
-        }).await.into_result().map_err(|e| Error::USB("Control transfer: DFU_GET_STATUS".into(), e.into()))?;
+        });
+        let data = timed(timeout, transfer)
+            .await
+            .map_err(|e| Error::USB(format!("DFU_GET_STATUS on interface {}", interface.interface_number()), e))?;
+        Self::from_bytes(&data)
+    }
 
-        let mut data = data.iter();
-        if data.len() != 6 {
-            return Err(Error::InvalidControlResponse(format!(
+    /// Decode the 6 byte GET_STATUS answer, bwPollTimeout is 24 bits little endian
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        match *data {
+            [status, t0, t1, t2, state, string_index] => Ok(Status {
+                status,
+                poll_timeout: u32::from_le_bytes([t0, t1, t2, 0]) as usize,
+                state,
+                string_index,
+            }),
+            _ => Err(Error::InvalidControlResponse(format!(
                 "Status length was {}",
                 data.len()
-            )));
+            ))),
         }
-        s.status = *(data.next().unwrap_or(&0_u8));
-        s.poll_timeout = ((*(data.next().unwrap_or(&0_u8)) as usize) << 16) as usize;
-        s.poll_timeout |= ((*(data.next().unwrap_or(&0_u8)) as usize) << 8) as usize;
-        s.poll_timeout |= (*(data.next().unwrap_or(&0_u8))) as usize;
-        s.state = *(data.next().unwrap_or(&0_u8));
-        s.string_index = *(data.next().unwrap_or(&0_u8));
+    }
+
+    /// How long the host should wait before the next GET_STATUS
+    pub fn poll_timeout(&self) -> Duration {
+        Duration::from_millis(self.poll_timeout as u64)
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_status_name() {
+        use crate::status::*;
+        assert_eq!("OK", status_name(0));
+        assert_eq!("errCHECK_ERASED", status_name(5));
+        assert_eq!("errSTALLEDPKT", status_name(0x0F));
+        assert_eq!("reserved", status_name(0x10));
+    }
 
-        Ok(s)
+    #[test]
+    fn test_status_from_bytes() {
+        use crate::status::*;
+        // dfuDNLOAD-BUSY after a page erase with 100 ms
+        let s = Status::from_bytes(&[0x00, 0x64, 0x00, 0x00, 0x04, 0x00]).unwrap();
+        assert_eq!(Duration::from_millis(100), s.poll_timeout());
+        assert_eq!(State::DfuDownloadBusy, State::from(s.state));
+        assert_eq!(Duration::from_millis(10_000), Status::from_bytes(&[0, 0x10, 0x27, 0, 4, 0]).unwrap().poll_timeout());
+        assert_eq!(0x03_0201, Status::from_bytes(&[0, 1, 2, 3, 4, 0]).unwrap().poll_timeout);
+        // errWRITE in dfuERROR with an iString
+        let s = Status::from_bytes(&[0x03, 0, 0, 0, 0x0A, 0x05]).unwrap();
+        assert_eq!((0x03, State::DfuError, 5), (s.status, State::from(s.state), s.string_index));
+        assert_eq!(Duration::ZERO, s.poll_timeout());
+        assert!(Status::from_bytes(&[0, 0, 0, 0, 2]).is_err());
+        assert!(Status::from_bytes(&[0; 7]).is_err());
     }
 }
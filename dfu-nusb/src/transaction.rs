@@ -0,0 +1,138 @@
+/// One DNLOAD or UPLOAD of a [`Transaction`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chunk {
+    /// wBlockNum, 2 for the first chunk after Set Address to `base`
+    pub block: u16,
+    /// Address to send with Set Address before this chunk
+    pub base: u32,
+    /// Where the chunk starts, always `base + (block - 2) * transfer_size`
+    pub address: u32,
+    /// Bytes before this chunk
+    pub offset: u32,
+    pub length: u16,
+}
+
+/// Splits `length` bytes at `address` into DfuSe transfers of at most `transfer_size` bytes.
+/// wBlockNum starts at 2 since 0 carries commands and 1 is reserved. Once it would overflow the
+/// planner starts over at 2 from the next address, the caller has to Set Address to the new base.
+/// A range ending above 4 GiB is cut at the end of the address space.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    block: u16,
+    base: u32,
+    address: u32,
+    offset: u32,
+    pending: u32,
+    transfer_size: u16,
+}
+
+impl Transaction {
+    pub fn new(address: u32, length: u32, transfer_size: u16) -> Self {
+        let room = (1u64 << 32) - address as u64;
+        Transaction {
+            block: 2,
+            base: address,
+            address,
+            offset: 0,
+            pending: (length as u64).min(room) as u32,
+            transfer_size: transfer_size.max(1),
+        }
+    }
+}
+
+impl Iterator for Transaction {
+    type Item = Chunk;
+
+    fn next(&mut self) -> Option<Chunk> {
+        if self.pending == 0 {
+            return None;
+        }
+        let length = self.pending.min(self.transfer_size as u32) as u16;
+        let chunk = Chunk {
+            block: self.block,
+            base: self.base,
+            address: self.address,
+            offset: self.offset,
+            length,
+        };
+        self.pending -= length as u32;
+        self.offset += length as u32;
+        self.address = self.address.wrapping_add(length as u32);
+        if self.block == u16::MAX {
+            self.block = 2;
+            self.base = self.address;
+        } else {
+            self.block += 1;
+        }
+        Some(chunk)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.pending.div_ceil(self.transfer_size as u32) as usize;
+        (n, Some(n))
+    }
+}
+
+impl ExactSizeIterator for Transaction {}
+
+mod tests {
+    #[test]
+    fn test_transaction() {
+        use crate::transaction::*;
+        let chunks: Vec<(u16, u32, u16)> = Transaction::new(0x100, 10, 4).map(|c| (c.block, c.address, c.length)).collect();
+        assert_eq!(vec![(2, 0x100, 4), (3, 0x104, 4), (4, 0x108, 2)], chunks);
+        assert_eq!(0, Transaction::new(0x100, 0, 4).count());
+        assert_eq!(1, Transaction::new(0x100, 4, 4).count());
+        // Zero transfer size still makes progress
+        assert_eq!(3, Transaction::new(0, 3, 0).count());
+        // Cut at the end of the address space
+        let last = Transaction::new(0xFFFF_FFF0, 0x100, 8).last().unwrap();
+        assert_eq!((0xFFFF_FFF8, 8), (last.address, last.length));
+        // wBlockNum wraps to 2 with a new base
+        let mut t = Transaction::new(0, 70000, 1).skip(65533);
+        assert_eq!(Some((u16::MAX, 0, 65533)), t.next().map(|c| (c.block, c.base, c.address)));
+        assert_eq!(Some((2, 65534, 65534)), t.next().map(|c| (c.block, c.base, c.address)));
+    }
+
+    #[test]
+    fn test_transfer_sizes() {
+        use crate::transaction::*;
+        // Tiny, power of two and odd sizes over a length that is no multiple of any of them
+        for (size, full, last) in [(64, 156, 16), (1024, 9, 784), (2048, 4, 1808), (1000, 10, 0), (1023, 9, 793)] {
+            let lengths: Vec<u16> = Transaction::new(0x0800_0100, 10000, size).map(|c| c.length).collect();
+            let mut expected = vec![size; full];
+            if last > 0 {
+                expected.push(last);
+            }
+            assert_eq!(expected, lengths, "transfer size {}", size);
+        }
+    }
+
+    #[test]
+    fn test_transaction_invariants() {
+        use crate::transaction::*;
+        use proptest::prelude::*;
+        proptest!(|(address in any::<u32>(), length in 0u32..200_000, transfer_size in 0u16..=4096)| {
+            let t = Transaction::new(address, length, transfer_size);
+            let expected = length.min((0x1_0000_0000u64 - address as u64) as u32);
+            let xfer = transfer_size.max(1);
+            prop_assert_eq!(expected.div_ceil(xfer as u32) as usize, t.len());
+            let chunks: Vec<Chunk> = t.collect();
+            let mut offset = 0u32;
+            for (i, c) in chunks.iter().enumerate() {
+                // Contiguous, in order and without overlap
+                prop_assert_eq!(offset, c.offset);
+                prop_assert_eq!(address as u64 + offset as u64, c.address as u64);
+                prop_assert!(c.block >= 2);
+                prop_assert_eq!(c.base as u64 + (c.block as u64 - 2) * xfer as u64, c.address as u64);
+                // Only the last chunk may be short
+                prop_assert!(c.length >= 1 && c.length <= xfer);
+                if i + 1 < chunks.len() {
+                    prop_assert_eq!(xfer, c.length);
+                }
+                offset += c.length as u32;
+            }
+            prop_assert_eq!(expected, offset);
+        });
+    }
+}
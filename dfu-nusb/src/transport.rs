@@ -0,0 +1,320 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::device_lock::DeviceLock;
+#[cfg(not(target_arch = "wasm32"))]
+use nusb::descriptors::language_id::US_ENGLISH;
+#[cfg(not(target_arch = "wasm32"))]
+use nusb::transfer::{ControlIn, ControlOut, ControlType, Recipient};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::device_filter::DeviceFilter;
+
+/// How long [`NusbTransport::reopen`](DfuTransport::reopen) waits for the device to come back
+#[cfg(not(target_arch = "wasm32"))]
+const REOPEN_TIMEOUT: Duration = Duration::from_secs(5);
+#[cfg(not(target_arch = "wasm32"))]
+const REOPEN_POLL: Duration = Duration::from_millis(200);
+
+/// What [`Dfu`](crate::Dfu) needs from the USB stack: class requests to its claimed DFU interface,
+/// string descriptors and a timer. Opening and claiming is left to each backend's constructor.
+/// A stalled request fails with [`io::ErrorKind::ConnectionReset`], one not completing within
+/// `timeout` with [`io::ErrorKind::TimedOut`].
+pub trait DfuTransport {
+    /// bInterfaceNumber, sent as wIndex of every request
+    fn interface_number(&self) -> u8;
+
+    fn control_in(
+        &self,
+        request: u8,
+        value: u16,
+        length: u16,
+        timeout: Duration,
+    ) -> impl Future<Output = io::Result<Vec<u8>>>;
+
+    fn control_out(
+        &self,
+        request: u8,
+        value: u16,
+        data: &[u8],
+        timeout: Duration,
+    ) -> impl Future<Output = io::Result<()>>;
+
+    /// String descriptor `index` in the language of the device, such as the iString of a DFU status
+    fn string_descriptor(&self, index: u8, timeout: Duration) -> impl Future<Output = io::Result<String>>;
+
+    fn sleep(&self, duration: Duration) -> impl Future<Output = ()>;
+
+    /// USB bus reset of the device, for one that waits for it after manifestation
+    fn reset(&self) -> impl Future<Output = io::Result<()>> {
+        async { Err(io::ErrorKind::Unsupported.into()) }
+    }
+
+    /// Open the same physical device again after it dropped off the bus, claiming the interface
+    /// and selecting the alt setting it had
+    fn reopen(&mut self) -> impl Future<Output = io::Result<()>> {
+        async { Err(io::ErrorKind::Unsupported.into()) }
+    }
+}
+
+/// Text of a raw string descriptor: bLength, bDescriptorType 3 and UTF-16LE code units
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+pub(crate) fn parse_string_descriptor(desc: &[u8]) -> Option<String> {
+    let len = (*desc.first()? as usize).min(desc.len());
+    if len < 2 || desc[1] != 3 {
+        return None;
+    }
+    let units: Vec<u16> = desc[2..len]
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    Some(String::from_utf16_lossy(&units))
+}
+
+/// Log target of the control transfer dumps, only enabled at the highest verbosity
+pub const WIRE_TARGET: &str = "dfu_nusb::wire";
+
+/// A class request to interface `index` as seen on the wire: the setup packet, the data sent or
+/// received as a hex dump and how long it took
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub(crate) fn wire_dump(
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    result: Result<&[u8], &io::Error>,
+    elapsed: Duration,
+) -> String {
+    use std::fmt::Write;
+    let direction = if request_type & 0x80 != 0 { "IN " } else { "OUT" };
+    let mut out = format!(
+        "{} setup {:02x} {:02x} {:04x} {:04x} {:04x} {:.3} ms",
+        direction,
+        request_type,
+        request,
+        value,
+        index,
+        length,
+        elapsed.as_secs_f64() * 1000.0
+    );
+    match result {
+        Ok(data) => {
+            for (i, row) in data.chunks(16).enumerate() {
+                let _ = write!(out, "\n  {:04x}:", i * 16);
+                for b in row {
+                    let _ = write!(out, " {:02x}", b);
+                }
+            }
+        }
+        Err(e) => {
+            let _ = write!(out, " failed: {}", e);
+        }
+    }
+    out
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub type DefaultTransport = NusbTransport;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub type DefaultTransport = crate::webusb::WebUsbTransport;
+
+/// Claimed interface of a device opened with nusb, timed with tokio
+#[cfg(not(target_arch = "wasm32"))]
+pub struct NusbTransport {
+    device: nusb::Device,
+    interface: nusb::Interface,
+    _lock: Option<DeviceLock>,
+    /// The device by port path or serial number and the alt setting, to find it again
+    origin: Option<(DeviceFilter, u8)>,
+    /// LANGID string descriptors are read in
+    language: u16,
+}
+
+/// The first LANGID the device lists in string descriptor 0, US English if it lists none
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn default_language(device: &nusb::Device) -> u16 {
+    match device.get_string_descriptor_supported_languages(Duration::from_secs(1)) {
+        Ok(mut languages) => languages.next().unwrap_or(US_ENGLISH),
+        Err(e) => {
+            log::debug!("Reading the supported languages failed: {}, assuming US English", e);
+            US_ENGLISH
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl NusbTransport {
+    /// Strings are read in `language`, the first one the device supports when `None`
+    pub fn new(device: nusb::Device, interface: nusb::Interface, language: Option<u16>) -> Self {
+        NusbTransport {
+            language: language.unwrap_or_else(|| default_language(&device)),
+            device,
+            interface,
+            _lock: None,
+            origin: None,
+        }
+    }
+
+    /// LANGID string descriptors are read in, the first one the device supports by default
+    pub fn language(&self) -> u16 {
+        self.language
+    }
+
+    pub fn set_language(&mut self, language: u16) {
+        self.language = language;
+    }
+
+    /// Hold `lock` for as long as the device is open
+    pub(crate) fn set_lock(&mut self, lock: DeviceLock) {
+        self._lock = Some(lock);
+    }
+
+    /// Remember `dev` with alt setting `alt` for [`reopen`](DfuTransport::reopen). The port
+    /// path stays the same across a re-enumeration, the bus address does not.
+    pub(crate) fn set_origin(&mut self, dev: &nusb::DeviceInfo, alt: u8) {
+        let mut filter = DeviceFilter::vid_pid(dev.vendor_id(), dev.product_id());
+        match crate::device_filter::port_path(dev) {
+            Some(port) => filter.port = Some(port),
+            None => filter.serial = dev.serial_number().map(String::from),
+        }
+        self.origin = Some((filter, alt));
+    }
+
+    pub fn device(&self) -> &nusb::Device {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut nusb::Device {
+        &mut self.device
+    }
+
+    pub fn interface(&self) -> &nusb::Interface {
+        &self.interface
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn timed<T>(
+    timeout: Duration,
+    transfer: impl Future<Output = nusb::transfer::Completion<T>>,
+) -> io::Result<T> {
+    match tokio::time::timeout(timeout, transfer).await {
+        Ok(completion) => Ok(completion.into_result()?),
+        Err(_) => {
+            log::warn!("Control transfer timed out after {:?}", timeout);
+            Err(io::ErrorKind::TimedOut.into())
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl DfuTransport for NusbTransport {
+    fn interface_number(&self) -> u8 {
+        self.interface.interface_number()
+    }
+
+    async fn control_in(&self, request: u8, value: u16, length: u16, timeout: Duration) -> io::Result<Vec<u8>> {
+        let index = self.interface_number() as u16;
+        let transfer = self.interface.control_in(ControlIn {
+            control_type: ControlType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value,
+            index,
+            length,
+        });
+        let started = std::time::Instant::now();
+        let res = timed(timeout, transfer).await;
+        if log::log_enabled!(target: WIRE_TARGET, log::Level::Trace) {
+            let dump = wire_dump(0xA1, request, value, index, length, res.as_deref(), started.elapsed());
+            log::trace!(target: WIRE_TARGET, "{}", dump);
+        }
+        res
+    }
+
+    async fn control_out(&self, request: u8, value: u16, data: &[u8], timeout: Duration) -> io::Result<()> {
+        let index = self.interface_number() as u16;
+        let transfer = self.interface.control_out(ControlOut {
+            control_type: ControlType::Class,
+            recipient: Recipient::Interface,
+            request,
+            value,
+            index,
+            data,
+        });
+        let started = std::time::Instant::now();
+        let res = timed(timeout, transfer).await.map(|_| ());
+        if log::log_enabled!(target: WIRE_TARGET, log::Level::Trace) {
+            let sent = res.as_ref().map(|_| data);
+            let dump = wire_dump(0x21, request, value, index, data.len() as u16, sent, started.elapsed());
+            log::trace!(target: WIRE_TARGET, "{}", dump);
+        }
+        res
+    }
+
+    async fn string_descriptor(&self, index: u8, timeout: Duration) -> io::Result<String> {
+        self.device.get_string_descriptor(index, self.language, timeout)
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    async fn reset(&self) -> io::Result<()> {
+        self.device.reset()
+    }
+
+    async fn reopen(&mut self) -> io::Result<()> {
+        let Some((filter, alt)) = self.origin.clone() else {
+            return Err(io::ErrorKind::Unsupported.into());
+        };
+        let deadline = tokio::time::Instant::now() + REOPEN_TIMEOUT;
+        let dev = loop {
+            if let Some(dev) = nusb::list_devices()?.find(|d| filter.matches(d)) {
+                break dev;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} did not come back", filter)));
+            }
+            tokio::time::sleep(REOPEN_POLL).await;
+        };
+        // The lock of the old bus address is of no use any more
+        self._lock = None;
+        let lock = DeviceLock::acquire(dev.bus_number(), dev.device_address())
+            .map_err(|e| io::Error::new(io::ErrorKind::WouldBlock, e.to_string()))?;
+        let device = dev.open()?;
+        let interface = device.claim_interface(self.interface_number())?;
+        interface.set_alt_setting(alt)?;
+        log::info!("Reopened {} on bus {} device {}", filter, dev.bus_number(), dev.device_address());
+        self.device = device;
+        self.interface = interface;
+        self._lock = Some(lock);
+        Ok(())
+    }
+}
+
+mod tests {
+    #[test]
+    fn test_wire_dump() {
+        use crate::transport::wire_dump;
+        use std::time::Duration;
+        let data: Vec<u8> = (0..18).collect();
+        assert_eq!(
+            "OUT setup 21 01 0002 0000 0012 1.500 ms\n  0000: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f\n  0010: 10 11",
+            wire_dump(0x21, 1, 2, 0, 18, Ok(&data), Duration::from_micros(1500))
+        );
+        let stalled = std::io::ErrorKind::ConnectionReset.into();
+        assert!(wire_dump(0xA1, 3, 0, 0, 6, Err(&stalled), Duration::ZERO).starts_with("IN  setup a1 03 0000 0000 0006 0.000 ms failed:"));
+    }
+
+    #[test]
+    fn test_parse_string_descriptor() {
+        use crate::transport::parse_string_descriptor;
+        assert_eq!(Some("DFU".into()), parse_string_descriptor(&[8, 3, b'D', 0, b'F', 0, b'U', 0]));
+        // bLength shorter than the buffer, as when a device pads its answer
+        assert_eq!(Some("D".into()), parse_string_descriptor(&[4, 3, b'D', 0, b'F', 0]));
+        assert_eq!(None, parse_string_descriptor(&[4, 2, b'D', 0]));
+        assert_eq!(None, parse_string_descriptor(&[]));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::core::{AltSetting, Dfu, DfuDescriptor, DEFAULT_TIMEOUT, DFU_DETACH};
+use crate::device_filter::{is_dfu_mode, DeviceFilter, DFU_CLASS, DFU_SUBCLASS};
+use crate::error::Error;
+use crate::hotplug::wait_for_dfu_device_with_language;
+use std::fmt;
+use std::time::{Duration, Instant};
+use nusb::transfer::{ControlOut, ControlType, Recipient};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Detach,
+    WaitDfu,
+    Flash,
+    Verify,
+    Reset,
+    WaitApplication,
+    Done,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Phase::*;
+        match self {
+            Detach => write!(f, "Detach the application"),
+            WaitDfu => write!(f, "Wait for the DFU device"),
+            Flash => write!(f, "Flash"),
+            Verify => write!(f, "Verify"),
+            Reset => write!(f, "Leave DFU mode"),
+            WaitApplication => write!(f, "Wait for the application"),
+            Done => write!(f, "Done"),
+        }
+    }
+}
+
+/// Devices and options of an [`update`]
+#[derive(Debug, Clone)]
+pub struct Update {
+    /// The running application
+    pub runtime: DeviceFilter,
+    /// The same device in DFU mode
+    pub dfu: DeviceFilter,
+    pub iface_index: u8,
+    pub alt: AltSetting,
+    /// Limit of each wait for the device to re-enumerate
+    pub timeout: Duration,
+    pub verify: bool,
+    /// LANGID to read string descriptors in, see [`Dfu::open_with_language`]
+    pub language: Option<u16>,
+}
+
+/// Send DFU_DETACH to the runtime DFU interface of the device matching `filter`
+/// and reset it unless it detaches by itself. Returns false when no such device is connected.
+pub async fn detach_runtime(filter: &DeviceFilter) -> Result<bool, Error> {
+    let Some(dev) = nusb::list_devices()
+        .map_err(|e| Error::USB("list devices".into(), e))?
+        .find(|d| filter.matches(d) && !is_dfu_mode(d))
+    else {
+        return Ok(false);
+    };
+    let iface = dev
+        .interfaces()
+        .find(|i| i.class() == DFU_CLASS && i.subclass() == DFU_SUBCLASS)
+        .map(|i| i.interface_number())
+        .ok_or_else(|| Error::DeviceNotFound(format!("{} has no runtime DFU interface", filter)))?;
+    let usb = dev.open().map_err(|e| Error::USB("open".into(), e))?;
+    let descriptor = usb.active_configuration().ok().and_then(|conf| {
+        conf.descriptors()
+            .find(|d| d.descriptor_type() == 33)
+            .and_then(|d| DfuDescriptor::new(&d).ok())
+    });
+    let interface = usb
+        .claim_interface(iface)
+        .map_err(|e| Error::USB("Claim interface failed".into(), e))?;
+    let detach_timeout = descriptor.as_ref().map_or(1000, |d| d.detach_timeout);
+    let out = interface.control_out(ControlOut {
+        control_type: ControlType::Class,
+        recipient: Recipient::Interface,
+        request: DFU_DETACH,
+        value: detach_timeout,
+        index: iface as u16,
+        data: &[],
+    });
+    match tokio::time::timeout(DEFAULT_TIMEOUT, out).await {
+        Ok(c) => c.into_result().map_err(|e| Error::USB("Detach".into(), e.into()))?,
+        Err(_) => return Err(Error::USB("Detach".into(), std::io::ErrorKind::TimedOut.into())),
+    };
+    if descriptor.is_none_or(|d| !d.will_detach()) {
+        log::debug!("Device does not detach by itself, resetting it");
+        if let Err(e) = usb.reset() {
+            // Devices often drop off the bus while the reset is still being acknowledged
+            log::debug!("Reset after detach: {}", e);
+        }
+    }
+    Ok(true)
+}
+
+/// Poll until a device matching `filter` enumerates outside of DFU mode
+async fn wait_for_application(filter: &DeviceFilter, timeout: Duration) -> Result<(), Error> {
+    let end = Instant::now() + timeout;
+    loop {
+        if nusb::list_devices()
+            .map_err(|e| Error::USB("list devices".into(), e))?
+            .any(|d| filter.matches(&d) && !is_dfu_mode(&d))
+        {
+            return Ok(());
+        }
+        if Instant::now() > end {
+            return Err(Error::DeviceNotFound(format!("{} within {:?}", filter, timeout)));
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+/// Bring the application matching `u.runtime` into DFU mode, flash `image` at the address
+/// `prepare` returns, optionally verify, start the application again and wait for it to come
+/// back. `prepare` gets the opened device first, e.g. to set its protected ranges. Without a
+/// running application a device already in DFU mode is flashed directly. `on_phase` is called
+/// as each phase starts.
+pub async fn update(
+    u: &Update,
+    image: &[u8],
+    prepare: impl FnOnce(&mut Dfu) -> Result<u32, Error>,
+    mut on_phase: impl FnMut(Phase),
+) -> Result<(), Error> {
+    on_phase(Phase::Detach);
+    if !detach_runtime(&u.runtime).await? {
+        log::info!("No application matching {} running, expecting DFU mode", u.runtime);
+    }
+    on_phase(Phase::WaitDfu);
+    let mut dfu = wait_for_dfu_device_with_language(&u.dfu, u.timeout, u.iface_index, &u.alt, u.language).await?;
+    let address = prepare(&mut dfu)?;
+
+    on_phase(Phase::Flash);
+    dfu.download_slice(image, address).await?;
+    if u.verify {
+        on_phase(Phase::Verify);
+        dfu.verify_slice(image, address).await?;
+    }
+    on_phase(Phase::Reset);
+    dfu.reset_stm32(address).await?;
+    drop(dfu);
+
+    on_phase(Phase::WaitApplication);
+    wait_for_application(&u.runtime, u.timeout).await?;
+    on_phase(Phase::Done);
+    Ok(())
+}
@@ -0,0 +1,190 @@
+use crate::core::{parse_functional_descriptor, Dfu, DfuDescriptor};
+use crate::error::Error;
+use crate::memory_layout::MemoryLayout;
+use crate::transport::{parse_string_descriptor, DfuTransport};
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+use futures_lite::future;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    UsbAlternateInterface, UsbControlTransferParameters, UsbDevice, UsbInTransferResult, UsbInterface,
+    UsbOutTransferResult, UsbRecipient, UsbRequestType, UsbTransferStatus,
+};
+
+const GET_DESCRIPTOR: u8 = 6;
+const CONFIGURATION: u16 = 2;
+const STRING: u16 = 3;
+const US_ENGLISH: u16 = 0x0409;
+
+/// DFU interface of a WebUSB device, as granted by `navigator.usb.requestDevice()`
+pub struct WebUsbTransport {
+    device: UsbDevice,
+    interface_number: u8,
+}
+
+fn js_error(what: &str, e: wasm_bindgen::JsValue) -> io::Error {
+    io::Error::other(format!("{}: {:?}", what, e))
+}
+
+fn check(status: UsbTransferStatus) -> io::Result<()> {
+    match status {
+        UsbTransferStatus::Ok => Ok(()),
+        UsbTransferStatus::Stall => Err(io::ErrorKind::ConnectionReset.into()),
+        s => Err(io::Error::other(format!("transfer status {:?}", s))),
+    }
+}
+
+async fn sleep(duration: Duration) {
+    let Some(window) = web_sys::window() else {
+        return;
+    };
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        let _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(
+            &resolve,
+            duration.as_millis().min(i32::MAX as u128) as i32,
+        );
+    });
+    let _ = JsFuture::from(promise).await;
+}
+
+/// Fail with `TimedOut` unless `transfer` completes within `timeout`
+async fn timed<T>(timeout: Duration, transfer: impl std::future::Future<Output = io::Result<T>>) -> io::Result<T> {
+    future::or(transfer, async {
+        sleep(timeout).await;
+        log::warn!("Control transfer timed out after {:?}", timeout);
+        Err(io::ErrorKind::TimedOut.into())
+    })
+    .await
+}
+
+fn setup(request_type: UsbRequestType, recipient: UsbRecipient, request: u8, value: u16, index: u16) -> UsbControlTransferParameters {
+    UsbControlTransferParameters::new(index, recipient, request, request_type, value)
+}
+
+impl WebUsbTransport {
+    /// The configuration descriptor including the DFU functional descriptor, WebUSB does not expose it
+    async fn configuration_descriptor(&self) -> io::Result<Vec<u8>> {
+        let setup = setup(UsbRequestType::Standard, UsbRecipient::Device, GET_DESCRIPTOR, CONFIGURATION << 8, 0);
+        let head = self.transfer_in(&setup, 9).await?;
+        let total = head.get(2..4).map(|b| u16::from_le_bytes([b[0], b[1]])).unwrap_or(0);
+        self.transfer_in(&setup, total).await
+    }
+
+    async fn transfer_in(&self, setup: &UsbControlTransferParameters, length: u16) -> io::Result<Vec<u8>> {
+        let result = JsFuture::from(self.device.control_transfer_in(setup, length))
+            .await
+            .map_err(|e| js_error("controlTransferIn", e))?
+            .unchecked_into::<UsbInTransferResult>();
+        check(result.status())?;
+        Ok(result
+            .data()
+            .map(|view| js_sys::Uint8Array::new(&view.buffer()).subarray(
+                view.byte_offset() as u32,
+                (view.byte_offset() + view.byte_length()) as u32,
+            ).to_vec())
+            .unwrap_or_default())
+    }
+
+    /// Open `device`, claim interface `iface_index` with alt setting `alt` and read its memory layout
+    /// from the alt setting name
+    pub async fn open(device: UsbDevice, iface_index: u8, alt: u8) -> Result<Dfu<WebUsbTransport>, Error> {
+        let usb = |what: &str, e| Error::USB(what.into(), js_error(what, e));
+        JsFuture::from(device.open()).await.map_err(|e| usb("open", e))?;
+        JsFuture::from(device.claim_interface(iface_index))
+            .await
+            .map_err(|e| usb("claim interface", e))?;
+        JsFuture::from(device.select_alternate_interface(iface_index, alt))
+            .await
+            .map_err(|e| usb("select alternate interface", e))?;
+
+        let name = device
+            .configuration()
+            .ok_or_else(|| Error::DeviceNotFound("Missing active configuration".to_string()))?
+            .interfaces()
+            .iter()
+            .map(|i| i.unchecked_into::<UsbInterface>())
+            .find(|i| i.interface_number() == iface_index)
+            .and_then(|i| {
+                i.alternates()
+                    .iter()
+                    .map(|a| a.unchecked_into::<UsbAlternateInterface>())
+                    .find(|a| a.alternate_setting() == alt)
+            })
+            .and_then(|a| a.interface_name())
+            .ok_or_else(|| Error::DeviceNotFound("Missing configuration descriptor".to_string()))?;
+        let mem_layout = MemoryLayout::from_str(&name)?;
+
+        let identity = (
+            device.manufacturer_name(),
+            device.product_name(),
+            device.serial_number(),
+            // WebUSB splits bcdDevice into its digits
+            (device.device_version_major() as u16) << 8
+                | (device.device_version_minor() as u16) << 4
+                | device.device_version_subminor() as u16,
+        );
+        let transport = WebUsbTransport {
+            device,
+            interface_number: iface_index,
+        };
+        let config = transport
+            .configuration_descriptor()
+            .await
+            .map_err(|e| Error::USB("Get configuration descriptor".into(), e))?;
+        let (dfu_descriptor, warning) = DfuDescriptor::or_fallback(parse_functional_descriptor(&config));
+        let mut dfu = Dfu::with_transport(transport, dfu_descriptor, mem_layout);
+        dfu.set_descriptor_warning(warning);
+        dfu.set_alt_setting(alt);
+        dfu.set_identity(identity.0, identity.1, identity.2, Some(identity.3));
+        dfu.abort_to_idle_clear_once().await?;
+        Ok(dfu)
+    }
+
+    pub fn device(&self) -> &UsbDevice {
+        &self.device
+    }
+}
+
+impl DfuTransport for WebUsbTransport {
+    fn interface_number(&self) -> u8 {
+        self.interface_number
+    }
+
+    async fn control_in(&self, request: u8, value: u16, length: u16, timeout: Duration) -> io::Result<Vec<u8>> {
+        let setup = setup(UsbRequestType::Class, UsbRecipient::Interface, request, value, self.interface_number as u16);
+        timed(timeout, self.transfer_in(&setup, length)).await
+    }
+
+    async fn control_out(&self, request: u8, value: u16, data: &[u8], timeout: Duration) -> io::Result<()> {
+        let setup = setup(UsbRequestType::Class, UsbRecipient::Interface, request, value, self.interface_number as u16);
+        let promise = self
+            .device
+            .control_transfer_out_with_u8_slice(&setup, &mut data.to_vec())
+            .map_err(|e| js_error("controlTransferOut", e))?;
+        timed(timeout, async {
+            let result = JsFuture::from(promise)
+                .await
+                .map_err(|e| js_error("controlTransferOut", e))?
+                .unchecked_into::<UsbOutTransferResult>();
+            check(result.status())
+        })
+        .await
+    }
+
+    async fn string_descriptor(&self, index: u8, timeout: Duration) -> io::Result<String> {
+        let setup = setup(UsbRequestType::Standard, UsbRecipient::Device, GET_DESCRIPTOR, STRING << 8 | index as u16, US_ENGLISH);
+        let desc = timed(timeout, self.transfer_in(&setup, 255)).await?;
+        parse_string_descriptor(&desc).ok_or_else(|| io::Error::other("invalid string descriptor"))
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        sleep(duration).await
+    }
+
+    async fn reset(&self) -> io::Result<()> {
+        JsFuture::from(self.device.reset()).await.map_err(|e| js_error("reset", e))?;
+        Ok(())
+    }
+}
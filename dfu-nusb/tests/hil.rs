@@ -0,0 +1,74 @@
+// Hardware-in-the-loop tests, against the board named by DFU_TEST_DEVICE=<vid:pid>
+//
+//     DFU_TEST_DEVICE=0483:df11 cargo test -p dfu-nusb --test hil -- --ignored
+//
+// DFU_TEST_ALT selects the alt setting (default 0) and DFU_TEST_SCRATCH the address of the page
+// that is overwritten and restored (default the last page of the layout).
+
+use dfu_nusb::{Dfu, Error, MemoryLayout};
+use std::io::Cursor;
+
+fn env_u32(name: &str) -> Option<u32> {
+    let v = std::env::var(name).ok()?;
+    let v = v.trim();
+    match v.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => v.parse().ok(),
+    }
+}
+
+fn test_device() -> Option<(u16, u16)> {
+    let dev = std::env::var("DFU_TEST_DEVICE").ok()?;
+    let (vid, pid) = dev.split_once(':').expect("DFU_TEST_DEVICE must be vid:pid");
+    let parse = |s: &str| u16::from_str_radix(s, 16).expect("DFU_TEST_DEVICE must be hex vid:pid");
+    Some((parse(vid), parse(pid)))
+}
+
+/// Page to overwrite, which must allow reading, erasing and writing
+fn scratch_page(layout: &MemoryLayout) -> (u32, u32) {
+    let page = match env_u32("DFU_TEST_SCRATCH") {
+        Some(address) => layout.address(address).expect("DFU_TEST_SCRATCH outside the layout"),
+        None => layout.pages().last().expect("layout without pages").clone(),
+    };
+    if let Some(access) = page.access {
+        assert!(
+            access.readable() && access.erasable() && access.writable(),
+            "scratch page 0x{:08X} is {}",
+            page.address,
+            access
+        );
+    }
+    (page.address, page.size)
+}
+
+async fn write_verify(dfu: &mut Dfu, address: u32, image: &[u8]) -> Result<(), Error> {
+    dfu.download_raw(&mut Cursor::new(image), address, image.len() as u32).await?;
+    dfu.verify(&mut Cursor::new(image), address, image.len() as u32).await
+}
+
+#[test]
+#[ignore = "needs a board, set DFU_TEST_DEVICE=<vid:pid>"]
+fn hil_write_verify_restore() {
+    let Some((vid, pid)) = test_device() else {
+        eprintln!("DFU_TEST_DEVICE not set, skipping");
+        return;
+    };
+    let alt = env_u32("DFU_TEST_ALT").unwrap_or(0) as u8;
+    let rt = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+    rt.block_on(async {
+        let mut dfu = Dfu::from_vid_pid(vid, pid, 0, alt).await.expect("open test device");
+        assert!(!dfu.memory_layout().pages().is_empty());
+        let (address, size) = scratch_page(dfu.memory_layout());
+
+        let mut original = Vec::new();
+        dfu.upload(&mut original, address, size).await.expect("read scratch page");
+        assert_eq!(size as usize, original.len());
+
+        let pattern: Vec<u8> = (0..size).map(|i| (i % 251) as u8 ^ 0xA5).collect();
+        let written = write_verify(&mut dfu, address, &pattern).await;
+        // Put the page back even when writing the pattern failed
+        let restored = write_verify(&mut dfu, address, &original).await;
+        written.expect("write and verify pattern");
+        restored.expect("restore scratch page");
+    });
+}